@@ -0,0 +1,429 @@
+use crate::config::{BreakpointTimeoutAction, Config, RewritePhase};
+use crate::interceptor::{Action, Flow, Interceptor};
+use crate::rewrite::HeaderList;
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// 对暂停的流的编辑，没有设置的字段保持不变
+///
+/// 请求断点可以编辑 `method`、`url`、`headers` 和请求体，响应断点可以编辑 `status`、`headers` 和响应体。
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlowEdit {
+    /// 请求方法
+    pub method: Option<String>,
+    /// 请求URL，只能修改路径和查询参数
+    pub url: Option<String>,
+    /// 响应状态码
+    pub status: Option<u16>,
+    /// 全部请求头或响应头，如 `[["Accept", "*/*"]]`
+    pub headers: Option<HeaderList>,
+    /// 文本消息体
+    pub body: Option<String>,
+    /// base64编码的二进制消息体
+    pub body_base64: Option<String>,
+}
+
+/// 暂停的流的处理结果
+#[derive(Debug)]
+enum Resume {
+    /// 按编辑后的内容继续
+    Continue(Box<Flow>),
+    /// 中止，断开客户端连接
+    Abort,
+}
+
+/// 暂停中的流
+#[derive(Debug)]
+struct PausedFlow {
+    /// 暂停的流（包括已经做出的编辑）
+    flow: Flow,
+    /// 暂停的时间
+    paused_at: Instant,
+    /// 通知暂停的请求继续处理
+    resume: oneshot::Sender<Resume>,
+}
+
+/// 断点管理器，保存命中断点规则而暂停的流，由控制接口编辑、继续或中止
+///
+/// 超过配置的时间没有处理的流按 `on_timeout` 自动继续（保留已做出的编辑）或中止。
+#[derive(Debug)]
+pub struct Breakpoints {
+    /// 配置信息（断点规则和超时时间）
+    config: Arc<Config>,
+    /// 下一个暂停的流的编号
+    next_id: AtomicU64,
+    /// 暂停中的流
+    paused: Mutex<BTreeMap<u64, PausedFlow>>,
+}
+
+impl Breakpoints {
+    /// 创建断点管理器
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+            paused: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 暂停流，直到通过控制接口继续或中止，或者等待超时
+    ///
+    /// # 参数
+    /// * `flow` - 暂停的流，有响应时为响应断点
+    ///
+    /// # 返回值
+    /// 返回编辑后的流，被中止时返回None
+    pub async fn pause(&self, flow: Flow) -> Option<Flow> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (resume, mut resumed) = oneshot::channel();
+        log::info!("⏸️ Breakpoint #{id}: paused {} {} {}", phase_name(flow.phase()), flow.request.method, flow.request.url());
+        self.paused.lock().unwrap().insert(id, PausedFlow { flow, paused_at: Instant::now(), resume });
+
+        let timeout = Duration::from_secs(self.config.breakpoints.timeout_secs);
+        if let Ok(Ok(resume)) = tokio::time::timeout(timeout, &mut resumed).await {
+            return match resume {
+                Resume::Continue(flow) => Some(*flow),
+                Resume::Abort => None,
+            };
+        }
+
+        // 超时后按配置继续或中止；已经从列表中取出时说明刚刚被处理，以处理结果为准
+        let paused = self.paused.lock().unwrap().remove(&id);
+        if let Some(paused) = paused {
+            return match self.config.breakpoints.on_timeout {
+                BreakpointTimeoutAction::Continue => {
+                    log::warn!("⏯️ Breakpoint #{id}: timed out after {}s, continuing", timeout.as_secs());
+                    Some(paused.flow)
+                },
+                BreakpointTimeoutAction::Abort => {
+                    log::warn!("⏹️ Breakpoint #{id}: timed out after {}s, aborting", timeout.as_secs());
+                    None
+                },
+            };
+        }
+        match resumed.try_recv() {
+            Ok(Resume::Continue(flow)) => Some(*flow),
+            _ => None,
+        }
+    }
+
+    /// 所有暂停中的流
+    pub fn list(&self) -> Vec<serde_json::Value> {
+        let paused = self.paused.lock().unwrap();
+        paused.iter().map(|(id, paused)| self.describe(*id, paused)).collect()
+    }
+
+    /// 查看暂停中的流
+    ///
+    /// # 返回值
+    /// 流不存在（已经继续、中止或超时）时返回None
+    pub fn get(&self, id: u64) -> Option<serde_json::Value> {
+        let paused = self.paused.lock().unwrap();
+        paused.get(&id).map(|paused| self.describe(id, paused))
+    }
+
+    /// 编辑暂停中的流
+    ///
+    /// # 返回值
+    /// 返回编辑后的流，流不存在时返回None；编辑的内容无效时返回错误
+    pub fn edit(&self, id: u64, edit: FlowEdit) -> Result<Option<serde_json::Value>> {
+        let mut paused = self.paused.lock().unwrap();
+        let Some(entry) = paused.get_mut(&id) else {
+            return Ok(None);
+        };
+        apply_edit(&mut entry.flow, edit)?;
+        Ok(Some(self.describe(id, entry)))
+    }
+
+    /// 按编辑后的内容继续暂停中的流
+    ///
+    /// # 参数
+    /// * `id` - 流的编号
+    /// * `edit` - 继续前的最后编辑
+    ///
+    /// # 返回值
+    /// 流不存在时返回false；编辑的内容无效时返回错误，流保持暂停
+    pub fn resume(&self, id: u64, edit: FlowEdit) -> Result<bool> {
+        let mut paused = self.paused.lock().unwrap();
+        let Some(entry) = paused.get_mut(&id) else {
+            return Ok(false);
+        };
+        apply_edit(&mut entry.flow, edit)?;
+        let Some(entry) = paused.remove(&id) else {
+            return Ok(false);
+        };
+        log::info!("⏯️ Breakpoint #{id}: continued");
+        let _ = entry.resume.send(Resume::Continue(Box::new(entry.flow)));
+        Ok(true)
+    }
+
+    /// 中止暂停中的流
+    ///
+    /// # 返回值
+    /// 流不存在时返回false
+    pub fn abort(&self, id: u64) -> bool {
+        let Some(entry) = self.paused.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        log::info!("⏹️ Breakpoint #{id}: aborted");
+        let _ = entry.resume.send(Resume::Abort);
+        true
+    }
+
+    /// 控制接口返回的流信息
+    fn describe(&self, id: u64, paused: &PausedFlow) -> serde_json::Value {
+        let flow = &paused.flow;
+        let mut request = serde_json::json!({
+            "method": flow.request.method,
+            "url": flow.request.url(),
+            "headers": flow.request.headers,
+        });
+        insert_body(&mut request, &flow.request.body);
+        let response = flow.response.as_ref().map(|response| {
+            let mut value = serde_json::json!({
+                "status": response.status,
+                "headers": response.headers,
+            });
+            insert_body(&mut value, &response.body);
+            value
+        });
+
+        serde_json::json!({
+            "id": id,
+            "phase": phase_name(flow.phase()),
+            "host": flow.host,
+            "paused_secs": paused.paused_at.elapsed().as_secs(),
+            "timeout_secs": self.config.breakpoints.timeout_secs,
+            "on_timeout": self.config.breakpoints.on_timeout,
+            "request": request,
+            "response": response,
+        })
+    }
+}
+
+/// 断点作为最后一个拦截器，在其他拦截器处理之后暂停命中规则的流
+#[async_trait]
+impl Interceptor for Breakpoints {
+    fn name(&self) -> &str {
+        "breakpoint"
+    }
+
+    fn intercepts_host(&self, host: &str) -> bool {
+        self.config.breakpoints.rules.iter().any(|rule| rule.flow.matches_host(host))
+    }
+
+    fn matches(&self, phase: RewritePhase, host: &str, method: &str, path: &str) -> bool {
+        self.config.has_breakpoint(phase, host, method, path)
+    }
+
+    async fn on_request(&self, flow: &mut Flow) -> Action {
+        resume_flow(flow, self.pause(flow.clone()).await)
+    }
+
+    async fn on_response(&self, flow: &mut Flow) -> Action {
+        resume_flow(flow, self.pause(flow.clone()).await)
+    }
+}
+
+/// 按断点的处理结果更新流
+fn resume_flow(flow: &mut Flow, resumed: Option<Flow>) -> Action {
+    match resumed {
+        Some(resumed) => {
+            *flow = resumed;
+            Action::Continue
+        },
+        None => Action::Drop,
+    }
+}
+
+/// 断点类型的名称
+fn phase_name(phase: RewritePhase) -> &'static str {
+    match phase {
+        RewritePhase::Request => "request",
+        RewritePhase::Response => "response",
+    }
+}
+
+/// 添加消息体，UTF-8文本使用 `body`，其他内容使用base64编码的 `body_base64`
+fn insert_body(message: &mut serde_json::Value, body: &[u8]) {
+    let (key, value) = match std::str::from_utf8(body) {
+        Ok(text) => ("body", text.to_string()),
+        Err(_) => ("body_base64", base64::engine::general_purpose::STANDARD.encode(body)),
+    };
+    message[key] = serde_json::Value::String(value);
+}
+
+/// 把编辑应用到暂停的流，编辑无效时流保持不变
+fn apply_edit(flow: &mut Flow, edit: FlowEdit) -> Result<()> {
+    let body = match (edit.body, edit.body_base64) {
+        (Some(_), Some(_)) => anyhow::bail!("Only one of body and body_base64 can be set"),
+        (Some(body), None) => Some(body.into_bytes()),
+        (None, Some(body)) => Some(base64::engine::general_purpose::STANDARD.decode(body)?),
+        (None, None) => None,
+    };
+
+    match flow.response.as_mut() {
+        Some(response) => {
+            if edit.method.is_some() || edit.url.is_some() {
+                anyhow::bail!("The request has already been sent, only the response can be edited");
+            }
+            if let Some(status) = edit.status {
+                if !(100..1000).contains(&status) {
+                    anyhow::bail!("Invalid status code: {status}");
+                }
+                response.status = status;
+            }
+            if let Some(headers) = edit.headers {
+                response.headers = headers;
+            }
+            if let Some(body) = body {
+                response.body = body;
+            }
+        },
+        None => {
+            if edit.status.is_some() {
+                anyhow::bail!("Status can only be edited at a response breakpoint");
+            }
+            // 在副本上修改，编辑无效时请求保持不变
+            let mut request = flow.request.clone();
+            if let Some(url) = &edit.url {
+                request.set_url(url)?;
+            }
+            if let Some(method) = &edit.method {
+                request.set_method(method)?;
+            }
+            if let Some(headers) = edit.headers {
+                request.headers = headers;
+            }
+            if let Some(body) = body {
+                request.body = body;
+            }
+            flow.request = request;
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interceptor::{FlowRequest, FlowResponse};
+
+    fn create_breakpoints(timeout_secs: u64, on_timeout: &str) -> Arc<Breakpoints> {
+        let config: Config = serde_json::from_str(&format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": ["*"], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" }},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }},
+            "breakpoints": {{
+                "rules": [{{ "hosts": ["api.example.com"], "paths": ["/v1/*"] }}],
+                "timeout_secs": {timeout_secs},
+                "on_timeout": "{on_timeout}"
+            }}
+        }}"#)).unwrap();
+        Arc::new(Breakpoints::new(Arc::new(config)))
+    }
+
+    fn create_flow(response: Option<FlowResponse>) -> Flow {
+        Flow {
+            host: "api.example.com".to_string(),
+            request: FlowRequest {
+                method: "GET".to_string(),
+                origin: "https://api.example.com".to_string(),
+                path: "/v1/user".to_string(),
+                headers: vec![("Host".to_string(), "api.example.com".to_string())],
+                body: Vec::new(),
+            },
+            response,
+            error: None,
+        }
+    }
+
+    /// 等待流出现在暂停列表中
+    async fn wait_paused(breakpoints: &Breakpoints) -> u64 {
+        loop {
+            if let Some(flow) = breakpoints.list().first() {
+                return flow["id"].as_u64().unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_edit_and_continue_request() {
+        let breakpoints = create_breakpoints(60, "continue");
+        assert!(breakpoints.matches(RewritePhase::Request, "api.example.com", "POST", "/v1/user"));
+        assert!(!breakpoints.matches(RewritePhase::Response, "api.example.com", "GET", "/v1/user"));
+
+        let paused = tokio::spawn({
+            let breakpoints = Arc::clone(&breakpoints);
+            async move { breakpoints.pause(create_flow(None)).await }
+        });
+        let id = wait_paused(&breakpoints).await;
+        let flow = breakpoints.get(id).unwrap();
+        assert_eq!(flow["phase"], "request");
+        assert_eq!(flow["request"]["url"], "https://api.example.com/v1/user");
+        assert_eq!(flow["request"]["body"], "");
+
+        // 无效的编辑不改变流
+        let edit = |json: serde_json::Value| serde_json::from_value::<FlowEdit>(json).unwrap();
+        assert!(breakpoints.edit(id, edit(serde_json::json!({ "url": "https://evil.com/v1/user" }))).is_err());
+        assert!(breakpoints.edit(id, edit(serde_json::json!({ "status": 500 }))).is_err());
+        assert!(breakpoints.edit(id, edit(serde_json::json!({ "method": "GET /x" }))).is_err());
+
+        let edited = breakpoints.edit(id, edit(serde_json::json!({ "method": "POST", "url": "https://api.example.com/v2?x=1" }))).unwrap().unwrap();
+        assert_eq!(edited["request"]["method"], "POST");
+        assert!(breakpoints.resume(id, edit(serde_json::json!({ "body_base64": "/w==" }))).unwrap());
+        assert!(!breakpoints.abort(id));
+
+        let flow = paused.await.unwrap().unwrap();
+        assert_eq!(flow.request.method, "POST");
+        assert_eq!(flow.request.path, "/v2?x=1");
+        assert_eq!(flow.request.body, [0xff]);
+        assert!(breakpoints.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abort_and_timeout() {
+        let breakpoints = create_breakpoints(60, "continue");
+        let response = FlowResponse { status: 200, headers: Vec::new(), body: b"ok".to_vec() };
+        let paused = tokio::spawn({
+            let breakpoints = Arc::clone(&breakpoints);
+            let flow = create_flow(Some(response.clone()));
+            async move { breakpoints.pause(flow).await }
+        });
+        let id = wait_paused(&breakpoints).await;
+        assert_eq!(breakpoints.get(id).unwrap()["response"]["body"], "ok");
+        assert!(breakpoints.edit(id, serde_json::from_str(r#"{ "method": "PUT" }"#).unwrap()).is_err());
+        assert!(breakpoints.abort(id));
+        assert!(paused.await.unwrap().is_none());
+        assert!(breakpoints.get(id).is_none());
+
+        // 超时后按已做出的编辑自动继续
+        let breakpoints = create_breakpoints(0, "continue");
+        let flow = breakpoints.pause(create_flow(Some(response.clone()))).await.unwrap();
+        assert_eq!(flow.response.unwrap().body, b"ok");
+
+        // 配置为中止时超时后断开客户端连接
+        let breakpoints = create_breakpoints(0, "abort");
+        assert!(breakpoints.pause(create_flow(Some(response))).await.is_none());
+        assert!(breakpoints.list().is_empty());
+    }
+}
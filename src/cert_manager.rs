@@ -1,7 +1,7 @@
 use anyhow::Result;
 use std::process::Command;
 #[cfg(target_os = "linux")]
-use std::path::PathBuf;
+use std::path::Path;
 
 /// 证书环境管理器 - 负责CA证书的系统级安装和卸载
 ///
@@ -183,7 +183,7 @@ impl CertManager {
 
         // 复制证书到系统证书目录
         let copy_output = Command::new("sudo")
-            .args(&["cp", cert_path, &target_path])
+            .args(["cp", cert_path, &target_path])
             .output()?;
 
         if !copy_output.status.success() {
@@ -194,7 +194,7 @@ impl CertManager {
 
         // 更新证书存储
         let update_output = Command::new("sudo")
-            .args(&["update-ca-certificates"])
+            .args(["update-ca-certificates"])
             .output()?;
 
         if update_output.status.success() {
@@ -225,7 +225,7 @@ impl CertManager {
 
         // 删除证书文件
         let remove_output = Command::new("sudo")
-            .args(&["rm", &cert_path])
+            .args(["rm", &cert_path])
             .output()?;
 
         if !remove_output.status.success() {
@@ -236,7 +236,7 @@ impl CertManager {
 
         // 更新证书存储
         let update_output = Command::new("sudo")
-            .args(&["update-ca-certificates", "--fresh"])
+            .args(["update-ca-certificates", "--fresh"])
             .output()?;
 
         if update_output.status.success() {
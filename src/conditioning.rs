@@ -0,0 +1,391 @@
+use crate::config::{Config, NetworkProfile};
+use crate::net::ClientSocket;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Sleep};
+
+/// 不限制的网络模拟名称
+pub const NO_PROFILE: &str = "none";
+
+/// 单次读写的最大字节数，限速时数据按这个大小分段发送
+const MAX_SEGMENT: usize = 16 * 1024;
+
+/// 预设的网络模拟参数
+///
+/// # 参数
+/// * `name` - 预设名称：3g、edge、lossy-wifi
+pub fn preset(name: &str) -> Option<NetworkProfile> {
+    let profile = match name {
+        "3g" => NetworkProfile {
+            download_kbps: 780,
+            upload_kbps: 330,
+            latency_ms: 100,
+            jitter_ms: 20,
+            stall_probability: 0.0,
+            stall_ms: 0,
+        },
+        "edge" => NetworkProfile {
+            download_kbps: 240,
+            upload_kbps: 200,
+            latency_ms: 400,
+            jitter_ms: 50,
+            stall_probability: 0.0,
+            stall_ms: 0,
+        },
+        "lossy-wifi" => NetworkProfile {
+            download_kbps: 10_000,
+            upload_kbps: 5_000,
+            latency_ms: 30,
+            jitter_ms: 30,
+            stall_probability: 0.05,
+            stall_ms: 800,
+        },
+        _ => return None,
+    };
+    Some(profile)
+}
+
+/// 预设名称
+const PRESETS: [&str; 3] = ["3g", "edge", "lossy-wifi"];
+
+/// 数据传输方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 客户端到上游
+    Upload,
+    /// 上游到客户端
+    Download,
+}
+
+/// 网络模拟器，按域名规则或全局设置为客户端连接限速和增加延迟
+///
+/// 全局设置可以在运行时切换，已经建立的连接在下一次读写时使用新的设置。
+#[derive(Debug)]
+pub struct NetworkConditioner {
+    /// 配置信息（域名规则和自定义参数）
+    config: Arc<Config>,
+    /// 当前全局使用的网络模拟名称
+    active: RwLock<Option<String>>,
+}
+
+impl NetworkConditioner {
+    /// 创建网络模拟器
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    ///
+    /// # 返回值
+    /// 全局设置或规则引用了不存在的网络模拟名称时返回错误
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        let conditioner = Self {
+            active: RwLock::new(None),
+            config,
+        };
+        for rule in &conditioner.config.network.rules {
+            conditioner.lookup(&rule.profile)?;
+        }
+        conditioner.set_active_profile(conditioner.config.network.profile.clone())?;
+        Ok(conditioner)
+    }
+
+    /// 查找网络模拟参数，自定义参数优先于预设
+    ///
+    /// # 返回值
+    /// "none"返回None，名称不存在时返回错误
+    fn lookup(&self, name: &str) -> Result<Option<NetworkProfile>> {
+        if let Some(profile) = self.config.network.profiles.get(name) {
+            return Ok(Some(*profile));
+        }
+        if name == NO_PROFILE {
+            return Ok(None);
+        }
+        preset(name)
+            .map(Some)
+            .ok_or_else(|| anyhow::anyhow!("Unknown network profile: {name}"))
+    }
+
+    /// 当前全局使用的网络模拟名称
+    pub fn active_profile(&self) -> Option<String> {
+        self.active.read().unwrap().clone()
+    }
+
+    /// 切换全局使用的网络模拟
+    ///
+    /// # 参数
+    /// * `name` - 网络模拟名称，None或"none"表示不限制
+    pub fn set_active_profile(&self, name: Option<String>) -> Result<()> {
+        let name = name.filter(|name| name != NO_PROFILE);
+        if let Some(name) = &name {
+            self.lookup(name)?;
+            log::info!("📶 Network profile switched to {name}");
+        }
+        *self.active.write().unwrap() = name;
+        Ok(())
+    }
+
+    /// 所有可用的网络模拟参数（预设和自定义）
+    pub fn profiles(&self) -> BTreeMap<String, NetworkProfile> {
+        let mut profiles: BTreeMap<String, NetworkProfile> = PRESETS.iter()
+            .filter_map(|name| Some((name.to_string(), preset(name)?)))
+            .collect();
+        profiles.extend(self.config.network.profiles.iter().map(|(name, profile)| (name.clone(), *profile)));
+        profiles
+    }
+
+    /// 查找指定主机当前使用的网络模拟参数
+    ///
+    /// # 返回值
+    /// 不限制时返回None
+    pub fn profile_for(&self, host: &str) -> Option<NetworkProfile> {
+        let name = match self.config.network_rule_for(host) {
+            Some(rule) => rule.profile.clone(),
+            None => self.active_profile()?,
+        };
+        self.lookup(&name).ok().flatten()
+    }
+
+    /// 包装客户端连接，读取时按上传、写入时按下载限速
+    ///
+    /// # 参数
+    /// * `host` - 连接的目标主机
+    /// * `stream` - 客户端连接
+    pub fn wrap<S>(self: &Arc<Self>, host: &str, stream: S) -> Conditioned<S> {
+        Conditioned {
+            inner: stream,
+            link: Link {
+                conditioner: Arc::clone(self),
+                host: host.to_string(),
+                state: Arc::new(Mutex::new(LinkState::default())),
+            },
+            held: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// 一个客户端连接的发送计划
+#[derive(Debug, Default)]
+struct LinkState {
+    /// 上一次传输数据的方向
+    last_direction: Option<Direction>,
+    /// 上传方向的下一次可以发送的时间
+    upload_free_at: Option<Instant>,
+    /// 下载方向的下一次可以发送的时间
+    download_free_at: Option<Instant>,
+}
+
+/// 客户端连接的限速状态
+#[derive(Debug, Clone)]
+struct Link {
+    conditioner: Arc<NetworkConditioner>,
+    host: String,
+    state: Arc<Mutex<LinkState>>,
+}
+
+impl Link {
+    /// 当前使用的网络模拟参数
+    fn profile(&self) -> Option<NetworkProfile> {
+        self.conditioner.profile_for(&self.host)
+    }
+
+    /// 计算一段数据可以交付的时间
+    ///
+    /// 传输方向改变时先增加延迟，然后按带宽排队发送，偶尔发生卡顿。
+    ///
+    /// # 参数
+    /// * `profile` - 网络模拟参数
+    /// * `direction` - 传输方向
+    /// * `bytes` - 数据字节数
+    fn schedule(&self, profile: &NetworkProfile, direction: Direction, bytes: usize) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut ready_at = now;
+
+        if state.last_direction != Some(direction) {
+            state.last_direction = Some(direction);
+            let jitter = match profile.jitter_ms {
+                0 => 0,
+                jitter => fastrand::i64(-(jitter as i64)..=jitter as i64),
+            };
+            ready_at += Duration::from_millis((profile.latency_ms as i64 + jitter).max(0) as u64);
+        }
+        if profile.stall_probability > 0.0 && fastrand::f64() < profile.stall_probability {
+            log::debug!("Stalling {direction:?} to {} for {}ms", self.host, profile.stall_ms);
+            ready_at += Duration::from_millis(profile.stall_ms);
+        }
+
+        let (free_at, kbps) = match direction {
+            Direction::Upload => (&mut state.upload_free_at, profile.upload_kbps),
+            Direction::Download => (&mut state.download_free_at, profile.download_kbps),
+        };
+        // 带宽为0时不限速
+        if let Some(micros) = (bytes as u64 * 8 * 1000).checked_div(kbps) {
+            let start = free_at.map_or(ready_at, |free_at| free_at.max(ready_at));
+            ready_at = start + Duration::from_micros(micros);
+            *free_at = Some(ready_at);
+        }
+        ready_at
+    }
+}
+
+/// 按网络模拟参数限速的客户端连接
+pub struct Conditioned<S> {
+    inner: S,
+    link: Link,
+    /// 已从客户端读取、等待上传的数据
+    held: Option<(Vec<u8>, Pin<Box<Sleep>>)>,
+    /// 写入客户端之前的等待
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: ClientSocket> ClientSocket for Conditioned<S> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.inner.tcp_stream()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Conditioned<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some((data, delay)) = this.held.as_mut() {
+                ready!(delay.as_mut().poll(cx));
+                let n = data.len().min(buf.remaining());
+                buf.put_slice(&data[..n]);
+                data.drain(..n);
+                if data.is_empty() {
+                    this.held = None;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let Some(profile) = this.link.profile() else {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            };
+            let mut data = vec![0; buf.remaining().min(MAX_SEGMENT)];
+            let mut read_buf = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            let bytes_read = read_buf.filled().len();
+            if bytes_read == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            data.truncate(bytes_read);
+            let ready_at = this.link.schedule(&profile, Direction::Upload, bytes_read);
+            this.held = Some((data, Box::pin(tokio::time::sleep_until(ready_at))));
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Conditioned<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_delay.is_none() {
+            let Some(profile) = this.link.profile() else {
+                return Pin::new(&mut this.inner).poll_write(cx, buf);
+            };
+            let ready_at = this.link.schedule(&profile, Direction::Download, buf.len().min(MAX_SEGMENT));
+            this.write_delay = Some(Box::pin(tokio::time::sleep_until(ready_at)));
+        }
+        if let Some(delay) = this.write_delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+        }
+        // 等待期间已经按一个分段计算了发送时间，一次只写入一个分段
+        let segment = &buf[..buf.len().min(MAX_SEGMENT)];
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, segment));
+        this.write_delay = None;
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn create_conditioner(network: &str) -> Arc<NetworkConditioner> {
+        let config: Config = serde_json::from_str(&format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": ["*"], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" }},
+            "network": {network},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }}
+        }}"#)).unwrap();
+        Arc::new(NetworkConditioner::new(Arc::new(config)).unwrap())
+    }
+
+    #[test]
+    fn test_profile_selection() {
+        let conditioner = create_conditioner(r#"{
+            "profile": "3g",
+            "rules": [
+                { "domains": ["static.example.com"], "profile": "none" },
+                { "domains": ["api.example.com"], "profile": "slow" }
+            ],
+            "profiles": { "slow": { "download_kbps": 64 } }
+        }"#);
+        assert_eq!(conditioner.profile_for("www.example.com"), preset("3g"));
+        assert_eq!(conditioner.profile_for("static.example.com"), None);
+        assert_eq!(conditioner.profile_for("api.example.com").unwrap().download_kbps, 64);
+        assert!(conditioner.profiles().contains_key("lossy-wifi"));
+
+        // 运行时切换只影响全局设置
+        conditioner.set_active_profile(Some("edge".to_string())).unwrap();
+        assert_eq!(conditioner.profile_for("www.example.com"), preset("edge"));
+        assert_eq!(conditioner.profile_for("api.example.com").unwrap().download_kbps, 64);
+        assert!(conditioner.set_active_profile(Some("5g".to_string())).is_err());
+        assert_eq!(conditioner.active_profile().as_deref(), Some("edge"));
+        conditioner.set_active_profile(Some(NO_PROFILE.to_string())).unwrap();
+        assert_eq!(conditioner.profile_for("www.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_and_latency() {
+        let conditioner = create_conditioner(r#"{
+            "profiles": { "test": { "download_kbps": 800, "upload_kbps": 400, "latency_ms": 50 } }
+        }"#);
+        let (mut client, proxy_side) = tokio::io::duplex(64 * 1024);
+        let mut conditioned = conditioner.wrap("example.com", proxy_side);
+
+        // 不限制时没有延迟
+        let start = Instant::now();
+        conditioned.write_all(&[0; 10_000]).await.unwrap();
+        client.read_exact(&mut [0; 10_000]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // 800kbit/s下载10KB需要100ms，加上50ms延迟
+        conditioner.set_active_profile(Some("test".to_string())).unwrap();
+        let start = Instant::now();
+        conditioned.write_all(&[0; 10_000]).await.unwrap();
+        client.read_exact(&mut [0; 10_000]).await.unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(400), "{elapsed:?}");
+
+        // 方向改变时再次增加延迟，400kbit/s上传5KB需要100ms
+        let start = Instant::now();
+        client.write_all(&[0; 5_000]).await.unwrap();
+        conditioned.read_exact(&mut [0; 5_000]).await.unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(400), "{elapsed:?}");
+    }
+}
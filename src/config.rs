@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
+use std::time::Duration;
 use serde::de::{self, Visitor};
 use std::fmt;
+use regex::Regex;
 
 /// 代理服务器配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,6 +14,14 @@ pub struct ProxyConfig {
     pub host: String,
     /// 监听端口
     pub port: u16,
+    /// 关闭时等待正在处理的连接结束的最长时间（秒）
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace_secs: u64,
+}
+
+/// 默认关闭等待时间（秒）
+fn default_shutdown_grace() -> u64 {
+    10
 }
 
 /// 目标配置
@@ -56,6 +68,9 @@ pub struct DomainLogsConfig {
     /// 响应体大小限制
     #[serde(default = "default_response_body_limit")]
     pub response_body_limit: i64,
+    /// 原始TCP流十六进制转储的长度限制（每个方向，0不记录，-1完整记录）
+    #[serde(default)]
+    pub raw_dump_limit: i64,
 }
 
 /// 默认请求体大小限制
@@ -103,6 +118,903 @@ impl Default for SystemProxyConfig {
     }
 }
 
+/// SOCKS5监听配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Socks5Config {
+    /// 是否启用SOCKS5监听
+    #[serde(default)]
+    pub enabled: bool,
+    /// 监听主机地址
+    #[serde(default = "default_socks5_host")]
+    pub host: String,
+    /// 监听端口
+    #[serde(default = "default_socks5_port")]
+    pub port: u16,
+    /// 认证用户名（不配置时不需要认证）
+    #[serde(default)]
+    pub username: Option<String>,
+    /// 认证密码
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// 默认SOCKS5监听地址
+fn default_socks5_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// 默认SOCKS5监听端口
+fn default_socks5_port() -> u16 {
+    1080
+}
+
+impl Default for Socks5Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_socks5_host(),
+            port: default_socks5_port(),
+            username: None,
+            password: None,
+        }
+    }
+}
+
+/// 透明代理配置（仅Linux）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransparentConfig {
+    /// 是否启用透明代理监听
+    #[serde(default)]
+    pub enabled: bool,
+    /// 监听主机地址
+    #[serde(default = "default_transparent_host")]
+    pub host: String,
+    /// 监听端口（iptables/nftables REDIRECT的目标端口）
+    #[serde(default = "default_transparent_port")]
+    pub port: u16,
+}
+
+/// 默认透明代理监听地址
+fn default_transparent_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+/// 默认透明代理监听端口
+fn default_transparent_port() -> u16 {
+    8889
+}
+
+impl Default for TransparentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_transparent_host(),
+            port: default_transparent_port(),
+        }
+    }
+}
+
+/// 反向代理配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReverseProxyConfig {
+    /// 是否启用反向代理监听
+    #[serde(default)]
+    pub enabled: bool,
+    /// 监听主机地址
+    #[serde(default = "default_reverse_proxy_host")]
+    pub host: String,
+    /// 监听端口
+    #[serde(default = "default_reverse_proxy_port")]
+    pub port: u16,
+    /// 上游基础URL（如 http://127.0.0.1:3000 或 https://backend.local/api）
+    #[serde(default)]
+    pub upstream: String,
+    /// 是否在监听端口上终止TLS（使用CA签发的证书）
+    #[serde(default)]
+    pub tls: bool,
+    /// TLS证书使用的主机名
+    #[serde(default = "default_reverse_proxy_tls_hostname")]
+    pub tls_hostname: String,
+}
+
+/// 默认反向代理监听地址
+fn default_reverse_proxy_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// 默认反向代理监听端口
+fn default_reverse_proxy_port() -> u16 {
+    8080
+}
+
+/// 默认反向代理TLS证书主机名
+fn default_reverse_proxy_tls_hostname() -> String {
+    "localhost".to_string()
+}
+
+impl Default for ReverseProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_reverse_proxy_host(),
+            port: default_reverse_proxy_port(),
+            upstream: String::new(),
+            tls: false,
+            tls_hostname: default_reverse_proxy_tls_hostname(),
+        }
+    }
+}
+
+/// 上游代理类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProxyType {
+    /// 直接连接目标
+    Direct,
+    /// HTTP代理（TLS使用CONNECT，明文HTTP使用absolute-form）
+    Http,
+    /// SOCKS5代理
+    Socks5,
+}
+
+/// 上游代理规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamProxyRule {
+    /// 匹配的域名列表（支持子字符串匹配，"*"匹配所有域名）
+    pub domains: Vec<String>,
+    /// 代理类型
+    #[serde(rename = "type")]
+    pub proxy_type: UpstreamProxyType,
+    /// 代理服务器地址
+    #[serde(default)]
+    pub host: String,
+    /// 代理服务器端口
+    #[serde(default)]
+    pub port: u16,
+    /// 认证用户名
+    #[serde(default)]
+    pub username: Option<String>,
+    /// 认证密码
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// 上游代理配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpstreamProxyConfig {
+    /// 代理规则，按顺序匹配第一条
+    #[serde(default)]
+    pub rules: Vec<UpstreamProxyRule>,
+}
+
+/// TLS协议版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TlsVersion {
+    /// TLS 1.0
+    #[serde(rename = "1.0")]
+    Tls10,
+    /// TLS 1.1
+    #[serde(rename = "1.1")]
+    Tls11,
+    /// TLS 1.2
+    #[serde(rename = "1.2")]
+    Tls12,
+}
+
+/// 按域名覆盖的上游证书校验规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamTlsRule {
+    /// 匹配的域名列表（支持子字符串匹配，"*"匹配所有域名）
+    pub domains: Vec<String>,
+    /// 是否校验上游证书
+    pub verify: bool,
+}
+
+/// 向上游出示的客户端证书（mTLS）
+///
+/// 使用`cert`和`key`（PEM格式）或者`pkcs12`其中一种方式配置。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientCertificateRule {
+    /// 匹配的域名列表（支持子字符串匹配，"*"匹配所有域名）
+    pub domains: Vec<String>,
+    /// 证书文件（PEM格式，可以在站点证书之后附带中间证书）
+    #[serde(default)]
+    pub cert: Option<String>,
+    /// 私钥文件（PEM格式，PKCS#8）
+    #[serde(default)]
+    pub key: Option<String>,
+    /// PKCS#12文件
+    #[serde(default)]
+    pub pkcs12: Option<String>,
+    /// PKCS#12文件的密码
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// 上游TLS配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// 是否校验上游证书（没有匹配的规则时使用）
+    #[serde(default = "default_upstream_tls_verify")]
+    pub verify: bool,
+    /// 额外信任的CA证书文件（PEM格式，一个文件可包含多个证书）
+    #[serde(default)]
+    pub ca_bundles: Vec<String>,
+    /// 允许的最低TLS版本（不配置时使用系统TLS库的默认值）
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// 按域名覆盖是否校验，按顺序匹配第一条
+    #[serde(default)]
+    pub rules: Vec<UpstreamTlsRule>,
+    /// 按域名选择的客户端证书，按顺序匹配第一条
+    #[serde(default)]
+    pub client_certificates: Vec<ClientCertificateRule>,
+}
+
+/// 默认校验上游证书
+fn default_upstream_tls_verify() -> bool {
+    true
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        Self {
+            verify: default_upstream_tls_verify(),
+            ca_bundles: Vec::new(),
+            min_version: None,
+            rules: Vec::new(),
+            client_certificates: Vec::new(),
+        }
+    }
+}
+
+/// 上游连接池配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamPoolConfig {
+    /// 是否复用空闲的上游连接
+    #[serde(default = "default_upstream_pool_enabled")]
+    pub enabled: bool,
+    /// 每个源站最多保留的空闲连接数
+    #[serde(default = "default_upstream_pool_max_idle")]
+    pub max_idle_per_origin: usize,
+    /// 空闲连接的最长保留时间（秒）
+    #[serde(default = "default_upstream_pool_idle_timeout")]
+    pub idle_timeout_secs: u64,
+    /// 连接从建立起的最长使用时间（秒），超过后不再放回连接池
+    #[serde(default = "default_upstream_pool_max_lifetime")]
+    pub max_lifetime_secs: u64,
+}
+
+/// 默认启用上游连接池
+fn default_upstream_pool_enabled() -> bool {
+    true
+}
+
+/// 默认每个源站最多保留的空闲连接数
+fn default_upstream_pool_max_idle() -> usize {
+    8
+}
+
+/// 默认空闲连接保留时间（秒）
+fn default_upstream_pool_idle_timeout() -> u64 {
+    90
+}
+
+/// 默认连接最长使用时间（秒）
+fn default_upstream_pool_max_lifetime() -> u64 {
+    600
+}
+
+impl Default for UpstreamPoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_upstream_pool_enabled(),
+            max_idle_per_origin: default_upstream_pool_max_idle(),
+            idle_timeout_secs: default_upstream_pool_idle_timeout(),
+            max_lifetime_secs: default_upstream_pool_max_lifetime(),
+        }
+    }
+}
+
+/// 上游DNS解析配置
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DnsConfig {
+    /// 静态域名映射（域名 -> IP），支持 *.example.com 形式的通配符
+    #[serde(default)]
+    pub hosts: HashMap<String, IpAddr>,
+    /// 自定义DNS服务器（如 10.0.0.53 或 10.0.0.53:5353），为空时使用系统解析
+    #[serde(default)]
+    pub resolver: Option<String>,
+}
+
+/// 超时配置（秒），0表示不限制
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutConfig {
+    /// 等待客户端发送完整请求头的时间
+    #[serde(default = "default_client_header_timeout")]
+    pub client_header_secs: u64,
+    /// 建立上游TCP连接（含DNS解析和上游代理握手）的时间
+    #[serde(default = "default_connect_timeout")]
+    pub connect_secs: u64,
+    /// 与客户端或上游完成TLS握手的时间
+    #[serde(default = "default_tls_handshake_timeout")]
+    pub tls_handshake_secs: u64,
+    /// 请求发送完成后等待上游响应第一个字节的时间
+    #[serde(default = "default_first_byte_timeout")]
+    pub first_byte_secs: u64,
+    /// 隧道、WebSocket等双向转发连接没有任何数据的最长时间
+    #[serde(default = "default_tunnel_idle_timeout")]
+    pub tunnel_idle_secs: u64,
+}
+
+/// 默认请求头读取超时（秒）
+fn default_client_header_timeout() -> u64 {
+    30
+}
+
+/// 默认连接超时（秒）
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+/// 默认TLS握手超时（秒）
+fn default_tls_handshake_timeout() -> u64 {
+    10
+}
+
+/// 默认首字节超时（秒）
+fn default_first_byte_timeout() -> u64 {
+    60
+}
+
+/// 默认隧道空闲超时（秒）
+fn default_tunnel_idle_timeout() -> u64 {
+    300
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            client_header_secs: default_client_header_timeout(),
+            connect_secs: default_connect_timeout(),
+            tls_handshake_secs: default_tls_handshake_timeout(),
+            first_byte_secs: default_first_byte_timeout(),
+            tunnel_idle_secs: default_tunnel_idle_timeout(),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// 请求头读取超时
+    pub fn client_header(&self) -> Option<Duration> {
+        non_zero_secs(self.client_header_secs)
+    }
+
+    /// 上游连接超时
+    pub fn connect(&self) -> Option<Duration> {
+        non_zero_secs(self.connect_secs)
+    }
+
+    /// TLS握手超时
+    pub fn tls_handshake(&self) -> Option<Duration> {
+        non_zero_secs(self.tls_handshake_secs)
+    }
+
+    /// 首字节超时
+    pub fn first_byte(&self) -> Option<Duration> {
+        non_zero_secs(self.first_byte_secs)
+    }
+
+    /// 隧道空闲超时
+    pub fn tunnel_idle(&self) -> Option<Duration> {
+        non_zero_secs(self.tunnel_idle_secs)
+    }
+}
+
+/// 秒数转换为超时时间，0表示不限制
+fn non_zero_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// 网络模拟参数，带宽为0表示不限制
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct NetworkProfile {
+    /// 下载带宽（kbit/s，上游到客户端）
+    #[serde(default)]
+    pub download_kbps: u64,
+    /// 上传带宽（kbit/s，客户端到上游）
+    #[serde(default)]
+    pub upload_kbps: u64,
+    /// 数据传输方向改变时增加的延迟（毫秒）
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// 延迟的随机波动范围（毫秒），实际延迟在 latency_ms ± jitter_ms 之间
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// 每次传输数据时发生卡顿的概率（0到1）
+    #[serde(default)]
+    pub stall_probability: f64,
+    /// 卡顿的时长（毫秒）
+    #[serde(default)]
+    pub stall_ms: u64,
+}
+
+/// 按域名选择网络模拟参数的规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkRule {
+    /// 匹配的域名列表（支持子字符串匹配，"*"匹配所有域名）
+    pub domains: Vec<String>,
+    /// 使用的网络模拟名称（预设或自定义），"none"表示不限制
+    pub profile: String,
+}
+
+/// 网络模拟配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkConditioningConfig {
+    /// 全局使用的网络模拟名称，不配置时不限制，运行时可以通过控制接口切换
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// 按域名覆盖全局设置的规则，按顺序匹配第一条
+    #[serde(default)]
+    pub rules: Vec<NetworkRule>,
+    /// 自定义的网络模拟参数（名称 -> 参数），与预设同名时覆盖预设
+    #[serde(default)]
+    pub profiles: HashMap<String, NetworkProfile>,
+}
+
+/// 控制接口配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlConfig {
+    /// 是否启用控制接口
+    #[serde(default)]
+    pub enabled: bool,
+    /// 监听地址
+    #[serde(default = "default_control_host")]
+    pub host: String,
+    /// 监听端口
+    #[serde(default = "default_control_port")]
+    pub port: u16,
+    /// 访问令牌，设置后请求需要带有 `Authorization: Bearer <token>`；监听非回环地址时必须设置
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// 默认控制接口监听地址
+fn default_control_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// 默认控制接口端口
+fn default_control_port() -> u16 {
+    8899
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_control_host(),
+            port: default_control_port(),
+            token: None,
+        }
+    }
+}
+
+/// 请求匹配条件，没有配置的条件匹配所有请求
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlowMatch {
+    /// 主机列表，包含通配符时按glob匹配（如 *.example.com），否则按子字符串匹配
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// 路径glob列表（如 /api/*），匹配不含查询参数的路径
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// 请求方法列表（不区分大小写）
+    #[serde(default)]
+    pub methods: Vec<String>,
+}
+
+impl FlowMatch {
+    /// 判断请求是否匹配
+    ///
+    /// # 参数
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径，可以包含查询参数
+    pub fn matches(&self, host: &str, method: &str, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or(path);
+        let path_match = self.paths.is_empty() || self.paths.iter().any(|pattern| glob_match(pattern, path));
+        let method_match = self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+        self.matches_host(host) && path_match && method_match
+    }
+
+    /// 匹配的路径模式中第一个通配符之前的目录部分，用于把请求路径映射到其他位置
+    ///
+    /// 如 `/static/*.js` 返回 `/static/`，没有配置路径模式时返回 `/`
+    ///
+    /// # 参数
+    /// * `path` - 请求路径，可以包含查询参数
+    pub fn path_prefix(&self, path: &str) -> &str {
+        let path = path.split('?').next().unwrap_or(path);
+        let Some(pattern) = self.paths.iter().find(|pattern| glob_match(pattern, path)) else {
+            return "/";
+        };
+        let literal = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+        &literal[..literal.rfind('/').map(|i| i + 1).unwrap_or(0)]
+    }
+
+    /// 判断主机是否匹配，用于还不知道具体请求时的判断
+    ///
+    /// # 参数
+    /// * `host` - 请求的主机
+    pub fn matches_host(&self, host: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|pattern| match pattern.contains(['*', '?']) {
+            true => glob_match(&pattern.to_ascii_lowercase(), &host.to_ascii_lowercase()),
+            false => host.contains(pattern.as_str()),
+        })
+    }
+}
+
+/// glob匹配，`*`匹配任意长度的字符（包括`/`），`?`匹配单个字符
+///
+/// # 参数
+/// * `pattern` - glob模式
+/// * `text` - 要匹配的字符串
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // 最近一个*的位置和它匹配到的文本位置，用于回溯
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// 注入的故障
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fault {
+    /// 不连接上游，直接返回指定的状态码
+    Status {
+        /// 状态码
+        status: u16,
+        /// 响应体
+        #[serde(default)]
+        body: String,
+    },
+    /// 不返回响应，以TCP RST断开客户端连接（HTTP/2为重置流）
+    Reset,
+    /// 向客户端转发指定字节数的响应（HTTP/1.1包括响应头）后断开连接
+    CloseAfter {
+        /// 转发的字节数
+        bytes: usize,
+    },
+    /// 响应体只转发指定字节数后断开连接
+    Truncate {
+        /// 转发的响应体字节数
+        bytes: usize,
+    },
+    /// 随机修改响应体中的字节
+    Corrupt {
+        /// 每个字节被修改的概率（0到1）
+        #[serde(default = "default_corrupt_rate")]
+        rate: f64,
+    },
+    /// 延迟指定时间后再返回响应头
+    Delay {
+        /// 延迟时间（毫秒）
+        ms: u64,
+    },
+}
+
+/// 默认字节修改概率
+fn default_corrupt_rate() -> f64 {
+    0.01
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Status { status, .. } => write!(f, "status {status}"),
+            Fault::Reset => write!(f, "reset"),
+            Fault::CloseAfter { bytes } => write!(f, "close after {bytes} bytes"),
+            Fault::Truncate { bytes } => write!(f, "truncate body to {bytes} bytes"),
+            Fault::Corrupt { rate } => write!(f, "corrupt body (rate {rate})"),
+            Fault::Delay { ms } => write!(f, "delay headers {ms}ms"),
+        }
+    }
+}
+
+/// 故障注入规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultRule {
+    /// 匹配条件
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+    /// 注入的故障
+    pub fault: Fault,
+    /// 匹配的请求中注入故障的概率（0到1）
+    #[serde(default = "default_fault_probability")]
+    pub probability: f64,
+}
+
+/// 默认总是注入故障
+fn default_fault_probability() -> f64 {
+    1.0
+}
+
+/// 故障注入配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaultConfig {
+    /// 故障注入规则，按顺序匹配
+    #[serde(default)]
+    pub rules: Vec<FaultRule>,
+}
+
+/// Map Local规则，匹配的请求直接使用本地文件应答，不连接上游
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapLocalRule {
+    /// 匹配条件
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+    /// 本地文件或目录
+    ///
+    /// 映射到目录时，请求路径去掉路径模式中通配符之前的目录部分后拼接到目录下，
+    /// 如 `/static/*` 映射到 `./site` 时 `/static/js/app.js` 对应 `./site/js/app.js`，
+    /// 以 `/` 结尾的路径对应目录下的 `index.html`
+    pub local: String,
+    /// 额外添加的响应头
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Map Local配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MapLocalConfig {
+    /// Map Local规则，按顺序匹配第一条
+    #[serde(default)]
+    pub rules: Vec<MapLocalRule>,
+}
+
+/// Map Remote规则，把匹配的请求转发到其他上游，客户端看到的请求不变
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapRemoteRule {
+    /// 匹配条件
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+    /// 新的上游地址，如 `http://localhost:3000/v2`
+    ///
+    /// 地址没有路径时保持请求路径不变；有路径时请求路径去掉路径模式中通配符之前的目录部分后拼接到该路径下，
+    /// 如 `/v2/*` 映射到 `http://localhost:3000/api` 时 `/v2/users` 转发为 `/api/users`
+    pub to: String,
+    /// 是否保留原始的Host头，默认改为新的上游地址
+    #[serde(default)]
+    pub preserve_host: bool,
+}
+
+/// Map Remote配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MapRemoteConfig {
+    /// Map Remote规则，按顺序匹配第一条
+    #[serde(default)]
+    pub rules: Vec<MapRemoteRule>,
+}
+
+/// 规则作用的消息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RewritePhase {
+    /// 发送给上游的请求
+    #[default]
+    Request,
+    /// 返回给客户端的响应
+    Response,
+}
+
+/// 头部改写动作，头部名称不区分大小写
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum HeaderAction {
+    /// 添加头部，已有同名头部时也添加
+    Add {
+        /// 头部名称
+        name: String,
+        /// 头部值
+        value: String,
+    },
+    /// 设置头部，替换所有同名头部
+    Set {
+        /// 头部名称
+        name: String,
+        /// 头部值
+        value: String,
+    },
+    /// 没有同名头部时添加
+    Default {
+        /// 头部名称
+        name: String,
+        /// 头部值
+        value: String,
+    },
+    /// 删除所有同名头部
+    Remove {
+        /// 头部名称
+        name: String,
+    },
+    /// 对所有同名头部的值做正则替换
+    Replace {
+        /// 头部名称
+        name: String,
+        /// 正则表达式
+        #[serde(deserialize_with = "deserialize_regex", serialize_with = "serialize_regex")]
+        pattern: Regex,
+        /// 替换内容，可以用 `$1` 引用分组
+        replacement: String,
+    },
+}
+
+/// 头部改写规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderRule {
+    /// 匹配条件
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+    /// 改写请求头还是响应头（默认请求头）
+    #[serde(default)]
+    pub phase: RewritePhase,
+    /// 改写动作
+    #[serde(flatten)]
+    pub action: HeaderAction,
+}
+
+/// 头部改写配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HeaderRewriteConfig {
+    /// 头部改写规则，所有匹配的规则按顺序执行
+    #[serde(default)]
+    pub rules: Vec<HeaderRule>,
+}
+
+/// 消息体改写动作，作用在解压后的消息体上
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BodyAction {
+    /// 对消息体文本做正则替换（消息体不是UTF-8时跳过）
+    Replace {
+        /// 正则表达式
+        #[serde(deserialize_with = "deserialize_regex", serialize_with = "serialize_regex")]
+        pattern: Regex,
+        /// 替换内容，可以用 `$1` 引用分组
+        replacement: String,
+    },
+    /// 设置JSON Pointer指向的值，父节点存在时可以新增字段或追加数组元素
+    JsonSet {
+        /// JSON Pointer，如 `/features/new_checkout`
+        pointer: String,
+        /// 新的值
+        value: serde_json::Value,
+    },
+    /// 删除JSON Pointer指向的字段或数组元素
+    JsonDelete {
+        /// JSON Pointer
+        pointer: String,
+    },
+}
+
+/// 消息体改写规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BodyRule {
+    /// 匹配条件
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+    /// 改写请求体还是响应体（默认请求体）
+    #[serde(default)]
+    pub phase: RewritePhase,
+    /// 改写动作
+    #[serde(flatten)]
+    pub action: BodyAction,
+}
+
+/// 消息体改写配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BodyRewriteConfig {
+    /// 消息体改写规则，所有匹配的规则按顺序执行
+    #[serde(default)]
+    pub rules: Vec<BodyRule>,
+}
+
+/// 断点规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BreakpointRule {
+    /// 匹配条件
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+    /// 在请求发往上游前还是响应返回客户端前暂停（默认请求）
+    #[serde(default)]
+    pub phase: RewritePhase,
+}
+
+/// 暂停的流超时后的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakpointTimeoutAction {
+    /// 按已做出的编辑继续
+    #[default]
+    Continue,
+    /// 中止，断开客户端连接
+    Abort,
+}
+
+/// 断点配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BreakpointConfig {
+    /// 断点规则，匹配任意一条规则的请求或响应被暂停
+    #[serde(default)]
+    pub rules: Vec<BreakpointRule>,
+    /// 暂停的请求或响应没有被处理时的超时时间（秒）
+    #[serde(default = "default_breakpoint_timeout")]
+    pub timeout_secs: u64,
+    /// 超时后继续还是中止（默认继续）
+    #[serde(default)]
+    pub on_timeout: BreakpointTimeoutAction,
+}
+
+/// 默认断点超时时间（秒）
+fn default_breakpoint_timeout() -> u64 {
+    60
+}
+
+impl Default for BreakpointConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            timeout_secs: default_breakpoint_timeout(),
+            on_timeout: BreakpointTimeoutAction::default(),
+        }
+    }
+}
+
+/// 脚本规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptRule {
+    /// Rhai脚本文件，修改后自动重新加载
+    pub file: String,
+    /// 匹配条件，只有匹配的请求调用脚本
+    #[serde(flatten)]
+    pub flow: FlowMatch,
+}
+
+/// 脚本配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScriptConfig {
+    /// 脚本规则，匹配的脚本按顺序执行
+    #[serde(default)]
+    pub rules: Vec<ScriptRule>,
+    /// 脚本本地存储的持久化文件（可选），不配置时只保存在内存中
+    #[serde(default)]
+    pub storage_file: Option<String>,
+}
+
 /// 日志配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingConfig {
@@ -126,10 +1038,61 @@ pub struct Config {
     /// 系统代理配置
     #[serde(default)]
     pub system_proxy: SystemProxyConfig,
+    /// SOCKS5监听配置
+    #[serde(default)]
+    pub socks5: Socks5Config,
+    /// 透明代理配置
+    #[serde(default)]
+    pub transparent: TransparentConfig,
+    /// 反向代理配置
+    #[serde(default)]
+    pub reverse_proxy: ReverseProxyConfig,
     /// 目标配置
     pub target: TargetConfig,
     /// 证书配置
     pub certificates: CertificatesConfig,
+    /// 上游代理配置
+    #[serde(default)]
+    pub upstream_proxy: UpstreamProxyConfig,
+    /// 上游TLS配置
+    #[serde(default)]
+    pub upstream_tls: UpstreamTlsConfig,
+    /// 上游连接池配置
+    #[serde(default)]
+    pub upstream_pool: UpstreamPoolConfig,
+    /// 上游DNS解析配置
+    #[serde(default)]
+    pub dns: DnsConfig,
+    /// 超时配置
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    /// 网络模拟配置
+    #[serde(default)]
+    pub network: NetworkConditioningConfig,
+    /// 故障注入配置
+    #[serde(default)]
+    pub faults: FaultConfig,
+    /// Map Local配置
+    #[serde(default)]
+    pub map_local: MapLocalConfig,
+    /// Map Remote配置
+    #[serde(default)]
+    pub map_remote: MapRemoteConfig,
+    /// 头部改写配置
+    #[serde(default)]
+    pub headers: HeaderRewriteConfig,
+    /// 消息体改写配置
+    #[serde(default)]
+    pub body: BodyRewriteConfig,
+    /// 断点配置
+    #[serde(default)]
+    pub breakpoints: BreakpointConfig,
+    /// 脚本配置
+    #[serde(default)]
+    pub scripts: ScriptConfig,
+    /// 控制接口配置
+    #[serde(default)]
+    pub control: ControlConfig,
     /// 日志配置
     pub logging: LoggingConfig,
 }
@@ -173,6 +1136,209 @@ impl Config {
         
         domain_match && port_match
     }
+
+    /// 查找指定域名使用的上游代理规则
+    /// 
+    /// # 参数
+    /// * `domain` - 域名
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配的规则，没有匹配时返回None（直接连接）
+    pub fn upstream_proxy_for(&self, domain: &str) -> Option<&UpstreamProxyRule> {
+        self.upstream_proxy.rules.iter().find(|rule| {
+            rule.domains.iter().any(|d| match d.as_str() {
+                "*" => true,
+                d_str => domain.contains(d_str),
+            })
+        })
+    }
+
+    /// 判断是否校验指定域名的上游证书
+    /// 
+    /// # 参数
+    /// * `domain` - 域名
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配规则的设置，没有匹配时使用全局设置
+    pub fn upstream_tls_verify(&self, domain: &str) -> bool {
+        self.upstream_tls.rules.iter()
+            .find(|rule| {
+                rule.domains.iter().any(|d| match d.as_str() {
+                    "*" => true,
+                    d_str => domain.contains(d_str),
+                })
+            })
+            .map(|rule| rule.verify)
+            .unwrap_or(self.upstream_tls.verify)
+    }
+
+    /// 查找向指定域名出示的客户端证书
+    /// 
+    /// # 参数
+    /// * `domain` - 域名
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配的规则，没有匹配时返回None（不出示客户端证书）
+    pub fn client_certificate_for(&self, domain: &str) -> Option<&ClientCertificateRule> {
+        self.client_certificate_index(domain).map(|index| &self.upstream_tls.client_certificates[index])
+    }
+
+    /// 查找向指定域名出示的客户端证书规则的序号
+    /// 
+    /// # 参数
+    /// * `domain` - 域名
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配的规则在`upstream_tls.client_certificates`中的序号，没有匹配时返回None
+    pub fn client_certificate_index(&self, domain: &str) -> Option<usize> {
+        self.upstream_tls.client_certificates.iter().position(|rule| {
+            rule.domains.iter().any(|d| match d.as_str() {
+                "*" => true,
+                d_str => domain.contains(d_str),
+            })
+        })
+    }
+
+    /// 查找指定域名使用的网络模拟规则
+    /// 
+    /// # 参数
+    /// * `domain` - 域名
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配的规则，没有匹配时返回None（使用全局设置）
+    pub fn network_rule_for(&self, domain: &str) -> Option<&NetworkRule> {
+        self.network.rules.iter().find(|rule| {
+            rule.domains.iter().any(|d| match d.as_str() {
+                "*" => true,
+                d_str => domain.contains(d_str),
+            })
+        })
+    }
+
+    /// 查找匹配请求的故障注入规则
+    /// 
+    /// # 参数
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    /// 
+    /// # 返回值
+    /// 按配置顺序返回所有匹配的规则
+    pub fn fault_rules_for<'a>(&'a self, host: &'a str, method: &'a str, path: &'a str) -> impl Iterator<Item = &'a FaultRule> {
+        self.faults.rules.iter().filter(move |rule| rule.flow.matches(host, method, path))
+    }
+
+    /// 查找匹配请求的Map Local规则
+    /// 
+    /// # 参数
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配的规则，没有匹配时返回None
+    pub fn map_local_rule_for(&self, host: &str, method: &str, path: &str) -> Option<&MapLocalRule> {
+        self.map_local.rules.iter().find(|rule| rule.flow.matches(host, method, path))
+    }
+
+    /// 查找匹配请求的Map Remote规则
+    /// 
+    /// # 参数
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    /// 
+    /// # 返回值
+    /// 返回第一条匹配的规则，没有匹配时返回None
+    pub fn map_remote_rule_for(&self, host: &str, method: &str, path: &str) -> Option<&MapRemoteRule> {
+        self.map_remote.rules.iter().find(|rule| rule.flow.matches(host, method, path))
+    }
+
+    /// 查找匹配请求的头部改写动作
+    /// 
+    /// # 参数
+    /// * `phase` - 改写请求头还是响应头
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    /// 
+    /// # 返回值
+    /// 按配置顺序返回所有匹配规则的动作
+    pub fn header_actions_for(&self, phase: RewritePhase, host: &str, method: &str, path: &str) -> Vec<HeaderAction> {
+        self.headers.rules.iter()
+            .filter(|rule| rule.phase == phase && rule.flow.matches(host, method, path))
+            .map(|rule| rule.action.clone())
+            .collect()
+    }
+
+    /// 查找匹配请求的消息体改写动作
+    /// 
+    /// # 参数
+    /// * `phase` - 改写请求体还是响应体
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    /// 
+    /// # 返回值
+    /// 按配置顺序返回所有匹配规则的动作
+    pub fn body_actions_for(&self, phase: RewritePhase, host: &str, method: &str, path: &str) -> Vec<BodyAction> {
+        self.body.rules.iter()
+            .filter(|rule| rule.phase == phase && rule.flow.matches(host, method, path))
+            .map(|rule| rule.action.clone())
+            .collect()
+    }
+
+    /// 请求或响应是否命中断点规则
+    /// 
+    /// # 参数
+    /// * `phase` - 请求断点还是响应断点
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    pub fn has_breakpoint(&self, phase: RewritePhase, host: &str, method: &str, path: &str) -> bool {
+        self.breakpoints.rules.iter().any(|rule| rule.phase == phase && rule.flow.matches(host, method, path))
+    }
+
+    /// 主机是否需要按HTTP/1.1逐个请求拦截
+    /// 
+    /// 主机可能命中Map Local、Map Remote、消息体改写或断点规则时返回true。
+    /// 这样的主机在收到具体请求之前不预先连接上游，与客户端使用HTTP/1.1按请求处理。
+    /// 
+    /// # 参数
+    /// * `host` - 请求的主机
+    pub fn needs_http1_interception(&self, host: &str) -> bool {
+        self.map_local.rules.iter().any(|rule| rule.flow.matches_host(host))
+            || self.map_remote.rules.iter().any(|rule| rule.flow.matches_host(host))
+            || self.body.rules.iter().any(|rule| rule.flow.matches_host(host))
+            || self.breakpoints.rules.iter().any(|rule| rule.flow.matches_host(host))
+    }
+
+    /// 查找域名的静态DNS映射
+    /// 
+    /// # 参数
+    /// * `domain` - 域名
+    /// 
+    /// # 返回值
+    /// 优先返回精确匹配的IP，其次返回最长的通配符匹配，没有映射时返回None
+    pub fn dns_override(&self, domain: &str) -> Option<IpAddr> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(ip) = self.dns.hosts.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&domain))
+            .map(|(_, ip)| *ip)
+        {
+            return Some(ip);
+        }
+
+        // *.example.com 匹配任意层级的子域名，但不匹配 example.com 本身
+        self.dns.hosts.iter()
+            .filter_map(|(name, ip)| {
+                let suffix = name.strip_prefix('*')?.to_ascii_lowercase();
+                let matched = suffix.is_empty() || (suffix.starts_with('.') && domain.ends_with(&suffix));
+                matched.then_some((suffix.len(), *ip))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, ip)| ip)
+    }
 }
 
 /// 端口反序列化函数
@@ -221,6 +1387,23 @@ where
     deserializer.deserialize_seq(PortsVisitor)
 }
 
+/// 正则表达式反序列化函数，无效的表达式在加载配置时报错
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(de::Error::custom)
+}
+
+/// 正则表达式序列化函数
+fn serialize_regex<S>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(regex.as_str())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -266,7 +1449,13 @@ mod tests {
         
         assert_eq!(config.proxy.host, "127.0.0.1");
         assert_eq!(config.proxy.port, 8888);
-        assert_eq!(config.system_proxy.enabled, true);
+        assert!(config.system_proxy.enabled);
+        assert!(!config.socks5.enabled);
+        assert_eq!(config.socks5.port, 1080);
+        assert!(!config.transparent.enabled);
+        assert!(!config.reverse_proxy.enabled);
+        assert!(config.upstream_pool.enabled);
+        assert_eq!(config.upstream_pool.max_idle_per_origin, 8);
         assert_eq!(config.target.domains, vec!["example.com"]);
         assert_eq!(config.target.ports, vec![80, 443]);
         assert_eq!(config.certificates.ca_cert, "certs/ca.crt");
@@ -277,6 +1466,121 @@ mod tests {
         
         std::fs::remove_file("test_config_file.json").unwrap();
     }
+
+    #[test]
+    fn test_upstream_proxy_rules() {
+        let config_content = r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "upstream_proxy": {
+                "rules": [
+                    { "domains": ["intranet.corp"], "type": "direct" },
+                    { "domains": ["onion"], "type": "socks5", "host": "127.0.0.1", "port": 9050 },
+                    { "domains": ["*"], "type": "http", "host": "proxy.corp", "port": 3128,
+                      "username": "user", "password": "secret" }
+                ]
+            },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            }
+        }"#;
+        let config: Config = serde_json::from_str(config_content).unwrap();
+
+        let rule = config.upstream_proxy_for("wiki.intranet.corp").unwrap();
+        assert_eq!(rule.proxy_type, UpstreamProxyType::Direct);
+
+        let rule = config.upstream_proxy_for("example.onion").unwrap();
+        assert_eq!(rule.proxy_type, UpstreamProxyType::Socks5);
+        assert_eq!(rule.port, 9050);
+
+        let rule = config.upstream_proxy_for("example.com").unwrap();
+        assert_eq!(rule.proxy_type, UpstreamProxyType::Http);
+        assert_eq!(rule.username.as_deref(), Some("user"));
+
+        // 未配置上游代理时直接连接
+        let mut config = config;
+        config.upstream_proxy = UpstreamProxyConfig::default();
+        assert!(config.upstream_proxy_for("example.com").is_none());
+    }
+
+    #[test]
+    fn test_upstream_tls_rules() {
+        let config_content = r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "upstream_tls": {
+                "ca_bundles": ["certs/corp-ca.pem"],
+                "min_version": "1.2",
+                "rules": [
+                    { "domains": ["staging.local"], "verify": false }
+                ],
+                "client_certificates": [
+                    { "domains": ["internal.corp"], "pkcs12": "certs/client.p12", "password": "secret" }
+                ]
+            },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            }
+        }"#;
+        let config: Config = serde_json::from_str(config_content).unwrap();
+
+        assert_eq!(config.upstream_tls.ca_bundles, vec!["certs/corp-ca.pem"]);
+        assert_eq!(config.upstream_tls.min_version, Some(TlsVersion::Tls12));
+        assert!(!config.upstream_tls_verify("api.staging.local"));
+        assert!(config.upstream_tls_verify("example.com"));
+
+        let rule = config.client_certificate_for("api.internal.corp").unwrap();
+        assert_eq!(rule.pkcs12.as_deref(), Some("certs/client.p12"));
+        assert!(config.client_certificate_for("example.com").is_none());
+
+        // 默认校验上游证书
+        assert!(UpstreamTlsConfig::default().verify);
+        assert!(serde_json::from_str::<UpstreamTlsConfig>(r#"{"min_version": "1.3"}"#).is_err());
+    }
+
+    #[test]
+    fn test_dns_override() {
+        let dns: DnsConfig = serde_json::from_str(r#"{
+            "hosts": {
+                "api.example.com": "10.0.0.5",
+                "*.example.com": "10.0.0.6",
+                "*.cdn.example.com": "::1"
+            },
+            "resolver": "10.0.0.53"
+        }"#).unwrap();
+        let mut config: Config = serde_json::from_str(r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            }
+        }"#).unwrap();
+        assert!(config.dns.hosts.is_empty());
+        assert!(config.dns.resolver.is_none());
+        config.dns = dns;
+
+        assert_eq!(config.dns_override("API.example.com."), Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(config.dns_override("www.example.com"), Some("10.0.0.6".parse().unwrap()));
+        assert_eq!(config.dns_override("img.cdn.example.com"), Some("::1".parse().unwrap()));
+        assert_eq!(config.dns_override("example.com"), None);
+        assert_eq!(config.dns_override("badexample.com"), None);
+        assert_eq!(config.dns.resolver.as_deref(), Some("10.0.0.53"));
+    }
     
     #[test]
     fn test_should_intercept_exact_match() {
@@ -284,6 +1588,7 @@ mod tests {
             proxy: ProxyConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8888,
+                shutdown_grace_secs: 10,
             },
             target: TargetConfig {
                 domains: vec!["example.com".to_string()],
@@ -298,6 +1603,23 @@ mod tests {
                 configure_curl: true,
             },
             system_proxy: SystemProxyConfig::default(),
+            socks5: Socks5Config::default(),
+            transparent: TransparentConfig::default(),
+            reverse_proxy: ReverseProxyConfig::default(),
+            upstream_proxy: UpstreamProxyConfig::default(),
+            upstream_tls: UpstreamTlsConfig::default(),
+            upstream_pool: UpstreamPoolConfig::default(),
+            dns: DnsConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            network: NetworkConditioningConfig::default(),
+            faults: FaultConfig::default(),
+            map_local: MapLocalConfig::default(),
+            map_remote: MapRemoteConfig::default(),
+            headers: HeaderRewriteConfig::default(),
+            body: BodyRewriteConfig::default(),
+            breakpoints: BreakpointConfig::default(),
+            scripts: ScriptConfig::default(),
+            control: ControlConfig::default(),
             logging: LoggingConfig {
                 level: "debug".to_string(),
                 output: "file".to_string(),
@@ -308,6 +1630,7 @@ mod tests {
                     format: "domain_{domain}_{date}.log".to_string(),
                     request_body_limit: 1024,
                     response_body_limit: 1024,
+                    raw_dump_limit: 0,
                 },
             },
         };
@@ -327,6 +1650,7 @@ mod tests {
             proxy: ProxyConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8888,
+                shutdown_grace_secs: 10,
             },
             target: TargetConfig {
                 domains: vec!["*".to_string()],
@@ -341,6 +1665,23 @@ mod tests {
                 configure_curl: true,
             },
             system_proxy: SystemProxyConfig::default(),
+            socks5: Socks5Config::default(),
+            transparent: TransparentConfig::default(),
+            reverse_proxy: ReverseProxyConfig::default(),
+            upstream_proxy: UpstreamProxyConfig::default(),
+            upstream_tls: UpstreamTlsConfig::default(),
+            upstream_pool: UpstreamPoolConfig::default(),
+            dns: DnsConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            network: NetworkConditioningConfig::default(),
+            faults: FaultConfig::default(),
+            map_local: MapLocalConfig::default(),
+            map_remote: MapRemoteConfig::default(),
+            headers: HeaderRewriteConfig::default(),
+            body: BodyRewriteConfig::default(),
+            breakpoints: BreakpointConfig::default(),
+            scripts: ScriptConfig::default(),
+            control: ControlConfig::default(),
             logging: LoggingConfig {
                 level: "debug".to_string(),
                 output: "file".to_string(),
@@ -351,6 +1692,7 @@ mod tests {
                     format: "domain_{domain}_{date}.log".to_string(),
                     request_body_limit: 1024,
                     response_body_limit: 1024,
+                    raw_dump_limit: 0,
                 },
             },
         };
@@ -370,6 +1712,7 @@ mod tests {
             proxy: ProxyConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8888,
+                shutdown_grace_secs: 10,
             },
             target: TargetConfig {
                 domains: vec!["example.com".to_string()],
@@ -384,6 +1727,23 @@ mod tests {
                 configure_curl: true,
             },
             system_proxy: SystemProxyConfig::default(),
+            socks5: Socks5Config::default(),
+            transparent: TransparentConfig::default(),
+            reverse_proxy: ReverseProxyConfig::default(),
+            upstream_proxy: UpstreamProxyConfig::default(),
+            upstream_tls: UpstreamTlsConfig::default(),
+            upstream_pool: UpstreamPoolConfig::default(),
+            dns: DnsConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            network: NetworkConditioningConfig::default(),
+            faults: FaultConfig::default(),
+            map_local: MapLocalConfig::default(),
+            map_remote: MapRemoteConfig::default(),
+            headers: HeaderRewriteConfig::default(),
+            body: BodyRewriteConfig::default(),
+            breakpoints: BreakpointConfig::default(),
+            scripts: ScriptConfig::default(),
+            control: ControlConfig::default(),
             logging: LoggingConfig {
                 level: "debug".to_string(),
                 output: "file".to_string(),
@@ -394,6 +1754,7 @@ mod tests {
                     format: "domain_{domain}_{date}.log".to_string(),
                     request_body_limit: 1024,
                     response_body_limit: 1024,
+                    raw_dump_limit: 0,
                 },
             },
         };
@@ -425,4 +1786,116 @@ mod tests {
         assert_eq!(default_request_body_limit(), 1024);
         assert_eq!(default_response_body_limit(), 1024);
     }
+
+    #[test]
+    fn test_timeout_config() {
+        let timeouts: TimeoutConfig = serde_json::from_str(r#"{ "connect_secs": 3, "tunnel_idle_secs": 0 }"#).unwrap();
+        assert_eq!(timeouts.connect(), Some(Duration::from_secs(3)));
+        // 0表示不限制，未配置的使用默认值
+        assert_eq!(timeouts.tunnel_idle(), None);
+        assert_eq!(timeouts.client_header(), Some(Duration::from_secs(30)));
+        assert_eq!(timeouts.first_byte(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_network_config() {
+        let config: NetworkConditioningConfig = serde_json::from_str(r#"{
+            "profile": "3g",
+            "rules": [{ "domains": ["cdn.example.com"], "profile": "none" }],
+            "profiles": { "slow": { "download_kbps": 64, "latency_ms": 500 } }
+        }"#).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("3g"));
+        assert_eq!(config.rules[0].profile, "none");
+        let slow = config.profiles["slow"];
+        assert_eq!((slow.download_kbps, slow.upload_kbps, slow.latency_ms), (64, 0, 500));
+
+        let control = ControlConfig::default();
+        assert!(!control.enabled);
+        assert_eq!((control.host.as_str(), control.port), ("127.0.0.1", 8899));
+    }
+
+    #[test]
+    fn test_flow_match() {
+        assert!(glob_match("/api/*", "/api/users/1"));
+        assert!(glob_match("/api/*/orders", "/api/v1/orders"));
+        assert!(glob_match("/v?/items", "/v2/items"));
+        assert!(!glob_match("/api/*", "/static/app.js"));
+        assert!(!glob_match("/api/*/orders", "/api/v1/orders/2"));
+
+        let flow: FlowMatch = serde_json::from_str(r#"{
+            "hosts": ["*.example.com", "localhost"],
+            "paths": ["/api/*"],
+            "methods": ["post"]
+        }"#).unwrap();
+        assert!(flow.matches("api.example.com", "POST", "/api/orders?page=2"));
+        assert!(flow.matches("localhost", "POST", "/api/orders"));
+        assert!(!flow.matches("example.com", "POST", "/api/orders"));
+        assert!(!flow.matches("api.example.com", "GET", "/api/orders"));
+        assert!(!flow.matches("api.example.com", "POST", "/"));
+        assert!(FlowMatch::default().matches("any.host", "GET", "/"));
+        assert_eq!(flow.path_prefix("/api/orders?page=2"), "/api/");
+        assert_eq!(FlowMatch::default().path_prefix("/api/orders"), "/");
+    }
+
+    #[test]
+    fn test_fault_config() {
+        let faults: FaultConfig = serde_json::from_str(r#"{ "rules": [
+            { "hosts": ["api.example.com"], "fault": { "type": "status", "status": 503 }, "probability": 0.5 },
+            { "paths": ["/download/*"], "fault": { "type": "truncate", "bytes": 100 } },
+            { "fault": { "type": "corrupt" } }
+        ] }"#).unwrap();
+        assert_eq!(faults.rules[0].fault, Fault::Status { status: 503, body: String::new() });
+        assert_eq!(faults.rules[0].probability, 0.5);
+        assert_eq!(faults.rules[1].probability, 1.0);
+        assert_eq!(faults.rules[2].fault, Fault::Corrupt { rate: 0.01 });
+        assert_eq!(faults.rules[1].fault.to_string(), "truncate body to 100 bytes");
+        assert!(serde_json::from_str::<FaultConfig>(r#"{ "rules": [{ "fault": { "type": "explode" } }] }"#).is_err());
+    }
+
+    #[test]
+    fn test_header_rules() {
+        let headers: HeaderRewriteConfig = serde_json::from_str(r#"{ "rules": [
+            { "action": "default", "name": "User-Agent", "value": "study-proxy" },
+            { "hosts": ["api.example.com"], "phase": "response", "action": "replace", "name": "Set-Cookie", "pattern": "; Secure", "replacement": "" },
+            { "paths": ["/upload"], "action": "remove", "name": "Cookie" }
+        ] }"#).unwrap();
+        assert_eq!(headers.rules[0].phase, RewritePhase::Request);
+        assert_eq!(headers.rules[1].flow.hosts, ["api.example.com"]);
+        assert!(matches!(&headers.rules[1].action, HeaderAction::Replace { pattern, .. } if pattern.as_str() == "; Secure"));
+
+        let invalid = r#"{ "rules": [{ "action": "replace", "name": "Host", "pattern": "(", "replacement": "" }] }"#;
+        assert!(serde_json::from_str::<HeaderRewriteConfig>(invalid).is_err());
+    }
+    #[test]
+    fn test_body_rules() {
+        let mut config: Config = serde_json::from_str(r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            },
+            "body": { "rules": [
+                { "hosts": ["api.example.com"], "phase": "response", "action": "json_set", "pointer": "/flags/beta", "value": true },
+                { "paths": ["/page"], "phase": "response", "action": "replace", "pattern": "https://cdn\\.", "replacement": "http://localhost:8080/" },
+                { "methods": ["POST"], "action": "json_delete", "pointer": "/debug" }
+            ] }
+        }"#).unwrap();
+
+        let actions = config.body_actions_for(RewritePhase::Response, "api.example.com", "GET", "/page");
+        assert_eq!(actions.len(), 2);
+        assert!(matches!(&actions[0], BodyAction::JsonSet { pointer, value } if pointer == "/flags/beta" && value == &serde_json::json!(true)));
+        assert!(matches!(&actions[1], BodyAction::Replace { pattern, .. } if pattern.as_str() == "https://cdn\\."));
+        assert!(config.body_actions_for(RewritePhase::Request, "example.com", "GET", "/").is_empty());
+        assert_eq!(config.body_actions_for(RewritePhase::Request, "example.com", "POST", "/").len(), 1);
+
+        // 有消息体改写规则的主机按请求处理
+        assert!(config.needs_http1_interception("example.com"));
+        config.body.rules.clear();
+        assert!(!config.needs_http1_interception("example.com"));
+    }
 }
\ No newline at end of file
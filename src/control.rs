@@ -0,0 +1,431 @@
+use crate::breakpoints::{Breakpoints, FlowEdit};
+use crate::conditioning::NetworkConditioner;
+use crate::shutdown::ShutdownHandle;
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// 控制接口请求体的最大长度
+const MAX_CONTROL_BODY_SIZE: usize = 1024 * 1024;
+
+/// 切换网络模拟的请求体
+#[derive(Debug, Deserialize)]
+struct SetNetworkProfile {
+    /// 网络模拟名称，null或"none"表示不限制
+    profile: Option<String>,
+}
+
+/// 运行控制接口监听循环，直到触发关闭
+///
+/// 设置了访问令牌时，所有请求都需要带有 `Authorization: Bearer <token>`，否则返回401。
+/// 接口：
+/// * `GET /network` - 查看当前的网络模拟和所有可用的参数
+/// * `PUT /network` - 切换全局网络模拟，请求体为 `{"profile": "3g"}`
+/// * `GET /breakpoints` - 查看所有在断点处暂停的请求和响应
+/// * `GET /breakpoints/{id}` - 查看暂停的请求或响应
+/// * `PUT /breakpoints/{id}` - 编辑暂停的请求或响应，请求体为 [`FlowEdit`]
+/// * `POST /breakpoints/{id}/continue` - 继续，可以带有最后的编辑
+/// * `POST /breakpoints/{id}/abort` - 中止并断开客户端连接
+///
+/// # 参数
+/// * `listener` - 控制接口监听器
+/// * `token` - 访问令牌（可选）
+/// * `conditioner` - 网络模拟器
+/// * `breakpoints` - 断点管理器
+/// * `shutdown` - 关闭句柄
+pub async fn run_control_listener(
+    listener: TcpListener,
+    token: Option<String>,
+    conditioner: Arc<NetworkConditioner>,
+    breakpoints: Arc<Breakpoints>,
+    shutdown: ShutdownHandle,
+) {
+    let token: Option<Arc<str>> = token.map(Arc::from);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait() => break,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!("Control accept error: {e}");
+                continue;
+            }
+        };
+
+        let token = token.clone();
+        let conditioner = Arc::clone(&conditioner);
+        let breakpoints = Arc::clone(&breakpoints);
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                handle_control_request(request, token.clone(), Arc::clone(&conditioner), Arc::clone(&breakpoints))
+            });
+            if let Err(e) = Http::new().http1_only(true).serve_connection(stream, service).await {
+                log::debug!("Control connection error: {e}");
+            }
+        });
+    }
+}
+
+/// 处理控制接口请求
+async fn handle_control_request(
+    request: Request<Body>,
+    token: Option<Arc<str>>,
+    conditioner: Arc<NetworkConditioner>,
+    breakpoints: Arc<Breakpoints>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(token) = &token {
+        if !is_authorized(&request, token) {
+            let mut response = json_response(StatusCode::UNAUTHORIZED, serde_json::json!({ "error": "Unauthorized" }));
+            response.headers_mut().insert("WWW-Authenticate", hyper::header::HeaderValue::from_static("Bearer"));
+            return Ok(response);
+        }
+    }
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let body = match read_body(request.into_body()).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let response = match (&method, path.as_str()) {
+        (&Method::GET, "/network") => json_response(StatusCode::OK, network_status(&conditioner)),
+        (&Method::PUT, "/network") => {
+            let result = serde_json::from_slice::<SetNetworkProfile>(&body)
+                .map_err(anyhow::Error::from)
+                .and_then(|body| conditioner.set_active_profile(body.profile));
+            match result {
+                Ok(()) => json_response(StatusCode::OK, network_status(&conditioner)),
+                Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({ "error": e.to_string() })),
+            }
+        },
+        (&Method::GET, "/breakpoints") => json_response(StatusCode::OK, serde_json::json!({ "paused": breakpoints.list() })),
+        (_, "/network") | (_, "/breakpoints") => method_not_allowed(),
+        (_, path) => match path.strip_prefix("/breakpoints/") {
+            Some(target) => handle_breakpoint_request(&method, target, &body, &breakpoints),
+            None => not_found(),
+        },
+    };
+    Ok(response)
+}
+
+/// 处理单个暂停的请求或响应的接口
+///
+/// # 参数
+/// * `method` - 请求方法
+/// * `target` - `/breakpoints/` 之后的路径，如 `3` 或 `3/continue`
+/// * `body` - 请求体
+/// * `breakpoints` - 断点管理器
+fn handle_breakpoint_request(method: &Method, target: &str, body: &[u8], breakpoints: &Breakpoints) -> Response<Body> {
+    let (id, action) = match target.split_once('/') {
+        Some((id, action)) => (id, Some(action)),
+        None => (target, None),
+    };
+    let Ok(id) = id.parse::<u64>() else {
+        return not_found();
+    };
+    // 继续时可以不带编辑
+    let edit = || match body.is_empty() {
+        true => Ok(FlowEdit::default()),
+        false => serde_json::from_slice::<FlowEdit>(body).map_err(anyhow::Error::from),
+    };
+
+    let result = match (method, action) {
+        (&Method::GET, None) => Ok(breakpoints.get(id)),
+        (&Method::PUT, None) => edit().and_then(|edit| breakpoints.edit(id, edit)),
+        (&Method::POST, Some("continue")) => edit()
+            .and_then(|edit| breakpoints.resume(id, edit))
+            .map(|resumed| resumed.then(|| serde_json::json!({ "id": id, "status": "continued" }))),
+        (&Method::POST, Some("abort")) => Ok(breakpoints.abort(id).then(|| serde_json::json!({ "id": id, "status": "aborted" }))),
+        (_, None) | (_, Some("continue")) | (_, Some("abort")) => return method_not_allowed(),
+        _ => return not_found(),
+    };
+    match result {
+        Ok(Some(body)) => json_response(StatusCode::OK, body),
+        Ok(None) => json_response(StatusCode::NOT_FOUND, serde_json::json!({ "error": format!("No paused flow with id {id}") })),
+        Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({ "error": e.to_string() })),
+    }
+}
+
+/// 请求是否带有正确的访问令牌
+fn is_authorized(request: &Request<Body>, token: &str) -> bool {
+    let provided = request.headers().get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // 按固定时间比较，不通过响应时间泄露令牌内容
+    provided.len() == token.len()
+        && provided.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// 读取请求体
+///
+/// # 返回值
+/// 请求体超过 [`MAX_CONTROL_BODY_SIZE`] 时返回413响应，读取失败时返回400响应
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || json_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        serde_json::json!({ "error": format!("Request body larger than {MAX_CONTROL_BODY_SIZE} bytes") }),
+    );
+    if body.size_hint().lower() > MAX_CONTROL_BODY_SIZE as u64 {
+        return Err(too_large());
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({ "error": format!("Failed to read request body: {e}") }),
+        ))?;
+        if data.len() + chunk.len() > MAX_CONTROL_BODY_SIZE {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// 生成405响应
+fn method_not_allowed() -> Response<Body> {
+    json_response(StatusCode::METHOD_NOT_ALLOWED, serde_json::json!({ "error": "Method not allowed" }))
+}
+
+/// 生成404响应
+fn not_found() -> Response<Body> {
+    json_response(StatusCode::NOT_FOUND, serde_json::json!({ "error": "Not found" }))
+}
+
+/// 当前的网络模拟状态
+fn network_status(conditioner: &NetworkConditioner) -> serde_json::Value {
+    serde_json::json!({
+        "profile": conditioner.active_profile(),
+        "profiles": conditioner.profiles(),
+    })
+}
+
+/// 生成JSON响应
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn send(addr: std::net::SocketAddr, request: &str) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_switch_network_profile() {
+        let config: Config = serde_json::from_str(r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            }
+        }"#).unwrap();
+        let config = Arc::new(config);
+        let conditioner = Arc::new(NetworkConditioner::new(Arc::clone(&config)).unwrap());
+        let breakpoints = Arc::new(Breakpoints::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_control_listener(listener, None, Arc::clone(&conditioner), breakpoints, ShutdownHandle::new()));
+
+        let (status, body) = send(addr, "GET /network HTTP/1.1\r\nHost: control\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(status, 200);
+        assert!(body["profile"].is_null());
+        assert_eq!(body["profiles"]["edge"]["download_kbps"], 240);
+
+        let put = |payload: &str| format!(
+            "PUT /network HTTP/1.1\r\nHost: control\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{payload}",
+            payload.len()
+        );
+        let (status, body) = send(addr, &put(r#"{"profile": "3g"}"#)).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["profile"], "3g");
+        assert_eq!(conditioner.active_profile().as_deref(), Some("3g"));
+
+        let (status, body) = send(addr, &put(r#"{"profile": "5g"}"#)).await;
+        assert_eq!(status, 400);
+        assert_eq!(body["error"], "Unknown network profile: 5g");
+        assert_eq!(conditioner.active_profile().as_deref(), Some("3g"));
+
+        let (status, _) = send(addr, "GET /missing HTTP/1.1\r\nHost: control\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_breakpoint_endpoints() {
+        let config: Config = serde_json::from_str(r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            },
+            "breakpoints": { "rules": [{ "phase": "response" }] }
+        }"#).unwrap();
+        let config = Arc::new(config);
+        let conditioner = Arc::new(NetworkConditioner::new(Arc::clone(&config)).unwrap());
+        let breakpoints = Arc::new(Breakpoints::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_control_listener(listener, None, conditioner, Arc::clone(&breakpoints), ShutdownHandle::new()));
+
+        let request = crate::interceptor::FlowRequest {
+            method: "GET".to_string(),
+            origin: "https://example.com".to_string(),
+            path: "/".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        let response = crate::interceptor::FlowResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"hello".to_vec(),
+        };
+        let flow = crate::interceptor::Flow { host: "example.com".to_string(), request, response: Some(response), error: None };
+        let paused = tokio::spawn({
+            let breakpoints = Arc::clone(&breakpoints);
+            async move { breakpoints.pause(flow).await }
+        });
+
+        let request = |method: &str, path: &str, payload: &str| format!(
+            "{method} {path} HTTP/1.1\r\nHost: control\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{payload}",
+            payload.len()
+        );
+        let id = loop {
+            let (status, body) = send(addr, &request("GET", "/breakpoints", "")).await;
+            assert_eq!(status, 200);
+            if let Some(flow) = body["paused"].as_array().unwrap().first() {
+                assert_eq!(flow["phase"], "response");
+                assert_eq!(flow["response"]["body"], "hello");
+                break flow["id"].as_u64().unwrap();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        };
+
+        let (status, body) = send(addr, &request("PUT", &format!("/breakpoints/{id}"), r#"{"status": 503, "body": "down"}"#)).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["response"]["status"], 503);
+        let (status, body) = send(addr, &request("PUT", &format!("/breakpoints/{id}"), r#"{"url": "https://example.com/x"}"#)).await;
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().contains("only the response can be edited"));
+        let (status, _) = send(addr, &request("DELETE", &format!("/breakpoints/{id}"), "")).await;
+        assert_eq!(status, 405);
+
+        let (status, body) = send(addr, &request("POST", &format!("/breakpoints/{id}/continue"), "")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["status"], "continued");
+        let response = paused.await.unwrap().unwrap().response.unwrap();
+        assert_eq!((response.status, response.body), (503, b"down".to_vec()));
+
+        let (status, _) = send(addr, &request("POST", &format!("/breakpoints/{id}/abort"), "")).await;
+        assert_eq!(status, 404);
+        let (status, _) = send(addr, &request("GET", "/breakpoints/x", "")).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_token_and_body_limit() {
+        let config: Config = serde_json::from_str(r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            }
+        }"#).unwrap();
+        let config = Arc::new(config);
+        let conditioner = Arc::new(NetworkConditioner::new(Arc::clone(&config)).unwrap());
+        let breakpoints = Arc::new(Breakpoints::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_control_listener(
+            listener,
+            Some("secret".to_string()),
+            Arc::clone(&conditioner),
+            breakpoints,
+            ShutdownHandle::new(),
+        ));
+
+        let (status, body) = send(addr, "GET /network HTTP/1.1\r\nHost: control\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(status, 401);
+        assert_eq!(body["error"], "Unauthorized");
+        let (status, _) = send(
+            addr,
+            "GET /network HTTP/1.1\r\nHost: control\r\nAuthorization: Bearer wrong!\r\nConnection: close\r\n\r\n",
+        ).await;
+        assert_eq!(status, 401);
+        let (status, _) = send(
+            addr,
+            "GET /network HTTP/1.1\r\nHost: control\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n",
+        ).await;
+        assert_eq!(status, 200);
+
+        // 声明的长度超过上限时不读取请求体直接拒绝
+        let (status, _) = send(addr, &format!(
+            "PUT /network HTTP/1.1\r\nHost: control\r\nAuthorization: Bearer secret\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            MAX_CONTROL_BODY_SIZE + 1
+        )).await;
+        assert_eq!(status, 413);
+
+        assert_eq!(conditioner.active_profile(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_body_errors() {
+        // 没有声明长度的请求体在读取过程中超过上限
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let chunk = hyper::body::Bytes::from(vec![b'a'; 64 * 1024]);
+            while sender.send_data(chunk.clone()).await.is_ok() {}
+        });
+        let response = read_body(body).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // 读取失败不能当作空的请求体
+        let (mut sender, body) = Body::channel();
+        sender.send_data("{\"profile\"".into()).await.unwrap();
+        sender.abort();
+        let response = read_body(body).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let (mut sender, body) = Body::channel();
+        sender.send_data("{}".into()).await.unwrap();
+        drop(sender);
+        assert_eq!(read_body(body).await.unwrap(), b"{}");
+    }
+}
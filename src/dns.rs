@@ -0,0 +1,305 @@
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::config::Config;
+
+/// 向自定义DNS服务器查询的超时时间
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UDP响应的最大长度
+const MAX_UDP_RESPONSE_SIZE: usize = 4096;
+
+/// A记录类型
+const RECORD_A: u16 = 1;
+
+/// AAAA记录类型
+const RECORD_AAAA: u16 = 28;
+
+/// 解析上游目标的地址
+///
+/// 依次使用IP字面量、静态映射、自定义DNS服务器和系统解析。
+/// 只影响实际连接的地址，SNI和Host头仍使用原始域名。
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+///
+/// # 返回值
+/// 返回可以依次尝试连接的地址列表
+pub async fn resolve(config: &Config, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    if let Some(ip) = config.dns_override(name) {
+        log::info!("📌 Resolved {name} to {ip} via static mapping");
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    if let Some(resolver) = &config.dns.resolver {
+        let server = resolver_address(resolver)?;
+        let ips = query(server, name).await
+            .with_context(|| format!("Failed to resolve {name} via DNS server {server}"))?;
+        log::info!("🔎 Resolved {name} to {ips:?} via DNS server {server}");
+        return Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect());
+    }
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name, port)).await
+        .with_context(|| format!("Failed to resolve {name}"))?
+        .collect();
+    Ok(addrs)
+}
+
+/// 解析自定义DNS服务器地址，未指定端口时使用53
+fn resolver_address(resolver: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = resolver.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip = resolver.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()
+        .with_context(|| format!("Invalid DNS resolver address: {resolver}"))?;
+    Ok(SocketAddr::new(ip, 53))
+}
+
+/// 向DNS服务器查询A记录，没有A记录时查询AAAA记录
+async fn query(server: SocketAddr, name: &str) -> Result<Vec<IpAddr>> {
+    for record_type in [RECORD_A, RECORD_AAAA] {
+        let ips = tokio::time::timeout(QUERY_TIMEOUT, query_record(server, name, record_type)).await
+            .map_err(|_| anyhow::anyhow!("DNS query timed out after {}s", QUERY_TIMEOUT.as_secs()))??;
+        if !ips.is_empty() {
+            return Ok(ips);
+        }
+    }
+    anyhow::bail!("No A or AAAA records found")
+}
+
+/// 通过UDP查询一种记录，响应被截断时改用TCP重新查询
+async fn query_record(server: SocketAddr, name: &str, record_type: u16) -> Result<Vec<IpAddr>> {
+    // 随机的查询ID，加上问题部分的校验，使伪造的响应难以被接受
+    let request = build_query(fastrand::u16(..), name, record_type)?;
+
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+    socket.send(&request).await?;
+
+    let mut response = vec![0; MAX_UDP_RESPONSE_SIZE];
+    loop {
+        let n = socket.recv(&mut response).await?;
+        // 忽略ID或问题与本次查询不符的响应
+        if matches_query(&request, &response[..n]) {
+            response.truncate(n);
+            break;
+        }
+    }
+
+    if is_truncated(&response) {
+        log::debug!("DNS response for {name} truncated, retrying over TCP");
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_all(&(request.len() as u16).to_be_bytes()).await?;
+        stream.write_all(&request).await?;
+        let len = stream.read_u16().await? as usize;
+        response = vec![0; len];
+        stream.read_exact(&mut response).await?;
+    }
+
+    parse_response(&request, &response, record_type)
+}
+
+/// 构造标准递归查询报文
+fn build_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // 标志：期望递归
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // 1个问题
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid domain name: {name}");
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&[0, 1]); // IN
+    Ok(query)
+}
+
+/// 响应是否设置了截断标志
+fn is_truncated(response: &[u8]) -> bool {
+    response.len() >= 3 && response[2] & 0x02 != 0
+}
+
+/// 响应的ID和问题部分是否与查询一致（域名不区分大小写）
+fn matches_query(query: &[u8], response: &[u8]) -> bool {
+    response.len() >= query.len()
+        && response[..2] == query[..2]
+        && response[4..6] == query[4..6]
+        && response[12..query.len()].eq_ignore_ascii_case(&query[12..])
+}
+
+/// 从响应中读取指定类型的地址记录
+///
+/// # 参数
+/// * `query` - 发送的查询报文
+/// * `response` - 收到的响应报文
+/// * `record_type` - 查询的记录类型
+fn parse_response(query: &[u8], response: &[u8], record_type: u16) -> Result<Vec<IpAddr>> {
+    let read_u16 = |pos: usize| -> Result<u16> {
+        response.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| anyhow::anyhow!("Truncated DNS response"))
+    };
+
+    if !matches_query(query, response) {
+        anyhow::bail!("DNS response does not match the query");
+    }
+    let flags = read_u16(2)?;
+    match flags & 0x000f {
+        0 => {},
+        // NXDOMAIN
+        3 => return Ok(Vec::new()),
+        rcode => anyhow::bail!("DNS server returned error code {rcode}"),
+    }
+    let answers = read_u16(6)?;
+
+    // 问题部分与查询相同，应答从查询报文的长度之后开始
+    let mut pos = query.len();
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(response, pos)?;
+        let answer_type = read_u16(pos)?;
+        let data_len = read_u16(pos + 8)? as usize;
+        let data = response.get(pos + 10..pos + 10 + data_len)
+            .ok_or_else(|| anyhow::anyhow!("Truncated DNS response"))?;
+        // CNAME等其他记录直接跳过
+        match (answer_type, data_len) {
+            (RECORD_A, 4) if record_type == RECORD_A => {
+                ips.push(IpAddr::from(<[u8; 4]>::try_from(data)?));
+            },
+            (RECORD_AAAA, 16) if record_type == RECORD_AAAA => {
+                ips.push(IpAddr::from(<[u8; 16]>::try_from(data)?));
+            },
+            _ => {},
+        }
+        pos += 10 + data_len;
+    }
+    Ok(ips)
+}
+
+/// 跳过报文中的域名（支持压缩指针），返回域名之后的位置
+fn skip_name(response: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *response.get(pos).ok_or_else(|| anyhow::anyhow!("Truncated DNS response"))? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            // 压缩指针占两个字节，之后的内容不在当前位置
+            l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+            l => pos += 1 + l,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 创建带DNS配置的测试配置
+    fn create_test_config(dns: &str) -> Config {
+        serde_json::from_str(&format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": ["*"], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" }},
+            "dns": {dns},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }}
+        }}"#)).unwrap()
+    }
+
+    /// 构造包含CNAME和A记录的应答
+    fn answer(query: &[u8], ips: &[[u8; 4]]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = (ips.len() + 1) as u8;
+        // CNAME指向问题中的域名
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 0x0c]);
+        for ip in ips {
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(ip);
+        }
+        response
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let query = build_query(0x1234, "api.example.com", RECORD_A).unwrap();
+        assert_eq!(&query[12..17], b"\x03api\x07");
+
+        let response = answer(&query, &[[10, 0, 0, 1], [10, 0, 0, 2]]);
+        let ips = parse_response(&query, &response, RECORD_A).unwrap();
+        assert_eq!(ips, vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])]);
+
+        // 查询AAAA时忽略A记录
+        let aaaa_query = build_query(0x1234, "api.example.com", RECORD_AAAA).unwrap();
+        assert!(parse_response(&aaaa_query, &answer(&aaaa_query, &[[10, 0, 0, 1]]), RECORD_AAAA).unwrap().is_empty());
+        assert!(parse_response(&query, &response[..response.len() - 2], RECORD_A).is_err());
+
+        // ID或问题与查询不符的响应被拒绝，域名大小写不同视为一致
+        let other_id = build_query(0x4321, "api.example.com", RECORD_A).unwrap();
+        assert!(parse_response(&other_id, &response, RECORD_A).is_err());
+        let other_name = build_query(0x1234, "evil.example.com", RECORD_A).unwrap();
+        assert!(!matches_query(&other_name, &answer(&query, &[[10, 0, 0, 1]])));
+        assert!(parse_response(&query, &answer(&other_name, &[[10, 0, 0, 1]]), RECORD_A).is_err());
+        let upper = build_query(0x1234, "API.example.COM", RECORD_A).unwrap();
+        assert!(parse_response(&query, &answer(&upper, &[[10, 0, 0, 1]]), RECORD_A).is_ok());
+        assert!(build_query(1, "bad..name", RECORD_A).is_err());
+
+        assert_eq!(resolver_address("10.0.0.53").unwrap(), "10.0.0.53:53".parse().unwrap());
+        assert_eq!(resolver_address("[::1]:5353").unwrap(), "[::1]:5353".parse().unwrap());
+        assert!(resolver_address("dns.local").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_via_custom_server() {
+        // 只应答A记录的DNS服务器，每个应答之前先发送一个问题不符的伪造应答
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            loop {
+                let (n, peer) = server.recv_from(&mut buffer).await.unwrap();
+                let mut spoofed = answer(&buffer[..n], &[[203, 0, 113, 66]]);
+                spoofed[13] ^= 0x01;
+                server.send_to(&spoofed, peer).await.unwrap();
+                server.send_to(&answer(&buffer[..n], &[[192, 0, 2, 7]]), peer).await.unwrap();
+            }
+        });
+
+        let config = create_test_config(&format!(
+            r#"{{ "hosts": {{ "pinned.example.com": "10.1.2.3" }}, "resolver": "{server_addr}" }}"#
+        ));
+
+        let addrs = resolve(&config, "www.example.com", 443).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.7:443".parse().unwrap()]);
+
+        // 静态映射和IP字面量不查询DNS服务器
+        let addrs = resolve(&config, "pinned.example.com", 80).await.unwrap();
+        assert_eq!(addrs, vec!["10.1.2.3:80".parse().unwrap()]);
+        let addrs = resolve(&config, "[::1]", 80).await.unwrap();
+        assert_eq!(addrs, vec!["[::1]:80".parse().unwrap()]);
+    }
+}
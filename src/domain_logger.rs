@@ -1,9 +1,11 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task;
 use crate::config::Config;
 use std::io::Write;
+use chrono::{DateTime, Local};
 
 /// 日志条目结构体
 #[derive(Debug, Clone)]
@@ -30,12 +32,94 @@ pub struct LogEntry {
     pub error: Option<String>,
     /// 处理耗时（毫秒）
     pub duration_ms: u128,
+    /// 协议（HTTP/1.1、HTTP/2等）
+    pub protocol: String,
+    /// 记录时间
+    pub timestamp: DateTime<Local>,
+    /// WebSocket消息信息（仅WebSocket消息日志）
+    pub websocket: Option<WebSocketMessage>,
+    /// 客户端发送的字节数（隧道和原始TCP连接）
+    pub client_bytes: u64,
+    /// 服务器发送的字节数（隧道和原始TCP连接）
+    pub server_bytes: u64,
+    /// 向上游出示的客户端证书（mTLS）
+    pub client_identity: Option<String>,
+    /// 上游连接耗时（转发到上游的HTTP请求）
+    pub timing: Option<ConnectionTiming>,
+    /// 上游实际连接的IP（直接连接上游时）
+    pub resolved_ip: Option<IpAddr>,
+    /// 注入的故障（故障注入规则命中时）
+    pub fault: Option<String>,
+    /// 请求被映射到的目标（Map Local为file://路径，Map Remote为转发的URL）
+    pub mapped: Option<String>,
+    /// 实际发送给上游的请求头（request_headers为从客户端收到的请求头）
+    pub sent_request_headers: Option<HashMap<String, String>>,
+    /// 实际返回给客户端的响应头（response_headers为从上游收到的响应头）
+    pub sent_response_headers: Option<HashMap<String, String>>,
+    /// 改写后实际发送给上游的请求体（消息体改写规则生效时）
+    pub sent_request_body: Option<String>,
+    /// 改写后实际返回给客户端的响应体（消息体改写规则生效时）
+    pub sent_response_body: Option<String>,
+}
+
+/// 请求使用的上游连接的耗时信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionTiming {
+    /// 建立上游连接的耗时（毫秒，复用连接时为0）
+    pub connect_ms: u128,
+    /// 是否复用了已有的上游连接
+    pub reused: bool,
+}
+
+/// WebSocket消息方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketDirection {
+    /// 客户端发往服务器
+    ClientToServer,
+    /// 服务器发往客户端
+    ServerToClient,
+}
+
+impl std::fmt::Display for WebSocketDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketDirection::ClientToServer => write!(f, "client->server"),
+            WebSocketDirection::ServerToClient => write!(f, "server->client"),
+        }
+    }
+}
+
+/// WebSocket消息信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketMessage {
+    /// 消息方向
+    pub direction: WebSocketDirection,
+    /// 操作码（分片消息为第一个分片的操作码）
+    pub opcode: u8,
+    /// 分片数量
+    pub fragments: usize,
+    /// 是否使用了掩码
+    pub masked: bool,
+    /// 是否为permessage-deflate压缩消息
+    pub compressed: bool,
+    /// 负载总长度
+    pub payload_length: u64,
+    /// 关闭码（仅close帧）
+    pub close_code: Option<u16>,
+}
+
+/// 发送给后台日志任务的消息
+enum LogMessage {
+    /// 需要写入的日志条目
+    Entry(Box<LogEntry>),
+    /// 之前的条目都写入后通知
+    Flush(oneshot::Sender<()>),
 }
 
 /// 域名日志记录器
 pub struct DomainLogger {
     /// 日志发送通道
-    sender: mpsc::UnboundedSender<LogEntry>,
+    sender: mpsc::UnboundedSender<LogMessage>,
 }
 
 impl DomainLogger {
@@ -52,8 +136,13 @@ impl DomainLogger {
         
         // 启动后台日志处理任务
         task::spawn(async move {
-            while let Some(entry) = receiver.recv().await {
-                Self::process_log_entry(entry, &config_clone);
+            while let Some(message) = receiver.recv().await {
+                match message {
+                    LogMessage::Entry(entry) => Self::process_log_entry(*entry, &config_clone),
+                    LogMessage::Flush(done) => {
+                        let _ = done.send(());
+                    },
+                }
             }
         });
 
@@ -66,7 +155,17 @@ impl DomainLogger {
     /// * `entry` - 日志条目
     pub fn log_request(&self, entry: LogEntry) {
         // 忽略发送错误，因为这通常意味着接收端已关闭
-        let _ = self.sender.send(entry);
+        let _ = self.sender.send(LogMessage::Entry(Box::new(entry)));
+    }
+
+    /// 等待已提交的日志全部写入文件
+    ///
+    /// 日志按提交顺序处理，返回时调用之前提交的条目都已写入。
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.sender.send(LogMessage::Flush(done)).is_ok() {
+            let _ = wait.await;
+        }
     }
 
     /// 处理日志条目
@@ -78,7 +177,6 @@ impl DomainLogger {
         use std::fs::{self, OpenOptions};
         use std::io::Write;
         use std::path::Path;
-        use chrono::Local;
         
         let date = Local::now().format("%Y-%m-%d").to_string();
         
@@ -105,7 +203,7 @@ impl DomainLogger {
 
         let log_line = format!(
             "[{}] {} {} {} - Status: {} - Duration: {}ms - Req: {} bytes - Resp: {} bytes - Params: {} - Error: {:?}",
-            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S%.3f"),
             entry.host,
             entry.method,
             entry.path,
@@ -129,12 +227,63 @@ impl DomainLogger {
             let _ = writeln!(file, "{log_line}");
             
             // 写入详细信息
+            let _ = writeln!(file, "  Protocol: {}", entry.protocol);
+            if let Some(fault) = &entry.fault {
+                let _ = writeln!(file, "  Fault Injected: {fault}");
+            }
+            if let Some(mapped) = &entry.mapped {
+                let _ = writeln!(file, "  Mapped To: {mapped}");
+            }
+            if let Some(message) = &entry.websocket {
+                let _ = writeln!(
+                    file,
+                    "  WebSocket: {} opcode={:#x} fragments={} masked={} compressed={} length={} close_code={:?}",
+                    message.direction,
+                    message.opcode,
+                    message.fragments,
+                    message.masked,
+                    message.compressed,
+                    message.payload_length,
+                    message.close_code
+                );
+            }
+            if entry.client_bytes > 0 || entry.server_bytes > 0 {
+                let _ = writeln!(file, "  Bytes: client={} server={}", entry.client_bytes, entry.server_bytes);
+            }
+            if let Some(timing) = &entry.timing {
+                let _ = writeln!(
+                    file,
+                    "  Timing: connect={}ms reused={}",
+                    timing.connect_ms,
+                    timing.reused
+                );
+            }
+            if let Some(ip) = &entry.resolved_ip {
+                let _ = writeln!(file, "  Resolved IP: {ip}");
+            }
+            if let Some(identity) = &entry.client_identity {
+                let _ = writeln!(file, "  Client Certificate: {identity}");
+            }
             let _ = writeln!(file, "  Request Headers: {:?}", entry.request_headers);
+            if let Some(headers) = &entry.sent_request_headers {
+                let _ = writeln!(file, "  Request Headers (sent): {headers:?}");
+            }
             let _ = writeln!(file, "  Response Headers: {:?}", entry.response_headers);
+            if let Some(headers) = &entry.sent_response_headers {
+                let _ = writeln!(file, "  Response Headers (sent): {headers:?}");
+            }
             
             // 根据内容是否为空决定是否写入
             Self::write_body_content_helper(&mut file, "Request Body", &truncated_request_body);
+            if let Some(body) = &entry.sent_request_body {
+                let body = Self::process_body_content_helper(body, config.logging.domain_logs.request_body_limit);
+                Self::write_body_content_helper(&mut file, "Request Body (sent)", &body);
+            }
             Self::write_body_content_helper(&mut file, "Response Body", &truncated_response_body);
+            if let Some(body) = &entry.sent_response_body {
+                let body = Self::process_body_content_helper(body, config.logging.domain_logs.response_body_limit);
+                Self::write_body_content_helper(&mut file, "Response Body (sent)", &body);
+            }
             
             let _ = writeln!(file, "---");
         } else {
@@ -225,6 +374,20 @@ impl DomainLogger {
             url_params,
             error,
             duration_ms,
+            protocol: "HTTP/1.1".to_string(),
+            timestamp: Local::now(),
+            websocket: None,
+            client_bytes: 0,
+            server_bytes: 0,
+            client_identity: None,
+            timing: None,
+            resolved_ip: None,
+            fault: None,
+            mapped: None,
+            sent_request_headers: None,
+            sent_response_headers: None,
+            sent_request_body: None,
+            sent_response_body: None,
         }
     }
 
@@ -254,6 +417,70 @@ impl DomainLogger {
             url_params: String::new(),
             error,
             duration_ms,
+            protocol: "TCP".to_string(),
+            timestamp: Local::now(),
+            websocket: None,
+            client_bytes: 0,
+            server_bytes: 0,
+            client_identity: None,
+            timing: None,
+            resolved_ip: None,
+            fault: None,
+            mapped: None,
+            sent_request_headers: None,
+            sent_response_headers: None,
+            sent_request_body: None,
+            sent_response_body: None,
+        }
+    }
+
+    /// 创建WebSocket消息日志条目
+    /// 
+    /// # 参数
+    /// * `host` - 主机名
+    /// * `path` - 升级请求的URL
+    /// * `message` - 消息信息
+    /// * `payload` - 消息内容（按方向记录为请求体或响应体）
+    /// 
+    /// # 返回值
+    /// 返回构建的LogEntry实例
+    pub fn create_websocket_log_entry(
+        host: String,
+        path: String,
+        message: WebSocketMessage,
+        payload: String,
+    ) -> LogEntry {
+        let (request_body, response_body) = match message.direction {
+            WebSocketDirection::ClientToServer => (payload, String::new()),
+            WebSocketDirection::ServerToClient => (String::new(), payload),
+        };
+
+        LogEntry {
+            host,
+            method: "WEBSOCKET".to_string(),
+            path,
+            request_headers: HashMap::new(),
+            response_headers: HashMap::new(),
+            status_code: 101,
+            request_body,
+            response_body,
+            url_params: String::new(),
+            error: None,
+            duration_ms: 0,
+            protocol: "WebSocket".to_string(),
+            timestamp: Local::now(),
+            websocket: Some(message),
+            client_bytes: 0,
+            server_bytes: 0,
+            client_identity: None,
+            timing: None,
+            resolved_ip: None,
+            fault: None,
+            mapped: None,
+            sent_request_headers: None,
+            sent_response_headers: None,
+            sent_request_body: None,
+            sent_response_body: None,
         }
     }
 }
@@ -269,6 +496,7 @@ mod tests {
             proxy: crate::config::ProxyConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8888,
+                shutdown_grace_secs: 10,
             },
             target: crate::config::TargetConfig {
                 domains: vec!["*".to_string()],
@@ -285,6 +513,23 @@ mod tests {
             system_proxy: crate::config::SystemProxyConfig {
                 enabled: true
             },
+            socks5: crate::config::Socks5Config::default(),
+            transparent: crate::config::TransparentConfig::default(),
+            reverse_proxy: crate::config::ReverseProxyConfig::default(),
+            upstream_proxy: crate::config::UpstreamProxyConfig::default(),
+            upstream_tls: crate::config::UpstreamTlsConfig::default(),
+            upstream_pool: crate::config::UpstreamPoolConfig::default(),
+            dns: crate::config::DnsConfig::default(),
+            timeouts: crate::config::TimeoutConfig::default(),
+            network: crate::config::NetworkConditioningConfig::default(),
+            faults: crate::config::FaultConfig::default(),
+            map_local: crate::config::MapLocalConfig::default(),
+            map_remote: crate::config::MapRemoteConfig::default(),
+            headers: crate::config::HeaderRewriteConfig::default(),
+            body: crate::config::BodyRewriteConfig::default(),
+            breakpoints: crate::config::BreakpointConfig::default(),
+            scripts: crate::config::ScriptConfig::default(),
+            control: crate::config::ControlConfig::default(),
             logging: crate::config::LoggingConfig {
                 level: "debug".to_string(),
                 output: "file".to_string(),
@@ -295,6 +540,7 @@ mod tests {
                     format: "domain_{domain}_{date}.log".to_string(),
                     request_body_limit: 1024,
                     response_body_limit: 1024,
+                    raw_dump_limit: 0,
                 },
             },
         }
@@ -355,6 +601,23 @@ mod tests {
         assert_eq!(log_entry.request_headers, request_headers);
         assert_eq!(log_entry.response_headers, response_headers);
         assert_eq!(log_entry.duration_ms, 150);
+        assert_eq!(log_entry.protocol, "HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_flush_writes_pending_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_dir = temp_dir.path().to_str().unwrap().to_string();
+        let logger = DomainLogger::new(Arc::new(create_test_config(&log_dir)));
+
+        for i in 0..20 {
+            logger.log_request(DomainLogger::create_tunnel_log_entry("flush.example.com".to_string(), i, None));
+        }
+        logger.flush().await;
+
+        let date = Local::now().format("%Y-%m-%d").to_string();
+        let content = std::fs::read_to_string(temp_dir.path().join(format!("{date}_flush.example.com.log"))).unwrap();
+        assert_eq!(content.matches("CONNECT TUNNEL").count(), 20);
     }
 
     #[test]
@@ -376,6 +639,34 @@ mod tests {
         assert!(log_entry.request_headers.is_empty());
         assert!(log_entry.response_headers.is_empty());
         assert_eq!(log_entry.duration_ms, 200);
+        assert_eq!(log_entry.protocol, "TCP");
+    }
+
+    #[test]
+    fn test_create_websocket_log_entry() {
+        let message = WebSocketMessage {
+            direction: WebSocketDirection::ServerToClient,
+            opcode: 0x1,
+            fragments: 2,
+            masked: false,
+            compressed: false,
+            payload_length: 5,
+            close_code: None,
+        };
+        let log_entry = DomainLogger::create_websocket_log_entry(
+            "example.com".to_string(),
+            "wss://example.com:443/ws".to_string(),
+            message.clone(),
+            "hello".to_string(),
+        );
+
+        assert_eq!(log_entry.method, "WEBSOCKET");
+        assert_eq!(log_entry.status_code, 101);
+        assert_eq!(log_entry.protocol, "WebSocket");
+        assert_eq!(log_entry.request_body, "");
+        assert_eq!(log_entry.response_body, "hello");
+        assert_eq!(log_entry.websocket, Some(message));
+        assert_eq!(WebSocketDirection::ClientToServer.to_string(), "client->server");
     }
 
     #[test]
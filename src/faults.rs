@@ -0,0 +1,144 @@
+use crate::config::{Config, Fault};
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// 选择请求要注入的故障
+///
+/// 按配置顺序检查匹配的规则，每条规则按自己的概率决定是否生效，
+/// 返回第一条生效规则的故障。
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 请求的主机
+/// * `method` - 请求方法
+/// * `path` - 请求路径
+///
+/// # 返回值
+/// 返回要注入的故障，没有规则生效时返回None
+pub fn pick_fault(config: &Config, host: &str, method: &str, path: &str) -> Option<Fault> {
+    config.fault_rules_for(host, method, path)
+        .find(|rule| rule.probability >= 1.0 || fastrand::f64() < rule.probability)
+        .map(|rule| rule.fault.clone())
+}
+
+/// 作用在响应数据上的故障状态
+#[derive(Debug)]
+pub struct BodyFault {
+    /// 注入的故障
+    fault: Fault,
+    /// 已计数的字节数（close_after计算所有字节，truncate只计算响应体）
+    counted: usize,
+}
+
+impl BodyFault {
+    /// 创建响应数据故障状态
+    ///
+    /// # 参数
+    /// * `fault` - 注入的故障
+    ///
+    /// # 返回值
+    /// 故障不作用在响应数据上时返回None
+    pub fn new(fault: &Fault) -> Option<Self> {
+        match fault {
+            Fault::CloseAfter { .. } | Fault::Truncate { .. } | Fault::Corrupt { .. } => Some(Self {
+                fault: fault.clone(),
+                counted: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    /// 对即将转发给客户端的数据应用故障
+    ///
+    /// # 参数
+    /// * `data` - 原始数据
+    /// * `body_ranges` - 数据中属于响应体内容的范围（不含chunk分帧）
+    ///
+    /// # 返回值
+    /// 返回实际转发的数据，以及转发之后是否应断开连接
+    pub fn apply<'a>(&mut self, data: &'a [u8], body_ranges: &[Range<usize>]) -> (Cow<'a, [u8]>, bool) {
+        match self.fault {
+            Fault::CloseAfter { bytes } => {
+                let allowed = bytes.saturating_sub(self.counted).min(data.len());
+                self.counted += allowed;
+                (Cow::Borrowed(&data[..allowed]), self.counted >= bytes)
+            },
+            Fault::Truncate { bytes } => {
+                for range in body_ranges {
+                    let remaining = bytes - self.counted;
+                    if range.len() >= remaining {
+                        self.counted = bytes;
+                        return (Cow::Borrowed(&data[..range.start + remaining]), true);
+                    }
+                    self.counted += range.len();
+                }
+                (Cow::Borrowed(data), false)
+            },
+            Fault::Corrupt { rate } => {
+                let mut corrupted = data.to_vec();
+                for index in body_ranges.iter().flat_map(|range| range.clone()) {
+                    if fastrand::f64() < rate {
+                        corrupted[index] ^= fastrand::u8(1..);
+                    }
+                }
+                (Cow::Owned(corrupted), false)
+            },
+            _ => (Cow::Borrowed(data), false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_fault() {
+        let mut config: Config = serde_json::from_str(r#"{
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            },
+            "faults": { "rules": [
+                { "paths": ["/flaky"], "fault": { "type": "reset" }, "probability": 0 },
+                { "hosts": ["api.example.com"], "methods": ["POST"], "fault": { "type": "status", "status": 503 } },
+                { "paths": ["/flaky"], "fault": { "type": "delay", "ms": 100 } }
+            ] }
+        }"#).unwrap();
+
+        assert_eq!(pick_fault(&config, "api.example.com", "POST", "/"), Some(Fault::Status { status: 503, body: String::new() }));
+        assert_eq!(pick_fault(&config, "api.example.com", "GET", "/"), None);
+        // 概率为0的规则不生效，继续匹配后面的规则
+        assert_eq!(pick_fault(&config, "example.com", "GET", "/flaky?retry=1"), Some(Fault::Delay { ms: 100 }));
+
+        config.faults.rules[0].probability = 1.0;
+        assert_eq!(pick_fault(&config, "example.com", "GET", "/flaky"), Some(Fault::Reset));
+    }
+
+    #[test]
+    fn test_body_faults() {
+        assert!(BodyFault::new(&Fault::Reset).is_none());
+
+        // close_after包括响应头
+        let mut fault = BodyFault::new(&Fault::CloseAfter { bytes: 10 }).unwrap();
+        assert_eq!(fault.apply(b"HTTP/1.1 ", &[]), (Cow::Borrowed(&b"HTTP/1.1 "[..]), false));
+        assert_eq!(fault.apply(b"200 OK\r\n", &[]), (Cow::Borrowed(&b"2"[..]), true));
+
+        // truncate只计算响应体内容，chunk分帧原样转发
+        let mut fault = BodyFault::new(&Fault::Truncate { bytes: 4 }).unwrap();
+        assert_eq!(fault.apply(b"2\r\nab\r\n", std::slice::from_ref(&(3..5))), (Cow::Borrowed(&b"2\r\nab\r\n"[..]), false));
+        assert_eq!(fault.apply(b"1\r\nc\r\n3\r\ndef\r\n", &[3..4, 9..12]), (Cow::Borrowed(&b"1\r\nc\r\n3\r\nd"[..]), true));
+
+        let mut fault = BodyFault::new(&Fault::Corrupt { rate: 1.0 }).unwrap();
+        let (data, cut) = fault.apply(b"head:body", std::slice::from_ref(&(5..9)));
+        assert!(!cut);
+        assert_eq!(&data[..5], b"head:");
+        assert!(data[5..].iter().zip(b"body").all(|(a, b)| a != b));
+    }
+}
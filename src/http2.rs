@@ -0,0 +1,420 @@
+use anyhow::Result;
+use bytes::Bytes;
+use h2::{RecvStream, SendStream};
+use http::{Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::config::{Fault, RewritePhase};
+use crate::domain_logger::DomainLogger;
+use crate::faults::{pick_fault, BodyFault};
+use crate::net::format_authority;
+use crate::proxy::{parse_url_params, BodyCapture, ProxyContext};
+use crate::rewrite::HeaderRewrite;
+use crate::timeout::{timeout_phase, with_timeout, TimeoutPhase};
+
+/// 处理协商为HTTP/2的拦截连接
+///
+/// 客户端和上游都已通过ALPN协商为h2，客户端的每个流都转发到上游连接上的一个新流，
+/// 并作为独立的请求记录日志。
+///
+/// # 参数
+/// * `client_stream` - 与客户端的TLS连接
+/// * `server_stream` - 与上游的TLS连接
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+/// * `resolved_ip` - 上游实际连接的IP
+/// * `context` - 共享的组件，关闭句柄触发后发送GOAWAY，已有的流继续处理
+///
+/// # 返回值
+/// 返回Result，如果过程中出现错误则返回错误信息
+pub async fn handle_h2_intercept<C, S>(
+    client_stream: C,
+    server_stream: S,
+    host: String,
+    port: u16,
+    resolved_ip: Option<IpAddr>,
+    context: Arc<ProxyContext>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send_request, upstream_connection) = h2::client::handshake(server_stream).await?;
+    let upstream_host = host.clone();
+    tokio::spawn(async move {
+        if let Err(e) = upstream_connection.await {
+            log::warn!("HTTP/2 upstream connection to {upstream_host} closed with error: {e}");
+        }
+    });
+
+    let mut connection = h2::server::handshake(client_stream).await?;
+    log::info!("HTTP/2 connection established for {host}:{port}");
+
+    let mut stream_count = 0;
+    let mut going_away = false;
+    loop {
+        let result = tokio::select! {
+            result = connection.accept() => result,
+            _ = context.shutdown.wait(), if !going_away => {
+                log::info!("Sending GOAWAY to HTTP/2 client of {host}:{port}");
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            },
+        };
+        let Some(result) = result else {
+            break;
+        };
+        let (request, respond) = result?;
+        stream_count += 1;
+
+        let send_request = send_request.clone();
+        let host = host.clone();
+        let context = Arc::clone(&context);
+
+        tokio::spawn(async move {
+            relay_h2_stream(request, respond, send_request, host, port, resolved_ip, context).await;
+        });
+    }
+
+    log::info!("HTTP/2 connection to {host}:{port} closed after {stream_count} stream(s)");
+    Ok(())
+}
+
+/// 转发单个h2流并记录日志
+async fn relay_h2_stream(
+    request: Request<RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    send_request: h2::client::SendRequest<Bytes>,
+    host: String,
+    port: u16,
+    resolved_ip: Option<IpAddr>,
+    context: Arc<ProxyContext>,
+) {
+    let config = &context.config;
+    let start_time = Instant::now();
+    let (parts, request_body) = request.into_parts();
+    let method = parts.method.to_string();
+    let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string();
+
+    log::info!("🌐 HTTP/2 REQUEST ========================================");
+    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
+    log::info!("📝 Method: {method}");
+    log::info!("🔗 Path: {path}");
+    log::info!("🌐 Host: {host}:{port}");
+
+    let request_headers = header_map_to_log(&parts.headers);
+    let mut request_capture = BodyCapture::new(config.logging.domain_logs.request_body_limit);
+    request_capture.set_content_encoding(header_value(&parts.headers, "content-encoding"));
+    let mut response_capture = BodyCapture::new(config.logging.domain_logs.response_body_limit);
+    let mut request_trailers = HashMap::new();
+    let mut response_headers = HashMap::new();
+    let mut status_code = 0;
+    let mut sent_request_headers = None;
+    let mut sent_response_headers = None;
+    let request_rewrite = HeaderRewrite::new(config, RewritePhase::Request, &host, &method, &path);
+    let response_rewrite = HeaderRewrite::new(config, RewritePhase::Response, &host, &method, &path);
+
+    let fault = pick_fault(config, &host, &method, &path);
+    if let Some(fault) = &fault {
+        log::warn!("💥 Injecting fault into HTTP/2 {method} https://{}{path}: {fault}", format_authority(&host, port));
+    }
+
+    let result = async {
+        match &fault {
+            Some(Fault::Status { status, body }) => {
+                let response = Response::builder()
+                    .status(*status)
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(())?;
+                let mut send = respond.send_response(response, body.is_empty())?;
+                if !body.is_empty() {
+                    send.send_data(Bytes::from(body.clone()), true)?;
+                }
+                status_code = *status;
+                response_capture.feed(body.as_bytes());
+                response_capture.finish();
+                return Ok(());
+            },
+            Some(Fault::Reset) => {
+                respond.send_reset(h2::Reason::INTERNAL_ERROR);
+                anyhow::bail!("Stream reset by fault injection");
+            },
+            Some(Fault::Delay { ms }) => tokio::time::sleep(Duration::from_millis(*ms)).await,
+            _ => {},
+        }
+
+        // 客户端请求没有authority时使用CONNECT目标
+        let mut upstream_request = Request::from_parts(parts, ());
+        if upstream_request.uri().authority().is_none() {
+            *upstream_request.uri_mut() = format!("https://{}{path}", format_authority(&host, port)).parse()?;
+        }
+        if !request_rewrite.is_empty() {
+            request_rewrite.apply_to_header_map(upstream_request.headers_mut());
+            sent_request_headers = Some(header_map_to_log(upstream_request.headers()));
+        }
+
+        let mut send_request = send_request.ready().await?;
+        let request_end = request_body.is_end_stream();
+        let (response_future, upstream_send) = send_request.send_request(upstream_request, request_end)?;
+
+        // 请求体和响应体并发转发，支持双向流式的调用（如gRPC）
+        let request_relay = async {
+            if !request_end {
+                if let Some(trailers) = relay_h2_body(request_body, upstream_send, &mut request_capture, None).await? {
+                    request_trailers = header_map_to_log(&trailers);
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let response_relay = async {
+            let response = match with_timeout(TimeoutPhase::FirstByte, config.timeouts.first_byte(), response_future).await {
+                Ok(response) => response,
+                Err(e) => {
+                    // 上游没有返回响应头时向客户端返回502，超时返回504
+                    let status = match timeout_phase(&e) {
+                        Some(_) => StatusCode::GATEWAY_TIMEOUT,
+                        None => StatusCode::BAD_GATEWAY,
+                    };
+                    let _ = respond.send_response(Response::builder().status(status).body(())?, true);
+                    status_code = status.as_u16();
+                    return Err(e);
+                }
+            };
+            let (mut parts, response_body) = response.into_parts();
+            status_code = parts.status.as_u16();
+            response_headers = header_map_to_log(&parts.headers);
+            response_capture.set_content_encoding(header_value(&parts.headers, "content-encoding"));
+            if !response_rewrite.is_empty() {
+                response_rewrite.apply_to_header_map(&mut parts.headers);
+                sent_response_headers = Some(header_map_to_log(&parts.headers));
+            }
+
+            let response_end = response_body.is_end_stream();
+            let client_send = respond.send_response(Response::from_parts(parts, ()), response_end)?;
+            if !response_end {
+                let body_fault = fault.as_ref().and_then(BodyFault::new);
+                if let Some(trailers) = relay_h2_body(response_body, client_send, &mut response_capture, body_fault).await? {
+                    response_headers.extend(header_map_to_log(&trailers));
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let (request_result, response_result) = tokio::join!(request_relay, response_relay);
+        request_result.and(response_result)
+    }.await;
+
+    let error = match result {
+        Ok(()) => None,
+        Err(e) => {
+            log::warn!("HTTP/2 stream {method} {path} to {host} failed: {e}");
+            Some(e.to_string())
+        }
+    };
+
+    let duration_ms = start_time.elapsed().as_millis();
+    log::info!("✅ HTTP/2 REQUEST COMPLETE - Status: {status_code} - Duration: {duration_ms}ms");
+
+    // trailers与头部一起记录
+    let mut request_headers = request_headers;
+    request_headers.extend(request_trailers);
+
+    let url_params = parse_url_params(&path);
+    let mut log_entry = DomainLogger::create_log_entry(
+        host.clone(),
+        method,
+        format!("https://{}{path}", format_authority(&host, port)),
+        request_headers,
+        response_headers,
+        status_code,
+        request_capture.as_string(),
+        response_capture.as_string(),
+        url_params,
+        duration_ms,
+        error,
+    );
+    log_entry.protocol = "HTTP/2".to_string();
+    log_entry.client_identity = crate::upstream_tls::client_identity(config, &host);
+    log_entry.resolved_ip = resolved_ip;
+    log_entry.fault = fault.as_ref().map(ToString::to_string);
+    log_entry.sent_request_headers = sent_request_headers;
+    log_entry.sent_response_headers = sent_response_headers;
+    context.logger.log_request(log_entry);
+}
+
+/// 在两个h2流之间转发消息体和trailers
+///
+/// 注入的故障要求断开时，转发已允许的数据后以CANCEL重置发送方向的流。
+///
+/// # 返回值
+/// 返回转发的trailers（如果有）
+async fn relay_h2_body(
+    mut body: RecvStream,
+    mut send: SendStream<Bytes>,
+    capture: &mut BodyCapture,
+    mut fault: Option<BodyFault>,
+) -> Result<Option<http::HeaderMap>> {
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let len = chunk.len();
+        capture.feed(&chunk);
+        let (chunk, cut) = match fault.as_mut() {
+            Some(fault) => {
+                let (data, cut) = fault.apply(&chunk, std::slice::from_ref(&(0..len)));
+                (Bytes::copy_from_slice(&data), cut)
+            },
+            None => (chunk, false),
+        };
+        send_h2_data(&mut send, chunk).await?;
+        body.flow_control().release_capacity(len)?;
+        if cut {
+            send.send_reset(h2::Reason::CANCEL);
+            capture.finish();
+            return Ok(None);
+        }
+    }
+
+    let trailers = body.trailers().await?;
+    match &trailers {
+        Some(trailers) => send.send_trailers(trailers.clone())?,
+        None => send.send_data(Bytes::new(), true)?,
+    }
+    capture.finish();
+    Ok(trailers)
+}
+
+/// 按流控窗口发送数据
+async fn send_h2_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = std::future::poll_fn(|cx| send.poll_capacity(cx))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("HTTP/2 stream closed while sending data"))??;
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+/// 将HeaderMap转换为日志使用的格式
+fn header_map_to_log(headers: &http::HeaderMap) -> HashMap<String, String> {
+    headers.iter()
+        .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).to_string()))
+        .collect()
+}
+
+/// 读取字符串形式的头部值
+fn header_value<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::CertManager;
+    use crate::config::Config;
+    use crate::conditioning::NetworkConditioner;
+    use crate::interceptor::InterceptorChain;
+    use crate::pool::ConnectionPool;
+    use crate::shutdown::ShutdownHandle;
+    use crate::upstream_tls::TlsConnectors;
+
+    fn create_test_config(dir: &str) -> Config {
+        let config = format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": ["*"], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "{dir}/ca.crt", "ca_key": "{dir}/ca.key" }},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "{dir}",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }}
+        }}"#);
+        serde_json::from_str(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_h2_stream_relay_with_trailers() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(temp_dir.path().to_str().unwrap()));
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
+        let context = Arc::new(ProxyContext {
+            cert_manager: Arc::new(cert_manager),
+            pool: Arc::new(ConnectionPool::new(config.upstream_pool.clone())),
+            tls_connectors: Arc::new(TlsConnectors::new(&config).unwrap()),
+            logger: DomainLogger::new(Arc::clone(&config)),
+            conditioner: Arc::new(NetworkConditioner::new(Arc::clone(&config)).unwrap()),
+            interceptors: Arc::new(InterceptorChain::new(Vec::new())),
+            shutdown: ShutdownHandle::new(),
+            pinned_host: None,
+            config,
+        });
+
+        let (client_io, proxy_client_io) = tokio::io::duplex(65536);
+        let (proxy_server_io, server_io) = tokio::io::duplex(65536);
+
+        // 上游h2服务器：回显请求体并返回trailers
+        tokio::spawn(async move {
+            let mut connection = h2::server::handshake(server_io).await.unwrap();
+            while let Some(result) = connection.accept().await {
+                let (request, mut respond) = result.unwrap();
+                tokio::spawn(async move {
+                    let mut body = request.into_body();
+                    let mut received = Vec::new();
+                    while let Some(chunk) = body.data().await {
+                        let chunk = chunk.unwrap();
+                        body.flow_control().release_capacity(chunk.len()).unwrap();
+                        received.extend_from_slice(&chunk);
+                    }
+                    let response = Response::builder().status(201).body(()).unwrap();
+                    let mut send = respond.send_response(response, false).unwrap();
+                    send.send_data(Bytes::from(received), false).unwrap();
+                    let mut trailers = http::HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    send.send_trailers(trailers).unwrap();
+                });
+            }
+        });
+
+        tokio::spawn(handle_h2_intercept(
+            proxy_client_io,
+            proxy_server_io,
+            "example.com".to_string(),
+            443,
+            None,
+            context,
+        ));
+
+        let (send_request, connection) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        let mut send_request = send_request.ready().await.unwrap();
+
+        let request = Request::post("https://example.com/echo?a=1").body(()).unwrap();
+        let (response_future, mut send) = send_request.send_request(request, false).unwrap();
+        send.send_data(Bytes::from_static(b"ping"), true).unwrap();
+
+        let response = response_future.await.unwrap();
+        assert_eq!(response.status(), 201);
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, b"ping");
+
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+    }
+}
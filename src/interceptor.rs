@@ -0,0 +1,411 @@
+use crate::config::RewritePhase;
+use crate::rewrite::HeaderList;
+pub use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+
+/// 完整读取的请求
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowRequest {
+    /// 请求方法
+    pub method: String,
+    /// 请求发往的源站，如 `https://api.example.com`
+    pub origin: String,
+    /// 请求路径（含查询参数）
+    pub path: String,
+    /// 发往上游的请求头
+    pub headers: HeaderList,
+    /// 解压后的请求体
+    pub body: Vec<u8>,
+}
+
+impl FlowRequest {
+    /// 完整的请求URL
+    pub fn url(&self) -> String {
+        format!("{}{}", self.origin, self.path)
+    }
+
+    /// 修改请求URL，只能修改路径和查询参数
+    ///
+    /// # 参数
+    /// * `url` - 新的URL，必须与原来的源站相同
+    pub fn set_url(&mut self, url: &str) -> anyhow::Result<()> {
+        let path = url.strip_prefix(&self.origin)
+            .filter(|path| path.is_empty() || path.starts_with('/') || path.starts_with('?'))
+            .ok_or_else(|| anyhow::anyhow!("URL must stay on {}, use Map Remote to change the upstream", self.origin))?;
+        self.path = match path.starts_with('/') {
+            true => path.to_string(),
+            false => format!("/{path}"),
+        };
+        Ok(())
+    }
+
+    /// 修改请求方法
+    ///
+    /// # 参数
+    /// * `method` - 新的请求方法，只能包含字母和 `-`
+    pub fn set_method(&mut self, method: &str) -> anyhow::Result<()> {
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_alphabetic() || b == b'-') {
+            anyhow::bail!("Invalid method: {method}");
+        }
+        self.method = method.to_string();
+        Ok(())
+    }
+}
+
+/// 完整读取的响应
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowResponse {
+    /// 状态码
+    pub status: u16,
+    /// 返回给客户端的响应头
+    pub headers: HeaderList,
+    /// 解压后的响应体
+    pub body: Vec<u8>,
+}
+
+/// 经过代理的一次请求和响应
+#[derive(Debug, Clone)]
+pub struct Flow {
+    /// 请求的主机
+    pub host: String,
+    /// 请求
+    pub request: FlowRequest,
+    /// 响应，请求阶段为None
+    pub response: Option<FlowResponse>,
+    /// 拦截器记录的错误，写入域名日志的 `error`（流仍按拦截器的处理结果继续）
+    pub error: Option<String>,
+}
+
+impl Flow {
+    /// 处于请求阶段还是响应阶段
+    pub fn phase(&self) -> RewritePhase {
+        match self.response {
+            Some(_) => RewritePhase::Response,
+            None => RewritePhase::Request,
+        }
+    }
+}
+
+/// 拦截器对请求或响应的处理结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// 按（可能修改过的）流继续处理
+    Continue,
+    /// 使用给定的响应应答客户端：请求阶段不再发往上游，响应阶段替换上游的响应
+    Respond(FlowResponse),
+    /// 丢弃流，断开客户端连接
+    Drop,
+}
+
+/// 拦截器对新连接的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectAction {
+    /// 继续处理连接
+    Continue,
+    /// 断开客户端连接
+    Drop,
+}
+
+/// 流量拦截器，在代理转发的过程中查看、修改、直接应答或丢弃流
+///
+/// 拦截器按添加到 [`crate::ProxyServerBuilder`] 的顺序调用，前面的拦截器做出的修改对后面的拦截器可见；
+/// 返回 [`Action::Respond`] 或 [`Action::Drop`] 后不再调用之后的拦截器。
+///
+/// 拦截的主机与客户端使用HTTP/1.1按请求处理，处理请求或响应的拦截器需要通过 `intercepts_host` 声明主机。
+/// 消息体超过16MB或使用不支持的Content-Encoding时不调用 `on_request` / `on_response`，请求和响应原样转发。
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    /// 拦截器的名称，用于日志和丢弃流时记录的错误
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// 是否拦截主机的请求，默认不拦截任何主机
+    ///
+    /// 返回true的HTTPS主机与客户端只使用HTTP/1.1，其他主机仍可使用HTTP/2。
+    /// 返回false的主机不调用 `on_request` / `on_response`，只使用 `on_connect` 的拦截器不需要覆盖此方法。
+    ///
+    /// # 参数
+    /// * `host` - 请求的主机
+    fn intercepts_host(&self, _host: &str) -> bool {
+        false
+    }
+
+    /// 是否处理请求或响应，默认处理所有请求
+    ///
+    /// 按客户端发送的原始请求匹配；返回false时代理不为该拦截器读取完整的消息体。
+    ///
+    /// # 参数
+    /// * `phase` - 请求阶段还是响应阶段
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    fn matches(&self, _phase: RewritePhase, _host: &str, _method: &str, _path: &str) -> bool {
+        true
+    }
+
+    /// 客户端连接到目标时调用（CONNECT、SOCKS5、透明代理、明文HTTP请求和反向代理连接）
+    ///
+    /// # 参数
+    /// * `host` - 目标主机
+    /// * `port` - 目标端口
+    async fn on_connect(&self, _host: &str, _port: u16) -> ConnectAction {
+        ConnectAction::Continue
+    }
+
+    /// 请求发往上游之前调用，可以修改 `flow.request`
+    async fn on_request(&self, _flow: &mut Flow) -> Action {
+        Action::Continue
+    }
+
+    /// 收到完整的响应、返回给客户端之前调用，可以修改 `flow.response`
+    async fn on_response(&self, _flow: &mut Flow) -> Action {
+        Action::Continue
+    }
+
+    /// 连接上游或读取响应失败时调用（代理随后返回502或504）
+    ///
+    /// # 参数
+    /// * `flow` - 失败的流，请求体只在请求被拦截器处理过时可用
+    /// * `error` - 错误信息
+    async fn on_error(&self, _flow: &Flow, _error: &anyhow::Error) {}
+}
+
+/// 拦截器链的处理结果
+#[derive(Debug)]
+pub(crate) enum Verdict {
+    /// 继续处理
+    Continue,
+    /// 拦截器直接应答客户端
+    Respond {
+        /// 返回给客户端的响应
+        response: FlowResponse,
+        /// 应答的拦截器名称
+        by: String,
+    },
+    /// 拦截器丢弃了流
+    Drop {
+        /// 丢弃流的拦截器名称
+        by: String,
+    },
+}
+
+/// 按顺序调用的拦截器
+#[derive(Clone)]
+pub(crate) struct InterceptorChain {
+    /// 拦截器列表
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl fmt::Debug for InterceptorChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.interceptors.iter().map(|interceptor| interceptor.name())).finish()
+    }
+}
+
+impl InterceptorChain {
+    /// 创建拦截器链
+    ///
+    /// # 参数
+    /// * `interceptors` - 按调用顺序排列的拦截器
+    pub(crate) fn new(interceptors: Vec<Arc<dyn Interceptor>>) -> Self {
+        Self { interceptors }
+    }
+
+    /// 是否没有拦截器
+    pub(crate) fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// 是否有拦截器拦截主机的请求
+    pub(crate) fn intercepts_host(&self, host: &str) -> bool {
+        self.interceptors.iter().any(|interceptor| interceptor.intercepts_host(host))
+    }
+
+    /// 选出处理请求或响应的拦截器，只保留拦截该主机并且匹配请求的拦截器
+    ///
+    /// # 参数
+    /// * `phase` - 请求阶段还是响应阶段
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    pub(crate) fn select(&self, phase: RewritePhase, host: &str, method: &str, path: &str) -> Self {
+        let interceptors = self.interceptors.iter()
+            .filter(|interceptor| interceptor.intercepts_host(host) && interceptor.matches(phase, host, method, path))
+            .cloned()
+            .collect();
+        Self { interceptors }
+    }
+
+    /// 依次检查新连接
+    ///
+    /// # 返回值
+    /// 连接被丢弃时返回丢弃连接的拦截器名称
+    pub(crate) async fn on_connect(&self, host: &str, port: u16) -> Option<String> {
+        for interceptor in &self.interceptors {
+            if interceptor.on_connect(host, port).await == ConnectAction::Drop {
+                return Some(interceptor.name().to_string());
+            }
+        }
+        None
+    }
+
+    /// 依次处理请求
+    pub(crate) async fn on_request(&self, flow: &mut Flow) -> Verdict {
+        for interceptor in &self.interceptors {
+            match interceptor.on_request(flow).await {
+                Action::Continue => continue,
+                Action::Respond(response) => return Verdict::Respond { response, by: interceptor.name().to_string() },
+                Action::Drop => return Verdict::Drop { by: interceptor.name().to_string() },
+            }
+        }
+        Verdict::Continue
+    }
+
+    /// 依次处理响应，拦截器给出的响应替换 `flow.response`
+    pub(crate) async fn on_response(&self, flow: &mut Flow) -> Verdict {
+        for interceptor in &self.interceptors {
+            match interceptor.on_response(flow).await {
+                Action::Continue => continue,
+                Action::Respond(response) => {
+                    flow.response = Some(response);
+                    break;
+                },
+                Action::Drop => return Verdict::Drop { by: interceptor.name().to_string() },
+            }
+        }
+        Verdict::Continue
+    }
+
+    /// 通知所有拦截器请求失败
+    pub(crate) async fn on_error(&self, flow: &Flow, error: &anyhow::Error) {
+        for interceptor in &self.interceptors {
+            interceptor.on_error(flow, error).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 给请求加上请求头，按路径直接应答或丢弃
+    struct Tagger;
+
+    #[async_trait]
+    impl Interceptor for Tagger {
+        fn name(&self) -> &str {
+            "tagger"
+        }
+
+        fn intercepts_host(&self, host: &str) -> bool {
+            host.ends_with("example.com")
+        }
+
+        fn matches(&self, _phase: RewritePhase, _host: &str, _method: &str, path: &str) -> bool {
+            path != "/skip"
+        }
+
+        async fn on_connect(&self, host: &str, _port: u16) -> ConnectAction {
+            match host {
+                "blocked.example.com" => ConnectAction::Drop,
+                _ => ConnectAction::Continue,
+            }
+        }
+
+        async fn on_request(&self, flow: &mut Flow) -> Action {
+            flow.request.headers.push(("X-Tag".to_string(), "1".to_string()));
+            match flow.request.path.as_str() {
+                "/respond" => Action::Respond(FlowResponse { status: 204, headers: Vec::new(), body: Vec::new() }),
+                "/drop" => Action::Drop,
+                _ => Action::Continue,
+            }
+        }
+
+        async fn on_response(&self, flow: &mut Flow) -> Action {
+            match flow.request.path.as_str() {
+                "/respond" => Action::Respond(FlowResponse { status: 201, headers: Vec::new(), body: b"replaced".to_vec() }),
+                _ => Action::Continue,
+            }
+        }
+    }
+
+    /// 拦截所有主机，记录看到的请求头数量
+    struct Counter(std::sync::Mutex<Vec<usize>>);
+
+    #[async_trait]
+    impl Interceptor for Counter {
+        fn intercepts_host(&self, _host: &str) -> bool {
+            true
+        }
+
+        async fn on_request(&self, flow: &mut Flow) -> Action {
+            self.0.lock().unwrap().push(flow.request.headers.len());
+            Action::Continue
+        }
+    }
+
+    fn create_flow(path: &str) -> Flow {
+        Flow {
+            host: "example.com".to_string(),
+            request: FlowRequest {
+                method: "GET".to_string(),
+                origin: "https://example.com".to_string(),
+                path: path.to_string(),
+                headers: vec![("Host".to_string(), "example.com".to_string())],
+                body: Vec::new(),
+            },
+            response: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_order_and_short_circuit() {
+        let counter = Arc::new(Counter(std::sync::Mutex::new(Vec::new())));
+        let chain = InterceptorChain::new(vec![Arc::new(Tagger), counter.clone()]);
+        assert_eq!(format!("{chain:?}"), format!("[\"tagger\", \"{}\"]", std::any::type_name::<Counter>()));
+        assert!(chain.intercepts_host("example.com"));
+        assert_eq!(chain.on_connect("blocked.example.com", 443).await.as_deref(), Some("tagger"));
+        assert_eq!(chain.on_connect("example.com", 443).await, None);
+
+        // 前一个拦截器的修改对后一个可见
+        let mut flow = create_flow("/");
+        assert!(matches!(chain.on_request(&mut flow).await, Verdict::Continue));
+        assert_eq!(*counter.0.lock().unwrap(), [2]);
+
+        // 直接应答或丢弃后不再调用之后的拦截器
+        let mut flow = create_flow("/respond");
+        assert!(matches!(chain.on_request(&mut flow).await, Verdict::Respond { response, by } if response.status == 204 && by == "tagger"));
+        assert!(matches!(chain.on_request(&mut create_flow("/drop")).await, Verdict::Drop { by } if by == "tagger"));
+        assert_eq!(counter.0.lock().unwrap().len(), 1);
+
+        flow.response = Some(FlowResponse { status: 200, headers: Vec::new(), body: b"ok".to_vec() });
+        assert!(matches!(chain.on_response(&mut flow).await, Verdict::Continue));
+        assert_eq!(flow.response.unwrap().body, b"replaced");
+
+        // 不匹配的拦截器不参与处理
+        let selected = chain.select(RewritePhase::Request, "example.com", "GET", "/skip");
+        assert_eq!(format!("{selected:?}"), format!("[\"{}\"]", std::any::type_name::<Counter>()));
+        let selected = chain.select(RewritePhase::Request, "other.test", "GET", "/");
+        assert_eq!(format!("{selected:?}"), format!("[\"{}\"]", std::any::type_name::<Counter>()));
+        assert!(chain.intercepts_host("other.test"));
+        assert!(!InterceptorChain::new(vec![Arc::new(Tagger)]).intercepts_host("other.test"));
+    }
+
+    #[tokio::test]
+    async fn test_default_intercepts_no_host() {
+        /// 只使用默认实现
+        struct Passive;
+
+        #[async_trait]
+        impl Interceptor for Passive {}
+
+        // 没有声明主机的拦截器不影响HTTP/2，也不参与处理请求
+        let chain = InterceptorChain::new(vec![Arc::new(Passive)]);
+        assert!(!chain.intercepts_host("example.com"));
+        assert!(chain.select(RewritePhase::Request, "example.com", "GET", "/").is_empty());
+        assert_eq!(chain.on_connect("example.com", 443).await, None);
+    }
+}
@@ -4,6 +4,28 @@ pub mod domain_logger;
 pub mod system_proxy;
 pub mod cert_manager;
 pub mod curl_manager;
+pub mod interceptor;
+
+mod proxy;
+mod http2;
+mod websocket;
+mod upstream;
+mod upstream_tls;
+mod pool;
+mod dns;
+mod net;
+mod timeout;
+mod shutdown;
+mod conditioning;
+mod control;
+mod faults;
+mod map_local;
+mod rewrite;
+mod breakpoints;
+mod script;
+mod socks;
+mod sniff;
+mod transparent;
 
 // 公共导出
 pub use config::Config;
@@ -12,6 +34,9 @@ pub use domain_logger::{DomainLogger, LogEntry};
 pub use system_proxy::{SystemProxyManager, ProxyConfig};
 pub use cert_manager::CertManager as CertEnvManager;
 pub use curl_manager::CurlManager;
+pub use interceptor::{async_trait, Action, ConnectAction, Flow, FlowRequest, FlowResponse, Interceptor};
+pub use proxy::{ProxyServer, ProxyServerBuilder};
+pub use shutdown::ShutdownHandle;
 
 #[cfg(test)]
 mod tests {
@@ -1,16 +1,6 @@
-mod config;
-mod cert;
-mod proxy;
-mod domain_logger;
-mod system_proxy;
-mod cert_manager;
-mod curl_manager;
-
 use anyhow::Result;
 use clap::Parser;
-use system_proxy::{SystemProxyManager, ProxyConfig};
-use cert_manager::CertManager as CertEnvManager;
-use curl_manager::CurlManager;
+use study_proxy::{Config, CertEnvManager, CurlManager, ProxyConfig, ProxyServer, SystemProxyManager};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,7 +16,7 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     
     log::info!("Loading configuration from: {}", cli.config);
-    let config = config::Config::from_file(&cli.config)?;
+    let config = Config::from_file(&cli.config)?;
     
     // 创建系统代理管理器
     let proxy_manager = SystemProxyManager::new()?;
@@ -48,7 +38,7 @@ async fn main() -> Result<()> {
 
     // 先创建代理服务器（会生成证书）
     log::info!("Starting proxy server...");
-    let server = proxy::ProxyServer::new(config.clone())?;
+    let server = ProxyServer::new(config.clone())?;
     
     // 自动安装证书到系统信任存储（确保证书已生成）
     if config.certificates.auto_install {
@@ -69,19 +59,30 @@ async fn main() -> Result<()> {
     }
     
     // 使用tokio::select!来同时监听信号和服务器运行
-    tokio::select! {
-        server_result = server.run() => {
-            match server_result {
-                Ok(_) => {
-                    log::info!("Proxy server stopped normally");
-                }
-                Err(e) => {
-                    log::error!("Proxy server error: {}", e);
+    let shutdown = server.shutdown_handle();
+    let server_run = server.run();
+    tokio::pin!(server_run);
+    let server_result = tokio::select! {
+        server_result = &mut server_run => server_result,
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("Received interrupt signal, shutting down...");
+            shutdown.shutdown();
+            // 等待正在处理的连接结束并写入日志，再次中断则立即退出
+            tokio::select! {
+                server_result = &mut server_run => server_result,
+                _ = tokio::signal::ctrl_c() => {
+                    log::warn!("Received second interrupt signal, skipping connection draining");
+                    Ok(())
                 }
             }
         }
-        _ = tokio::signal::ctrl_c() => {
-            log::info!("Received interrupt signal, shutting down...");
+    };
+    match server_result {
+        Ok(_) => {
+            log::info!("Proxy server stopped normally");
+        }
+        Err(e) => {
+            log::error!("Proxy server error: {}", e);
         }
     }
     
@@ -0,0 +1,217 @@
+use crate::config::MapLocalRule;
+use crate::proxy::BodyCapture;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// 使用本地文件生成的响应
+#[derive(Debug)]
+pub struct LocalResponse {
+    /// 状态码，文件不存在时为404
+    pub status: u16,
+    /// 响应头
+    pub headers: Vec<(String, String)>,
+    /// 响应体
+    pub body: Vec<u8>,
+    /// 映射到的本地文件
+    pub file: PathBuf,
+}
+
+impl LocalResponse {
+    /// 读取请求映射到的本地文件并生成响应
+    ///
+    /// # 参数
+    /// * `rule` - 匹配的Map Local规则
+    /// * `path` - 请求路径，可以包含查询参数
+    ///
+    /// # 返回值
+    /// 文件不存在或路径试图访问映射目录之外时返回404响应
+    pub async fn load(rule: &MapLocalRule, path: &str) -> Self {
+        let file = local_file(rule, path);
+        let content = match &file {
+            Some(file) => tokio::fs::read(file).await.map_err(|e| e.to_string()),
+            None => Err("Path escapes the mapped directory".to_string()),
+        };
+        let file = file.unwrap_or_else(|| PathBuf::from(&rule.local));
+
+        let (status, content_type, body) = match content {
+            Ok(body) => {
+                let content_type = mime_guess::from_path(&file).first_or_octet_stream().to_string();
+                (200, content_type, body)
+            },
+            Err(e) => {
+                // 本地路径只记录在日志中，不返回给客户端
+                log::warn!("Map Local file {} not available: {e}", file.display());
+                (404, "text/plain; charset=utf-8".to_string(), b"Not Found\n".to_vec())
+            },
+        };
+
+        let mut headers = vec![
+            ("Content-Type".to_string(), content_type),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ];
+        // 额外的响应头覆盖同名的默认响应头
+        for (key, value) in &rule.headers {
+            headers.retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+            headers.push((key.clone(), value.clone()));
+        }
+
+        Self { status, headers, body, file }
+    }
+
+    /// 生成HTTP/1.1响应
+    ///
+    /// # 参数
+    /// * `head_request` - 是否为HEAD请求（不发送响应体）
+    /// * `keep_alive` - 响应之后是否保持客户端连接
+    pub fn to_http1(&self, head_request: bool, keep_alive: bool) -> Vec<u8> {
+        let reason = http::StatusCode::from_u16(self.status).ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("");
+        let mut response = format!("HTTP/1.1 {} {reason}\r\n", self.status);
+        for (key, value) in &self.headers {
+            response.push_str(&format!("{key}: {value}\r\n"));
+        }
+        if !keep_alive {
+            response.push_str("Connection: close\r\n");
+        }
+        response.push_str("\r\n");
+
+        let mut response = response.into_bytes();
+        if !head_request {
+            response.extend_from_slice(&self.body);
+        }
+        response
+    }
+
+    /// 日志记录用的响应头
+    pub fn headers_for_log(&self) -> HashMap<String, String> {
+        self.headers.iter().map(|(k, v)| (k.to_lowercase(), v.clone())).collect()
+    }
+
+    /// 日志记录用的响应体
+    ///
+    /// # 参数
+    /// * `limit` - 响应体记录长度限制
+    pub fn body_for_log(&self, limit: i64) -> String {
+        let mut capture = BodyCapture::new(limit);
+        capture.feed(&self.body);
+        capture.finish();
+        capture.as_string()
+    }
+
+    /// 日志记录用的映射目标
+    pub fn mapped_url(&self) -> String {
+        format!("file://{}", self.file.display())
+    }
+}
+
+/// 计算请求映射到的本地文件
+///
+/// # 返回值
+/// 路径包含 `..` 等试图访问映射目录之外的部分时返回None
+fn local_file(rule: &MapLocalRule, path: &str) -> Option<PathBuf> {
+    let local = Path::new(&rule.local);
+    if !local.is_dir() {
+        return Some(local.to_path_buf());
+    }
+
+    let prefix = rule.flow.path_prefix(path);
+    let path = path.split('?').next().unwrap_or(path);
+    let relative = percent_decode(path.strip_prefix(prefix).unwrap_or(path));
+
+    let mut file = local.to_path_buf();
+    for component in Path::new(&relative).components() {
+        match component {
+            Component::Normal(part) => file.push(part),
+            Component::RootDir | Component::CurDir => {},
+            _ => return None,
+        }
+    }
+    if relative.is_empty() || relative.ends_with('/') {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+/// 解码路径中的百分号编码，无效的编码按原样保留
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(local: &Path, paths: &[&str]) -> MapLocalRule {
+        serde_json::from_value(serde_json::json!({
+            "paths": paths,
+            "local": local,
+            "headers": { "Cache-Control": "no-store", "content-type": "application/x-custom" }
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_map_local_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(temp_dir.path().join("js")).unwrap();
+        std::fs::write(temp_dir.path().join("js/app v2.js"), "console.log(1)").unwrap();
+        std::fs::write(temp_dir.path().join("index.html"), "<html></html>").unwrap();
+        let mut rule = rule(temp_dir.path(), &["/static/*"]);
+        rule.headers.remove("content-type");
+
+        let response = LocalResponse::load(&rule, "/static/js/app%20v2.js?v=1").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"console.log(1)");
+        assert_eq!(response.file, temp_dir.path().join("js/app v2.js"));
+        let headers = response.headers_for_log();
+        assert_eq!(headers["content-type"], "text/javascript");
+        assert_eq!(headers["content-length"], "14");
+        assert_eq!(headers["cache-control"], "no-store");
+
+        let response = LocalResponse::load(&rule, "/static/").await;
+        assert_eq!(response.body, b"<html></html>");
+
+        let missing = LocalResponse::load(&rule, "/static/missing.css").await;
+        assert_eq!(missing.status, 404);
+        assert_eq!(missing.body, b"Not Found\n");
+        assert_eq!(LocalResponse::load(&rule, "/static/../secret").await.status, 404);
+        assert_eq!(LocalResponse::load(&rule, "/static/%2e%2e/secret").await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_map_local_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("user.json");
+        std::fs::write(&file, r#"{"id":1}"#).unwrap();
+
+        let response = LocalResponse::load(&rule(&file, &[]), "/api/user/1").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.mapped_url(), format!("file://{}", file.display()));
+        // 额外的响应头覆盖默认的Content-Type
+        assert_eq!(response.headers_for_log()["content-type"], "application/x-custom");
+
+        let http1 = String::from_utf8(response.to_http1(false, false)).unwrap();
+        assert!(http1.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(http1.contains("Content-Length: 8\r\n"));
+        assert!(http1.ends_with("Connection: close\r\n\r\n{\"id\":1}"));
+        assert!(response.to_http1(true, true).ends_with(b"\r\n\r\n"));
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// 监听队列长度
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// 解析RFC 3986的authority（host[:port]）
+///
+/// IPv6地址必须放在方括号中，返回的主机不带方括号。不允许userinfo，
+/// 省略端口或端口为空时使用默认端口。
+///
+/// # 参数
+/// * `authority` - authority字符串，如 example.com:8443 或 [::1]:8443
+/// * `default_port` - 默认端口
+///
+/// # 返回值
+/// 返回主机和端口
+pub fn parse_authority(authority: &str, default_port: u16) -> Result<(String, u16)> {
+    if authority.contains('@') {
+        anyhow::bail!("Userinfo is not allowed in authority: {authority}");
+    }
+
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated IPv6 literal in authority: {authority}"))?;
+            host.parse::<Ipv6Addr>()
+                .map_err(|_| anyhow::anyhow!("Invalid IPv6 address in authority: {authority}"))?;
+            let port = match after {
+                "" => None,
+                _ => Some(after.strip_prefix(':')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid authority: {authority}"))?),
+            };
+            (host, port)
+        },
+        None => match authority.split_once(':') {
+            Some((_, port)) if port.contains(':') => {
+                anyhow::bail!("IPv6 address must be enclosed in brackets: {authority}");
+            },
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+
+    if host.is_empty() {
+        anyhow::bail!("Missing host in authority: {authority}");
+    }
+    // reg-name只允许unreserved、pct-encoded和sub-delims字符
+    if !host.contains(':') && !host.chars().all(|c| c.is_ascii_alphanumeric() || "-._~%!$&'()*+,;=".contains(c)) {
+        anyhow::bail!("Invalid host in authority: {authority}");
+    }
+
+    let port = match port {
+        None | Some("") => default_port,
+        Some(port) => port.parse::<u16>().ok()
+            .filter(|p| *p != 0 && port.bytes().all(|b| b.is_ascii_digit()))
+            .ok_or_else(|| anyhow::anyhow!("Invalid port in authority: {authority}"))?,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// 格式化authority，IPv6地址需要加方括号
+///
+/// # 参数
+/// * `host` - 主机（IPv6地址可以带或不带方括号）
+/// * `port` - 端口
+pub fn format_authority(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// 可以访问底层TCP连接的客户端连接（TLS、网络模拟等包装之下）
+pub trait ClientSocket {
+    /// 底层TCP连接
+    fn tcp_stream(&self) -> &TcpStream;
+
+    /// 把SO_LINGER设为0，关闭连接时发送RST而不是FIN，客户端看到连接被重置
+    fn reset_on_close(&self) -> std::io::Result<()> {
+        socket2::SockRef::from(self.tcp_stream()).set_linger(Some(Duration::ZERO))
+    }
+}
+
+impl ClientSocket for TcpStream {
+    fn tcp_stream(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl<S: ClientSocket> ClientSocket for tokio_rustls::server::TlsStream<S> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.get_ref().0.tcp_stream()
+    }
+}
+
+/// absolute-form请求目标（如 http://example.com:8080/path?q=1）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbsoluteUrl {
+    /// 协议（小写）
+    pub scheme: String,
+    /// 主机（IPv6地址不带方括号）
+    pub host: String,
+    /// 端口，省略时使用协议的默认端口
+    pub port: u16,
+    /// 路径和查询参数，不含片段
+    pub path: String,
+}
+
+/// 解析absolute-form URL，只支持http和https
+///
+/// # 参数
+/// * `url` - 完整URL
+pub fn parse_absolute_url(url: &str) -> Result<AbsoluteUrl> {
+    let (scheme, rest) = url.split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("Not an absolute URL: {url}"))?;
+    let scheme = scheme.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => anyhow::bail!("Unsupported URL scheme: {url}"),
+    };
+
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, port) = parse_authority(&rest[..authority_end], default_port)?;
+
+    let path = rest[authority_end..].split('#').next().unwrap_or("");
+    let path = match path.chars().next() {
+        None => "/".to_string(),
+        Some('/') => path.to_string(),
+        Some(_) => format!("/{path}"),
+    };
+    Ok(AbsoluteUrl { scheme, host, port, path })
+}
+
+/// 绑定监听地址
+///
+/// 主机可以是IP地址（IPv6可带方括号）或主机名。`::`同时接受IPv4和IPv6连接，
+/// 主机名（如localhost）绑定解析出的每个地址。
+///
+/// # 参数
+/// * `host` - 监听主机
+/// * `port` - 监听端口
+///
+/// # 返回值
+/// 返回至少一个监听器，所有地址都绑定失败时返回错误
+pub async fn bind_listeners(host: &str, port: u16) -> Result<Vec<TcpListener>> {
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    let mut addrs: Vec<SocketAddr> = match name.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((name, port)).await
+            .with_context(|| format!("Failed to resolve listen address {host}"))?
+            .collect(),
+    };
+    addrs.dedup();
+
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for addr in addrs {
+        match bind_listener(addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                log::warn!("Failed to listen on {addr}: {e}");
+                last_error = Some(e);
+            },
+        }
+    }
+    match (listeners.is_empty(), last_error) {
+        (true, Some(e)) => Err(anyhow::anyhow!("Failed to listen on {}: {e}", format_authority(host, port))),
+        (true, None) => anyhow::bail!("No listen address found for {host}"),
+        _ => Ok(listeners),
+    }
+}
+
+/// 绑定单个监听地址，IPv6通配地址关闭IPV6_V6ONLY以支持双栈
+fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_parse_authority() {
+        assert_eq!(parse_authority("example.com", 443).unwrap(), ("example.com".to_string(), 443));
+        assert_eq!(parse_authority("example.com:8443", 443).unwrap(), ("example.com".to_string(), 8443));
+        assert_eq!(parse_authority("example.com:", 80).unwrap(), ("example.com".to_string(), 80));
+        assert_eq!(parse_authority("[::1]:8443", 443).unwrap(), ("::1".to_string(), 8443));
+        assert_eq!(parse_authority("[2001:db8::1]", 443).unwrap(), ("2001:db8::1".to_string(), 443));
+        assert_eq!(parse_authority("127.0.0.1:80", 443).unwrap(), ("127.0.0.1".to_string(), 80));
+
+        for invalid in [
+            "user:pass@example.com:443",
+            "::1:443",
+            "[::1",
+            "[::1]443",
+            "[example.com]:443",
+            ":443",
+            "example.com:0",
+            "example.com:65536",
+            "example.com:+80",
+            "exa mple.com:80",
+            "example.com/path:80",
+        ] {
+            assert!(parse_authority(invalid, 443).is_err(), "{invalid} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_absolute_url() {
+        let url = parse_absolute_url("http://localhost:18080/api/users?id=1#top").unwrap();
+        assert_eq!(url, AbsoluteUrl {
+            scheme: "http".to_string(),
+            host: "localhost".to_string(),
+            port: 18080,
+            path: "/api/users?id=1".to_string(),
+        });
+
+        let url = parse_absolute_url("HTTPS://[::1]?q=1").unwrap();
+        assert_eq!((url.scheme.as_str(), url.host.as_str(), url.port, url.path.as_str()), ("https", "::1", 443, "/?q=1"));
+        assert_eq!(parse_absolute_url("http://example.com").unwrap().path, "/");
+
+        assert!(parse_absolute_url("ftp://example.com/").is_err());
+        assert!(parse_absolute_url("http://user@example.com/").is_err());
+        assert!(parse_absolute_url("/relative").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() {
+        // 没有IPv6的环境跳过
+        let Ok(listeners) = bind_listeners("::", 0).await else {
+            return;
+        };
+        assert_eq!(listeners.len(), 1);
+        let port = listeners[0].local_addr().unwrap().port();
+
+        for client_addr in ["127.0.0.1", "::1"] {
+            let mut client = TcpStream::connect((client_addr, port)).await.unwrap();
+            let (mut server, _) = listeners[0].accept().await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut data = [0; 4];
+            server.read_exact(&mut data).await.unwrap();
+            assert_eq!(&data, b"ping");
+        }
+
+        let listeners = bind_listeners("[::1]", 0).await.unwrap();
+        assert!(listeners[0].local_addr().unwrap().is_ipv6());
+        assert!(bind_listeners("not a host", 0).await.is_err());
+    }
+}
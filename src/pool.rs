@@ -0,0 +1,218 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::UpstreamPoolConfig;
+
+/// 从连接池取出或新建立的上游连接
+pub struct PooledConnection<S> {
+    /// 上游连接
+    pub stream: S,
+    /// 连接建立的时间
+    pub created_at: Instant,
+    /// 建立连接的耗时（毫秒，复用的连接为0）
+    pub connect_ms: u128,
+    /// 是否复用了之前请求使用过的连接
+    pub reused: bool,
+    /// 上游实际连接的IP（经过上游代理时为None）
+    pub resolved_ip: Option<IpAddr>,
+}
+
+impl<S> PooledConnection<S> {
+    /// 包装新建立的连接
+    ///
+    /// # 参数
+    /// * `stream` - 上游连接
+    /// * `connect_ms` - 建立连接的耗时（毫秒）
+    /// * `resolved_ip` - 上游实际连接的IP
+    pub fn new(stream: S, connect_ms: u128, resolved_ip: Option<IpAddr>) -> Self {
+        Self {
+            stream,
+            created_at: Instant::now(),
+            connect_ms,
+            reused: false,
+            resolved_ip,
+        }
+    }
+
+    /// 标记连接已经完成一次请求，之后的请求视为复用
+    pub fn mark_reused(&mut self) {
+        self.connect_ms = 0;
+        self.reused = true;
+    }
+}
+
+/// 空闲连接
+struct IdleConnection<S> {
+    stream: S,
+    created_at: Instant,
+    resolved_ip: Option<IpAddr>,
+    idle_since: Instant,
+}
+
+/// 按源站保存空闲keep-alive连接的连接池
+///
+/// 连接只在取出和放回时检查是否过期，不使用后台任务。
+pub struct ConnectionPool<S> {
+    /// 连接池配置
+    config: UpstreamPoolConfig,
+    /// 每个源站的空闲连接，最近放回的在队尾
+    idle: Mutex<HashMap<String, VecDeque<IdleConnection<S>>>>,
+}
+
+impl<S> ConnectionPool<S> {
+    /// 创建连接池
+    ///
+    /// # 参数
+    /// * `config` - 连接池配置
+    pub fn new(config: UpstreamPoolConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 取出源站最近放回的空闲连接
+    ///
+    /// # 参数
+    /// * `origin` - 源站标识（如 https://example.com:443）
+    ///
+    /// # 返回值
+    /// 返回未过期的空闲连接，没有时返回None。调用方还需要检查连接是否已被对端关闭。
+    pub fn checkout(&self, origin: &str) -> Option<PooledConnection<S>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let now = Instant::now();
+        let mut idle = self.idle.lock().unwrap();
+        let queue = idle.get_mut(origin)?;
+
+        let mut connection = None;
+        while let Some(entry) = queue.pop_back() {
+            if !self.is_expired(&entry, now) {
+                connection = Some(PooledConnection {
+                    stream: entry.stream,
+                    created_at: entry.created_at,
+                    connect_ms: 0,
+                    reused: true,
+                    resolved_ip: entry.resolved_ip,
+                });
+                break;
+            }
+        }
+        if queue.is_empty() {
+            idle.remove(origin);
+        }
+        connection
+    }
+
+    /// 放回可以继续使用的连接
+    ///
+    /// 超过最长使用时间的连接直接关闭；源站空闲连接已满时关闭最早放回的连接。
+    ///
+    /// # 参数
+    /// * `origin` - 源站标识
+    /// * `connection` - 完成请求后仍保持的连接
+    pub fn checkin(&self, origin: &str, connection: PooledConnection<S>) {
+        let now = Instant::now();
+        let lifetime = Duration::from_secs(self.config.max_lifetime_secs);
+        if !self.config.enabled
+            || self.config.max_idle_per_origin == 0
+            || now.duration_since(connection.created_at) >= lifetime
+        {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        // 顺便清理所有源站中过期的连接
+        idle.retain(|_, queue| {
+            queue.retain(|entry| !self.is_expired(entry, now));
+            !queue.is_empty()
+        });
+
+        let queue = idle.entry(origin.to_string()).or_default();
+        while queue.len() >= self.config.max_idle_per_origin {
+            queue.pop_front();
+        }
+        queue.push_back(IdleConnection {
+            stream: connection.stream,
+            created_at: connection.created_at,
+            resolved_ip: connection.resolved_ip,
+            idle_since: now,
+        });
+        log::debug!("Returned connection to {origin} to pool ({} idle)", queue.len());
+    }
+
+    /// 空闲连接是否超过空闲时间或最长使用时间
+    fn is_expired(&self, entry: &IdleConnection<S>, now: Instant) -> bool {
+        now.duration_since(entry.idle_since) >= Duration::from_secs(self.config.idle_timeout_secs)
+            || now.duration_since(entry.created_at) >= Duration::from_secs(self.config.max_lifetime_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_pool(max_idle: usize, idle_timeout_secs: u64, max_lifetime_secs: u64) -> ConnectionPool<u32> {
+        ConnectionPool::new(UpstreamPoolConfig {
+            enabled: true,
+            max_idle_per_origin: max_idle,
+            idle_timeout_secs,
+            max_lifetime_secs,
+        })
+    }
+
+    #[test]
+    fn test_checkout_most_recent_per_origin() {
+        let pool = create_pool(2, 60, 600);
+        assert!(pool.checkout("http://a:80").is_none());
+
+        pool.checkin("http://a:80", PooledConnection::new(1, 5, None));
+        pool.checkin("http://a:80", PooledConnection::new(2, 5, None));
+        pool.checkin("http://a:80", PooledConnection::new(3, 5, None));
+        pool.checkin("http://b:80", PooledConnection::new(4, 5, Some(IpAddr::from([10, 0, 0, 4]))));
+
+        // 超过空闲上限时关闭最早放回的连接
+        let connection = pool.checkout("http://a:80").unwrap();
+        assert_eq!(connection.stream, 3);
+        assert!(connection.reused);
+        assert_eq!(connection.connect_ms, 0);
+        assert_eq!(pool.checkout("http://a:80").unwrap().stream, 2);
+        assert!(pool.checkout("http://a:80").is_none());
+        let connection = pool.checkout("http://b:80").unwrap();
+        assert_eq!(connection.stream, 4);
+        assert_eq!(connection.resolved_ip, Some(IpAddr::from([10, 0, 0, 4])));
+    }
+
+    #[test]
+    fn test_expired_connections_are_dropped() {
+        // 空闲时间为0时放回的连接立即过期
+        let pool = create_pool(4, 0, 600);
+        pool.checkin("http://a:80", PooledConnection::new(1, 5, None));
+        assert!(pool.checkout("http://a:80").is_none());
+
+        // 超过最长使用时间的连接不再放回
+        let pool = create_pool(4, 60, 0);
+        pool.checkin("http://a:80", PooledConnection::new(1, 5, None));
+        assert!(pool.checkout("http://a:80").is_none());
+
+        let pool = ConnectionPool::new(UpstreamPoolConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        pool.checkin("http://a:80", PooledConnection::new(1u32, 5, None));
+        assert!(pool.checkout("http://a:80").is_none());
+    }
+
+    #[test]
+    fn test_mark_reused() {
+        let mut connection = PooledConnection::new(1, 12, None);
+        assert!(!connection.reused);
+        assert_eq!(connection.connect_ms, 12);
+        connection.mark_reused();
+        assert!(connection.reused);
+        assert_eq!(connection.connect_ms, 0);
+    }
+}
@@ -1,769 +1,1898 @@
 use anyhow::Result;
-use std::net::SocketAddr;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, Instant};
+use std::time::{Duration, SystemTime, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
+use tokio_rustls::rustls::server::Acceptor;
 use tokio_rustls::rustls::{ServerConfig};
 use std::io::{BufReader, Cursor};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use flate2::read::GzDecoder;
-use std::io::Read;
+use std::io::Write;
 
-use crate::config::Config;
+use crate::config::{Config, Fault, RewritePhase};
 use crate::cert::CertManager;
-use crate::domain_logger::DomainLogger;
+use crate::domain_logger::{ConnectionTiming, DomainLogger};
+use crate::net::{bind_listeners, format_authority, parse_absolute_url, parse_authority, ClientSocket};
+use crate::pool::{ConnectionPool, PooledConnection};
+use crate::timeout::{timeout_phase, with_timeout, IdleTracker, TimeoutPhase};
+use crate::conditioning::NetworkConditioner;
+use crate::breakpoints::Breakpoints;
+use crate::script::load_scripts;
+use crate::interceptor::{Flow, FlowRequest, FlowResponse, Interceptor, InterceptorChain, Verdict};
+use crate::faults::{pick_fault, BodyFault};
+use crate::map_local::LocalResponse;
+use crate::rewrite::{
+    decode_body, encode_body, find_header, format_header_lines, headers_for_log, parse_header_lines, set_content_length,
+BodyRewrite, HeaderList, HeaderRewrite, MAX_BODY_REWRITE_SIZE,
+};
+use crate::control::run_control_listener;
+use crate::shutdown::ShutdownHandle;
+use crate::sniff::{classify, parse_http_host, parse_sni, peek_initial_data, StreamKind};
+use crate::socks::{accept_socks5, reply_socks5};
+use crate::transparent::{default_lookup, OriginalDestination};
+use crate::upstream::{self, connect_http, connect_tunnel, http_forward_proxy, HttpForwardProxy};
+use crate::upstream_tls::{self, TlsConnectors};
+use crate::websocket::{is_websocket_upgrade, relay_websocket, WebSocketContext};
+
+/// 请求头最大长度
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+
+/// 响应头最大长度
+const MAX_RESPONSE_HEADER_SIZE: usize = 65536;
+
+/// chunk大小行或trailer行的最大长度
+const MAX_CHUNK_LINE_SIZE: usize = 8192;
+
+/// 无法解析请求目标时返回的响应
+const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// 拦截器拒绝连接目标时返回的响应
+const FORBIDDEN_RESPONSE: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// 客户端没有在超时前发送完整请求头时返回的响应
+const REQUEST_TIMEOUT_RESPONSE: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// HTTP/2的ALPN协议标识
+const ALPN_H2: &[u8] = b"h2";
+
+/// HTTP/1.1的ALPN协议标识
+const ALPN_HTTP11: &[u8] = b"http/1.1";
 
 /// HTTP响应处理器，用于正确处理各种HTTP响应格式
 #[derive(Debug)]
 struct HttpResponseProcessor {
     /// 响应头是否已解析
     headers_parsed: bool,
-    /// 响应头结束位置
-    header_end: Option<usize>,
+    /// 尚未解析完成的响应头数据
+    header_buffer: Vec<u8>,
+    /// 对应请求是否为HEAD请求（HEAD响应没有响应体）
+    head_request: bool,
+    /// 响应状态码
+    status_code: u16,
+    /// 响应HTTP版本
+    version: String,
+    /// 响应头（键为小写）
+    headers: HashMap<String, String>,
     /// Content-Length
     content_length: Option<usize>,
     /// Transfer-Encoding
     transfer_encoding: Option<String>,
-    /// Content-Encoding (gzip, deflate等)
+    /// Content-Encoding (gzip, deflate, br等)
     content_encoding: Option<String>,
     /// Connection类型
     connection: Option<String>,
-    /// 当前chunk解析状态
-    chunk_state: ChunkState,
-    /// 已转发的数据长度
+    /// 响应体分帧状态
+    body_framer: Option<BodyFramer>,
+    /// 已转发的数据长度（含响应头）
     forwarded_bytes: usize,
     /// 解压缩后的响应体（用于日志记录）
-    decompressed_body: Vec<u8>,
-    /// 响应体记录长度限制
-    response_body_limit: i64,
+    body_capture: BodyCapture,
+    /// 响应结束后多读到的数据（如101升级后的WebSocket帧）
+    leftover: Vec<u8>,
+    /// 注入到响应数据的故障
+    fault: Option<BodyFault>,
+    /// 是否因注入的故障提前结束了转发
+    truncated: bool,
+    /// 响应头改写
+    header_rewrite: HeaderRewrite,
+    /// 改写后返回给客户端的响应头（有改写规则时）
+    sent_headers: Option<HeaderList>,
+    /// 响应体改写
+    body_rewrite: BodyRewrite,
+    /// 为改写响应体而暂存、尚未转发的响应
+    pending: Option<PendingResponse>,
+    /// 改写后返回给客户端的响应体（用于日志记录）
+    sent_body: Option<String>,
+    /// 处理响应的拦截器，收到完整的响应后调用
+    interception: Option<ResponseInterception>,
+    /// 丢弃响应的拦截器名称
+    dropped_by: Option<String>,
+    /// 拦截器记录的错误（用于日志记录）
+    flow_error: Option<String>,
+}
+
+/// 处理响应的拦截器
+#[derive(Debug)]
+struct ResponseInterception {
+    /// 匹配响应的拦截器
+    interceptors: InterceptorChain,
+    /// 请求的主机
+    host: String,
+    /// 发往上游的请求
+    request: FlowRequest,
+    /// 处理请求的拦截器记录的错误
+    error: Option<String>,
 }
 
+/// 为改写响应体或交给拦截器处理而暂存的响应
 #[derive(Debug)]
+struct PendingResponse {
+    /// 状态行
+    status_line: String,
+    /// 响应头（已按头部改写规则改写）
+    headers: HeaderList,
+    /// 从上游读取的原始响应体数据（含chunk分帧）
+    raw: Vec<u8>,
+    /// 原始数据中属于响应体内容的范围
+    body_ranges: Vec<std::ops::Range<usize>>,
+    /// 去除分帧后的响应体
+    body: Vec<u8>,
+}
+
+/// 消息体分帧方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyFraming {
+    /// 按Content-Length读取，记录剩余长度
+    ContentLength { remaining: usize },
+    /// chunked编码
+    Chunked,
+    /// 读取到连接关闭为止
+    UntilClose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum ChunkState {
-    /// 等待chunk大小
+    /// 等待chunk大小行
     WaitingSize,
     /// 等待chunk数据
     WaitingData { remaining: usize },
     /// 等待chunk结束符\r\n
-    WaitingEnd,
-    /// 等待终止chunk (0\r\n\r\n)
-    WaitingTerminator,
+    WaitingEnd { remaining: usize },
+    /// 等待trailer或终止空行
+    WaitingTrailer,
     /// 完成
     Complete,
 }
 
+/// 消息体分帧器
+///
+/// 只负责识别消息体边界，数据本身按原样转发，
+/// 通过回调交出去除chunk分帧后的消息体内容。
+#[derive(Debug, Clone)]
+struct BodyFramer {
+    /// 分帧方式
+    framing: BodyFraming,
+    /// chunked编码解析状态
+    chunk_state: ChunkState,
+    /// 尚未读完的chunk大小行或trailer行
+    line_buffer: Vec<u8>,
+}
+
+impl BodyFramer {
+    fn new(framing: BodyFraming) -> Self {
+        Self {
+            framing,
+            chunk_state: ChunkState::WaitingSize,
+            line_buffer: Vec::new(),
+        }
+    }
+
+    /// 消息体是否已完整读取
+    fn is_complete(&self) -> bool {
+        match self.framing {
+            BodyFraming::ContentLength { remaining } => remaining == 0,
+            BodyFraming::Chunked => self.chunk_state == ChunkState::Complete,
+            BodyFraming::UntilClose => false,
+        }
+    }
+
+    /// 处理一段数据
+    ///
+    /// # 参数
+    /// * `data` - 新读取的数据
+    /// * `on_body` - 消息体内容回调（chunked编码时不含分帧数据）
+    ///
+    /// # 返回值
+    /// 返回属于当前消息的字节数，剩余数据属于后续消息
+    fn advance(&mut self, data: &[u8], mut on_body: impl FnMut(&[u8])) -> Result<usize> {
+        match &mut self.framing {
+            BodyFraming::ContentLength { remaining } => {
+                let to_forward = (*remaining).min(data.len());
+                on_body(&data[..to_forward]);
+                *remaining -= to_forward;
+                Ok(to_forward)
+            },
+            BodyFraming::UntilClose => {
+                on_body(data);
+                Ok(data.len())
+            },
+            BodyFraming::Chunked => self.advance_chunked(data, on_body),
+        }
+    }
+
+    /// 处理chunked编码数据
+    fn advance_chunked(&mut self, data: &[u8], mut on_body: impl FnMut(&[u8])) -> Result<usize> {
+        let mut pos = 0;
+
+        while pos < data.len() {
+            match &mut self.chunk_state {
+                ChunkState::WaitingSize => {
+                    let Some(line) = self.take_line(data, &mut pos)? else {
+                        break;
+                    };
+                    let line = String::from_utf8_lossy(&line);
+                    // 忽略chunk扩展参数
+                    let size_str = line.split(';').next().unwrap_or("").trim();
+                    let size = usize::from_str_radix(size_str, 16)
+                        .map_err(|_| anyhow::anyhow!("Invalid chunk size: {size_str}"))?;
+
+                    self.chunk_state = if size == 0 {
+                        ChunkState::WaitingTrailer
+                    } else {
+                        ChunkState::WaitingData { remaining: size }
+                    };
+                },
+
+                ChunkState::WaitingData { remaining } => {
+                    let to_forward = (*remaining).min(data.len() - pos);
+                    on_body(&data[pos..pos + to_forward]);
+                    pos += to_forward;
+                    *remaining -= to_forward;
+
+                    if *remaining == 0 {
+                        self.chunk_state = ChunkState::WaitingEnd { remaining: 2 };
+                    }
+                },
+
+                ChunkState::WaitingEnd { remaining } => {
+                    let to_skip = (*remaining).min(data.len() - pos);
+                    pos += to_skip;
+                    *remaining -= to_skip;
+
+                    if *remaining == 0 {
+                        self.chunk_state = ChunkState::WaitingSize;
+                    }
+                },
+
+                ChunkState::WaitingTrailer => {
+                    let Some(line) = self.take_line(data, &mut pos)? else {
+                        break;
+                    };
+                    // 空行表示消息结束，否则是trailer字段
+                    if line.iter().all(|b| *b == b'\r') {
+                        self.chunk_state = ChunkState::Complete;
+                    }
+                },
+
+                ChunkState::Complete => break,
+            }
+        }
+
+        Ok(pos)
+    }
+
+    /// 读取一行（不含\n），数据不足时暂存并返回None
+    fn take_line(&mut self, data: &[u8], pos: &mut usize) -> Result<Option<Vec<u8>>> {
+        match data[*pos..].iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                self.line_buffer.extend_from_slice(&data[*pos..*pos + end]);
+                *pos += end + 1;
+                Ok(Some(std::mem::take(&mut self.line_buffer)))
+            },
+            None => {
+                self.line_buffer.extend_from_slice(&data[*pos..]);
+                *pos = data.len();
+                if self.line_buffer.len() > MAX_CHUNK_LINE_SIZE {
+                    anyhow::bail!("Chunk line too large");
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// 流式解压器
+enum BodyDecoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Deflate(flate2::write::ZlibDecoder<Vec<u8>>),
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+}
+
+impl std::fmt::Debug for BodyDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyDecoder::Gzip(_) => f.write_str("Gzip"),
+            BodyDecoder::Deflate(_) => f.write_str("Deflate"),
+            BodyDecoder::Brotli(_) => f.write_str("Brotli"),
+        }
+    }
+}
+
+/// 消息体记录器，按limit累积（必要时解压后的）消息体用于日志记录
+#[derive(Debug)]
+pub(crate) struct BodyCapture {
+    /// 记录长度限制（0不记录，负数不限制）
+    limit: i64,
+    /// 压缩内容的解压器
+    decoder: Option<BodyDecoder>,
+    /// 已记录的内容
+    body: Vec<u8>,
+    /// 收到的原始字节数
+    raw_bytes: usize,
+}
+
+impl BodyCapture {
+    pub(crate) fn new(limit: i64) -> Self {
+        Self {
+            limit,
+            decoder: None,
+            body: Vec::new(),
+            raw_bytes: 0,
+        }
+    }
+
+    /// 根据Content-Encoding设置解压方式
+    pub(crate) fn set_content_encoding(&mut self, content_encoding: Option<&str>) {
+        self.decoder = match content_encoding.map(|e| e.trim().to_lowercase()).as_deref() {
+            Some("gzip") | Some("x-gzip") => Some(BodyDecoder::Gzip(flate2::write::GzDecoder::new(Vec::new()))),
+            Some("deflate") => Some(BodyDecoder::Deflate(flate2::write::ZlibDecoder::new(Vec::new()))),
+            Some("br") => Some(BodyDecoder::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096)))),
+            _ => None,
+        };
+    }
+
+    /// 是否已达到记录上限
+    fn is_full(&self) -> bool {
+        self.limit == 0 || (self.limit > 0 && self.body.len() >= self.limit as usize)
+    }
+
+    /// 按limit累积内容
+    fn accumulate(&mut self, data: &[u8]) {
+        if self.is_full() {
+            return;
+        }
+        if self.limit < 0 {
+            self.body.extend_from_slice(data);
+        } else {
+            let remain = self.limit as usize - self.body.len();
+            let to_copy = remain.min(data.len());
+            self.body.extend_from_slice(&data[..to_copy]);
+        }
+    }
+
+    /// 处理一段消息体数据
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        self.raw_bytes += data.len();
+        if self.is_full() {
+            return;
+        }
+
+        let decoded = match &mut self.decoder {
+            None => {
+                self.accumulate(data);
+                return;
+            },
+            Some(BodyDecoder::Gzip(decoder)) => decoder.write_all(data).map(|_| std::mem::take(decoder.get_mut())),
+            Some(BodyDecoder::Deflate(decoder)) => decoder.write_all(data).map(|_| std::mem::take(decoder.get_mut())),
+            Some(BodyDecoder::Brotli(decoder)) => decoder.write_all(data).map(|_| std::mem::take(decoder.get_mut())),
+        };
+
+        match decoded {
+            Ok(decoded) => self.accumulate(&decoded),
+            Err(e) => {
+                log::warn!("Failed to decompress body for logging: {e}");
+                self.decoder = None;
+            }
+        }
+    }
+
+    /// 结束解压，取出解压器中剩余的数据
+    pub(crate) fn finish(&mut self) {
+        let remaining = match self.decoder.take() {
+            Some(BodyDecoder::Gzip(decoder)) => decoder.finish(),
+            Some(BodyDecoder::Deflate(decoder)) => decoder.finish(),
+            Some(BodyDecoder::Brotli(mut decoder)) => decoder.close().map(|_| std::mem::take(decoder.get_mut())),
+            None => return,
+        };
+        match remaining {
+            Ok(remaining) => {
+                self.accumulate(&remaining);
+                log::info!("Decompressed {} bytes to {} bytes", self.raw_bytes, self.body.len());
+            },
+            Err(e) => log::warn!("Failed to decompress body for logging: {e}"),
+        }
+    }
+
+    /// 获取记录的内容
+    pub(crate) fn as_string(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    /// 取出记录的原始内容
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.body
+    }
+}
+
 impl HttpResponseProcessor {
-    fn new(response_body_limit: i64) -> Self {
+    /// 创建响应处理器
+    ///
+    /// # 参数
+    /// * `response_body_limit` - 响应体记录长度限制
+    /// * `request_method` - 对应请求的方法，用于判断响应是否有响应体
+    fn new(response_body_limit: i64, request_method: &str) -> Self {
         Self {
             headers_parsed: false,
-            header_end: None,
+            header_buffer: Vec::new(),
+            head_request: request_method.eq_ignore_ascii_case("HEAD"),
+            status_code: 0,
+            version: String::new(),
+            headers: HashMap::new(),
             content_length: None,
             transfer_encoding: None,
             content_encoding: None,
             connection: None,
-            chunk_state: ChunkState::WaitingSize,
+            body_framer: None,
             forwarded_bytes: 0,
-            decompressed_body: Vec::new(),
-            response_body_limit,
+            body_capture: BodyCapture::new(response_body_limit),
+            leftover: Vec::new(),
+            fault: None,
+            truncated: false,
+            header_rewrite: HeaderRewrite::default(),
+            sent_headers: None,
+            body_rewrite: BodyRewrite::default(),
+            pending: None,
+            sent_body: None,
+            interception: None,
+            dropped_by: None,
+            flow_error: None,
         }
     }
 
-    /// 按limit累积响应体
-    fn accumulate_body(&mut self, data: &[u8]) {
-        if self.response_body_limit == 0 {
-            return;
-        }
-        if self.response_body_limit < 0 {
-            self.decompressed_body.extend_from_slice(data);
-        } else {
-            let remain = self.response_body_limit as usize - self.decompressed_body.len();
-            if remain > 0 {
-                let to_copy = remain.min(data.len());
-                self.decompressed_body.extend_from_slice(&data[..to_copy]);
-            }
-        }
+    /// 设置处理响应的拦截器
+    fn with_interception(mut self, interception: Option<ResponseInterception>) -> Self {
+        self.flow_error = interception.as_ref().and_then(|interception| interception.error.clone());
+        self.interception = interception;
+        self
+    }
+
+    /// 设置响应体改写
+    fn with_body_rewrite(mut self, body_rewrite: BodyRewrite) -> Self {
+        self.body_rewrite = body_rewrite;
+        self
     }
 
-    /// 处理响应数据块（TLS版本）
-    async fn process_chunk_tls(
+    /// 设置响应头改写
+    fn with_header_rewrite(mut self, header_rewrite: HeaderRewrite) -> Self {
+        self.header_rewrite = header_rewrite;
+        self
+    }
+
+    /// 按规则改写响应头，返回状态行和改写后的响应头
+    fn rewrite_head(&self, head: &[u8]) -> (String, HeaderList) {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default().to_string();
+        let mut headers = parse_header_lines(lines);
+        self.header_rewrite.apply(&mut headers);
+        (status_line, headers)
+    }
+
+    /// 设置注入到响应数据的故障
+    fn with_fault(mut self, fault: Option<BodyFault>) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    /// 向客户端转发数据，有注入的故障时先应用故障
+    ///
+    /// # 参数
+    /// * `data` - 从上游读取的数据
+    /// * `body_ranges` - 数据中属于响应体内容的范围
+    /// * `client_stream` - 客户端连接
+    async fn forward<W: AsyncWrite + Unpin>(
         &mut self,
         data: &[u8],
-        client_stream: &mut tokio_rustls::server::TlsStream<TcpStream>,
-    ) -> Result<ProcessingResult> {
-        if !self.headers_parsed {
-            return self.process_headers_tls(data, client_stream).await;
-        }
-        
-        match self.transfer_encoding.as_deref() {
-            Some("chunked") => self.process_chunked_body_tls(data, client_stream).await,
-            _ => self.process_normal_body_tls(data, client_stream).await,
-        }
+        body_ranges: &[std::ops::Range<usize>],
+        client_stream: &mut W,
+    ) -> Result<()> {
+        self.forwarded_bytes += data.len();
+        let Some(fault) = self.fault.as_mut() else {
+            client_stream.write_all(data).await?;
+            return Ok(());
+        };
+        let (data, cut) = fault.apply(data, body_ranges);
+        client_stream.write_all(&data).await?;
+        self.truncated = cut;
+        Ok(())
     }
 
-    /// 处理响应数据块（HTTP版本）
-    async fn process_chunk_http(
+    /// 处理响应数据块
+    async fn process_chunk<W: AsyncWrite + Unpin>(
         &mut self,
         data: &[u8],
-        client_stream: &mut TcpStream,
+        client_stream: &mut W,
     ) -> Result<ProcessingResult> {
         if !self.headers_parsed {
-            return self.process_headers_http(data, client_stream).await;
-        }
-        
-        match self.transfer_encoding.as_deref() {
-            Some("chunked") => self.process_chunked_body_http(data, client_stream).await,
-            _ => self.process_normal_body_http(data, client_stream).await,
+            return self.process_headers(data, client_stream).await;
         }
+
+        self.process_body(data, client_stream).await
     }
 
-    /// 处理响应头（TLS版本）
-    async fn process_headers_tls(
+    /// 处理响应头
+    async fn process_headers<W: AsyncWrite + Unpin>(
         &mut self,
         data: &[u8],
-        client_stream: &mut tokio_rustls::server::TlsStream<TcpStream>,
+        client_stream: &mut W,
     ) -> Result<ProcessingResult> {
-        // 查找响应头结束标记
-        if let Some(header_end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+        self.header_buffer.extend_from_slice(data);
+
+        loop {
+            // 查找响应头结束标记
+            let Some(header_end) = self.header_buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if self.header_buffer.len() > MAX_RESPONSE_HEADER_SIZE {
+                    anyhow::bail!("HTTP response header too large");
+                }
+                // 还没找到完整的响应头，继续读取
+                return Ok(ProcessingResult::Continue);
+            };
             let header_end = header_end + 4;
-            self.header_end = Some(header_end);
-            self.headers_parsed = true;
-            
+            let rest = self.header_buffer.split_off(header_end);
+            let head = std::mem::replace(&mut self.header_buffer, rest);
+
             // 解析响应头
-            let headers_str = String::from_utf8_lossy(&data[..header_end]);
-            self.parse_headers(&headers_str)?;
-            
-            // 立即转发响应头
-            client_stream.write_all(&data[..header_end]).await?;
-            self.forwarded_bytes += header_end;
-            
-            // 如果有响应体，继续处理
-            if header_end < data.len() {
-                let body_data = &data[header_end..];
-                return self.process_body_after_headers_tls(body_data, client_stream).await;
+            self.parse_headers(&String::from_utf8_lossy(&head))?;
+
+            // 1xx中间响应（101除外）原样转发，之后还有最终响应头
+            if (100..200).contains(&self.status_code) && self.status_code != 101 {
+                self.forward(&head, &[], client_stream).await?;
+                if self.truncated {
+                    return Ok(self.current_result());
+                }
+                continue;
             }
-            
-            return Ok(ProcessingResult::Continue);
-        }
-        
-        // 还没找到完整的响应头，继续读取
-        Ok(ProcessingResult::Continue)
-    }
 
-    /// 处理响应头（HTTP版本）
-    async fn process_headers_http(
-        &mut self,
-        data: &[u8],
-        client_stream: &mut TcpStream,
-    ) -> Result<ProcessingResult> {
-        // 查找响应头结束标记
-        if let Some(header_end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
-            let header_end = header_end + 4;
-            self.header_end = Some(header_end);
             self.headers_parsed = true;
-            
-            // 解析响应头
-            let headers_str = String::from_utf8_lossy(&data[..header_end]);
-            self.parse_headers(&headers_str)?;
-            
-            // 立即转发响应头
-            client_stream.write_all(&data[..header_end]).await?;
-            self.forwarded_bytes += header_end;
-            
-            // 如果有响应体，继续处理
-            if header_end < data.len() {
-                let body_data = &data[header_end..];
-                return self.process_body_after_headers_http(body_data, client_stream).await;
+            self.body_capture.set_content_encoding(self.content_encoding.as_deref());
+            self.body_framer = self.response_framing().map(BodyFramer::new);
+
+            // 有响应体改写规则或拦截器时暂存响应，收到完整响应体后再转发；否则按规则改写响应头后立即转发
+            let intercepted = self.interception.is_some() && self.status_code != 101;
+            if (self.body_framer.is_some() && !self.body_rewrite.is_empty()) || intercepted {
+                let (status_line, headers) = self.rewrite_head(&head);
+                self.pending = Some(PendingResponse {
+                    status_line,
+                    headers,
+                    raw: Vec::new(),
+                    body_ranges: Vec::new(),
+                    body: Vec::new(),
+                });
+            } else {
+                let head = match self.header_rewrite.is_empty() {
+                    true => head,
+                    false => {
+                        let (status_line, headers) = self.rewrite_head(&head);
+                        let head = format!("{status_line}\r\n{}\r\n", format_header_lines(&headers));
+                        self.sent_headers = Some(headers);
+                        head.into_bytes()
+                    },
+                };
+                self.forward(&head, &[], client_stream).await?;
+                if self.truncated {
+                    return Ok(self.current_result());
+                }
+            }
+
+            let body_data = std::mem::take(&mut self.header_buffer);
+            if self.body_framer.is_none() {
+                self.leftover = body_data;
+                self.flush_pending(client_stream).await?;
+                return Ok(self.current_result());
+            }
+            if body_data.is_empty() {
+                return Ok(self.current_result());
             }
-            
-            return Ok(ProcessingResult::Continue);
+            return self.process_body(&body_data, client_stream).await;
         }
-        
-        // 还没找到完整的响应头，继续读取
-        Ok(ProcessingResult::Continue)
     }
 
     /// 解析响应头
     fn parse_headers(&mut self, headers_str: &str) -> Result<()> {
         let lines: Vec<&str> = headers_str.lines().collect();
-        
+
+        let status_parts: Vec<&str> = lines.first().map(|l| l.split_whitespace().collect()).unwrap_or_default();
+        if status_parts.len() < 2 {
+            anyhow::bail!("Invalid HTTP response status line");
+        }
+        self.version = status_parts[0].to_string();
+        self.status_code = status_parts[1].parse().unwrap_or(0);
+        self.headers.clear();
+        self.content_length = None;
+        self.transfer_encoding = None;
+        self.content_encoding = None;
+        self.connection = None;
+
         for line in &lines[1..] {
             if line.is_empty() {
                 break;
             }
-            
+
             if let Some(colon_pos) = line.find(':') {
                 let key = line[..colon_pos].trim().to_lowercase();
                 let value = line[colon_pos + 1..].trim().to_string();
-                
+
                 match key.as_str() {
                     "content-length" => {
                         self.content_length = value.parse().ok();
                     },
                     "transfer-encoding" => {
-                        self.transfer_encoding = Some(value);
+                        self.transfer_encoding = Some(value.clone());
                     },
                     "content-encoding" => {
-                        self.content_encoding = Some(value);
+                        self.content_encoding = Some(value.clone());
                     },
                     "connection" => {
-                        self.connection = Some(value);
+                        self.connection = Some(value.clone());
                     },
                     _ => {}
                 }
+                self.headers.insert(key, value);
             }
         }
-        
+
         Ok(())
     }
 
-    /// 处理响应头后的数据（TLS版本）
-    async fn process_body_after_headers_tls(
-        &mut self,
-        data: &[u8],
-        client_stream: &mut tokio_rustls::server::TlsStream<TcpStream>,
-    ) -> Result<ProcessingResult> {
-        match self.transfer_encoding.as_deref() {
-            Some("chunked") => self.process_chunked_body_tls(data, client_stream).await,
-            _ => self.process_normal_body_tls(data, client_stream).await,
+    /// 根据响应头确定响应体分帧方式，没有响应体时返回None
+    fn response_framing(&self) -> Option<BodyFraming> {
+        if self.head_request
+            || (100..200).contains(&self.status_code)
+            || self.status_code == 204
+            || self.status_code == 304
+        {
+            return None;
         }
-    }
 
-    /// 处理响应头后的数据（HTTP版本）
-    async fn process_body_after_headers_http(
-        &mut self,
-        data: &[u8],
-        client_stream: &mut TcpStream,
-    ) -> Result<ProcessingResult> {
-        match self.transfer_encoding.as_deref() {
-            Some("chunked") => self.process_chunked_body_http(data, client_stream).await,
-            _ => self.process_normal_body_http(data, client_stream).await,
+        let chunked = self.transfer_encoding.as_deref()
+            .map(|te| te.to_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        if chunked {
+            return Some(BodyFraming::Chunked);
+        }
+
+        match self.content_length {
+            Some(0) => None,
+            Some(content_length) => Some(BodyFraming::ContentLength { remaining: content_length }),
+            None => Some(BodyFraming::UntilClose),
         }
     }
 
-    /// 处理普通响应体（非chunked，TLS版本）
-    async fn process_normal_body_tls(
+    /// 处理响应体数据
+    async fn process_body<W: AsyncWrite + Unpin>(
         &mut self,
         data: &[u8],
-        client_stream: &mut tokio_rustls::server::TlsStream<TcpStream>,
+        client_stream: &mut W,
     ) -> Result<ProcessingResult> {
-        // 处理压缩数据（用于日志记录）
-        self.process_compressed_data(data)?;
-        
-        if let Some(content_length) = self.content_length {
-            // 有Content-Length的情况
-            let remaining = content_length.saturating_sub(self.forwarded_bytes);
-            let to_forward = std::cmp::min(remaining, data.len());
-            
-            if to_forward > 0 {
-                client_stream.write_all(&data[..to_forward]).await?;
-                self.forwarded_bytes += to_forward;
+        let Some(framer) = self.body_framer.as_mut() else {
+            self.leftover.extend_from_slice(data);
+            return Ok(ProcessingResult::Complete);
+        };
+
+        let body_capture = &mut self.body_capture;
+        let mut body_ranges = Vec::new();
+        let consumed = framer.advance(data, |body| {
+            // 回调交出的是data的子切片，记录响应体内容在data中的位置
+            let start = body.as_ptr() as usize - data.as_ptr() as usize;
+            body_ranges.push(start..start + body.len());
+            body_capture.feed(body)
+        })?;
+
+        let complete = framer.is_complete();
+        if let Some(pending) = self.pending.as_mut() {
+            // 暂存响应体，完整或超过改写上限时转发
+            let offset = pending.raw.len();
+            for range in &body_ranges {
+                pending.body.extend_from_slice(&data[range.clone()]);
+                pending.body_ranges.push(range.start + offset..range.end + offset);
             }
-            
-            if self.forwarded_bytes >= content_length {
-                Ok(ProcessingResult::Complete)
-            } else {
-                Ok(ProcessingResult::Continue)
+            pending.raw.extend_from_slice(&data[..consumed]);
+            if complete || pending.raw.len() > MAX_BODY_REWRITE_SIZE {
+                self.flush_pending(client_stream).await?;
             }
-        } else if self.connection.as_deref() == Some("close") {
-            // Connection: close，转发所有数据并继续读取
-            client_stream.write_all(data).await?;
-            self.forwarded_bytes += data.len();
-            Ok(ProcessingResult::Continue)
-        } else {
-            // 没有Content-Length且不是close，转发所有数据
-            client_stream.write_all(data).await?;
-            self.forwarded_bytes += data.len();
-            Ok(ProcessingResult::Continue)
+        } else if consumed > 0 {
+            self.forward(&data[..consumed], &body_ranges, client_stream).await?;
         }
+        if consumed < data.len() {
+            log::debug!("{} bytes received after the end of response", data.len() - consumed);
+            self.leftover.extend_from_slice(&data[consumed..]);
+        }
+
+        Ok(self.current_result())
     }
 
-    /// 处理普通响应体（非chunked，HTTP版本）
-    async fn process_normal_body_http(
-        &mut self,
-        data: &[u8],
-        client_stream: &mut TcpStream,
-    ) -> Result<ProcessingResult> {
-        // 处理压缩数据（用于日志记录）
-        self.process_compressed_data(data)?;
-        
-        if let Some(content_length) = self.content_length {
-            // 有Content-Length的情况
-            let remaining = content_length.saturating_sub(self.forwarded_bytes);
-            let to_forward = std::cmp::min(remaining, data.len());
-            
-            if to_forward > 0 {
-                client_stream.write_all(&data[..to_forward]).await?;
-                self.forwarded_bytes += to_forward;
+    /// 转发暂存的响应
+    ///
+    /// 响应体完整时按规则改写响应体并修正Content-Length，命中响应断点时再暂停等待编辑；
+    /// 响应体不完整（上游提前断开或超过改写上限）时原样转发已读取的数据。
+    async fn flush_pending<W: AsyncWrite + Unpin>(&mut self, client_stream: &mut W) -> Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        let complete = pending.raw.len() <= MAX_BODY_REWRITE_SIZE
+            && self.body_framer.as_ref()
+                .map(|framer| framer.is_complete() || framer.framing == BodyFraming::UntilClose)
+                .unwrap_or(true);
+        if !complete {
+            log::warn!("Response body incomplete or larger than {MAX_BODY_REWRITE_SIZE} bytes, forwarding without rewrite");
+        }
+        let rewritten = match complete {
+            true => self.body_rewrite.apply(&pending.body, self.content_encoding.as_deref()),
+            false => None,
+        };
+
+        let mut status_line = pending.status_line;
+        let mut headers = pending.headers;
+        let (mut body, mut body_ranges, decoded) = match rewritten {
+            Some(rewritten) => {
+                log::info!("✏️ Rewrote response body ({} -> {} bytes)", pending.body.len(), rewritten.encoded.len());
+                set_content_length(&mut headers, rewritten.encoded.len());
+                self.sent_body = Some(rewritten.body_for_log(self.body_capture.limit));
+                let body_ranges = std::iter::once(0..rewritten.encoded.len()).collect();
+                (rewritten.encoded, body_ranges, Some(rewritten.decoded))
+            },
+            None => (pending.raw, pending.body_ranges, None),
+        };
+
+        // 把完整的响应交给拦截器处理（包括响应断点），按修改后的状态码、响应头和响应体转发
+        let interception = self.interception.take().filter(|_| complete);
+        let decoded = match interception {
+            Some(_) => decoded.or_else(|| decode_body(&pending.body, self.content_encoding.as_deref())),
+            None => None,
+        };
+        if interception.is_some() && decoded.is_none() {
+            log::warn!("Cannot decode response body with Content-Encoding {:?}, skipping interceptors", self.content_encoding);
+        }
+        if let (Some(interception), Some(decoded)) = (interception, decoded) {
+            let response = FlowResponse {
+                status: self.status_code,
+                headers,
+                body: decoded,
+            };
+            let mut flow = Flow {
+                host: interception.host,
+                request: interception.request,
+                response: Some(response.clone()),
+                error: interception.error,
+            };
+            let verdict = interception.interceptors.on_response(&mut flow).await;
+            self.flow_error = flow.error.take();
+            if let Verdict::Drop { by } = verdict {
+                self.dropped_by = Some(by);
+                self.truncated = true;
+                return Ok(());
             }
-            
-            if self.forwarded_bytes >= content_length {
-                Ok(ProcessingResult::Complete)
-            } else {
-                Ok(ProcessingResult::Continue)
+            let edited = flow.response.unwrap_or_else(|| response.clone());
+
+            let modified = edited != response;
+            if edited.status != response.status {
+                let reason = http::StatusCode::from_u16(edited.status).ok()
+                    .and_then(|status| status.canonical_reason())
+                    .unwrap_or("");
+                status_line = format!("{} {} {reason}", self.version, edited.status);
+                self.status_code = edited.status;
+            }
+            headers = edited.headers;
+            if edited.body != response.body || headers != response.headers {
+                // 不允许有响应体的响应只发送响应头
+                let body_allowed = !self.head_request && !matches!(edited.status, 100..=199 | 204 | 304);
+                body = match body_allowed {
+                    true => encode_body(&edited.body, &mut headers),
+                    false => Vec::new(),
+                };
+                body_ranges = std::iter::once(0..body.len()).collect();
+                let mut capture = BodyCapture::new(self.body_capture.limit);
+                capture.feed(&edited.body);
+                self.sent_body = Some(capture.as_string());
+            }
+            if modified {
+                log::info!("✏️ Response modified by interceptors");
+                self.sent_headers = Some(headers.clone());
             }
-        } else if self.connection.as_deref() == Some("close") {
-            // Connection: close，转发所有数据并继续读取
-            client_stream.write_all(data).await?;
-            self.forwarded_bytes += data.len();
-            Ok(ProcessingResult::Continue)
-        } else {
-            // 没有Content-Length且不是close，转发所有数据
-            client_stream.write_all(data).await?;
-            self.forwarded_bytes += data.len();
-            Ok(ProcessingResult::Continue)
         }
+        if self.sent_body.is_some() || !self.header_rewrite.is_empty() {
+            self.sent_headers = Some(headers.clone());
+        }
+
+        let mut data = format!("{status_line}\r\n{}\r\n", format_header_lines(&headers)).into_bytes();
+        let offset = data.len();
+        data.extend_from_slice(&body);
+        let body_ranges: Vec<_> = body_ranges.iter().map(|range| range.start + offset..range.end + offset).collect();
+        self.forward(&data, &body_ranges, client_stream).await
     }
 
-    /// 处理chunked响应体（TLS版本）
-    async fn process_chunked_body_tls(
-        &mut self,
-        data: &[u8],
-        client_stream: &mut tokio_rustls::server::TlsStream<TcpStream>,
-    ) -> Result<ProcessingResult> {
-        let mut pos = 0;
-        
-        while pos < data.len() {
-            match &mut self.chunk_state {
-                ChunkState::WaitingSize => {
-                    // 查找chunk大小行结束符
-                    if let Some(end_pos) = data[pos..].windows(2).position(|w| w == b"\r\n") {
-                        let size_line = &data[pos..pos + end_pos];
-                        let size_str = String::from_utf8_lossy(size_line);
-                        
-                        // 解析chunk大小
-                        if let Ok(size) = usize::from_str_radix(size_str.trim(), 16) {
-                            if size == 0 {
-                                // 终止chunk，检查是否有\r\n\r\n
-                                let remaining = &data[pos + end_pos + 2..];
-                                if remaining.len() >= 2 && remaining[..2] == *b"\r\n" {
-                                    // 完整的终止chunk
-                                    client_stream.write_all(&data[pos..pos + end_pos + 4]).await?;
-                                    return Ok(ProcessingResult::Complete);
-                                } else {
-                                    // 等待更多数据
-                                    client_stream.write_all(&data[pos..]).await?;
-                                    self.chunk_state = ChunkState::WaitingTerminator;
-                                    return Ok(ProcessingResult::Continue);
-                                }
-                            } else {
-                                // 非零chunk
-                                client_stream.write_all(&data[pos..pos + end_pos + 2]).await?;
-                                self.chunk_state = ChunkState::WaitingData { remaining: size };
-                                pos += end_pos + 2;
-                            }
-                        } else {
-                            log::warn!("Invalid chunk size: {}", size_str);
-                            return Ok(ProcessingResult::Complete);
-                        }
-                    } else {
-                        // 没有找到完整的chunk大小行
-                        client_stream.write_all(&data[pos..]).await?;
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::WaitingData { remaining } => {
-                    let to_forward = std::cmp::min(*remaining, data.len() - pos);
-                    let chunk_data = &data[pos..pos + to_forward];
-                    
-                    // 处理压缩数据（用于日志记录）
-                    Self::process_compressed_data_static(
-                        &self.content_encoding,
-                        &mut self.decompressed_body,
-                        chunk_data,
-                        self.response_body_limit,
-                    )?;
-                    
-                    client_stream.write_all(chunk_data).await?;
-                    pos += to_forward;
-                    *remaining -= to_forward;
-                    
-                    if *remaining == 0 {
-                        self.chunk_state = ChunkState::WaitingEnd;
-                    } else {
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::WaitingEnd => {
-                    if pos + 1 < data.len() && data[pos..pos + 2] == *b"\r\n" {
-                        client_stream.write_all(&data[pos..pos + 2]).await?;
-                        pos += 2;
-                        self.chunk_state = ChunkState::WaitingSize;
-                    } else {
-                        // 等待更多数据
-                        client_stream.write_all(&data[pos..]).await?;
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::WaitingTerminator => {
-                    // 等待终止chunk的\r\n\r\n
-                    if data.len() >= 2 && data[..2] == *b"\r\n" {
-                        client_stream.write_all(&data[..2]).await?;
-                        return Ok(ProcessingResult::Complete);
-                    } else {
-                        client_stream.write_all(data).await?;
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::Complete => {
-                    return Ok(ProcessingResult::Complete);
-                }
-            }
+    /// 根据当前状态返回处理结果
+    fn current_result(&self) -> ProcessingResult {
+        if self.truncated || self.is_complete() {
+            ProcessingResult::Complete
+        } else {
+            ProcessingResult::Continue
         }
-        
-        Ok(ProcessingResult::Continue)
     }
 
-    /// 处理chunked响应体（HTTP版本）
-    async fn process_chunked_body_http(
-        &mut self,
-        data: &[u8],
-        client_stream: &mut TcpStream,
-    ) -> Result<ProcessingResult> {
-        let mut pos = 0;
-        
-        while pos < data.len() {
-            match &mut self.chunk_state {
-                ChunkState::WaitingSize => {
-                    // 查找chunk大小行结束符
-                    if let Some(end_pos) = data[pos..].windows(2).position(|w| w == b"\r\n") {
-                        let size_line = &data[pos..pos + end_pos];
-                        let size_str = String::from_utf8_lossy(size_line);
-                        
-                        // 解析chunk大小
-                        if let Ok(size) = usize::from_str_radix(size_str.trim(), 16) {
-                            if size == 0 {
-                                // 终止chunk，检查是否有\r\n\r\n
-                                let remaining = &data[pos + end_pos + 2..];
-                                if remaining.len() >= 2 && remaining[..2] == *b"\r\n" {
-                                    // 完整的终止chunk
-                                    client_stream.write_all(&data[pos..pos + end_pos + 4]).await?;
-                                    return Ok(ProcessingResult::Complete);
-                                } else {
-                                    // 等待更多数据
-                                    client_stream.write_all(&data[pos..]).await?;
-                                    self.chunk_state = ChunkState::WaitingTerminator;
-                                    return Ok(ProcessingResult::Continue);
-                                }
-                            } else {
-                                // 非零chunk
-                                client_stream.write_all(&data[pos..pos + end_pos + 2]).await?;
-                                self.chunk_state = ChunkState::WaitingData { remaining: size };
-                                pos += end_pos + 2;
-                            }
-                        } else {
-                            log::warn!("Invalid chunk size: {}", size_str);
-                            return Ok(ProcessingResult::Complete);
-                        }
-                    } else {
-                        // 没有找到完整的chunk大小行
-                        client_stream.write_all(&data[pos..]).await?;
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::WaitingData { remaining } => {
-                    let to_forward = std::cmp::min(*remaining, data.len() - pos);
-                    let chunk_data = &data[pos..pos + to_forward];
-                    
-                    // 处理压缩数据（用于日志记录）
-                    Self::process_compressed_data_static(
-                        &self.content_encoding,
-                        &mut self.decompressed_body,
-                        chunk_data,
-                        self.response_body_limit,
-                    )?;
-                    
-                    client_stream.write_all(chunk_data).await?;
-                    pos += to_forward;
-                    *remaining -= to_forward;
-                    
-                    if *remaining == 0 {
-                        self.chunk_state = ChunkState::WaitingEnd;
-                    } else {
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::WaitingEnd => {
-                    if pos + 1 < data.len() && data[pos..pos + 2] == *b"\r\n" {
-                        client_stream.write_all(&data[pos..pos + 2]).await?;
-                        pos += 2;
-                        self.chunk_state = ChunkState::WaitingSize;
-                    } else {
-                        // 等待更多数据
-                        client_stream.write_all(&data[pos..]).await?;
-                        return Ok(ProcessingResult::Continue);
-                    }
-                },
-                
-                ChunkState::WaitingTerminator => {
-                    // 等待终止chunk的\r\n\r\n
-                    if data.len() >= 2 && data[..2] == *b"\r\n" {
-                        client_stream.write_all(&data[..2]).await?;
-                        return Ok(ProcessingResult::Complete);
-                    } else {
-                        client_stream.write_all(data).await?;
-                        return Ok(ProcessingResult::Continue);
+    /// 响应是否已完整接收并转发（注入故障提前结束转发时不算完整）
+    fn is_complete(&self) -> bool {
+        !self.truncated && self.headers_parsed && self.body_framer.as_ref().map(|f| f.is_complete()).unwrap_or(true)
+    }
+
+    /// 响应结束后上游连接是否可以复用
+    fn keep_alive(&self) -> bool {
+        self.is_complete() && is_keep_alive(&self.version, self.connection.as_deref())
+    }
+
+    /// 结束响应处理，完成日志用响应体的解压
+    fn finish(&mut self) {
+        self.body_capture.finish();
+    }
+
+    /// 获取解压缩后的响应体
+    fn get_decompressed_body(&self) -> String {
+        self.body_capture.as_string()
+    }
+
+    /// 取出响应结束后多读到的数据
+    fn take_leftover(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.leftover)
+    }
+}
+
+/// HTTP请求体处理器，按Content-Length或chunked编码将请求体原样流式转发到上游
+#[derive(Debug)]
+struct HttpRequestProcessor {
+    /// 请求体分帧状态，没有请求体时为None
+    body_framer: Option<BodyFramer>,
+    /// 已转发的请求体字节数（含chunk分帧数据）
+    forwarded_bytes: usize,
+    /// 请求体记录（用于日志记录）
+    body_capture: BodyCapture,
+    /// 请求体是否已在改写前记录，转发时不再记录
+    body_captured: bool,
+}
+
+impl HttpRequestProcessor {
+    /// 创建请求体处理器
+    ///
+    /// # 参数
+    /// * `headers` - 请求头（键为小写）
+    /// * `request_body_limit` - 请求体记录长度限制
+    fn new(headers: &HashMap<String, String>, request_body_limit: i64) -> Self {
+        let chunked = headers.get("transfer-encoding")
+            .map(|te| te.to_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        let content_length = headers.get("content-length").and_then(|v| v.parse::<usize>().ok());
+
+        // 请求没有Transfer-Encoding和Content-Length时没有请求体
+        let framing = match (chunked, content_length) {
+            (true, _) => Some(BodyFraming::Chunked),
+            (false, Some(0)) | (false, None) => None,
+            (false, Some(content_length)) => Some(BodyFraming::ContentLength { remaining: content_length }),
+        };
+
+        let mut body_capture = BodyCapture::new(request_body_limit);
+        body_capture.set_content_encoding(headers.get("content-encoding").map(String::as_str));
+
+        Self {
+            body_framer: framing.map(BodyFramer::new),
+            forwarded_bytes: 0,
+            body_capture,
+            body_captured: false,
+        }
+    }
+
+    /// 读取完整的请求体用于改写，读取的数据不转发
+    ///
+    /// # 参数
+    /// * `initial` - 读取请求头时已经读到的数据
+    /// * `client_stream` - 客户端流
+    ///
+    /// # 返回值
+    /// 返回读取到的所有原始数据（含分帧和属于后续请求的数据）；请求体完整时同时返回去除分帧后的请求体和请求体在原始数据中的结束位置，
+    /// 请求体超过改写上限时停止读取并返回None
+    async fn buffer_body<C: AsyncRead + Unpin>(
+        &self,
+        initial: &[u8],
+        client_stream: &mut C,
+    ) -> Result<(Vec<u8>, Option<(Vec<u8>, usize)>)> {
+        let mut raw = initial.to_vec();
+        let Some(mut framer) = self.body_framer.clone() else {
+            return Ok((raw, Some((Vec::new(), 0))));
+        };
+
+        let mut body = Vec::new();
+        let mut position = 0;
+        let mut buffer = [0; 8192];
+        loop {
+            position += framer.advance(&raw[position..], |data| body.extend_from_slice(data))?;
+            if framer.is_complete() {
+                return Ok((raw, Some((body, position))));
+            }
+            if raw.len() > MAX_BODY_REWRITE_SIZE {
+                return Ok((raw, None));
+            }
+
+            let bytes_read = client_stream.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                anyhow::bail!("Client closed connection before request body was complete");
+            }
+            raw.extend_from_slice(&buffer[..bytes_read]);
+        }
+    }
+
+    /// 改写请求体后按新的请求体长度转发
+    ///
+    /// # 参数
+    /// * `received` - 从客户端收到的、去除分帧后的请求体（用于日志记录）
+    /// * `length` - 改写后的请求体长度
+    fn replace_body(&mut self, received: &[u8], length: usize) {
+        if !self.body_captured {
+            self.body_capture.feed(received);
+            self.body_capture.finish();
+            self.body_captured = true;
+        }
+        self.body_framer = (length > 0).then(|| BodyFramer::new(BodyFraming::ContentLength { remaining: length }));
+    }
+
+    /// 请求是否带有请求体
+    fn has_body(&self) -> bool {
+        self.body_framer.is_some()
+    }
+
+    /// 将请求体从客户端转发到上游
+    ///
+    /// # 参数
+    /// * `initial` - 读取请求头时已经读到的数据
+    /// * `client_stream` - 客户端流
+    /// * `server_stream` - 上游流
+    ///
+    /// # 返回值
+    /// 返回读取到的、属于后续请求的数据
+    async fn forward_body<C, S>(
+        &mut self,
+        initial: &[u8],
+        client_stream: &mut C,
+        server_stream: &mut S,
+    ) -> Result<Vec<u8>>
+    where
+        C: AsyncRead + Unpin,
+        S: AsyncWrite + Unpin,
+    {
+        let Some(framer) = self.body_framer.as_mut() else {
+            return Ok(initial.to_vec());
+        };
+
+        let mut data = initial.to_vec();
+        let mut buffer = [0; 8192];
+
+        loop {
+            if !data.is_empty() {
+                let body_capture = &mut self.body_capture;
+                let captured = self.body_captured;
+                let consumed = framer.advance(&data, |body| if !captured { body_capture.feed(body) })?;
+                server_stream.write_all(&data[..consumed]).await?;
+                self.forwarded_bytes += consumed;
+
+                if framer.is_complete() {
+                    server_stream.flush().await?;
+                    if !captured {
+                        self.body_capture.finish();
                     }
-                },
-                
-                ChunkState::Complete => {
-                    return Ok(ProcessingResult::Complete);
+                    log::info!("Forwarded request body ({} bytes)", self.forwarded_bytes);
+                    return Ok(data[consumed..].to_vec());
                 }
             }
+
+            let bytes_read = client_stream.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                anyhow::bail!("Client closed connection before request body was complete");
+            }
+            data = buffer[..bytes_read].to_vec();
         }
-        
-        Ok(ProcessingResult::Continue)
     }
 
-    /// 检查是否需要处理压缩内容
-    fn needs_decompression(&self) -> bool {
-        matches!(self.content_encoding.as_deref(), Some("gzip") | Some("deflate"))
+    /// 获取请求体（用于日志记录）
+    fn get_body(&self) -> String {
+        self.body_capture.as_string()
+    }
+}
+
+/// 根据HTTP版本和Connection头判断连接是否保持
+fn is_keep_alive(version: &str, connection: Option<&str>) -> bool {
+    let connection = connection.map(|c| c.to_lowercase()).unwrap_or_default();
+    if connection.contains("close") {
+        false
+    } else if connection.contains("keep-alive") {
+        true
+    } else {
+        version.eq_ignore_ascii_case("HTTP/1.1")
     }
+}
 
-    /// 解压缩gzip数据
-    fn decompress_gzip(&mut self, compressed_data: &[u8]) -> Result<Vec<u8>> {
-        let mut decoder = GzDecoder::new(compressed_data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+/// 解析URL中的查询参数
+pub(crate) fn parse_url_params(path: &str) -> String {
+    match path.split_once('?') {
+        Some((_, query)) => query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&"),
+        None => String::new(),
     }
+}
 
-    /// 处理压缩内容并更新解压缩缓冲区
-    fn process_compressed_data(&mut self, data: &[u8]) -> Result<()> {
-        if !self.needs_decompression() {
-            self.accumulate_body(data);
-            return Ok(());
+/// 从流中读取HTTP头直到找到空行
+///
+/// # 参数
+/// * `stream` - 输入流
+/// * `buffer` - 读取缓冲区，可以预先包含已读取的数据
+///
+/// # 返回值
+/// 返回HTTP头结束位置（含空行），对端在发送完整HTTP头前关闭连接时返回None
+async fn read_http_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<usize>> {
+    let mut temp_buffer = [0; 4096];
+
+    loop {
+        // 检查是否找到HTTP头的结束标记 \r\n\r\n
+        if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            return Ok(Some(pos + 4));
         }
 
-        match self.content_encoding.as_deref() {
-            Some("gzip") => {
-                // 解压缩gzip数据
-                let decompressed = self.decompress_gzip(data)?;
-                let decompressed_len = decompressed.len();
-                self.accumulate_body(&decompressed);
-                log::info!("Decompressed {} bytes to {} bytes", data.len(), decompressed_len);
-            },
-            Some("deflate") => {
-                // TODO: 实现deflate解压缩
-                log::warn!("Deflate decompression not yet implemented");
-                self.accumulate_body(data);
-            },
-            _ => {
-                // 非压缩数据，直接添加
-                self.accumulate_body(data);
-            }
+        // 防止读取过多数据
+        if buffer.len() > MAX_REQUEST_HEADER_SIZE {
+            log::warn!("HTTP request header too large");
+            return Ok(None);
         }
-        Ok(())
+
+        let bytes_read = stream.read(&mut temp_buffer).await?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&temp_buffer[..bytes_read]);
     }
+}
 
-    /// 处理压缩数据（静态方法，避免借用冲突）
-    fn process_compressed_data_static(
-        content_encoding: &Option<String>,
-        decompressed_body: &mut Vec<u8>,
-        data: &[u8],
-        response_body_limit: i64,
-    ) -> Result<()> {
-        if !matches!(content_encoding.as_deref(), Some("gzip") | Some("deflate")) {
-            // 非压缩数据，直接添加
-            if response_body_limit == 0 {
-                return Ok(());
-            }
-            if response_body_limit < 0 {
-                decompressed_body.extend(data);
-            } else {
-                let remain = response_body_limit as usize - decompressed_body.len();
-                if remain > 0 {
-                    let to_copy = remain.min(data.len());
-                    decompressed_body.extend(&data[..to_copy]);
-                }
+/// 在请求头超时内读取客户端的请求头
+///
+/// 超时前没有收到任何数据的空闲连接按客户端关闭处理；已收到部分请求头时向客户端返回408，
+/// 并返回超时错误。触发关闭时，还没有收到新请求的连接同样按关闭处理。
+///
+/// # 参数
+/// * `stream` - 客户端连接
+/// * `buffer` - 读取缓冲区，可以预先包含已读取的数据
+/// * `limit` - 请求头超时时间
+/// * `shutdown` - 关闭句柄
+///
+/// # 返回值
+/// 与 [`read_http_head`] 相同
+async fn read_request_head<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    limit: Option<Duration>,
+    shutdown: &ShutdownHandle,
+) -> Result<Option<usize>> {
+    let read = async {
+        // 等待新请求期间可以因为关闭而放弃，收到数据后读完整个请求头
+        if buffer.is_empty() {
+            let mut chunk = [0; 4096];
+            let bytes_read = tokio::select! {
+                result = stream.read(&mut chunk) => result?,
+                _ = shutdown.wait() => return Ok(None),
+            };
+            if bytes_read == 0 {
+                return Ok(None);
             }
-            return Ok(());
+            buffer.extend_from_slice(&chunk[..bytes_read]);
         }
+        read_http_head(stream, buffer).await
+    };
+    match with_timeout(TimeoutPhase::ClientHeader, limit, read).await {
+        Err(e) if timeout_phase(&e).is_some() => {
+            if buffer.is_empty() {
+                log::debug!("Closing idle client connection: {e}");
+                return Ok(None);
+            }
+            let _ = stream.write_all(REQUEST_TIMEOUT_RESPONSE).await;
+            let _ = stream.shutdown().await;
+            Err(e)
+        },
+        result => result,
+    }
+}
 
-        match content_encoding.as_deref() {
-            Some("gzip") => {
-                // 解压缩gzip数据
-                let mut decoder = GzDecoder::new(data);
-                let mut decompressed = Vec::new();
-                decoder.read_to_end(&mut decompressed)?;
-                let decompressed_len = decompressed.len();
-                if response_body_limit == 0 {
-                    // 不记录
-                } else if response_body_limit < 0 {
-                    decompressed_body.extend(decompressed);
-                } else {
-                    let remain = response_body_limit as usize - decompressed_body.len();
-                    if remain > 0 {
-                        let to_copy = remain.min(decompressed.len());
-                        decompressed_body.extend(&decompressed[..to_copy]);
-                    }
-                }
-                log::info!("Decompressed {} bytes to {} bytes", data.len(), decompressed_len);
-            },
-            Some("deflate") => {
-                // TODO: 实现deflate解压缩
-                log::warn!("Deflate decompression not yet implemented");
-                if response_body_limit == 0 {
-                } else if response_body_limit < 0 {
-                    decompressed_body.extend_from_slice(data);
-                } else {
-                    let remain = response_body_limit as usize - decompressed_body.len();
-                    if remain > 0 {
-                        let to_copy = remain.min(data.len());
-                        decompressed_body.extend_from_slice(&data[..to_copy]);
-                    }
-                }
-            },
-            _ => {
-                // 非压缩数据，直接添加
-                if response_body_limit == 0 {
-                } else if response_body_limit < 0 {
-                    decompressed_body.extend_from_slice(data);
-                } else {
-                    let remain = response_body_limit as usize - decompressed_body.len();
-                    if remain > 0 {
-                        let to_copy = remain.min(data.len());
-                        decompressed_body.extend_from_slice(&data[..to_copy]);
-                    }
-                }
+/// 读取上游响应并转发给客户端
+///
+/// 上游读取出错按连接关闭处理，由调用方根据处理器状态判断响应是否完整。
+/// 等待第一个字节超时时返回超时错误，此时还没有向客户端发送任何数据。
+///
+/// # 参数
+/// * `server_stream` - 已发送完请求的上游连接
+/// * `client_stream` - 客户端连接
+/// * `response_processor` - 响应处理器
+/// * `first_byte_timeout` - 等待上游第一个字节的超时时间
+///
+/// # 返回值
+/// 返回响应处理器和从上游读取的字节数
+async fn relay_response<S, C>(
+    server_stream: &mut S,
+    client_stream: &mut C,
+    mut response_processor: HttpResponseProcessor,
+    first_byte_timeout: Option<Duration>,
+) -> Result<(HttpResponseProcessor, usize)>
+where
+    S: AsyncRead + Unpin,
+    C: AsyncWrite + Unpin,
+{
+    let mut buffer = [0; 4096];
+    let mut total_bytes = 0;
+
+    loop {
+        let read = server_stream.read(&mut buffer);
+        let read_result = match total_bytes {
+            0 => with_timeout(TimeoutPhase::FirstByte, first_byte_timeout, read).await,
+            _ => read.await.map_err(Into::into),
+        };
+        let bytes_read = match read_result {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if timeout_phase(&e).is_some() => return Err(e),
+            Err(e) => {
+                log::warn!("Error reading upstream response: {e}");
+                break;
             }
+        };
+        total_bytes += bytes_read;
+
+        // 使用响应处理器处理数据块
+        match response_processor.process_chunk(&buffer[..bytes_read], client_stream).await? {
+            ProcessingResult::Continue => continue,
+            ProcessingResult::Complete => break,
         }
-        Ok(())
     }
 
-    /// 获取解压缩后的响应体
-    fn get_decompressed_body(&self) -> String {
-        String::from_utf8_lossy(&self.decompressed_body).to_string()
+    // 读到连接关闭为止的响应体在连接关闭后才完整
+    response_processor.flush_pending(client_stream).await?;
+    client_stream.flush().await?;
+    response_processor.finish();
+    Ok((response_processor, total_bytes))
+}
+
+/// 检查空闲的上游连接是否仍然可用
+///
+/// 空闲连接上不应有可读数据，读到EOF或数据都说明连接不能再复用。
+async fn is_connection_alive<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut probe = [0; 1];
+    tokio::time::timeout(std::time::Duration::ZERO, stream.read(&mut probe)).await.is_err()
+}
+
+/// 处理连接时共享的组件
+#[derive(Clone)]
+pub(crate) struct ProxyContext {
+    /// 配置信息
+    pub config: Arc<Config>,
+    /// 证书管理器
+    pub cert_manager: Arc<CertManager>,
+    /// 上游连接池
+    pub pool: Arc<UpstreamPool>,
+    /// 上游TLS连接器
+    pub tls_connectors: Arc<TlsConnectors>,
+    /// 日志记录器
+    pub logger: Arc<DomainLogger>,
+    /// 网络模拟器
+    pub conditioner: Arc<NetworkConditioner>,
+    /// 拦截器链（自定义拦截器之后是断点）
+    pub interceptors: Arc<InterceptorChain>,
+    /// 关闭句柄
+    pub shutdown: ShutdownHandle,
+    /// 固定连接到指定IP的主机（透明代理连接的原始目标）
+    pub pinned_host: Option<(String, IpAddr)>,
+}
+
+impl ProxyContext {
+    /// 获取连接主机时固定使用的IP
+    ///
+    /// # 参数
+    /// * `host` - 目标主机
+    ///
+    /// # 返回值
+    /// 主机被固定到原始目标时返回其IP，否则返回None（按DNS解析）
+    fn pinned_ip(&self, host: &str) -> Option<IpAddr> {
+        self.pinned_host.as_ref()
+            .filter(|(pinned, _)| pinned.eq_ignore_ascii_case(host.trim_end_matches('.')))
+            .map(|(_, ip)| *ip)
     }
 }
 
 /// 代理服务器主结构体
 pub struct ProxyServer {
+    /// 处理连接时共享的组件
+    context: Arc<ProxyContext>,
+    /// 断点管理器
+    breakpoints: Arc<Breakpoints>,
+}
+
+/// 代理服务器构建器，用于在创建代理服务器时添加拦截器
+pub struct ProxyServerBuilder {
     /// 配置信息
-    config: Arc<Config>,
-    /// 证书管理器
-    cert_manager: Arc<CertManager>,
-    /// 日志记录器
-    logger: Arc<DomainLogger>,
+    config: Config,
+    /// 按调用顺序排列的拦截器
+    interceptors: Vec<Arc<dyn Interceptor>>,
 }
 
-impl ProxyServer {
-    /// 创建新的代理服务器实例
-    /// 
+impl ProxyServerBuilder {
+    /// 在拦截器链末尾添加拦截器
+    ///
+    /// 拦截器只处理 [`Interceptor::intercepts_host`] 返回true的主机，这些HTTPS主机与客户端只协商HTTP/1.1，
+    /// 其他主机仍可使用HTTP/2。
+    ///
     /// # 参数
-    /// * `config` - 配置信息
-    /// 
+    /// * `interceptor` - 拦截器
+    pub fn interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// 创建代理服务器
+    ///
     /// # 返回值
     /// 返回Result包装的ProxyServer实例，如果过程中出现错误则返回错误信息
-    pub fn new(config: Config) -> Result<Self> {
+    pub fn build(self) -> Result<ProxyServer> {
+        let config = self.config;
         let cert_manager = CertManager::new(
             &config.certificates.ca_cert,
             &config.certificates.ca_key,
             &config.certificates.name,
         )?;
+        for rule in &config.map_remote.rules {
+            UpstreamTarget::from_base_url(&rule.to)
+                .map_err(|e| anyhow::anyhow!("Invalid Map Remote rule: {e}"))?;
+        }
+        let tls_connectors = Arc::new(TlsConnectors::new(&config)?);
 
-        let logger = DomainLogger::new(Arc::new(config.clone()));
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let pool = Arc::new(ConnectionPool::new(config.upstream_pool.clone()));
+        let conditioner = Arc::new(NetworkConditioner::new(Arc::clone(&config))?);
+        let breakpoints = Arc::new(Breakpoints::new(Arc::clone(&config)));
+        // 拦截器顺序：用户拦截器、脚本、断点
+        let mut interceptors = self.interceptors;
+        if !interceptors.is_empty() {
+            log::info!("🧩 {} interceptor(s) installed, HTTP/2 is disabled for the hosts they intercept", interceptors.len());
+        }
+        interceptors.extend(load_scripts(&config)?);
+        interceptors.push(Arc::clone(&breakpoints) as Arc<dyn Interceptor>);
 
-        Ok(Self {
-            config: Arc::new(config),
-            cert_manager: Arc::new(cert_manager),
-            logger,
+        Ok(ProxyServer {
+            context: Arc::new(ProxyContext {
+                config,
+                cert_manager: Arc::new(cert_manager),
+                pool,
+                tls_connectors,
+                logger,
+                conditioner,
+                interceptors: Arc::new(InterceptorChain::new(interceptors)),
+                shutdown: ShutdownHandle::new(),
+                pinned_host: None,
+            }),
+            breakpoints,
         })
     }
+}
+
+impl ProxyServer {
+    /// 创建新的代理服务器实例
+    /// 
+    /// # 参数
+    /// * `config` - 配置信息
+    /// 
+    /// # 返回值
+    /// 返回Result包装的ProxyServer实例，如果过程中出现错误则返回错误信息
+    pub fn new(config: Config) -> Result<Self> {
+        Self::builder(config).build()
+    }
+
+    /// 创建代理服务器构建器
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    pub fn builder(config: Config) -> ProxyServerBuilder {
+        ProxyServerBuilder {
+            config,
+            interceptors: Vec::new(),
+        }
+    }
 
-    /// 运行代理服务器
+    /// 获取关闭句柄
+    ///
+    /// 触发关闭后 [`ProxyServer::run`] 停止接受新连接，在配置的等待时间内等待正在处理的连接结束，
+    /// 然后把所有日志写入文件后返回。
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.context.shutdown.clone()
+    }
+
+    /// 运行代理服务器，直到通过关闭句柄触发关闭
     /// 
     /// # 返回值
     /// 返回Result，如果过程中出现错误则返回错误信息
     pub async fn run(self) -> Result<()> {
-        let addr = SocketAddr::new(
-            self.config.proxy.host.parse().unwrap(),
-            self.config.proxy.port,
-        );
+        // 监听地址可以是IPv6（::为双栈）或解析为多个地址的主机名
+        let listeners = bind_listeners(&self.context.config.proxy.host, self.context.config.proxy.port).await?;
+        for listener in &listeners {
+            log::info!("Proxy server listening on {}", listener.local_addr()?);
+        }
 
-        let listener = TcpListener::bind(addr).await?;
-        log::info!("Proxy server listening on {addr}");
+        // 可选的SOCKS5监听，与HTTP代理共用拦截和隧道处理逻辑
+        if self.context.config.socks5.enabled {
+            let socks_config = &self.context.config.socks5;
+            for socks_listener in bind_listeners(&socks_config.host, socks_config.port).await? {
+                log::info!("SOCKS5 proxy listening on {}", socks_listener.local_addr()?);
+                tokio::spawn(run_socks5_listener(
+                    socks_listener,
+                    Arc::clone(&self.context),
+                ));
+            }
+        }
 
-        loop {
-            let (stream, peer_addr) = listener.accept().await?;
-            log::info!("New connection from {peer_addr}");
+        // 可选的透明代理监听，接收iptables/nftables重定向的连接
+        if self.context.config.transparent.enabled {
+            match default_lookup() {
+                Some(lookup) => {
+                    let transparent_config = &self.context.config.transparent;
+                    for transparent_listener in bind_listeners(&transparent_config.host, transparent_config.port).await? {
+                        log::info!("Transparent proxy listening on {}", transparent_listener.local_addr()?);
+                        tokio::spawn(run_transparent_listener(
+                            transparent_listener,
+                            Arc::clone(&lookup),
+                            Arc::clone(&self.context),
+                        ));
+                    }
+                },
+                None => log::warn!("Transparent proxy mode is only supported on Linux"),
+            }
+        }
 
-            let config = Arc::clone(&self.config);
-            let cert_manager = Arc::clone(&self.cert_manager);
-            let logger = self.logger.clone();
+        // 可选的反向代理监听，把请求转发到固定的上游服务
+        if self.context.config.reverse_proxy.enabled {
+            let reverse_config = &self.context.config.reverse_proxy;
+            let target = UpstreamTarget::from_base_url(&reverse_config.upstream)?;
+            let tls_acceptor = match reverse_config.tls {
+                true => Some(create_tls_acceptor(&self.context.cert_manager, &reverse_config.tls_hostname)?),
+                false => None,
+            };
+            for reverse_listener in bind_listeners(&reverse_config.host, reverse_config.port).await? {
+                log::info!(
+                    "Reverse proxy listening on {} -> {}",
+                    reverse_listener.local_addr()?,
+                    reverse_config.upstream
+                );
+                tokio::spawn(run_reverse_proxy_listener(
+                    reverse_listener,
+                    target.clone(),
+                    tls_acceptor.clone(),
+                    Arc::clone(&self.context),
+                ));
+            }
+        }
 
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, config, cert_manager, logger).await {
-                    log::error!("Connection error: {e}");
+        // 可选的控制接口，用于运行时切换网络模拟等设置
+        if self.context.config.control.enabled {
+            let control_listeners = bind_listeners(&self.context.config.control.host, self.context.config.control.port).await?;
+            // 控制接口可以修改和放行暂停的流量，只有设置了令牌才允许监听非回环地址
+            for control_listener in &control_listeners {
+                let addr = control_listener.local_addr()?;
+                if !addr.ip().is_loopback() && self.context.config.control.token.is_none() {
+                    anyhow::bail!("Control API on non-loopback address {addr} requires control.token");
                 }
-            });
+            }
+            for control_listener in control_listeners {
+                log::info!("Control API listening on {}", control_listener.local_addr()?);
+                tokio::spawn(run_control_listener(
+                    control_listener,
+                    self.context.config.control.token.clone(),
+                    Arc::clone(&self.context.conditioner),
+                    Arc::clone(&self.breakpoints),
+                    self.context.shutdown.clone(),
+                ));
+            }
+        }
+
+        let tasks: Vec<_> = listeners.into_iter()
+            .map(|listener| tokio::spawn(run_proxy_listener(
+                listener,
+                Arc::clone(&self.context),
+            )))
+            .collect();
+        for task in tasks {
+            task.await?;
+        }
+
+        // 监听已经停止，等待正在处理的连接结束后写入剩余的日志
+        let grace = Duration::from_secs(self.context.config.proxy.shutdown_grace_secs);
+        let active = self.context.shutdown.active_connections();
+        if active > 0 {
+            log::info!("⏳ Waiting up to {}s for {active} active connection(s) to finish...", grace.as_secs());
         }
+        if !self.context.shutdown.drain(grace).await {
+            log::warn!(
+                "Closing {} connection(s) still active after {}s",
+                self.context.shutdown.active_connections(),
+                grace.as_secs()
+            );
+        }
+        self.context.logger.flush().await;
+        log::info!("📝 Domain logs flushed");
+        Ok(())
     }
 }
 
+/// 接受HTTP代理连接
+async fn run_proxy_listener(
+    listener: TcpListener,
+    context: Arc<ProxyContext>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.wait() => break,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Proxy accept error: {e}");
+                continue;
+            }
+        };
+        log::info!("New connection from {peer_addr}");
 
-/// 记录请求开始日志
-fn log_request_start(method: &str, path: &str, host: Option<&str>) {
-    log::info!("🔍 REQUEST START ========================================");
-    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
-    log::info!("📝 Method: {method}");
-    log::info!("🔗 Path: {path}");
-    if let Some(host) = host {
-        log::info!("🌐 Host: {host}");
+        let context = Arc::clone(&context);
+        let connection = context.shutdown.track();
+
+        tokio::spawn(async move {
+            let _connection = connection;
+            if let Err(e) = handle_connection(stream, context).await {
+                log::error!("Connection error: {e}");
+            }
+        });
     }
 }
 
+/// 接受SOCKS5连接
+async fn run_socks5_listener(
+    listener: TcpListener,
+    context: Arc<ProxyContext>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.wait() => break,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("SOCKS5 accept error: {e}");
+                continue;
+            }
+        };
+        log::info!("New SOCKS5 connection from {peer_addr}");
 
+        let context = Arc::clone(&context);
+        let connection = context.shutdown.track();
 
-/// 处理TCP连接
-/// 
-/// # 参数
-/// * `stream` - TCP流
-/// * `config` - 配置信息
-/// * `cert_manager` - 证书管理器
-/// * `logger` - 日志记录器
-/// 
-/// # 返回值
-/// 返回Result，如果过程中出现错误则返回错误信息
-async fn handle_connection(
-    mut stream: TcpStream,
-    config: Arc<Config>,
-    cert_manager: Arc<CertManager>,
-    logger: Arc<DomainLogger>,
+        tokio::spawn(async move {
+            let _connection = connection;
+            if let Err(e) = handle_socks5_connection(stream, context).await {
+                log::error!("SOCKS5 connection error: {e}");
+            }
+        });
+    }
+}
+
+/// 处理SOCKS5连接
+///
+/// 握手完成后按SOCKS目标主机和端口进入与CONNECT相同的拦截或隧道逻辑。
+async fn handle_socks5_connection(
+    mut client_stream: TcpStream,
+    context: Arc<ProxyContext>,
 ) -> Result<()> {
-    let mut buffer = Vec::new();
-    let mut temp_buffer = [0; 1024];
-    
-    // 读取HTTP头直到找到空行
-    loop {
-        let bytes_read = stream.read(&mut temp_buffer).await?;
-        if bytes_read == 0 {
-            return Ok(());
-        }
-        
-        buffer.extend_from_slice(&temp_buffer[..bytes_read]);
-        
-        // 检查是否找到HTTP头的结束标记 \r\n\r\n
-        if buffer.windows(4).any(|window| window == b"\r\n\r\n") {
-            break;
-        }
-        
-        // 防止读取过多数据
-        if buffer.len() > 8192 {
-            log::warn!("HTTP header too large");
-            return Ok(());
-        }
+    let start_time = Instant::now();
+    let handshake = accept_socks5(&mut client_stream, &context.config.socks5);
+    let (host, port) = with_timeout(TimeoutPhase::ClientHeader, context.config.timeouts.client_header(), handshake).await?;
+
+    log::info!("🧦 SOCKS5 CONNECT ========================================");
+    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
+    log::info!("🎯 Target: {host}:{port}");
+    log::info!("🔍 Intercept: {}", context.config.should_intercept(&host, port));
+
+    let mut log_entry = DomainLogger::create_tunnel_log_entry(host.clone(), start_time.elapsed().as_millis(), None);
+    log_entry.method = "SOCKS5".to_string();
+    context.logger.log_request(log_entry);
+
+    // 拦截器拒绝时应答“规则不允许连接”，不先应答成功再断开
+    if let Some(by) = context.interceptors.on_connect(&host, port).await {
+        log::warn!("⛔ Connection to {host}:{port} dropped by {by}");
+        reply_socks5(&mut client_stream, false).await?;
+        return Ok(());
+    }
+    reply_socks5(&mut client_stream, true).await?;
+    handle_tunnel_target(host, port, client_stream, context, start_time).await
+}
+
+/// 接受透明代理连接
+///
+/// # 参数
+/// * `listener` - 透明代理监听
+/// * `lookup` - 原始目标地址查询方式
+/// * `context` - 共享的组件
+async fn run_transparent_listener(
+    listener: TcpListener,
+    lookup: Arc<dyn OriginalDestination>,
+    context: Arc<ProxyContext>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.wait() => break,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Transparent proxy accept error: {e}");
+                continue;
+            }
+        };
+        log::info!("New transparent connection from {peer_addr}");
+
+        let lookup = Arc::clone(&lookup);
+        let context = Arc::clone(&context);
+        let connection = context.shutdown.track();
+
+        tokio::spawn(async move {
+            let _connection = connection;
+            if let Err(e) = handle_transparent_connection(stream, lookup, context).await {
+                log::error!("Transparent connection error: {e}");
+            }
+        });
+    }
+}
+
+/// 处理透明代理连接
+///
+/// 查看客户端首批数据区分TLS和明文HTTP：TLS使用SNI作为主机名进入与CONNECT相同的拦截逻辑，
+/// 需要拦截的HTTP请求按普通代理请求处理，其他流量直接建立隧道。
+async fn handle_transparent_connection(
+    client_stream: TcpStream,
+    lookup: Arc<dyn OriginalDestination>,
+    context: Arc<ProxyContext>,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let original_destination = lookup.lookup(&client_stream)
+        .map_err(|e| anyhow::anyhow!("Failed to get original destination: {e}"))?;
+
+    let initial_data = peek_initial_data(&client_stream).await?;
+    let kind = classify(&initial_data);
+    let host = match kind {
+        StreamKind::Tls => parse_sni(&initial_data),
+        StreamKind::Http => parse_http_host(&initial_data),
+        StreamKind::Unknown => None,
+    }.unwrap_or_else(|| original_destination.ip().to_string());
+    let port = original_destination.port();
+
+    log::info!("🪞 TRANSPARENT CONNECTION ================================");
+    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
+    log::info!("🎯 Target: {host}:{port} ({original_destination}, {kind:?})");
+    log::info!("🔍 Intercept: {}", context.config.should_intercept(&host, port));
+
+    // 上游连接客户端原本访问的地址，主机名只用于SNI、Host头和拦截判断，
+    // 避免重新解析到其他服务器或再次被重定向回代理
+    let context = Arc::new(ProxyContext {
+        pinned_host: Some((host.trim_end_matches('.').to_string(), original_destination.ip())),
+        ..ProxyContext::clone(&context)
+    });
+
+    let mut log_entry = DomainLogger::create_tunnel_log_entry(host.clone(), start_time.elapsed().as_millis(), None);
+    log_entry.method = "TRANSPARENT".to_string();
+    context.logger.log_request(log_entry);
+
+    if let Some(by) = context.interceptors.on_connect(&host, port).await {
+        log::warn!("⛔ Connection to {host}:{port} dropped by {by}");
+        return Ok(());
+    }
+    if !context.config.should_intercept(&host, port) {
+        return direct_tunnel(host, port, client_stream, context, start_time).await;
+    }
+    intercept_stream(host, port, client_stream, kind, context).await
+}
+
+/// 接受反向代理连接
+///
+/// # 参数
+/// * `listener` - 反向代理监听
+/// * `target` - 上游目标
+/// * `tls_acceptor` - 终止TLS时使用的接受器
+/// * `context` - 共享的组件
+async fn run_reverse_proxy_listener(
+    listener: TcpListener,
+    target: UpstreamTarget,
+    tls_acceptor: Option<TlsAcceptor>,
+    context: Arc<ProxyContext>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.wait() => break,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Reverse proxy accept error: {e}");
+                continue;
+            }
+        };
+        log::info!("New reverse proxy connection from {peer_addr}");
+
+        let target = target.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let context = Arc::clone(&context);
+        let connection = context.shutdown.track();
+
+        tokio::spawn(async move {
+            let _connection = connection;
+            if let Some(by) = context.interceptors.on_connect(&target.host, target.port).await {
+                log::warn!("⛔ Reverse proxy connection from {peer_addr} dropped by {by}");
+                return;
+            }
+            let stream = context.conditioner.wrap(&target.host, stream);
+            let result = match tls_acceptor {
+                Some(acceptor) => match with_timeout(TimeoutPhase::TlsHandshake, context.config.timeouts.tls_handshake(), acceptor.accept(stream)).await {
+                    Ok(tls_stream) => serve_http1(tls_stream, None, target, context).await,
+                    Err(e) => Err(anyhow::anyhow!("TLS handshake failed: {e}")),
+                },
+                None => serve_http1(stream, None, target, context).await,
+            };
+            if let Err(e) = result {
+                log::error!("Reverse proxy connection error: {e}");
+            }
+        });
+    }
+}
+
+/// 使用CA签发的证书创建TLS接受器
+///
+/// # 参数
+/// * `cert_manager` - 证书管理器
+/// * `hostname` - 证书主机名
+pub(crate) fn create_tls_acceptor(cert_manager: &CertManager, hostname: &str) -> Result<TlsAcceptor> {
+    let (cert_pem, key_pem) = cert_manager.generate_site_cert(hostname)?;
+    let mut tls_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certificates(&cert_pem), load_private_key(&key_pem))?;
+    tls_config.alpn_protocols = vec![ALPN_HTTP11.to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// 生成上游请求失败时返回给客户端的响应
+///
+/// 超时返回504，其他错误返回502。
+///
+/// # 参数
+/// * `host` - 上游主机
+/// * `port` - 上游端口
+/// * `error` - 上游请求失败的原因
+///
+/// # 返回值
+/// 返回状态码和完整的响应
+fn upstream_error_response(host: &str, port: u16, error: &anyhow::Error) -> (u16, Vec<u8>) {
+    match timeout_phase(error) {
+        Some(_) => (504, gateway_timeout_response(host, port, error)),
+        None => (502, bad_gateway_response(host, port, error)),
+    }
+}
+
+/// 生成上游不可用时返回给客户端的502响应
+///
+/// # 参数
+/// * `host` - 上游主机
+/// * `port` - 上游端口
+/// * `error` - 连接上游失败的原因
+fn bad_gateway_response(host: &str, port: u16, error: &anyhow::Error) -> Vec<u8> {
+    let message = format!("The proxy could not connect to <b>{}</b>.", escape_html(&format_authority(host, port)));
+    error_page("502 Bad Gateway", &message, error)
+}
+
+/// 生成上游超时时返回给客户端的504响应
+///
+/// # 参数
+/// * `host` - 上游主机
+/// * `port` - 上游端口
+/// * `error` - 超时的原因
+fn gateway_timeout_response(host: &str, port: u16, error: &anyhow::Error) -> Vec<u8> {
+    let message = format!("The proxy did not get a timely response from <b>{}</b>.", escape_html(&format_authority(host, port)));
+    error_page("504 Gateway Timeout", &message, error)
+}
+
+/// 生成代理自身返回的HTML错误页面
+///
+/// # 参数
+/// * `status` - 状态行中的状态码和原因短语
+/// * `message` - 已转义的说明
+/// * `error` - 错误原因
+fn error_page(status: &str, message: &str, error: &anyhow::Error) -> Vec<u8> {
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{status}</title></head>\n<body>\n\
+         <h1>{status}</h1>\n<p>{message}</p>\n\
+         <pre>{}</pre>\n</body>\n</html>\n",
+        escape_html(&error.to_string()),
+    );
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    ).into_bytes()
+}
+
+/// 转义HTML特殊字符
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 注入不需要转发到上游的故障
+///
+/// status故障向客户端返回指定的响应；reset故障不返回任何数据，把SO_LINGER设为0，
+/// 调用方关闭客户端连接时发送RST。
+///
+/// # 参数
+/// * `fault` - 注入的故障
+/// * `client_stream` - 客户端连接
+///
+/// # 返回值
+/// 返回记录日志用的状态码、响应体和错误，故障需要转发到上游时返回None
+async fn inject_terminal_fault<W: AsyncWrite + ClientSocket + Unpin>(
+    fault: &Fault,
+    client_stream: &mut W,
+) -> Result<Option<(u16, String, Option<String>)>> {
+    match fault {
+        Fault::Status { status, body } => {
+            let reason = http::StatusCode::from_u16(*status).ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or("");
+            let response = format!(
+                "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len(),
+            );
+            client_stream.write_all(response.as_bytes()).await?;
+            client_stream.flush().await?;
+            Ok(Some((*status, body.clone(), None)))
+        },
+        Fault::Reset => {
+            client_stream.reset_on_close()?;
+            Ok(Some((0, String::new(), Some("Connection reset by fault injection".to_string()))))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// 上游不可用时读取客户端的第一个请求，返回502（超时为504）页面并记录日志
+///
+/// # 参数
+/// * `client_stream` - 已完成TLS握手的客户端连接
+/// * `host` - 上游主机
+/// * `port` - 上游端口
+/// * `error` - 连接上游失败的原因
+/// * `config` - 配置信息
+/// * `logger` - 日志记录器
+/// * `shutdown` - 关闭句柄
+async fn reject_with_bad_gateway<C>(
+    mut client_stream: C,
+    host: &str,
+    port: u16,
+    error: anyhow::Error,
+    config: &Config,
+    logger: &DomainLogger,
+    shutdown: &ShutdownHandle,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let start_time = Instant::now();
+    let mut buffer = Vec::new();
+    let Ok(Some(header_end)) = read_request_head(&mut client_stream, &mut buffer, config.timeouts.client_header(), shutdown).await else {
+        return Err(error);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = request_line.next().unwrap_or("/").to_string();
+    let request_headers: HashMap<String, String> = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let (status_code, response) = upstream_error_response(host, port, &error);
+    client_stream.write_all(&response).await?;
+    let _ = client_stream.shutdown().await;
+
+    let mut log_entry = DomainLogger::create_log_entry(
+        host.to_string(),
+        method,
+        format!("https://{}{path}", format_authority(host, port)),
+        request_headers,
+        HashMap::new(),
+        status_code,
+        String::new(),
+        String::new(),
+        parse_url_params(&path),
+        start_time.elapsed().as_millis(),
+        Some(error.to_string()),
+    );
+    log_entry.client_identity = upstream_tls::client_identity(config, host);
+    logger.log_request(log_entry);
+    Ok(())
+}
+
+/// 记录请求开始日志
+fn log_request_start(method: &str, path: &str, host: Option<&str>) {
+    log::info!("🔍 REQUEST START ========================================");
+    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
+    log::info!("📝 Method: {method}");
+    log::info!("🔗 Path: {path}");
+    if let Some(host) = host {
+        log::info!("🌐 Host: {host}");
     }
+}
+
+
+
+/// 处理TCP连接
+/// 
+/// # 参数
+/// * `stream` - TCP流
+/// * `context` - 共享的组件
+/// 
+/// # 返回值
+/// 返回Result，如果过程中出现错误则返回错误信息
+async fn handle_connection(
+    mut stream: TcpStream,
+    context: Arc<ProxyContext>,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    
+    // 读取HTTP头直到找到空行
+    let Some(header_end) = read_request_head(&mut stream, &mut buffer, context.config.timeouts.client_header(), &context.shutdown).await? else {
+        return Ok(());
+    };
 
-    let request_str = String::from_utf8_lossy(&buffer);
+    let request_str = String::from_utf8_lossy(&buffer[..header_end]).to_string();
     let lines: Vec<&str> = request_str.lines().collect();
     
     if lines.is_empty() {
@@ -802,15 +1931,15 @@ async fn handle_connection(
     
     // 记录完整的原始请求
     log::info!("📝 RAW REQUEST:");
-    log::info!("{}", String::from_utf8_lossy(&buffer));
+    log::info!("{request_str}");
 
     // 根据HTTP方法处理不同类型的请求
     match method {
         "CONNECT" => {
-            handle_https_connect(path, stream, config, cert_manager, logger).await?;
+            handle_https_connect(path, stream, context).await?;
         },
         _ => {
-            handle_http_request(request_str.to_string(), stream, config, logger).await?;
+            handle_http_request(request_str.clone(), buffer[header_end..].to_vec(), stream, context).await?;
         }
     }
 
@@ -822,19 +1951,21 @@ async fn handle_connection(
 async fn handle_https_connect(
     path: &str,
     mut client_stream: TcpStream,
-    config: Arc<Config>,
-    cert_manager: Arc<CertManager>,
-    logger: Arc<DomainLogger>,
+    context: Arc<ProxyContext>,
 ) -> Result<()> {
     let start_time = Instant::now();
-    let parts: Vec<&str> = path.split(':').collect();
-    let host = parts[0].to_string();
-    let port = parts.get(1).unwrap_or(&"443").parse::<u16>().unwrap_or(443);
+    let (host, port) = match parse_authority(path, 443) {
+        Ok(target) => target,
+        Err(e) => {
+            client_stream.write_all(BAD_REQUEST_RESPONSE).await?;
+            return Err(e);
+        },
+    };
 
     log::info!("🔒 HTTPS CONNECT =========================================");
     log::info!("⏰ Timestamp: {:?}", SystemTime::now());
     log::info!("🎯 Target: {host}:{port}");
-    log::info!("🔍 Intercept: {}", config.should_intercept(&host, port));
+    log::info!("🔍 Intercept: {}", context.config.should_intercept(&host, port));
 
     // 记录CONNECT请求
     let duration_ms = start_time.elapsed().as_millis();
@@ -843,631 +1974,3519 @@ async fn handle_https_connect(
             duration_ms,
             None,
         );
-    logger.log_request(log_entry);
+    context.logger.log_request(log_entry);
 
-    if !config.should_intercept(&host, port) {
-        log::info!("🚇 DIRECT TUNNEL MODE ===================================");
-        
-        // 发送200 Connection Established
-        let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
-        client_stream.write_all(response.as_bytes()).await?;
-
-        // 建立直接隧道
-        log::info!("Connecting to target server: {host}:{port}");
-        let server_stream = TcpStream::connect(format!("{host}:{port}")).await?;
-        log::info!("Tunnel established successfully");
-        
-        let (client_bytes, server_bytes) = tunnel_connection_with_logging(client_stream, server_stream).await?;
-        let duration_ms = start_time.elapsed().as_millis();
-        log::info!("=== DIRECT TUNNEL CLOSED ===");
-        log::info!("Bytes transferred: client={client_bytes}, server={server_bytes}");
-        
-        // 使用新的DomainLogger记录隧道模式日志
-        let log_entry = DomainLogger::create_log_entry(
-            host.clone(),
-            "CONNECT".to_string(),
-            format!("{host}:{port}"),
-            HashMap::new(),
-            HashMap::new(),
-            200,
-            String::new(),
-            String::new(),
-            String::new(),
-            duration_ms,
-            None,
-        );
-        logger.log_request(log_entry);
+    // 拦截器在建立隧道之前检查目标，拒绝时客户端收到403而不是已建立的隧道
+    if let Some(by) = context.interceptors.on_connect(&host, port).await {
+        log::warn!("⛔ Connection to {host}:{port} dropped by {by}");
+        client_stream.write_all(FORBIDDEN_RESPONSE).await?;
         return Ok(());
     }
 
-    log::info!("=== INTERCEPT MODE ===");
-    log::info!("Intercepting HTTPS connection to {host}:{port}");
-    
     // 发送200 Connection Established
     let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
     client_stream.write_all(response.as_bytes()).await?;
 
+    handle_tunnel_target(host, port, client_stream, context, start_time).await
+}
+
+/// 处理已建立隧道的客户端连接（CONNECT或SOCKS5），调用前拦截器已经检查过目标
+///
+/// 目标需要拦截时查看客户端首批数据，按TLS、明文HTTP或原始TCP分别处理，否则直接建立隧道。
+///
+/// # 参数
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+/// * `client_stream` - 客户端连接
+/// * `context` - 共享的组件
+/// * `start_time` - 连接开始时间
+async fn handle_tunnel_target(
+    host: String,
+    port: u16,
+    client_stream: TcpStream,
+    context: Arc<ProxyContext>,
+    start_time: Instant,
+) -> Result<()> {
+    if !context.config.should_intercept(&host, port) {
+        return direct_tunnel(host, port, client_stream, context, start_time).await;
+    }
+
+    let initial_data = peek_initial_data(&client_stream).await?;
+    let kind = classify(&initial_data);
+    log::info!("Detected {kind:?} traffic to {host}:{port}");
+
+    intercept_stream(host, port, client_stream, kind, context).await
+}
+
+/// 按首批数据的协议类型拦截客户端连接
+///
+/// # 参数
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+/// * `client_stream` - 客户端连接
+/// * `kind` - 首批数据的协议类型
+/// * `context` - 共享的组件
+async fn intercept_stream(
+    host: String,
+    port: u16,
+    client_stream: TcpStream,
+    kind: StreamKind,
+    context: Arc<ProxyContext>,
+) -> Result<()> {
+    match kind {
+        StreamKind::Tls => intercept_tls(host, port, client_stream, context).await,
+        StreamKind::Http => {
+            log::info!("Processing plain HTTP requests to {host}:{port}...");
+            let target = UpstreamTarget {
+                scheme: "http",
+                host,
+                port,
+                base_path: String::new(),
+            };
+            let client_stream = context.conditioner.wrap(&target.host, client_stream);
+            serve_http1(client_stream, None, target, context).await
+        },
+        StreamKind::Unknown => relay_raw_tcp(host, port, client_stream, context).await,
+    }
+}
+
+/// 转发无法识别协议的TCP流，记录双向字节数和可选的十六进制转储
+async fn relay_raw_tcp(
+    host: String,
+    port: u16,
+    client_stream: TcpStream,
+    context: Arc<ProxyContext>,
+) -> Result<()> {
+    let start_time = Instant::now();
+    log::info!("🧱 RAW TCP RELAY ========================================");
+    log::info!("🎯 Target: {host}:{port}");
+
+    let server_stream = connect_tunnel(&context.config, &host, port, context.pinned_ip(&host)).await?;
+    let resolved_ip = upstream::resolved_ip(&context.config, &host, &server_stream);
+    let dump_limit = context.config.logging.domain_logs.raw_dump_limit;
+
+    let mut client_capture = BodyCapture::new(dump_limit);
+    let mut server_capture = BodyCapture::new(dump_limit);
+    let (client_bytes, server_bytes, error) = relay_bidirectional(
+        context.conditioner.wrap(&host, client_stream),
+        server_stream,
+        &mut client_capture,
+        &mut server_capture,
+        context.config.timeouts.tunnel_idle(),
+    ).await;
+
+    let duration_ms = start_time.elapsed().as_millis();
+    log::info!("=== RAW TCP RELAY CLOSED ===");
+    log::info!("Bytes transferred: client={client_bytes}, server={server_bytes}");
+
+    let mut log_entry = DomainLogger::create_log_entry(
+        host.clone(),
+        "TCP".to_string(),
+        format_authority(&host, port),
+        HashMap::new(),
+        HashMap::new(),
+        0,
+        hex_dump(&client_capture.into_bytes()),
+        hex_dump(&server_capture.into_bytes()),
+        String::new(),
+        duration_ms,
+        error,
+    );
+    log_entry.protocol = "TCP".to_string();
+    log_entry.client_bytes = client_bytes;
+    log_entry.server_bytes = server_bytes;
+    log_entry.resolved_ip = resolved_ip;
+    context.logger.log_request(log_entry);
+    Ok(())
+}
+
+/// 双向转发数据并记录，直到两个方向都结束或连接空闲超时
+///
+/// # 参数
+/// * `client_stream` - 客户端连接
+/// * `server_stream` - 上游连接
+/// * `client_capture` - 记录客户端发送的数据
+/// * `server_capture` - 记录上游发送的数据
+/// * `idle_timeout` - 隧道空闲超时时间
+///
+/// # 返回值
+/// 返回客户端和上游发送的字节数，以及转发出错或超时的原因
+async fn relay_bidirectional<C, S>(
+    client_stream: C,
+    server_stream: S,
+    client_capture: &mut BodyCapture,
+    server_capture: &mut BodyCapture,
+    idle_timeout: Option<Duration>,
+) -> (u64, u64, Option<String>)
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tracker = IdleTracker::new();
+    let (mut client_reader, mut client_writer) = tokio::io::split(tracker.wrap(client_stream));
+    let (mut server_reader, mut server_writer) = tokio::io::split(tracker.wrap(server_stream));
+
+    let relay = async {
+        tokio::join!(
+            copy_with_capture(&mut client_reader, &mut server_writer, client_capture),
+            copy_with_capture(&mut server_reader, &mut client_writer, server_capture),
+        )
+    };
+    match tracker.run(idle_timeout, relay).await {
+        Ok((client_result, server_result)) => {
+            let error = match (&client_result, &server_result) {
+                (Err(e), _) | (_, Err(e)) => Some(e.to_string()),
+                _ => None,
+            };
+            (client_result.unwrap_or_default(), server_result.unwrap_or_default(), error)
+        },
+        Err(e) => {
+            log::warn!("⏱️ {e}, closing tunnel");
+            (client_capture.raw_bytes as u64, server_capture.raw_bytes as u64, Some(e.to_string()))
+        },
+    }
+}
+
+/// 单方向复制数据并记录，读到EOF后关闭写方向
+async fn copy_with_capture<R, W>(reader: &mut R, writer: &mut W, capture: &mut BodyCapture) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = [0; 8192];
+    let mut total_bytes = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            let _ = writer.shutdown().await;
+            return Ok(total_bytes);
+        }
+        capture.feed(&buffer[..bytes_read]);
+        writer.write_all(&buffer[..bytes_read]).await?;
+        total_bytes += bytes_read as u64;
+    }
+}
+
+/// 生成十六进制转储（每行16字节，附带可打印字符）
+fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = chunk.iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            format!("\n    {:08x}  {:<47}  |{ascii}|", i * 16, hex.join(" "))
+        })
+        .collect()
+}
+
+/// 在客户端和目标之间直接建立隧道并记录日志
+async fn direct_tunnel(
+    host: String,
+    port: u16,
+    client_stream: TcpStream,
+    context: Arc<ProxyContext>,
+    start_time: Instant,
+) -> Result<()> {
+    log::info!("🚇 DIRECT TUNNEL MODE ===================================");
+
+    // 建立直接隧道
+    log::info!("Connecting to target server: {host}:{port}");
+    let server_stream = connect_tunnel(&context.config, &host, port, context.pinned_ip(&host)).await?;
+    let resolved_ip = upstream::resolved_ip(&context.config, &host, &server_stream);
+    log::info!("Tunnel established successfully");
+    
+    let (client_bytes, server_bytes, error) = relay_bidirectional(
+        context.conditioner.wrap(&host, client_stream),
+        server_stream,
+        &mut BodyCapture::new(0),
+        &mut BodyCapture::new(0),
+        context.config.timeouts.tunnel_idle(),
+    ).await;
+    let duration_ms = start_time.elapsed().as_millis();
+    log::info!("=== DIRECT TUNNEL CLOSED ===");
+    log::info!("Bytes transferred: client={client_bytes}, server={server_bytes}");
+    
+    // 使用新的DomainLogger记录隧道模式日志
+    let mut log_entry = DomainLogger::create_log_entry(
+        host.clone(),
+        "CONNECT".to_string(),
+        format_authority(&host, port),
+        HashMap::new(),
+        HashMap::new(),
+        200,
+        String::new(),
+        String::new(),
+        String::new(),
+        duration_ms,
+        error,
+    );
+    log_entry.client_bytes = client_bytes;
+    log_entry.server_bytes = server_bytes;
+    log_entry.resolved_ip = resolved_ip;
+    context.logger.log_request(log_entry);
+    Ok(())
+}
+
+/// 拦截TLS连接
+async fn intercept_tls(
+    host: String,
+    port: u16,
+    client_stream: TcpStream,
+    context: Arc<ProxyContext>,
+) -> Result<()> {
+    let config = &context.config;
+    log::info!("=== INTERCEPT MODE ===");
+    log::info!("Intercepting HTTPS connection to {host}:{port}");
+
     // 生成站点证书
-    let (cert_pem, key_pem) = cert_manager.generate_site_cert(&host)?;
+    let (cert_pem, key_pem) = context.cert_manager.generate_site_cert(&host)?;
     log::debug!("Generated site certificate for {host}");
 
     // 创建TLS配置
     let cert_chain = load_certificates(&cert_pem);
     let private_key = load_private_key(&key_pem);
     
-    let tls_config = ServerConfig::builder()
+    let mut tls_config = ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
         .with_single_cert(cert_chain, private_key)?;
 
+    // 先读取ClientHello，按客户端支持的ALPN协议与上游协商
+    let handshake_timeout = config.timeouts.tls_handshake();
+    let start_handshake = with_timeout(
+        TimeoutPhase::TlsHandshake,
+        handshake_timeout,
+        LazyConfigAcceptor::new(Acceptor::default(), context.conditioner.wrap(&host, client_stream)),
+    ).await?;
+    let client_alpn: Vec<Vec<u8>> = start_handshake.client_hello()
+        .alpn()
+        .map(|protocols| protocols.map(|p| p.to_vec()).collect())
+        .unwrap_or_default();
+    let offer_h2 = client_alpn.iter().any(|p| p.as_slice() == ALPN_H2);
+
+    let target = UpstreamTarget {
+        scheme: "https",
+        host: host.clone(),
+        port,
+        base_path: String::new(),
+    };
+
+    // 连接池只保存HTTP/1.1连接，有空闲连接说明上游之前没有协商h2，直接复用
+    let pooled = checkout_upstream(&context.pool, &target.origin()).await;
+
+    // 可能命中Map Local、Map Remote规则或被拦截器处理的主机不预先连接上游，由serve_http1按请求连接
+    let mapped = config.needs_http1_interception(&host) || context.interceptors.intercepts_host(&host);
+
+    let upstream_alpn = if offer_h2 { upstream_tls::OFFER_H2 } else { upstream_tls::OFFER_HTTP1 };
+    // 上游不可用时仍完成与客户端的握手，以便返回502页面
+    let connect_start = Instant::now();
+    let upstream = match pooled.is_some() || mapped {
+        true => Ok(None),
+        false => connect_upstream_tls(&context, &host, port, upstream_alpn).await.map(Some),
+    };
+    let connect_ms = connect_start.elapsed().as_millis();
+    let upstream_h2 = matches!(
+        &upstream,
+        Ok(Some(upstream)) if upstream.get_ref().negotiated_alpn().ok().flatten().as_deref() == Some(ALPN_H2)
+    );
+
+    // 客户端使用上游协商出的协议
+    if !client_alpn.is_empty() {
+        let protocol = if upstream_h2 { ALPN_H2 } else { ALPN_HTTP11 };
+        tls_config.alpn_protocols = vec![protocol.to_vec()];
+    }
+
     // 建立TLS连接
-    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
-    let mut tls_stream = match acceptor.accept(client_stream).await {
+    let tls_stream = match with_timeout(TimeoutPhase::TlsHandshake, handshake_timeout, start_handshake.into_stream(Arc::new(tls_config))).await {
         Ok(stream) => {
             log::info!("TLS handshake successful for {host}");
             stream
         },
         Err(e) => {
             log::error!("TLS handshake failed for {host}: {e}");
-            return Err(e.into());
+            return Err(e);
         }
     };
 
+    let server_connection = match upstream {
+        Err(e) => return reject_with_bad_gateway(tls_stream, &host, port, e, config, &context.logger, &context.shutdown).await,
+        Ok(Some(upstream)) if upstream_h2 => {
+            log::info!("Negotiated HTTP/2 with client and upstream for {host}:{port}");
+            let resolved_ip = upstream_tls::resolved_ip(config, &host, &upstream);
+            return crate::http2::handle_h2_intercept(tls_stream, upstream, host, port, resolved_ip, context).await;
+        },
+        Ok(Some(upstream)) => {
+            let resolved_ip = upstream_tls::resolved_ip(config, &host, &upstream);
+            Some(PooledConnection::new(Box::new(upstream) as Box<dyn AsyncStream>, connect_ms, resolved_ip))
+        },
+        Ok(None) => pooled,
+    };
+
     // 对于拦截的HTTPS，使用HTTPS客户端重新建立连接
-    log::info!("Processing HTTPS request through intercept mode...");
-    
-    // 读取完整的HTTPS请求
-    let mut request_buffer = Vec::new();
-    let mut temp_buffer = [0; 4096];
-    
-    // 读取请求头直到找到空行
-    loop {
-        let bytes_read = tls_stream.read(&mut temp_buffer).await?;
-        if bytes_read == 0 {
-            return Ok(());
-        }
-        
-        request_buffer.extend_from_slice(&temp_buffer[..bytes_read]);
-        
-        // 检查是否找到HTTP头的结束标记 \r\n\r\n
-        if request_buffer.windows(4).any(|window| window == b"\r\n\r\n") {
-            break;
-        }
-        
-        // 防止读取过多数据
-        if request_buffer.len() > 8192 {
-            log::warn!("HTTPS request header too large");
-            return Ok(());
+    log::info!("Processing HTTPS requests through intercept mode...");
+    serve_http1(tls_stream, server_connection, target, context).await
+}
+
+/// HTTP/1.1请求转发的上游目标
+#[derive(Debug, Clone)]
+struct UpstreamTarget {
+    /// 上游协议（http或https）
+    scheme: &'static str,
+    /// 上游主机
+    host: String,
+    /// 上游端口
+    port: u16,
+    /// 加在请求路径前的基础路径（反向代理模式使用）
+    base_path: String,
+}
+
+impl UpstreamTarget {
+    /// 解析上游基础URL
+    ///
+    /// # 参数
+    /// * `url` - 形如 http://host:port/base 的URL
+    fn from_base_url(url: &str) -> Result<Self> {
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            ("http", rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            ("https", rest)
+        } else {
+            anyhow::bail!("Upstream URL must start with http:// or https://: {url}");
+        };
+
+        let (authority, base_path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], rest[pos..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let default_port = if scheme == "https" { 443 } else { 80 };
+        let (host, port) = parse_authority(authority, default_port)
+            .map_err(|e| anyhow::anyhow!("Invalid upstream URL {url}: {e}"))?;
+
+        Ok(Self {
+            scheme,
+            host,
+            port,
+            base_path: base_path.to_string(),
+        })
+    }
+
+    /// 连接池中使用的源站标识
+    fn origin(&self) -> String {
+        format!("{}://{}", self.scheme, format_authority(&self.host, self.port))
+    }
+}
+
+/// Map Remote规则映射后的请求目标
+#[derive(Debug)]
+struct RemoteMapping {
+    /// 新的上游目标
+    target: UpstreamTarget,
+    /// 发送给新上游的请求路径（含查询参数）
+    path: String,
+    /// 是否保留原始的Host头
+    preserve_host: bool,
+}
+
+impl RemoteMapping {
+    /// 按Map Remote规则计算请求的新目标
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    ///
+    /// # 返回值
+    /// 没有匹配的规则时返回None
+    fn resolve(config: &Config, host: &str, method: &str, path: &str) -> Option<Self> {
+        let rule = config.map_remote_rule_for(host, method, path)?;
+        // 规则的地址在启动时已经校验过
+        let target = UpstreamTarget::from_base_url(&rule.to).ok()?;
+        let path = match target.base_path.is_empty() {
+            true => path.to_string(),
+            false => {
+                let relative = path.strip_prefix(rule.flow.path_prefix(path)).unwrap_or(path.trim_start_matches('/'));
+                format!("{}/{relative}", target.base_path)
+            },
+        };
+        Some(Self {
+            target,
+            path,
+            preserve_host: rule.preserve_host,
+        })
+    }
+
+    /// 发送给新上游的Host头
+    ///
+    /// # 参数
+    /// * `original` - 原始请求的Host
+    fn host_header(&self, original: String) -> String {
+        match self.preserve_host {
+            true => original,
+            false => format_authority(&self.target.host, self.target.port),
         }
     }
-    
-    let request_str = String::from_utf8_lossy(&request_buffer);
-    let lines: Vec<&str> = request_str.lines().collect();
-    if lines.is_empty() {
-        return Ok(());
+
+    /// 映射后的完整URL，用于日志记录
+    fn url(&self) -> String {
+        format!("{}{}", self.target.origin(), self.path)
     }
-    
-    let first_line = lines[0];
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-    if parts.len() < 3 {
-        log::warn!("Invalid HTTPS request: {first_line}");
-        return Ok(());
+}
+
+/// 可以作为上游连接的双向流
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// 保存空闲上游连接的连接池
+pub(crate) type UpstreamPool = ConnectionPool<Box<dyn AsyncStream>>;
+
+/// 从连接池取出仍然可用的空闲连接
+///
+/// # 参数
+/// * `pool` - 上游连接池
+/// * `origin` - 源站标识
+async fn checkout_upstream(pool: &UpstreamPool, origin: &str) -> Option<PooledConnection<Box<dyn AsyncStream>>> {
+    while let Some(mut connection) = pool.checkout(origin) {
+        if is_connection_alive(&mut connection.stream).await {
+            log::info!("♻️ Reusing pooled connection to {origin}");
+            return Some(connection);
+        }
+        log::debug!("Discarding pooled connection to {origin} closed by server");
     }
-    
-    let method = parts[0];
-    let path = parts[1];
-    
-    log::info!("🌐 HTTPS REQUEST ==========================================");
-    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
-    log::info!("📝 Method: {method}");
-    log::info!("🔗 Path: {path}");
-    log::info!("🌐 Host: {host}:{port}");
-    
-    // 解析请求头和请求体
-    let mut headers = HashMap::new();
-    let mut request_body = String::new();
-    
-    for line in &lines[1..] {
-        if line.is_empty() {
-            break;
+    None
+}
+
+/// 建立到上游目标的连接，https目标使用TLS
+///
+/// # 参数
+/// * `context` - 共享的组件
+/// * `target` - 上游目标
+///
+/// # 返回值
+/// 返回上游连接和实际连接的IP
+async fn connect_upstream(context: &ProxyContext, target: &UpstreamTarget) -> Result<(Box<dyn AsyncStream>, Option<IpAddr>)> {
+    let config = &context.config;
+    match target.scheme {
+        "https" => {
+            let stream = connect_upstream_tls(context, &target.host, target.port, upstream_tls::OFFER_HTTP1).await?;
+            let resolved_ip = upstream_tls::resolved_ip(config, &target.host, &stream);
+            Ok((Box::new(stream), resolved_ip))
+        },
+        _ => {
+            let stream = connect_tunnel(config, &target.host, target.port, context.pinned_ip(&target.host)).await?;
+            let resolved_ip = upstream::resolved_ip(config, &target.host, &stream);
+            Ok((Box::new(stream), resolved_ip))
+        },
+    }
+}
+
+/// 一个客户端连接转发请求使用的上游
+struct UpstreamSlot {
+    /// 上游目标
+    target: UpstreamTarget,
+    /// 明文请求经过的HTTP上游代理（仅正向代理模式使用）
+    forward_proxy: Option<HttpForwardProxy>,
+    /// 当前上游连接
+    connection: Option<PooledConnection<Box<dyn AsyncStream>>>,
+    /// 当前上游连接的源站，Map Remote的请求会切换到其他源站
+    origin: String,
+}
+
+impl UpstreamSlot {
+    /// 创建上游
+    ///
+    /// # 参数
+    /// * `target` - 上游目标
+    /// * `forward_proxy` - 明文请求经过的HTTP上游代理
+    /// * `connection` - 已建立的到上游目标的连接（可选）
+    fn new(
+        target: UpstreamTarget,
+        forward_proxy: Option<HttpForwardProxy>,
+        connection: Option<PooledConnection<Box<dyn AsyncStream>>>,
+    ) -> Self {
+        let mut slot = Self { target, forward_proxy, connection, origin: String::new() };
+        slot.origin = slot.target_origin();
+        slot
+    }
+
+    /// 未经Map Remote映射的请求发往的源站
+    ///
+    /// 经过HTTP上游代理时连接的是代理服务器，与直连的连接分开复用。
+    fn target_origin(&self) -> String {
+        match self.forward_proxy {
+            Some(_) => format!("http+proxy://{}", format_authority(&self.target.host, self.target.port)),
+            None => self.target.origin(),
         }
-        if let Some(colon_pos) = line.find(':') {
-            let key = line[..colon_pos].trim().to_lowercase();
-            let value = line[colon_pos + 1..].trim().to_string();
-            headers.insert(key, value);
+    }
+
+    /// 上游连接仍然可用时放回连接池
+    ///
+    /// # 参数
+    /// * `pool` - 上游连接池
+    fn checkin(self, pool: &UpstreamPool) {
+        if let Some(connection) = self.connection {
+            pool.checkin(&self.origin, connection);
         }
     }
-    
-    // 提取请求体（如果有）
-    if let Some(body_start) = request_str.find("\r\n\r\n") {
-        request_body = request_str[body_start + 4..].to_string();
+}
+
+/// 已读取请求头的HTTP/1.1请求
+struct ParsedRequest<'a> {
+    /// 请求方法
+    method: &'a str,
+    /// 客户端请求的路径
+    path: &'a str,
+    /// 请求行之后的各行
+    header_lines: &'a [&'a str],
+    /// 名称转为小写的请求头
+    headers: HashMap<String, String>,
+    /// 读取请求头时已经读到的数据
+    body: &'a [u8],
+    /// 客户端是否保持连接
+    keep_alive: bool,
+    /// 上游TLS连接使用的客户端证书（用于日志记录）
+    client_identity: Option<String>,
+    /// 开始处理请求的时间
+    start: Instant,
+}
+
+/// 一个请求处理完成后客户端连接的处理方式
+enum ExchangeOutcome {
+    /// 继续处理下一个请求，附带已读取的下一个请求的数据
+    KeepAlive(Vec<u8>),
+    /// 关闭客户端连接
+    Close,
+    /// 已注入reset故障，不发送TLS close_notify直接断开连接
+    Reset,
+    /// 连接已切换为升级后的协议并处理完毕
+    Upgraded,
+}
+
+impl ExchangeOutcome {
+    /// 响应完整发送之后的处理方式
+    ///
+    /// # 参数
+    /// * `keep_alive` - 客户端是否保持连接
+    /// * `pending` - 已读取的下一个请求的数据
+    fn finished(keep_alive: bool, pending: Vec<u8>) -> Self {
+        match keep_alive {
+            true => Self::KeepAlive(pending),
+            false => Self::Close,
+        }
     }
-    
-    // 解析URL参数
-    let url_params = if let Some(query_start) = path.find('?') {
-        let query = &path[query_start + 1..];
-        query.split('&')
-            .filter_map(|pair| pair.split_once('='))
-            .map(|(k, v)| format!("{k}={v}"))
-            .collect::<Vec<_>>()
-            .join("&")
-    } else {
-        String::new()
+}
+
+/// 在一个客户端连接上循环处理HTTP/1.1请求，直到任意一方关闭连接
+///
+/// 每个请求转发到上游目标并记录日志，上游连接在响应允许时复用。
+///
+/// # 参数
+/// * `client_stream` - 客户端连接（已完成TLS握手或明文）
+/// * `server_connection` - 已建立的上游连接（可选），结束时仍可复用的连接放回连接池
+/// * `target` - 上游目标
+/// * `context` - 共享的组件
+async fn serve_http1<C>(
+    mut client_stream: C,
+    server_connection: Option<PooledConnection<Box<dyn AsyncStream>>>,
+    target: UpstreamTarget,
+    context: Arc<ProxyContext>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + ClientSocket + Unpin,
+{
+    let config = &context.config;
+    let logger = &context.logger;
+    let host = target.host.clone();
+    let port = target.port;
+    let scheme = target.scheme;
+    let mut request_count = 0;
+    let client_identity = match scheme {
+        "https" => upstream_tls::client_identity(config, &host),
+        _ => None,
     };
-    
+    let mut slot = UpstreamSlot::new(target, None, server_connection);
+    // 上一个请求之后已读取的数据
+    let mut pending = Vec::new();
+
+    loop {
+        let mut request_buffer = std::mem::take(&mut pending);
+        let read_start = Instant::now();
+        let header_end = match read_request_head(&mut client_stream, &mut request_buffer, config.timeouts.client_header(), &context.shutdown).await {
+            Ok(Some(header_end)) => header_end,
+            Ok(None) => break,
+            Err(e) if timeout_phase(&e).is_some() => {
+                // 已向客户端返回408，记录收到的部分请求
+                log::warn!("⏱️ {e} on connection to {host}:{port}");
+                let partial = String::from_utf8_lossy(&request_buffer).to_string();
+                let mut request_line = partial.lines().next().unwrap_or("").split_whitespace();
+                let method = request_line.next().unwrap_or("").to_string();
+                let path = request_line.next().unwrap_or("/");
+                let mut log_entry = DomainLogger::create_log_entry(
+                    host.clone(),
+                    method,
+                    format!("{scheme}://{}{}{path}", format_authority(&host, port), slot.target.base_path),
+                    HashMap::new(),
+                    HashMap::new(),
+                    408,
+                    String::new(),
+                    String::new(),
+                    parse_url_params(path),
+                    read_start.elapsed().as_millis(),
+                    Some(e.to_string()),
+                );
+                log_entry.client_identity = client_identity.clone();
+                logger.log_request(log_entry);
+                break;
+            },
+            Err(e) => return Err(e),
+        };
+        let exchange_start = Instant::now();
+        request_count += 1;
+
+        let request_str = String::from_utf8_lossy(&request_buffer[..header_end]).to_string();
+        let lines: Vec<&str> = request_str.lines().collect();
+        if lines.is_empty() {
+            break;
+        }
+
+        let first_line = lines[0];
+        let parts: Vec<&str> = first_line.split_whitespace().collect();
+        if parts.len() < 3 {
+            log::warn!("Invalid {} request: {first_line}", scheme.to_uppercase());
+            break;
+        }
+
+        let method = parts[0];
+        let path = parts[1];
+        let version = parts[2];
+
+        log::info!("🌐 {} REQUEST #{request_count} ======================================", scheme.to_uppercase());
+        log::info!("⏰ Timestamp: {:?}", SystemTime::now());
+        log::info!("📝 Method: {method}");
+        log::info!("🔗 Path: {path}");
+        log::info!("🌐 Host: {host}:{port}");
+
+        // 解析请求头
+        let mut headers = HashMap::new();
+        for line in &lines[1..] {
+            if line.is_empty() {
+                break;
+            }
+            if let Some(colon_pos) = line.find(':') {
+                let key = line[..colon_pos].trim().to_lowercase();
+                let value = line[colon_pos + 1..].trim().to_string();
+                headers.insert(key, value);
+            }
+        }
+        let keep_alive = is_keep_alive(version, headers.get("connection").map(String::as_str));
+
+        let request = ParsedRequest {
+            method,
+            path,
+            header_lines: &lines[1..],
+            headers,
+            body: &request_buffer[header_end..],
+            keep_alive,
+            client_identity: client_identity.clone(),
+            start: exchange_start,
+        };
+        match serve_exchange(&mut client_stream, request, &mut slot, &context).await? {
+            ExchangeOutcome::KeepAlive(rest) => pending = rest,
+            ExchangeOutcome::Close => break,
+            ExchangeOutcome::Reset => {
+                slot.checkin(&context.pool);
+                return Ok(());
+            },
+            ExchangeOutcome::Upgraded => {
+                log::info!("Connection to {host}:{port} closed after protocol upgrade");
+                return Ok(());
+            },
+        }
+
+        // 正在关闭时处理完已收到的请求后关闭连接
+        if context.shutdown.is_shutdown() && pending.is_empty() {
+            break;
+        }
+    }
+
+    log::info!("Connection to {host}:{port} closed after {request_count} request(s)");
+    let _ = client_stream.shutdown().await;
+
+    // 上游连接仍然可用时放回连接池
+    slot.checkin(&context.pool);
+
+    Ok(())
+}
+
+/// 转发一个已读取请求头的HTTP/1.1请求并记录日志
+///
+/// 依次处理故障注入、Map Local、Map Remote、请求头和请求体改写以及拦截器，
+/// 然后把请求发往上游并把响应转发给客户端；上游不可用时返回502/504页面。
+///
+/// # 参数
+/// * `client_stream` - 客户端流
+/// * `request` - 已读取请求头的请求
+/// * `slot` - 客户端连接使用的上游，处理完成后仍可复用的上游连接保留在其中
+/// * `context` - 共享的组件
+///
+/// # 返回值
+/// 返回客户端连接接下来的处理方式
+async fn serve_exchange<C>(
+    client_stream: &mut C,
+    request: ParsedRequest<'_>,
+    slot: &mut UpstreamSlot,
+    context: &ProxyContext,
+) -> Result<ExchangeOutcome>
+where
+    C: AsyncRead + AsyncWrite + ClientSocket + Unpin,
+{
+    let config = &context.config;
+    let logger = &context.logger;
+    let pool = &context.pool;
+    let ParsedRequest { method, path, header_lines, headers, body, keep_alive: client_keep_alive, client_identity, start } = request;
+    let target = slot.target.clone();
+    let host = target.host.clone();
+    let port = target.port;
+    let scheme = target.scheme;
+    let authority = format_authority(&host, port);
+
+    // 解析URL参数
+    let url_params = parse_url_params(path);
+
     // 收集请求头
-    let request_headers: HashMap<String, String> = lines[1..].iter()
+    let request_headers: HashMap<String, String> = header_lines.iter()
         .take_while(|l| !l.is_empty())
         .filter_map(|l| l.split_once(':'))
         .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
         .collect();
 
-    // 不再提前记录日志，将在获取完整响应信息后记录
-    
-    // 构建新的HTTP请求
-    let mut new_request = format!("{method} {path} HTTP/1.1\r\n");
-    new_request.push_str(&format!("Host: {host}:{port}\r\n"));
-    
-    // 保留原始头部
-    for (key, value) in &headers {
-        if key != "host" {
-            new_request.push_str(&format!("{key}: {value}\r\n"));
+    // 构建新的HTTP请求，Map Remote规则匹配时发送到新的上游，客户端看到的请求不变
+    let upstream_path = format!("{}{path}", target.base_path);
+    let log_url = format!("{scheme}://{authority}{upstream_path}");
+    let mapping = RemoteMapping::resolve(config, &host, method, path);
+    let forward_proxy = match mapping {
+        Some(_) => None,
+        None => slot.forward_proxy.clone(),
+    };
+    let (request_target, request_path, host_header) = match &mapping {
+        Some(mapping) => (&mapping.target, mapping.path.as_str(), mapping.host_header(authority.clone())),
+        None => (&target, upstream_path.as_str(), authority.clone()),
+    };
+
+    // 保留原始头部（100-continue由代理直接应答），再按规则改写；上游代理认证不参与改写
+    let mut sent_headers = vec![("Host".to_string(), host_header)];
+    sent_headers.extend(
+        parse_header_lines(header_lines.iter().copied())
+            .into_iter()
+            .filter(|(key, _)| !["host", "expect", "proxy-authorization"].iter().any(|name| key.eq_ignore_ascii_case(name))),
+    );
+    HeaderRewrite::new(config, RewritePhase::Request, &host, method, path).apply(&mut sent_headers);
+
+    let fault = pick_fault(config, &host, method, path);
+    if let Some(fault) = &fault {
+        log::warn!("💥 Injecting fault into {method} {scheme}://{authority}{path}: {fault}");
+        if let Some((status_code, body, error)) = inject_terminal_fault(fault, client_stream).await? {
+            let mut log_entry = DomainLogger::create_log_entry(
+                host.clone(),
+                method.to_string(),
+                log_url,
+                request_headers,
+                HashMap::new(),
+                status_code,
+                String::new(),
+                body,
+                url_params,
+                start.elapsed().as_millis(),
+                error,
+            );
+            log_entry.client_identity = client_identity;
+            log_entry.fault = Some(fault.to_string());
+            logger.log_request(log_entry);
+
+            return Ok(match fault {
+                Fault::Reset => ExchangeOutcome::Reset,
+                _ => ExchangeOutcome::Close,
+            });
+        }
+        if let Fault::Delay { ms } = fault {
+            tokio::time::sleep(Duration::from_millis(*ms)).await;
         }
     }
-    
-    // 添加必要的头部
-    if !headers.contains_key("user-agent") {
-        new_request.push_str("User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36\r\n");
-    }
-    if !headers.contains_key("accept") {
-        new_request.push_str("Accept: */*\r\n");
+    let body_fault = || fault.as_ref().and_then(BodyFault::new);
+
+    let mut request_processor = HttpRequestProcessor::new(&headers, config.logging.domain_logs.request_body_limit);
+    let expect_continue = headers.get("expect")
+        .map(|v| v.eq_ignore_ascii_case("100-continue"))
+        .unwrap_or(false);
+    if expect_continue && request_processor.has_body() {
+        client_stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
     }
-    
-    new_request.push_str("\r\n");
-    
-    // 添加请求体
-    if !request_body.is_empty() {
-        new_request.push_str(&request_body);
+
+    // Map Local规则匹配时使用本地文件应答，不连接上游
+    if let Some(rule) = config.map_local_rule_for(&host, method, path) {
+        let pending = request_processor
+            .forward_body(body, client_stream, &mut tokio::io::sink())
+            .await?;
+        let local = LocalResponse::load(rule, path).await;
+        log::info!("📁 Map Local {method} {scheme}://{authority}{path} -> {}", local.file.display());
+        client_stream.write_all(&local.to_http1(method.eq_ignore_ascii_case("HEAD"), client_keep_alive)).await?;
+        client_stream.flush().await?;
+
+        let mut log_entry = DomainLogger::create_log_entry(
+            host.clone(),
+            method.to_string(),
+            log_url,
+            request_headers,
+            local.headers_for_log(),
+            local.status,
+            request_processor.get_body(),
+            local.body_for_log(config.logging.domain_logs.response_body_limit),
+            url_params,
+            start.elapsed().as_millis(),
+            None,
+        );
+        log_entry.client_identity = client_identity;
+        log_entry.fault = fault.as_ref().map(ToString::to_string);
+        log_entry.mapped = Some(local.mapped_url());
+        logger.log_request(log_entry);
+        return Ok(ExchangeOutcome::finished(client_keep_alive, pending));
     }
-    
-    // 使用HTTPS连接器建立到目标服务器的连接
-    log::info!("Connecting to HTTPS server: {host}:{port}");
-    let server_stream = TcpStream::connect(format!("{host}:{port}")).await?;
-    
-    // 建立TLS连接
-    let connector = tokio_native_tls::TlsConnector::from(
-        tokio_native_tls::native_tls::TlsConnector::builder()
-            .danger_accept_invalid_certs(true)
-            .build()?,
-    );
-    
-    let mut tls_server_stream = connector
-        .connect(&host, server_stream)
-        .await?;
-    
-    log::info!("HTTPS connection established to target server");
-    
-    // 发送请求
-    tls_server_stream.write_all(new_request.as_bytes()).await?;
-    
-    // 使用新的响应处理器
-    let mut response_processor = HttpResponseProcessor::new(config.logging.domain_logs.response_body_limit);
-    let mut buffer = [0; 4096];
-    let mut total_response_buffer = Vec::new();
 
-    log::info!("Reading HTTPS response...");
+    // 有请求体改写规则时先读取完整的请求体，改写后再发送请求头
+    let body_rewrite = BodyRewrite::new(config, RewritePhase::Request, &host, method, path);
+    let request_body_limit = config.logging.domain_logs.request_body_limit;
+    let (request_data, mut sent_body) = match !body_rewrite.is_empty() && request_processor.has_body() {
+        true => {
+            let (data, sent_body) = rewrite_request_body(
+                &mut request_processor,
+                body,
+                client_stream,
+                &body_rewrite,
+                &mut sent_headers,
+                request_body_limit,
+            ).await?;
+            (Cow::Owned(data), sent_body)
+        },
+        false => (Cow::Borrowed(body), None),
+    };
+
+    // 有拦截器处理请求或响应时读取完整的请求，处理请求的拦截器在发往上游前调用
+    let mut sent_request = FlowRequest {
+        method: method.to_string(),
+        origin: request_target.origin(),
+        path: request_path.to_string(),
+        headers: sent_headers,
+        body: Vec::new(),
+    };
+    let mut flow_error = None;
+    let request_interceptors = context.interceptors.select(RewritePhase::Request, &host, method, path);
+    let response_interceptors = context.interceptors.select(RewritePhase::Response, &host, method, path);
+    let request_data = match request_interceptors.is_empty() && response_interceptors.is_empty() {
+        true => request_data,
+        false => {
+            let buffered = request_processor.buffer_body(&request_data, client_stream).await?;
+            let materialized = materialize_request(
+                &mut sent_request,
+                &host,
+                &request_interceptors,
+                &mut request_processor,
+                buffered,
+                &mut sent_body,
+                &mut flow_error,
+            ).await?;
+            match materialized {
+                MaterializedRequest::Forward(data) => Cow::Owned(data),
+                MaterializedRequest::Respond { response, by, pending } => {
+                    log::info!("↩️ {method} {scheme}://{authority}{path} answered by {by}");
+                    let head_request = method.eq_ignore_ascii_case("HEAD");
+                    client_stream.write_all(&intercepted_response_to_http1(&response, head_request, client_keep_alive)).await?;
+                    client_stream.flush().await?;
 
-    loop {
-        let bytes_read = tls_server_stream.read(&mut buffer).await?;
-        if bytes_read == 0 {
-            break;
-        }
+                    let mut response_body = BodyCapture::new(config.logging.domain_logs.response_body_limit);
+                    response_body.feed(&response.body);
+                    let mut log_entry = DomainLogger::create_log_entry(
+                        host.clone(),
+                        method.to_string(),
+                        log_url,
+                        request_headers,
+                        headers_for_log(&response.headers),
+                        response.status,
+                        request_processor.get_body(),
+                        response_body.as_string(),
+                        url_params,
+                        start.elapsed().as_millis(),
+                        flow_error,
+                    );
+                    log_entry.client_identity = client_identity;
+                    log_entry.fault = fault.as_ref().map(ToString::to_string);
+                    log_entry.sent_request_headers = Some(headers_for_log(&sent_request.headers));
+                    logger.log_request(log_entry);
+                    return Ok(ExchangeOutcome::finished(client_keep_alive, pending));
+                },
+                MaterializedRequest::Drop { by } => {
+                    log::warn!("⏹️ {method} {scheme}://{authority}{path} dropped by {by}");
+                    let mut log_entry = DomainLogger::create_log_entry(
+                        host.clone(),
+                        method.to_string(),
+                        log_url,
+                        request_headers,
+                        HashMap::new(),
+                        0,
+                        request_processor.get_body(),
+                        String::new(),
+                        url_params,
+                        start.elapsed().as_millis(),
+                        Some(format!("Request dropped by {by}")),
+                    );
+                    log_entry.client_identity = client_identity;
+                    log_entry.fault = fault.as_ref().map(ToString::to_string);
+                    log_entry.mapped = mapping.as_ref().map(RemoteMapping::url);
+                    log_entry.sent_request_headers = Some(headers_for_log(&sent_request.headers));
+                    logger.log_request(log_entry);
+                    return Ok(ExchangeOutcome::Close);
+                },
+            }
+        },
+    };
 
-        let chunk_data = &buffer[..bytes_read];
-        total_response_buffer.extend_from_slice(chunk_data);
+    // 经过HTTP上游代理时使用absolute-form并附加代理认证
+    let request_line_target = match forward_proxy {
+        Some(_) => sent_request.url(),
+        None => sent_request.path.clone(),
+    };
+    let mut new_request = format!("{} {request_line_target} HTTP/1.1\r\n", sent_request.method);
+    if let Some(authorization) = forward_proxy.as_ref().and_then(|p| p.authorization.as_ref()) {
+        new_request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    new_request.push_str(&format_header_lines(&sent_request.headers));
+    new_request.push_str("\r\n");
 
-        // 使用响应处理器处理数据块
-        match response_processor.process_chunk_tls(chunk_data, &mut tls_stream).await? {
-            ProcessingResult::Continue => continue,
-            ProcessingResult::Complete => break,
+    // 请求发往与当前上游连接不同的源站时，当前连接放回连接池
+    let request_origin = match mapping {
+        Some(_) => request_target.origin(),
+        None => slot.target_origin(),
+    };
+    if request_origin != slot.origin {
+        if let Some(connection) = slot.connection.take() {
+            pool.checkin(&slot.origin, connection);
         }
+        slot.origin = request_origin;
     }
-    
-    let duration_ms = start_time.elapsed().as_millis();
-    log::info!("✅ HTTPS REQUEST COMPLETE - {} bytes transferred - Duration: {}ms", total_response_buffer.len(), duration_ms);
-    
-    // 解析响应头和状态码用于日志记录
-    let response_str = String::from_utf8_lossy(&total_response_buffer);
-    let response_lines: Vec<&str> = response_str.lines().collect();
-    let mut response_headers_map = HashMap::new();
-    let mut response_status = 0;
-    
-    if let Some(status_line) = response_lines.first() {
-        let status_parts: Vec<&str> = status_line.split_whitespace().collect();
-        if status_parts.len() >= 2 {
-            response_status = status_parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+    if let Some(mapping) = &mapping {
+        log::info!("🔀 Map Remote {method} {log_url} -> {}", mapping.url());
+    }
+
+    // 空闲期间被服务器关闭的连接不再复用
+    if let Some(connection) = slot.connection.as_mut() {
+        if !is_connection_alive(&mut connection.stream).await {
+            slot.connection = None;
         }
     }
-    
-    let mut header_end = 0;
-    if let Some(pos) = total_response_buffer.windows(4).position(|w| w == b"\r\n\r\n") {
-        header_end = pos + 4;
+    if slot.connection.is_none() {
+        slot.connection = checkout_upstream(pool, &slot.origin).await;
     }
-    
-    for line in response_lines.iter().skip(1) {
-        if line.is_empty() {
-            break;
+
+    // 复用的上游连接可能已被服务器关闭，没有请求体时重新建立连接并重试一次
+    let mut reused = slot.connection.is_some();
+    let mut pending = Vec::new();
+    let exchange = loop {
+        let server_stream = match slot.connection.as_mut() {
+            Some(connection) => &mut connection.stream,
+            None => {
+                // 经过HTTP上游代理时连接代理服务器
+                let connect_start = Instant::now();
+                let connected = match forward_proxy {
+                    Some(_) => connect_http(config, &host, port).await.map(|(stream, _)| {
+                        let resolved_ip = upstream::resolved_ip(config, &host, &stream);
+                        (Box::new(stream) as Box<dyn AsyncStream>, resolved_ip)
+                    }),
+                    None => connect_upstream(context, request_target).await,
+                };
+                match connected {
+                    Ok((stream, resolved_ip)) => {
+                        let connection = PooledConnection::new(stream, connect_start.elapsed().as_millis(), resolved_ip);
+                        &mut slot.connection.insert(connection).stream
+                    },
+                    Err(e) => break Err(e),
+                }
+            },
+        };
+
+        // 发送请求头并流式转发请求体
+        if let Err(e) = server_stream.write_all(new_request.as_bytes()).await {
+            if !reused {
+                return Err(e.into());
+            }
+            log::info!("Reused upstream connection to {host}:{port} failed ({e}), reconnecting");
+            slot.connection = None;
+            reused = false;
+            continue;
         }
-        if let Some(colon_pos) = line.find(':') {
-            let key = line[..colon_pos].trim().to_lowercase();
-            let value = line[colon_pos + 1..].trim().to_string();
-            response_headers_map.insert(key, value);
+        pending = request_processor
+            .forward_body(&request_data, client_stream, server_stream)
+            .await?;
+
+        let interception = (!response_interceptors.is_empty()).then(|| ResponseInterception {
+            interceptors: response_interceptors.clone(),
+            host: host.clone(),
+            request: sent_request.clone(),
+            error: flow_error.clone(),
+        });
+        let response_processor = HttpResponseProcessor::new(config.logging.domain_logs.response_body_limit, &sent_request.method)
+            .with_fault(body_fault())
+            .with_header_rewrite(HeaderRewrite::new(config, RewritePhase::Response, &host, method, path))
+            .with_body_rewrite(BodyRewrite::new(config, RewritePhase::Response, &host, method, path))
+            .with_interception(interception);
+        let first_byte_timeout = config.timeouts.first_byte();
+        let (processor, bytes) = match relay_response(server_stream, client_stream, response_processor, first_byte_timeout).await {
+            Ok(result) => result,
+            Err(e) => break Err(e),
+        };
+
+        if reused && processor.forwarded_bytes == 0 && !request_processor.has_body() {
+            log::info!("Reused upstream connection to {host}:{port} was closed, reconnecting");
+            slot.connection = None;
+            reused = false;
+            continue;
         }
+        break Ok((processor, bytes));
+    };
+
+    let duration_ms = start.elapsed().as_millis();
+    let timing = slot.connection.as_ref().map(|connection| ConnectionTiming {
+        connect_ms: connection.connect_ms,
+        reused: connection.reused,
+    });
+    let resolved_ip = slot.connection.as_ref().and_then(|connection| connection.resolved_ip);
+    if let Some(connection) = slot.connection.as_mut() {
+        connection.mark_reused();
     }
-    
-    // 使用新的DomainLogger记录完整的HTTPS请求响应日志
-    let response_body_str = if response_processor.needs_decompression() {
-        // 使用解压缩后的响应体
-        response_processor.get_decompressed_body()
-    } else if header_end > 0 && header_end < total_response_buffer.len() {
-        // 使用原始响应体
-        String::from_utf8_lossy(&total_response_buffer[header_end..]).to_string()
+    let (mut response_processor, response_bytes) = match exchange {
+        Ok(result) => result,
+        Err(e) => {
+            // 无法连接上游或上游超时时返回502/504页面并关闭连接，超时的上游连接不再复用
+            log::error!("❌ Upstream request to {}:{} failed: {e}", request_target.host, request_target.port);
+            let flow = Flow { host: host.clone(), request: sent_request.clone(), response: None, error: flow_error.clone() };
+            context.interceptors.on_error(&flow, &e).await;
+            slot.connection = None;
+            let (status_code, response) = upstream_error_response(&request_target.host, request_target.port, &e);
+            client_stream.write_all(&response).await?;
+            let mut log_entry = DomainLogger::create_log_entry(
+                host.clone(),
+                method.to_string(),
+                log_url,
+                request_headers,
+                HashMap::new(),
+                status_code,
+                request_processor.get_body(),
+                String::new(),
+                url_params,
+                duration_ms,
+                Some(e.to_string()),
+            );
+            log_entry.client_identity = client_identity;
+            log_entry.timing = timing;
+            log_entry.resolved_ip = resolved_ip;
+            log_entry.fault = fault.as_ref().map(ToString::to_string);
+            log_entry.mapped = mapping.as_ref().map(RemoteMapping::url);
+            log_entry.sent_request_headers = Some(headers_for_log(&sent_request.headers));
+            log_entry.sent_request_body = sent_body;
+            logger.log_request(log_entry);
+            return Ok(ExchangeOutcome::Close);
+        }
+    };
+    log::info!("✅ {} REQUEST COMPLETE - {response_bytes} bytes transferred - Duration: {duration_ms}ms", scheme.to_uppercase());
+
+    // 使用DomainLogger记录完整的请求响应日志
+    let error = if let Some(by) = &response_processor.dropped_by {
+        Some(format!("Response dropped by {by}"))
+    } else if response_processor.is_complete() || response_processor.forwarded_bytes > 0 {
+        None
     } else {
-        String::new()
+        Some("Upstream closed connection without response".to_string())
+    };
+    let flow_error = match response_interceptors.is_empty() {
+        true => flow_error,
+        false => response_processor.flow_error.take(),
     };
-    let log_entry = DomainLogger::create_log_entry(
+    let mut log_entry = DomainLogger::create_log_entry(
         host.clone(),
         method.to_string(),
-        format!("https://{host}:{port}{path}"),
+        log_url.clone(),
         request_headers,
-        response_headers_map,
-        response_status,
-        request_body,
-        response_body_str,
+        response_processor.headers.clone(),
+        response_processor.status_code,
+        request_processor.get_body(),
+        response_processor.get_decompressed_body(),
         url_params,
         duration_ms,
-        None,
+        error.or(flow_error),
     );
+    log_entry.client_identity = client_identity;
+    log_entry.timing = timing;
+    log_entry.resolved_ip = resolved_ip;
+    log_entry.fault = fault.as_ref().map(ToString::to_string);
+    log_entry.mapped = mapping.as_ref().map(RemoteMapping::url);
+    log_entry.sent_request_headers = Some(headers_for_log(&sent_request.headers));
+    log_entry.sent_response_headers = response_processor.sent_headers.as_ref().map(headers_for_log);
+    log_entry.sent_request_body = sent_body;
+    log_entry.sent_response_body = response_processor.sent_body.take();
     logger.log_request(log_entry);
-    
-    Ok(())
+
+    // 101响应之后连接切换为升级后的协议，不再按HTTP处理
+    if response_processor.status_code == 101 {
+        let Some(PooledConnection { stream: server_stream, .. }) = slot.connection.take() else {
+            return Ok(ExchangeOutcome::Close);
+        };
+        let websocket_context = WebSocketContext {
+            host: host.clone(),
+            url: format!("{}://{authority}{upstream_path}", if scheme == "https" { "wss" } else { "ws" }),
+            request_payload_limit: config.logging.domain_logs.request_body_limit,
+            response_payload_limit: config.logging.domain_logs.response_body_limit,
+            logger: Arc::clone(logger),
+        };
+        relay_upgraded(
+            client_stream,
+            server_stream,
+            pending,
+            response_processor.take_leftover(),
+            is_websocket_upgrade(&headers),
+            websocket_context,
+            config.timeouts.tunnel_idle(),
+        ).await?;
+        return Ok(ExchangeOutcome::Upgraded);
+    }
+
+    // 上游不再保持连接时，下一个请求重新建立连接
+    if !response_processor.keep_alive() {
+        slot.connection = None;
+    }
+
+    // 响应体以连接关闭为结束标志时不再处理后续请求
+    match response_processor.is_complete() {
+        true => Ok(ExchangeOutcome::finished(client_keep_alive, pending)),
+        false => Ok(ExchangeOutcome::Close),
+    }
+}
+
+/// 读取完整的请求体并按规则改写
+///
+/// 改写后的请求体按Content-Length转发，发往上游的Content-Length和Transfer-Encoding随之修正；
+/// 请求体没有变化或超过改写上限时按原始分帧转发已读取的数据。
+///
+/// # 参数
+/// * `request_processor` - 请求体处理器
+/// * `initial` - 读取请求头时已经读到的数据
+/// * `client_stream` - 客户端流
+/// * `body_rewrite` - 请求体改写
+/// * `headers` - 发往上游的请求头
+/// * `request_body_limit` - 请求体记录长度限制
+///
+/// # 返回值
+/// 返回代替 `initial` 交给请求体处理器转发的数据，以及请求体被改写时用于日志记录的改写后请求体
+async fn rewrite_request_body<C: AsyncRead + Unpin>(
+    request_processor: &mut HttpRequestProcessor,
+    initial: &[u8],
+    client_stream: &mut C,
+    body_rewrite: &BodyRewrite,
+    headers: &mut HeaderList,
+    request_body_limit: i64,
+) -> Result<(Vec<u8>, Option<String>)> {
+    let (raw, body) = request_processor.buffer_body(initial, client_stream).await?;
+    let Some((body, end)) = body else {
+        log::warn!("Request body larger than {MAX_BODY_REWRITE_SIZE} bytes, forwarding without rewrite");
+        return Ok((raw, None));
+    };
+    let Some(rewritten) = body_rewrite.apply(&body, find_header(headers, "content-encoding")) else {
+        return Ok((raw, None));
+    };
+
+    log::info!("✏️ Rewrote request body ({} -> {} bytes)", body.len(), rewritten.encoded.len());
+    set_content_length(headers, rewritten.encoded.len());
+    request_processor.replace_body(&body, rewritten.encoded.len());
+    let sent_body = rewritten.body_for_log(request_body_limit);
+    let mut data = rewritten.encoded;
+    data.extend_from_slice(&raw[end..]);
+    Ok((data, Some(sent_body)))
+}
+
+/// 读取完整请求的结果
+enum MaterializedRequest {
+    /// 发往上游的数据
+    Forward(Vec<u8>),
+    /// 拦截器直接应答，不发往上游
+    Respond {
+        /// 返回给客户端的响应
+        response: FlowResponse,
+        /// 应答的拦截器名称
+        by: String,
+        /// 请求之后已读取的数据
+        pending: Vec<u8>,
+    },
+    /// 拦截器丢弃了请求
+    Drop {
+        /// 丢弃请求的拦截器名称
+        by: String,
+    },
 }
 
+/// 读取完整的请求，交给拦截器处理（包括请求断点）
+///
+/// 修改后的请求体按请求头中的Content-Encoding重新压缩，按Content-Length转发；
+/// 请求体超过上限或无法解压时不调用拦截器，按原始分帧转发已读取的数据。
+///
+/// # 参数
+/// * `request` - 发往上游的请求，读取完整的请求体后按拦截器的修改更新
+/// * `host` - 请求的主机
+/// * `interceptors` - 处理请求的拦截器，为空时只读取请求体（用于处理响应的拦截器）
+/// * `request_processor` - 请求体处理器
+/// * `buffered` - 请求体处理器读取到的原始数据和请求体（见`HttpRequestProcessor::buffer_body`）
+/// * `sent_body` - 改写或修改后的请求体（用于日志记录）
+/// * `flow_error` - 拦截器记录的错误（用于日志记录）
+async fn materialize_request(
+    request: &mut FlowRequest,
+    host: &str,
+    interceptors: &InterceptorChain,
+    request_processor: &mut HttpRequestProcessor,
+    buffered: (Vec<u8>, Option<(Vec<u8>, usize)>),
+    sent_body: &mut Option<String>,
+    flow_error: &mut Option<String>,
+) -> Result<MaterializedRequest> {
+    let (raw, body) = buffered;
+    let Some((body, end)) = body else {
+        log::warn!("Request body larger than {MAX_BODY_REWRITE_SIZE} bytes, skipping interceptors");
+        return Ok(MaterializedRequest::Forward(raw));
+    };
+    let content_encoding = find_header(&request.headers, "content-encoding");
+    let Some(decoded) = decode_body(&body, content_encoding) else {
+        log::warn!("Cannot decode request body with Content-Encoding {content_encoding:?}, skipping interceptors");
+        return Ok(MaterializedRequest::Forward(raw));
+    };
+    request.body = decoded;
+    if interceptors.is_empty() {
+        return Ok(MaterializedRequest::Forward(raw));
+    }
+
+    let mut flow = Flow {
+        host: host.to_string(),
+        request: request.clone(),
+        response: None,
+        error: None,
+    };
+    let verdict = interceptors.on_request(&mut flow).await;
+    *flow_error = flow.error.take();
+    match verdict {
+        Verdict::Continue => (),
+        Verdict::Respond { response, by } => {
+            *request = flow.request;
+            return Ok(MaterializedRequest::Respond { response, by, pending: raw[end..].to_vec() });
+        },
+        Verdict::Drop { by } => return Ok(MaterializedRequest::Drop { by }),
+    }
+    let mut edited = flow.request;
+    if edited.headers == request.headers && edited.body == request.body {
+        *request = edited;
+        return Ok(MaterializedRequest::Forward(raw));
+    }
+
+    log::info!("✏️ Request modified by interceptors");
+    let mut data = encode_body(&edited.body, &mut edited.headers);
+    request_processor.replace_body(&body, data.len());
+    let mut capture = BodyCapture::new(request_processor.body_capture.limit);
+    capture.feed(&edited.body);
+    *sent_body = Some(capture.as_string());
+    data.extend_from_slice(&raw[end..]);
+    *request = edited;
+    Ok(MaterializedRequest::Forward(data))
+}
+
+/// 把拦截器给出的响应编码为HTTP/1.1响应
+///
+/// # 参数
+/// * `response` - 拦截器给出的响应
+/// * `head_request` - 是否为HEAD请求（不发送响应体）
+/// * `keep_alive` - 是否保持客户端连接
+fn intercepted_response_to_http1(response: &FlowResponse, head_request: bool, keep_alive: bool) -> Vec<u8> {
+    let mut headers: HeaderList = response.headers.iter()
+        .filter(|(key, _)| !key.eq_ignore_ascii_case("connection"))
+        .cloned()
+        .collect();
+    let body = encode_body(&response.body, &mut headers);
+    let reason = http::StatusCode::from_u16(response.status).ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let mut head = format!("HTTP/1.1 {} {reason}\r\n{}", response.status, format_header_lines(&headers));
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    let mut data = head.into_bytes();
+    if !head_request {
+        data.extend_from_slice(&body);
+    }
+    data
+}
+
+/// 建立到目标服务器的TLS连接
+///
+/// # 参数
+/// * `context` - 共享的组件（用于选择上游代理、固定的IP和TLS连接器）
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+/// * `alpn` - 通过ALPN提供的协议列表
+async fn connect_upstream_tls(
+    context: &ProxyContext,
+    host: &str,
+    port: u16,
+    alpn: &'static [&'static str],
+) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
+    // 使用HTTPS连接器建立到目标服务器的连接
+    log::info!("Connecting to HTTPS server: {host}:{port}");
+    let config = &context.config;
+    let server_stream = connect_tunnel(config, host, port, context.pinned_ip(host)).await?;
+
+    // 建立TLS连接，按配置校验上游证书
+    let tls_server_stream = upstream_tls::handshake(&context.tls_connectors, config, host, port, server_stream, alpn).await?;
+
+    log::info!("HTTPS connection established to target server");
+    Ok(tls_server_stream)
+}
+
+
+
+/// 处理正向代理模式下的明文HTTP请求
+///
+/// 每个客户端连接只处理一个请求，经过HTTP上游代理时以absolute-form转发。
+///
+/// # 参数
+/// * `request` - 请求头
+/// * `initial_body` - 读取请求头时已经读到的数据
+/// * `client_stream` - 客户端连接
+/// * `context` - 共享的组件
+async fn handle_http_request(
+    request: String,
+    initial_body: Vec<u8>,
+    mut client_stream: TcpStream,
+    context: Arc<ProxyContext>,
+) -> Result<()> {
+    let config = &context.config;
+    let start_time = Instant::now();
+    let lines: Vec<&str> = request.lines().collect();
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    let first_line = lines[0];
+    let parts: Vec<&str> = first_line.split_whitespace().collect();
+    
+    if parts.len() < 3 {
+        log::warn!("Invalid HTTP request: {first_line}");
+        return Ok(());
+    }
+
+    let method = parts[0];
+    let url = parts[1];
+    let _version = parts[2];
+
+    // 解析URL和目标
+    let (host, port, path) = match parse_url_and_target(url, &lines) {
+        Ok(target) => target,
+        Err(e) => {
+            client_stream.write_all(BAD_REQUEST_RESPONSE).await?;
+            return Err(e);
+        },
+    };
+    if let Some(by) = context.interceptors.on_connect(&host, port).await {
+        log::warn!("⛔ Connection to {host}:{port} dropped by {by}");
+        client_stream.write_all(FORBIDDEN_RESPONSE).await?;
+        return Ok(());
+    }
+    let mut client_stream = context.conditioner.wrap(&host, client_stream);
+
+    log::info!("🌐 HTTP REQUEST ==========================================");
+    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
+    log::info!("📝 Method: {method}");
+    log::info!("🔗 Path: {path}");
+    log::info!("🌐 Host: {host}:{port}");
+    log::info!("📋 Full Request:");
+    log::info!("{request}");
+
+    if config.should_intercept(&host, port) {
+        log::info!("Intercepting HTTP request to {host}:{port}{path}");
+    }
+
+    // 收集并打印原始请求头
+    let mut headers_map = HashMap::new();
+    for line in &lines[1..] {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon_pos) = line.find(':') {
+            let key = line[..colon_pos].trim().to_lowercase();
+            let value = line[colon_pos + 1..].trim().to_string();
+            log::info!("📋 Request Header: {key}: {value}");
+            headers_map.insert(key, value);
+        }
+    }
+
+    // 优先复用连接池中的连接，经过HTTP上游代理时连接的是代理服务器
+    let target = UpstreamTarget {
+        scheme: "http",
+        host: host.clone(),
+        port,
+        base_path: String::new(),
+    };
+    let mut slot = UpstreamSlot::new(target, http_forward_proxy(config, &host), None);
+    let request = ParsedRequest {
+        method,
+        path: &path,
+        header_lines: &lines[1..],
+        headers: headers_map,
+        body: &initial_body,
+        keep_alive: false,
+        client_identity: None,
+        start: start_time,
+    };
+    serve_exchange(&mut client_stream, request, &mut slot, &context).await?;
+
+    // 上游连接仍然可用时放回连接池
+    slot.checkin(&context.pool);
+
+    Ok(())
+}
+
+/// 在101响应之后转发升级后的协议数据
+///
+/// WebSocket连接按帧解析并记录每条消息，其他协议原样转发。
+///
+/// # 参数
+/// * `client_stream` - 客户端流
+/// * `server_stream` - 上游流
+/// * `client_initial` - 升级前已从客户端读取的数据
+/// * `server_initial` - 101响应之后已从上游读取的数据
+/// * `websocket` - 是否为WebSocket升级
+/// * `context` - WebSocket连接信息
+/// * `idle_timeout` - 连接空闲超时时间，超时后关闭连接并记录日志
+async fn relay_upgraded<C, S>(
+    client_stream: C,
+    server_stream: S,
+    client_initial: Vec<u8>,
+    server_initial: Vec<u8>,
+    websocket: bool,
+    context: WebSocketContext,
+    idle_timeout: Option<Duration>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start_time = Instant::now();
+    let tracker = IdleTracker::new();
+    let mut client_stream = tracker.wrap(client_stream);
+    let mut server_stream = tracker.wrap(server_stream);
+
+    let relay = async {
+        if websocket {
+            relay_websocket(&mut client_stream, &mut server_stream, client_initial, server_initial, context.clone()).await?;
+            return Ok(());
+        }
+
+        log::info!("Relaying upgraded connection to {} without inspection", context.url);
+        server_stream.write_all(&client_initial).await?;
+        client_stream.write_all(&server_initial).await?;
+        let (client_bytes, server_bytes) = tokio::io::copy_bidirectional(&mut client_stream, &mut server_stream).await?;
+        log::info!("Upgraded connection closed: client={client_bytes}, server={server_bytes}");
+        Ok::<_, anyhow::Error>(())
+    };
+
+    match tracker.run(idle_timeout, relay).await {
+        Ok(result) => result,
+        Err(e) => {
+            log::warn!("⏱️ {e}, closing upgraded connection to {}", context.url);
+            let mut log_entry = DomainLogger::create_tunnel_log_entry(
+                context.host.clone(),
+                start_time.elapsed().as_millis(),
+                Some(e.to_string()),
+            );
+            log_entry.method = if websocket { "WEBSOCKET" } else { "UPGRADE" }.to_string();
+            log_entry.path = context.url.clone();
+            log_entry.status_code = 101;
+            if websocket {
+                log_entry.protocol = "WebSocket".to_string();
+            }
+            context.logger.log_request(log_entry);
+            Ok(())
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProcessingResult {
+    Continue,
+    Complete,
+}
+
+
+
+
+
+fn parse_url_and_target(url: &str, lines: &[&str]) -> Result<(String, u16, String)> {
+    let url_info = UrlInfo::parse(url, lines)?;
+    Ok((url_info.host, url_info.port, url_info.path))
+}
+
+#[derive(Debug)]
+struct UrlInfo {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl UrlInfo {
+    fn parse(url: &str, lines: &[&str]) -> Result<Self> {
+        // origin-form以/开头，其他形式按absolute-form解析
+        if url.starts_with('/') {
+            Self::parse_relative_url(url, lines)
+        } else {
+            Self::parse_absolute_url(url)
+        }
+    }
+
+    fn parse_absolute_url(url: &str) -> Result<Self> {
+        let url = parse_absolute_url(url)?;
+        Ok(UrlInfo {
+            host: url.host,
+            port: url.port,
+            path: url.path,
+        })
+    }
+
+    fn parse_relative_url(url: &str, lines: &[&str]) -> Result<Self> {
+        let host_line = lines
+            .iter()
+            .find(|line| line.to_lowercase().starts_with("host:"))
+            .ok_or_else(|| anyhow::anyhow!("Missing Host header"))?;
+
+        let host_info = host_line[5..].trim();
+        let (host, port) = parse_authority(host_info, 80)?;
+        
+        Ok(UrlInfo {
+            host,
+            port,
+            path: url.to_string(),
+        })
+    }
+}
+
+
+
+fn load_certificates(cert_pem: &[u8]) -> Vec<rustls::Certificate> {
+    let mut reader = BufReader::new(Cursor::new(cert_pem));
+    rustls_pemfile::certs(&mut reader)
+        .unwrap()
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect()
+}
+
+fn load_private_key(key_pem: &[u8]) -> rustls::PrivateKey {
+    let mut reader = BufReader::new(Cursor::new(key_pem));
+    let key = rustls_pemfile::pkcs8_private_keys(&mut reader)
+        .unwrap()
+        .into_iter()
+        .next()
+        .unwrap();
+    rustls::PrivateKey(key)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_content_length_framing() {
+        let mut framer = BodyFramer::new(BodyFraming::ContentLength { remaining: 5 });
+        let mut body = Vec::new();
+
+        assert_eq!(framer.advance(b"abc", |b| body.extend_from_slice(b)).unwrap(), 3);
+        assert!(!framer.is_complete());
+        assert_eq!(framer.advance(b"deGET", |b| body.extend_from_slice(b)).unwrap(), 2);
+        assert!(framer.is_complete());
+        assert_eq!(body, b"abcde");
+    }
+
+    #[test]
+    fn test_chunked_framing_split_reads() {
+        let data = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let mut framer = BodyFramer::new(BodyFraming::Chunked);
+        let mut body = Vec::new();
+        let mut consumed = 0;
+
+        // 逐字节输入，覆盖所有跨读边界的情况
+        for i in 0..data.len() {
+            if framer.is_complete() {
+                break;
+            }
+            consumed += framer.advance(&data[i..i + 1], |b| body.extend_from_slice(b)).unwrap();
+        }
+
+        assert!(framer.is_complete());
+        assert_eq!(body, b"Wikipedia");
+        assert_eq!(&data[consumed..], b"NEXT");
+    }
+
+    #[test]
+    fn test_invalid_chunk_size() {
+        let mut framer = BodyFramer::new(BodyFraming::Chunked);
+        assert!(framer.advance(b"zz\r\n", |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_is_keep_alive() {
+        assert!(is_keep_alive("HTTP/1.1", None));
+        assert!(!is_keep_alive("HTTP/1.1", Some("close")));
+        assert!(!is_keep_alive("HTTP/1.0", None));
+        assert!(is_keep_alive("HTTP/1.0", Some("Keep-Alive")));
+    }
+
+    #[tokio::test]
+    async fn test_request_processor_content_length() {
+        let mut headers = HashMap::new();
+        headers.insert("content-length".to_string(), "6".to_string());
+        let mut processor = HttpRequestProcessor::new(&headers, 4);
+
+        // 二进制请求体分多次到达，多出的数据属于下一个请求
+        let mut client: &[u8] = b"\xff\x00\x01GET / HTTP/1.1\r\n";
+        let mut server = Vec::new();
+        let rest = processor.forward_body(b"\x00\x80\xfe", &mut client, &mut server).await.unwrap();
+
+        assert_eq!(server, b"\x00\x80\xfe\xff\x00\x01");
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+        assert_eq!(processor.body_capture.body.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_request_processor_chunked() {
+        let mut headers = HashMap::new();
+        headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+        let mut processor = HttpRequestProcessor::new(&headers, -1);
+
+        let mut client: &[u8] = b"llo\r\n0\r\n\r\n";
+        let mut server = Vec::new();
+        let rest = processor.forward_body(b"5\r\nhe", &mut client, &mut server).await.unwrap();
+
+        assert_eq!(server, b"5\r\nhello\r\n0\r\n\r\n");
+        assert!(rest.is_empty());
+        assert_eq!(processor.get_body(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_request_processor_truncated_body() {
+        let mut headers = HashMap::new();
+        headers.insert("content-length".to_string(), "10".to_string());
+        let mut processor = HttpRequestProcessor::new(&headers, -1);
+
+        let mut client: &[u8] = b"";
+        let mut server = Vec::new();
+        assert!(processor.forward_body(b"short", &mut client, &mut server).await.is_err());
+
+        // 没有请求体的请求原样返回已读取的数据
+        let mut processor = HttpRequestProcessor::new(&HashMap::new(), -1);
+        assert!(!processor.has_body());
+        let rest = processor.forward_body(b"next", &mut client, &mut server).await.unwrap();
+        assert_eq!(rest, b"next");
+    }
+
+    #[tokio::test]
+    async fn test_response_processor_split_headers() {
+        let mut processor = HttpResponseProcessor::new(-1, "GET");
+        let mut client = Vec::new();
+
+        let result = processor.process_chunk(b"HTTP/1.1 200 OK\r\nContent-Le", &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Continue);
+        assert!(client.is_empty());
+
+        let result = processor.process_chunk(b"ngth: 5\r\n\r\nhel", &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Continue);
+        let result = processor.process_chunk(b"lo", &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Complete);
+
+        processor.finish();
+        assert_eq!(client, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        assert_eq!(processor.status_code, 200);
+        assert_eq!(processor.get_decompressed_body(), "hello");
+        assert!(processor.keep_alive());
+    }
+
+    #[tokio::test]
+    async fn test_response_processor_without_body() {
+        // HEAD响应带有Content-Length但没有响应体
+        let mut processor = HttpResponseProcessor::new(-1, "HEAD");
+        let mut client = Vec::new();
+        let result = processor.process_chunk(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n", &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Complete);
+
+        // 100 Continue之后是最终响应
+        let mut processor = HttpResponseProcessor::new(-1, "POST");
+        let mut client = Vec::new();
+        let data = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n";
+        let result = processor.process_chunk(data, &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Complete);
+        assert_eq!(processor.status_code, 204);
+        assert_eq!(client, data);
+    }
+
+    #[tokio::test]
+    async fn test_response_processor_until_close() {
+        let mut processor = HttpResponseProcessor::new(-1, "GET");
+        let mut client = Vec::new();
+        let result = processor.process_chunk(b"HTTP/1.1 200 OK\r\n\r\ndata", &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Continue);
+        assert!(!processor.keep_alive());
+    }
+
+    #[tokio::test]
+    async fn test_response_processor_gzip_body() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"compressed body").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            compressed.len()
+        ).into_bytes();
+        response.extend_from_slice(&compressed);
+
+        let mut processor = HttpResponseProcessor::new(-1, "GET");
+        let mut client = Vec::new();
+        // 分两次输入，验证流式解压
+        let (first, second) = response.split_at(response.len() - 4);
+        processor.process_chunk(first, &mut client).await.unwrap();
+        let result = processor.process_chunk(second, &mut client).await.unwrap();
+        assert_eq!(result, ProcessingResult::Complete);
+
+        processor.finish();
+        assert_eq!(client, response);
+        assert_eq!(processor.get_decompressed_body(), "compressed body");
+    }
+
+    /// 固定的原始目标地址，模拟iptables重定向
+    struct FixedDestination(SocketAddr);
+
+    impl OriginalDestination for FixedDestination {
+        fn lookup(&self, _stream: &TcpStream) -> std::io::Result<SocketAddr> {
+            Ok(self.0)
+        }
+    }
+
+    /// 创建处理连接共享的组件，CA证书使用配置中的路径
+    fn create_context(config: &Arc<Config>, interceptors: Arc<InterceptorChain>, logger: &Arc<DomainLogger>) -> Arc<ProxyContext> {
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
+        Arc::new(ProxyContext {
+            config: Arc::clone(config),
+            cert_manager: Arc::new(cert_manager),
+            pool: Arc::new(ConnectionPool::new(config.upstream_pool.clone())),
+            tls_connectors: Arc::new(TlsConnectors::new(config).unwrap()),
+            logger: Arc::clone(logger),
+            conditioner: Arc::new(NetworkConditioner::new(Arc::clone(config)).unwrap()),
+            interceptors,
+            shutdown: ShutdownHandle::new(),
+            pinned_host: None,
+        })
+    }
+
+    fn create_interceptors(config: &Arc<Config>) -> Arc<InterceptorChain> {
+        let breakpoints = Arc::new(Breakpoints::new(Arc::clone(config)));
+        Arc::new(InterceptorChain::new(vec![breakpoints]))
+    }
+
+    /// 创建测试配置，证书和日志放在临时目录中
+    fn create_test_config(domains: &str, temp_dir: &std::path::Path) -> Config {
+        let dir = temp_dir.to_str().unwrap();
+        serde_json::from_str(&format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": [{domains}], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "{dir}/ca.crt", "ca_key": "{dir}/ca.key" }},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "{dir}",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }}
+        }}"#)).unwrap()
+    }
+
+    /// 启动透明代理监听，返回监听地址
+    async fn start_transparent_proxy(target: SocketAddr, domains: &str, temp_dir: &std::path::Path) -> SocketAddr {
+        let config = Arc::new(create_test_config(domains, temp_dir));
+        let logger = DomainLogger::new(Arc::clone(&config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_transparent_listener(
+            listener,
+            Arc::new(FixedDestination(target)),
+            create_context(&config, create_interceptors(&config), &logger),
+        ));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_transparent_raw_tcp_tunnel() {
+        // 回显服务器作为原始目标
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let proxy = start_transparent_proxy(target, r#""*""#, temp_dir.path()).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+        let mut echoed = [0; 14];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"SSH-2.0-test\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_transparent_http_intercept() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        let server_task = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut buffer = Vec::new();
+            let header_end = read_http_head(&mut stream, &mut buffer).await.unwrap().unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap();
+            String::from_utf8_lossy(&buffer[..header_end]).to_string()
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let proxy = start_transparent_proxy(target, r#""app.transparent.invalid""#, temp_dir.path()).await;
+
+        // 透明模式下客户端发送origin-form请求，Host中的域名无法解析，
+        // 代理必须连接原始目标地址而不是按Host重新解析
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let host = format!("app.transparent.invalid:{}", target.port());
+        let request = format!("GET /status HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"), "{}", String::from_utf8_lossy(&response));
+        assert!(response.ends_with(b"\r\n\r\nok"));
+
+        // 原始目标收到请求，Host头保留客户端使用的域名
+        let request = server_task.await.unwrap();
+        assert!(request.starts_with("GET /status HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("Host: {host}\r\n")));
+    }
+
+    #[test]
+    fn test_upstream_target_from_base_url() {
+        let target = UpstreamTarget::from_base_url("https://backend.local/api/").unwrap();
+        assert_eq!((target.scheme, target.host.as_str(), target.port), ("https", "backend.local", 443));
+        assert_eq!(target.base_path, "/api");
+
+        let target = UpstreamTarget::from_base_url("http://[::1]:3000").unwrap();
+        assert_eq!((target.scheme, target.host.as_str(), target.port), ("http", "::1", 3000));
+        assert_eq!(target.base_path, "");
+
+        assert!(UpstreamTarget::from_base_url("ftp://example.com").is_err());
+        assert!(UpstreamTarget::from_base_url("http://example.com:port").is_err());
+    }
+
+    /// 上游测试服务，记录收到的连接数和请求（请求头和请求体）
+    struct TestBackend {
+        addr: SocketAddr,
+        connections: Arc<std::sync::atomic::AtomicUsize>,
+        requests: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl TestBackend {
+        fn connections(&self) -> usize {
+            self.connections.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    /// 返回文本响应体的200响应
+    fn text_response(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    /// 在一个上游连接上按keep-alive处理请求，请求体按Content-Length读取
+    ///
+    /// 每个请求记录后用 `respond` 生成的响应应答，响应带有 `Connection: close` 时关闭连接。
+    async fn serve_backend_connection<S, F, R>(mut stream: S, respond: Arc<F>, requests: Arc<std::sync::Mutex<Vec<String>>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        F: Fn(&str, &[u8]) -> R,
+        R: Into<Vec<u8>>,
+    {
+        let mut buffer = Vec::new();
+        while let Ok(Some(header_end)) = read_http_head(&mut stream, &mut buffer).await {
+            let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+            let length: usize = head.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map(|(_, length)| length.trim().parse().unwrap())
+                .unwrap_or(0);
+            while buffer.len() < header_end + length {
+                let mut chunk = [0; 1024];
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                }
+            }
+            let body = buffer[header_end..header_end + length].to_vec();
+            requests.lock().unwrap().push(String::from_utf8_lossy(&buffer[..header_end + length]).to_string());
+            buffer.drain(..header_end + length);
+
+            let response: Vec<u8> = respond(&head, &body).into();
+            if stream.write_all(&response).await.is_err() {
+                return;
+            }
+            let response_head = String::from_utf8_lossy(&response).split("\r\n\r\n").next().unwrap_or("").to_string();
+            if response_head.contains("\r\nConnection: close") {
+                return;
+            }
+        }
+    }
+
+    /// 启动上游测试服务，`respond` 按请求头和请求体生成响应
+    async fn spawn_backend<F, R>(respond: F) -> TestBackend
+    where
+        F: Fn(&str, &[u8]) -> R + Send + Sync + 'static,
+        R: Into<Vec<u8>> + 'static,
+    {
+        spawn_tls_backend(None, respond).await
+    }
+
+    /// 启动上游测试服务，`acceptor` 不为空时使用TLS
+    async fn spawn_tls_backend<F, R>(acceptor: Option<TlsAcceptor>, respond: F) -> TestBackend
+    where
+        F: Fn(&str, &[u8]) -> R + Send + Sync + 'static,
+        R: Into<Vec<u8>> + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = TestBackend {
+            addr: listener.local_addr().unwrap(),
+            connections: Default::default(),
+            requests: Default::default(),
+        };
+        let connections = Arc::clone(&backend.connections);
+        let requests = Arc::clone(&backend.requests);
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let respond = Arc::clone(&respond);
+                let requests = Arc::clone(&requests);
+                match acceptor.clone() {
+                    Some(acceptor) => {
+                        tokio::spawn(async move {
+                            if let Ok(stream) = acceptor.accept(stream).await {
+                                serve_backend_connection(stream, respond, requests).await;
+                            }
+                        });
+                    },
+                    None => {
+                        tokio::spawn(serve_backend_connection(stream, respond, requests));
+                    },
+                }
+            }
+        });
+        backend
+    }
+
+    /// 启动HTTP代理监听，返回监听地址
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    /// * `interceptors` - 拦截器链
+    /// * `logger` - 日志记录器
+    async fn spawn_proxy(config: &Arc<Config>, interceptors: Arc<InterceptorChain>, logger: &Arc<DomainLogger>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_proxy_listener(
+            listener,
+            create_context(config, interceptors, logger),
+        ));
+        addr
+    }
+
+    /// 启动SOCKS5代理监听，返回监听地址
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    /// * `interceptors` - 拦截器链
+    /// * `logger` - 日志记录器
+    async fn spawn_socks5_proxy(config: &Arc<Config>, interceptors: Arc<InterceptorChain>, logger: &Arc<DomainLogger>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_socks5_listener(
+            listener,
+            create_context(config, interceptors, logger),
+        ));
+        addr
+    }
+
+    /// 通过SOCKS5代理请求连接目标（无认证），返回连接和应答码
+    async fn socks5_connect(proxy: SocketAddr, host: &str, port: u16) -> (TcpStream, u8) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+        client.write_all(&[5, 1, 0, 3, host.len() as u8]).await.unwrap();
+        client.write_all(host.as_bytes()).await.unwrap();
+        client.write_all(&port.to_be_bytes()).await.unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        (client, reply[1])
+    }
+
+    /// 通过HTTP代理的CONNECT隧道与目标建立TLS连接，信任临时目录中的代理CA
+    ///
+    /// # 参数
+    /// * `proxy` - 代理地址
+    /// * `host` - 目标主机
+    /// * `port` - 目标端口
+    /// * `temp_dir` - 测试配置所在的临时目录
+    async fn connect_tls_via_proxy(proxy: SocketAddr, host: &str, port: u16, temp_dir: &std::path::Path) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let client = open_connect_tunnel(proxy, host, port).await;
+        connect_tls_trusting_proxy_ca(client, host, &[], temp_dir).await
+    }
+
+    /// 通过HTTP代理的CONNECT请求建立到目标的隧道
+    async fn open_connect_tunnel(proxy: SocketAddr, host: &str, port: u16) -> TcpStream {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n").as_bytes()).await.unwrap();
+        let mut buffer = Vec::new();
+        let header_end = read_http_head(&mut client, &mut buffer).await.unwrap().unwrap();
+        assert!(buffer[..header_end].starts_with(b"HTTP/1.1 200"));
+        client
+    }
+
+    /// 在已经通往目标的连接上进行TLS握手，信任临时目录中的代理CA
+    ///
+    /// # 参数
+    /// * `client` - 通往目标的连接
+    /// * `host` - 目标主机
+    /// * `alpn` - 客户端提供的ALPN协议
+    /// * `temp_dir` - 测试配置所在的临时目录
+    async fn connect_tls_trusting_proxy_ca(client: TcpStream, host: &str, alpn: &[&[u8]], temp_dir: &std::path::Path) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut roots = rustls::RootCertStore::empty();
+        let ca = std::fs::read(temp_dir.join("ca.crt")).unwrap();
+        for cert in rustls_pemfile::certs(&mut &ca[..]).unwrap() {
+            roots.add(&rustls::Certificate(cert)).unwrap();
+        }
+        let mut tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
+        connector.connect(host.try_into().unwrap(), client).await.unwrap()
+    }
+
+    /// 读取一个按Content-Length分帧的响应
+    async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        let mut buffer = Vec::new();
+        let header_end = read_http_head(stream, &mut buffer).await.unwrap().unwrap();
+        let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+        let length: usize = head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, length)| length.trim().parse().unwrap())
+            .unwrap_or(0);
+        while buffer.len() < header_end + length {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the response body");
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+        String::from_utf8_lossy(&buffer[..header_end + length]).to_string()
+    }
+
+    /// 创建拦截HTTPS测试目标的配置：目标域名解析到本机，信任代理CA签发的上游证书
+    ///
+    /// # 参数
+    /// * `host` - 拦截的目标域名
+    /// * `temp_dir` - 证书和日志所在的临时目录
+    ///
+    /// # 返回值
+    /// 返回配置和上游测试服务使用的TLS acceptor
+    fn create_tls_test_config(host: &str, temp_dir: &std::path::Path) -> (Config, TlsAcceptor) {
+        let mut config = create_test_config(&format!("\"{host}\""), temp_dir);
+        config.dns.hosts.insert(host.to_string(), "127.0.0.1".parse().unwrap());
+        config.upstream_tls.ca_bundles = vec![config.certificates.ca_cert.clone()];
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
+        let acceptor = create_tls_acceptor(&cert_manager, host).unwrap();
+        (config, acceptor)
+    }
+
+    #[tokio::test]
+    async fn test_connect_tls_keep_alive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (config, acceptor) = create_tls_test_config("secure.test", temp_dir.path());
+        let backend = spawn_tls_backend(Some(acceptor), |_, _| text_response("ok")).await;
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        // 同一个拦截的TLS连接上依次发送两个请求，第二个请求要求关闭连接
+        let port = backend.addr.port();
+        let mut client = connect_tls_via_proxy(proxy, "secure.test", port, temp_dir.path()).await;
+        client.write_all(format!("GET /first HTTP/1.1\r\nHost: secure.test:{port}\r\n\r\n").as_bytes()).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\nok"), "{response}");
+
+        client.write_all(format!("GET /second HTTP/1.1\r\nHost: secure.test:{port}\r\nConnection: close\r\n\r\n").as_bytes()).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.ends_with("\r\n\r\nok"), "{response}");
+        // Connection: close结束请求循环，代理关闭客户端连接
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await;
+        assert!(closed.is_ok(), "proxy kept the connection open after Connection: close");
+        assert!(rest.is_empty());
+
+        let requests = backend.requests();
+        assert_eq!(backend.connections(), 1);
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("GET /first HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("GET /second HTTP/1.1\r\n"));
+
+        // 每次请求应答各自记录一条日志
+        let log = read_logs(&logger, temp_dir.path()).await;
+        for path in ["/first", "/second"] {
+            let line = format!(" secure.test GET https://secure.test:{port}{path} - Status: 200 ");
+            assert_eq!(log.matches(&line).count(), 1, "{log}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_tls_negotiates_h2() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (config, _) = create_tls_test_config("h2.test", temp_dir.path());
+
+        // 上游只支持h2，应答请求的路径
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
+        let (cert_pem, key_pem) = cert_manager.generate_site_cert("h2.test").unwrap();
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(load_certificates(&cert_pem), load_private_key(&key_pem))
+            .unwrap();
+        server_config.alpn_protocols = vec![ALPN_H2.to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = backend.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(ALPN_H2));
+            let mut connection = h2::server::handshake(stream).await.unwrap();
+            while let Some(result) = connection.accept().await {
+                let (request, mut respond) = result.unwrap();
+                let response = http::Response::builder().status(200).body(()).unwrap();
+                let mut send = respond.send_response(response, false).unwrap();
+                send.send_data(bytes::Bytes::from(request.uri().path().to_string()), true).unwrap();
+            }
+        });
+
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        // 客户端提供h2时代理与两侧都协商h2
+        let client = open_connect_tunnel(proxy, "h2.test", port).await;
+        let client = connect_tls_trusting_proxy_ca(client, "h2.test", &[ALPN_H2, ALPN_HTTP11], temp_dir.path()).await;
+        assert_eq!(client.get_ref().1.alpn_protocol(), Some(ALPN_H2));
+        let (send_request, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let mut send_request = send_request.ready().await.unwrap();
+        let request = http::Request::get(format!("https://h2.test:{port}/hello")).body(()).unwrap();
+        let (response, _) = send_request.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, b"/hello");
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("GET https://h2.test:{port}/hello - Status: 200")), "{log}");
+        assert!(log.contains("  Protocol: HTTP/2\n"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn test_socks5_intercepts_http_and_tls() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (mut config, acceptor) = create_tls_test_config("secure.test", temp_dir.path());
+        config.target.domains.push("127.0.0.1".to_string());
+        let tls_backend = spawn_tls_backend(Some(acceptor), |_, _| text_response("tls")).await;
+        let backend = spawn_backend(|_, _| text_response("plain")).await;
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_socks5_proxy(&config, create_interceptors(&config), &logger).await;
+
+        // 明文HTTP经过SOCKS5隧道后按HTTP处理并记录
+        let port = backend.addr.port();
+        let (mut client, reply) = socks5_connect(proxy, "127.0.0.1", port).await;
+        assert_eq!(reply, 0x00);
+        client.write_all(format!("GET /plain HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\n\r\n").as_bytes()).await.unwrap();
+        assert!(read_response(&mut client).await.ends_with("\r\n\r\nplain"));
+
+        // TLS连接由代理CA签发的证书终止后转发给上游
+        let tls_port = tls_backend.addr.port();
+        let (client, reply) = socks5_connect(proxy, "secure.test", tls_port).await;
+        assert_eq!(reply, 0x00);
+        let mut client = connect_tls_trusting_proxy_ca(client, "secure.test", &[], temp_dir.path()).await;
+        client.write_all(format!("GET /secure HTTP/1.1\r\nHost: secure.test:{tls_port}\r\n\r\n").as_bytes()).await.unwrap();
+        assert!(read_response(&mut client).await.ends_with("\r\n\r\ntls"));
+        drop(client);
+
+        assert!(backend.requests()[0].starts_with("GET /plain HTTP/1.1\r\n"));
+        assert!(tls_backend.requests()[0].starts_with("GET /secure HTTP/1.1\r\n"));
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("GET http://127.0.0.1:{port}/plain - Status: 200")), "{log}");
+        assert!(log.contains(&format!("GET https://secure.test:{tls_port}/secure - Status: 200")), "{log}");
+    }
+
+    /// 启动反向代理监听，返回监听地址
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    /// * `upstream` - 上游基础URL
+    /// * `interceptors` - 拦截器链
+    /// * `logger` - 日志记录器
+    async fn spawn_reverse_proxy(config: &Arc<Config>, upstream: &str, interceptors: Arc<InterceptorChain>, logger: &Arc<DomainLogger>) -> SocketAddr {
+        let target = UpstreamTarget::from_base_url(upstream).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_reverse_proxy_listener(
+            listener,
+            target,
+            None,
+            create_context(config, interceptors, logger),
+        ));
+        addr
+    }
+
+    /// 写入所有日志后读取目录中的日志文件
+    async fn read_logs(logger: &DomainLogger, dir: &std::path::Path) -> String {
+        logger.flush().await;
+        std::fs::read_dir(dir).unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_keep_alive() {
+        let backend = spawn_backend(|_, _| text_response("ok")).await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{}/api", backend.addr), create_interceptors(&config), &logger).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        for path in ["/users", "/orders?id=1"] {
+            let request = format!("GET {path} HTTP/1.1\r\nHost: frontend.local\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = [0; 40];
+            client.read_exact(&mut response).await.unwrap();
+            assert!(response.ends_with(b"\r\n\r\nok"));
+        }
+        drop(client);
+
+        // 上游服务在一个连接上依次应答两个请求
+        let requests = backend.requests();
+        assert_eq!(backend.connections(), 1);
+        assert!(requests[0].starts_with("GET /api/users HTTP/1.1\r\n"));
+        assert!(requests[0].contains(&format!("Host: {}\r\n", backend.addr)));
+        assert!(requests[1].starts_with("GET /api/orders?id=1 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_fault_injection() {
+        // 上游服务只应收到没有被status和reset故障拦截的请求
+        let backend = spawn_backend(|_, _| text_response("0123456789")).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.faults = serde_json::from_str(r#"{ "rules": [
+            { "paths": ["/down"], "fault": { "type": "status", "status": 503, "body": "maintenance" } },
+            { "paths": ["/big"], "methods": ["GET"], "fault": { "type": "truncate", "bytes": 5 } },
+            { "paths": ["/reset"], "fault": { "type": "reset" } },
+            { "paths": ["/close"], "fault": { "type": "close_after", "bytes": 20 } },
+            { "paths": ["/corrupt"], "fault": { "type": "corrupt", "rate": 1.0 } },
+            { "paths": ["/slow"], "fault": { "type": "delay", "ms": 200 } },
+            { "paths": ["/never"], "fault": { "type": "status", "status": 500 }, "probability": 0 }
+        ] }"#).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{}/api", backend.addr), create_interceptors(&config), &logger).await;
+
+        let exchange = |path: &'static str| async move {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\nHost: frontend.local\r\nConnection: close\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.map(|_| String::from_utf8_lossy(&response).to_string())
+        };
+
+        // status故障不连接上游，返回响应后关闭连接
+        let response = exchange("/down").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("\r\n\r\nmaintenance"));
+
+        // truncate故障转发5字节响应体后关闭连接
+        let response = exchange("/big").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n"));
+        assert!(response.ends_with("\r\n\r\n01234"));
+
+        // reset故障以RST断开连接，客户端看到连接被重置
+        let error = exchange("/reset").await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+
+        // close_after故障转发指定字节数（包括响应头）后关闭连接
+        assert_eq!(exchange("/close").await.unwrap(), "HTTP/1.1 200 OK\r\nCon");
+
+        // corrupt故障修改响应体，不改变长度
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /corrupt HTTP/1.1\r\nHost: frontend.local\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let (head, body) = response.split_at(response.len() - 10);
+        assert!(head.ends_with(b"Content-Length: 10\r\n\r\n"));
+        assert!(body.iter().zip(b"0123456789").all(|(corrupted, original)| corrupted != original));
+
+        // delay故障延迟后返回完整的响应
+        let start = Instant::now();
+        assert!(exchange("/slow").await.unwrap().ends_with("\r\n\r\n0123456789"));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // 概率为0的规则不生效，请求正常转发
+        assert!(exchange("/never").await.unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let paths: Vec<String> = backend.requests().iter()
+            .map(|request| request.split_whitespace().nth(1).unwrap().to_string())
+            .collect();
+        assert_eq!(paths, ["/api/big", "/api/close", "/api/corrupt", "/api/slow", "/api/never"]);
+
+        // 注入故障的请求在日志中标记
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("Fault Injected: status 503"));
+        assert!(log.contains("Fault Injected: truncate body to 5 bytes"));
+        assert!(log.contains("Connection reset by fault injection"));
+        assert!(log.contains("Fault Injected: close after 20 bytes"));
+        assert!(log.contains("Fault Injected: delay headers 200ms"));
+        assert!(!log.contains("Fault Injected: status 500"));
+    }
+
+    /// 通过正向代理发送一个absolute-form请求，读取到代理关闭连接为止的响应
+    async fn forward_exchange(proxy: SocketAddr, request: &str) -> std::io::Result<String> {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        Ok(String::from_utf8_lossy(&response).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_forward_proxy_fault_injection() {
+        let backend = spawn_backend(|_, _| text_response("0123456789")).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.faults = serde_json::from_str(r#"{ "rules": [
+            { "paths": ["/down"], "fault": { "type": "status", "status": 503, "body": "maintenance" } },
+            { "paths": ["/big"], "fault": { "type": "truncate", "bytes": 5 } },
+            { "paths": ["/reset"], "fault": { "type": "reset" } }
+        ] }"#).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+        let request = |path: &str| format!("GET http://{}{path} HTTP/1.1\r\nHost: {}\r\n\r\n", backend.addr, backend.addr);
+
+        // 正向代理的明文请求与拦截的连接使用相同的故障规则
+        let response = forward_exchange(proxy, &request("/down")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("\r\n\r\nmaintenance"));
+        let response = forward_exchange(proxy, &request("/big")).await.unwrap();
+        assert!(response.ends_with("\r\n\r\n01234"), "{response}");
+        let error = forward_exchange(proxy, &request("/reset")).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("GET /big HTTP/1.1\r\n"));
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("Fault Injected: status 503"));
+        assert!(log.contains("Fault Injected: truncate body to 5 bytes"));
+        assert!(log.contains("Connection reset by fault injection"));
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_map_local() {
+        // 上游不可用，匹配Map Local规则的请求仍然由本地文件应答
+        let backend_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let site = temp_dir.path().join("site");
+        std::fs::create_dir(&site).unwrap();
+        std::fs::write(site.join("app.js"), "console.log(1)").unwrap();
+
+        let mut config = create_test_config(r#""*""#, &temp_dir.path().join("logs"));
+        config.map_local = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/static/*"], "local": site, "headers": { "Cache-Control": "no-store" } }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{backend_addr}"), create_interceptors(&config), &logger).await;
+
+        // 请求体被读取后连接可以继续处理下一个请求
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"POST /static/app.js HTTP/1.1\r\nHost: frontend.local\r\nContent-Length: 4\r\n\r\nping").await.unwrap();
+        client.write_all(b"GET /static/missing.js HTTP/1.1\r\nHost: frontend.local\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let (first, second) = response.split_at(response.find("HTTP/1.1 404").unwrap());
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(first.contains("Content-Type: text/javascript\r\n"));
+        assert!(first.contains("Cache-Control: no-store\r\n"));
+        assert!(first.ends_with("\r\n\r\nconsole.log(1)"));
+        assert!(second.contains("Connection: close\r\n"));
+        assert!(second.ends_with("\r\n\r\nNot Found\n"), "{second}");
+
+        let log = read_logs(&logger, &temp_dir.path().join("logs")).await;
+        assert!(log.contains(&format!("Mapped To: file://{}", site.join("app.js").display())));
+    }
+
+    #[tokio::test]
+    async fn test_forward_proxy_map_local() {
+        // 上游不可用，匹配Map Local规则的明文请求仍然由本地文件应答
+        let backend_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let site = temp_dir.path().join("site");
+        std::fs::create_dir(&site).unwrap();
+        std::fs::write(site.join("app.js"), "console.log(1)").unwrap();
+
+        let mut config = create_test_config(r#""*""#, &temp_dir.path().join("logs"));
+        config.map_local = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/static/*"], "local": site }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        // 每个正向代理连接只处理一个请求，应答中声明关闭连接
+        let request = format!("POST http://{backend_addr}/static/app.js HTTP/1.1\r\nHost: {backend_addr}\r\nContent-Length: 4\r\n\r\nping");
+        let response = forward_exchange(proxy, &request).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Connection: close\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nconsole.log(1)"));
+
+        let log = read_logs(&logger, &temp_dir.path().join("logs")).await;
+        assert!(log.contains(&format!("Mapped To: file://{}", site.join("app.js").display())));
+        assert!(log.contains("  Request Body: ping\n"));
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_map_remote() {
+        let origin = spawn_backend(|_, _| text_response("origin")).await;
+        let mapped = spawn_backend(|_, _| text_response("mapped")).await;
+        let (origin_addr, mapped_addr) = (origin.addr, mapped.addr);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.map_remote = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/v2/*"], "to": format!("http://{mapped_addr}/api") },
+            { "paths": ["/keep/*"], "to": format!("http://{mapped_addr}"), "preserve_host": true }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{origin_addr}"), create_interceptors(&config), &logger).await;
+
+        // 同一个客户端连接上的请求按规则发往不同的上游
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        for (path, expected) in [("/users", "origin"), ("/v2/items?x=1", "mapped"), ("/keep/a", "mapped"), ("/users", "origin")] {
+            let request = format!("GET {path} HTTP/1.1\r\nHost: frontend.local\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = vec![0; 44];
+            client.read_exact(&mut response).await.unwrap();
+            assert!(response.ends_with(expected.as_bytes()), "{path}");
+        }
+        drop(client);
+
+        assert_eq!(origin.requests().len(), 2);
+        let mapped_requests = mapped.requests();
+        assert!(mapped_requests[0].starts_with("GET /api/items?x=1 HTTP/1.1\r\n"));
+        assert!(mapped_requests[0].contains(&format!("Host: {mapped_addr}\r\n")));
+        assert!(mapped_requests[1].starts_with("GET /keep/a HTTP/1.1\r\n"));
+        assert!(mapped_requests[1].contains(&format!("Host: {origin_addr}\r\n")));
+
+        // 日志同时记录原始URL和映射后的URL
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("GET http://{origin_addr}/v2/items?x=1 - Status: 200")));
+        assert!(log.contains(&format!("Mapped To: http://{mapped_addr}/api/items?x=1")));
+    }
+
+    #[tokio::test]
+    async fn test_forward_proxy_map_remote() {
+        let origin = spawn_backend(|_, _| text_response("origin")).await;
+        let mapped = spawn_backend(|_, _| text_response("mapped")).await;
+        let (origin_addr, mapped_addr) = (origin.addr, mapped.addr);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.map_remote = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/v2/*"], "to": format!("http://{mapped_addr}/api") }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        for (path, expected) in [("/v2/items?x=1", "mapped"), ("/users", "origin")] {
+            let request = format!("GET http://{origin_addr}{path} HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n");
+            let response = forward_exchange(proxy, &request).await.unwrap();
+            assert!(response.ends_with(expected), "{path}: {response}");
+        }
+
+        // 映射后的请求使用origin-form并改写Host头
+        let mapped_requests = mapped.requests();
+        assert!(mapped_requests[0].starts_with("GET /api/items?x=1 HTTP/1.1\r\n"));
+        assert!(mapped_requests[0].contains(&format!("Host: {mapped_addr}\r\n")));
+        assert!(origin.requests()[0].starts_with("GET /users HTTP/1.1\r\n"));
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("GET http://{origin_addr}/v2/items?x=1 - Status: 200")));
+        assert!(log.contains(&format!("Mapped To: http://{mapped_addr}/api/items?x=1")));
+    }
+
+    #[tokio::test]
+    async fn test_forward_proxy_websocket_frames() {
+        // 上游完成升级后应答一条文本消息并关闭WebSocket
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = Vec::new();
+            read_http_head(&mut stream, &mut buffer).await.unwrap().unwrap();
+            stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n").await.unwrap();
+            let mut frame = [0; 10];
+            stream.read_exact(&mut frame).await.unwrap();
+            stream.write_all(&[0x81, 4, b'p', b'o', b'n', b'g', 0x88, 2, 0x03, 0xe8]).await.unwrap();
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET http://{addr}/ws HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut buffer = Vec::new();
+        let header_end = read_http_head(&mut client, &mut buffer).await.unwrap().unwrap();
+        assert!(buffer[..header_end].starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+        // 升级之后的帧原样转发
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x81, 0x84];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"ping".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        client.write_all(&frame).await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x81, 4, b'p', b'o', b'n', b'g', 0x88, 2, 0x03, 0xe8]);
+
+        // 每条消息单独记录方向、操作码和内容
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("GET http://{addr}/ws - Status: 101")), "{log}");
+        assert!(log.contains(&format!("WEBSOCKET ws://{addr}/ws")), "{log}");
+        assert!(log.contains("  WebSocket: client->server opcode=0x1 fragments=1 masked=true"), "{log}");
+        assert!(log.contains("  WebSocket: server->client opcode=0x1 fragments=1 masked=false"), "{log}");
+        assert!(log.contains("close_code=Some(1000)"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_header_rewrite() {
+        let backend = spawn_backend(|_, _| text_response("ok")).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.headers = serde_json::from_value(serde_json::json!({ "rules": [
+            { "action": "default", "name": "User-Agent", "value": "study-proxy" },
+            { "action": "set", "name": "X-Env", "value": "staging" },
+            { "action": "remove", "name": "Cookie" },
+            { "methods": ["POST"], "action": "add", "name": "X-Post", "value": "1" },
+            { "phase": "response", "action": "set", "name": "Cache-Control", "value": "no-store" }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{}", backend.addr), create_interceptors(&config), &logger).await;
 
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\nHost: frontend.local\r\nX-Env: prod\r\nCookie: a=1\r\n\r\n").await.unwrap();
+        let mut buffer = Vec::new();
+        let header_end = read_http_head(&mut client, &mut buffer).await.unwrap().unwrap();
+        let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 2\r\nCache-Control: no-store\r\n\r\n"), "{head}");
+        drop(client);
 
-async fn handle_http_request(
-    request: String,
-    mut client_stream: TcpStream,
-    config: Arc<Config>,
-    logger: Arc<DomainLogger>,
-) -> Result<()> {
-    let start_time = Instant::now();
-    let lines: Vec<&str> = request.lines().collect();
-    if lines.is_empty() {
-        return Ok(());
+        let request = backend.requests()[0].clone();
+        assert!(request.contains("X-Env: staging\r\n"));
+        assert!(request.contains("User-Agent: study-proxy\r\n"));
+        assert!(!request.contains("Cookie") && !request.contains("X-Post"));
+
+        // 日志同时记录收到的和发出的头部
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(r#""Cookie": "a=1""#));
+        assert!(log.contains("Request Headers (sent):"));
+        assert!(log.contains(r#""X-Env": "staging""#));
+        assert!(log.contains("Response Headers (sent):"));
+        assert!(log.contains(r#""Cache-Control": "no-store""#));
     }
 
-    let first_line = lines[0];
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-    
-    if parts.len() < 3 {
-        log::warn!("Invalid HTTP request: {first_line}");
-        return Ok(());
+    #[tokio::test]
+    async fn test_forward_proxy_header_rewrite() {
+        let backend = spawn_backend(|_, _| text_response("ok")).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.headers = serde_json::from_value(serde_json::json!({ "rules": [
+            { "action": "set", "name": "X-Env", "value": "staging" },
+            { "action": "remove", "name": "Cookie" },
+            { "phase": "response", "action": "set", "name": "Cache-Control", "value": "no-store" }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        let addr = backend.addr;
+        let request = format!("GET http://{addr}/a HTTP/1.1\r\nHost: {addr}\r\nX-Env: prod\r\nCookie: a=1\r\nProxy-Authorization: Basic dTpw\r\n\r\n");
+        let response = forward_exchange(proxy, &request).await.unwrap();
+        assert!(response.contains("\r\nCache-Control: no-store\r\n"), "{response}");
+
+        // 发给本代理的认证头不转发给上游
+        let request = backend.requests()[0].clone();
+        assert!(request.starts_with("GET /a HTTP/1.1\r\n"));
+        assert!(request.contains("X-Env: staging\r\n"));
+        assert!(!request.contains("Cookie") && !request.contains("Proxy-Authorization"), "{request}");
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("Request Headers (sent):"));
+        assert!(log.contains("Response Headers (sent):"));
     }
 
-    let method = parts[0];
-    let url = parts[1];
-    let _version = parts[2];
+    /// 等待下一个在断点处暂停的流
+    async fn next_paused(breakpoints: &Breakpoints) -> serde_json::Value {
+        loop {
+            if let Some(flow) = breakpoints.list().into_iter().next() {
+                return flow;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
 
-    // 解析URL和目标
-    let (host, port, path) = parse_url_and_target(url, &lines)?;
+    #[tokio::test]
+    async fn test_reverse_proxy_breakpoints() {
+        // 上游记录收到的请求并返回固定的响应
+        let backend = spawn_backend(|_, _| "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+        let backend_addr = backend.addr;
 
-    log::info!("🌐 HTTP REQUEST ==========================================");
-    log::info!("⏰ Timestamp: {:?}", SystemTime::now());
-    log::info!("📝 Method: {method}");
-    log::info!("🔗 Path: {path}");
-    log::info!("🌐 Host: {host}:{port}");
-    log::info!("📋 Full Request:");
-    log::info!("{request}");
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.breakpoints = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/edit"], "methods": ["POST"] },
+            { "paths": ["/edit"], "phase": "response" },
+            { "paths": ["/abort"] }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let breakpoints = Arc::new(Breakpoints::new(Arc::clone(&config)));
+        let interceptors = Arc::new(InterceptorChain::new(vec![Arc::clone(&breakpoints) as Arc<dyn Interceptor>]));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{backend_addr}"), interceptors, &logger).await;
 
-    // 使用新的DomainLogger记录请求日志（异步，不阻塞主流程）
-    
-    // 收集请求信息
-    let request_headers: HashMap<String, String> = lines[1..].iter()
-        .take_while(|l| !l.is_empty())
-        .filter_map(|l| l.split_once(':'))
-        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
-        .collect();
+        // 在请求断点修改方法、路径和请求体，在响应断点修改状态码和响应体
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"POST /edit HTTP/1.1\r\nHost: frontend.local\r\nContent-Length: 3\r\n\r\na=1").await.unwrap();
+        let flow = next_paused(&breakpoints).await;
+        assert_eq!(flow["phase"], "request");
+        assert_eq!(flow["request"]["body"], "a=1");
+        let url = format!("http://{backend_addr}/edited?x=1");
+        let edit = serde_json::json!({ "method": "PUT", "url": url, "body": "a=22" });
+        assert!(breakpoints.resume(flow["id"].as_u64().unwrap(), serde_json::from_value(edit).unwrap()).unwrap());
 
-    let url_params = if let Some(query_start) = path.find('?') {
-        let query = &path[query_start + 1..];
-        query.split('&')
-            .filter_map(|pair| pair.split_once('='))
-            .map(|(k, v)| format!("{k}={v}"))
-            .collect::<Vec<_>>()
-            .join("&")
-    } else {
-        String::new()
-    };
+        let flow = next_paused(&breakpoints).await;
+        assert_eq!(flow["phase"], "response");
+        assert_eq!(flow["request"]["method"], "PUT");
+        assert_eq!(flow["response"]["body"], "hello");
+        let edit = serde_json::json!({ "status": 201, "body": "bye" });
+        assert!(breakpoints.resume(flow["id"].as_u64().unwrap(), serde_json::from_value(edit).unwrap()).unwrap());
 
-    let request_body = if let Some(body_start) = request.find("\r\n\r\n") {
-        request[body_start + 4..].to_string()
-    } else {
-        String::new()
-    };
+        let expected = "HTTP/1.1 201 Created\r\nContent-Length: 3\r\nConnection: close\r\n\r\nbye";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+        let recorded = backend.requests()[0].clone();
+        assert!(recorded.starts_with("PUT /edited?x=1 HTTP/1.1\r\n"));
+        assert!(recorded.ends_with("Content-Length: 4\r\n\r\na=22"), "{recorded}");
 
-    if config.should_intercept(&host, port) {
-        log::info!("Intercepting HTTP request to {host}:{port}{path}");
+        // 中止的请求不发往上游，直接断开客户端连接
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /abort HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        let flow = next_paused(&breakpoints).await;
+        assert!(breakpoints.abort(flow["id"].as_u64().unwrap()));
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        assert_eq!(backend.requests().len(), 1);
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("  Request Body (sent): a=22\n"));
+        assert!(log.contains("  Response Body (sent): bye\n"));
+        assert!(log.contains("Request dropped by breakpoint"));
     }
 
-    // 构建新的HTTP请求，保持原始请求头
-    let mut new_request = format!("{method} {path} HTTP/1.1\r\n");
-    
-    // 计算请求总大小
-    let _request_size = new_request.len() + request_body.len();
-    
-    // 收集并打印原始请求头
-    let mut headers_map = HashMap::new();
-    for line in &lines[1..] {
-        if line.is_empty() {
-            break;
+    #[tokio::test]
+    async fn test_forward_proxy_breakpoints() {
+        let backend = spawn_backend(|_, body| echo_response(body)).await;
+        let addr = backend.addr;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.breakpoints = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/edit", "/abort"] }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let breakpoints = Arc::new(Breakpoints::new(Arc::clone(&config)));
+        let interceptors = Arc::new(InterceptorChain::new(vec![Arc::clone(&breakpoints) as Arc<dyn Interceptor>]));
+        let proxy = spawn_proxy(&config, interceptors, &logger).await;
+
+        // 暂停的请求按修改后的请求体转发
+        let request = format!("POST http://{addr}/edit HTTP/1.1\r\nHost: {addr}\r\nContent-Length: 3\r\n\r\na=1");
+        let exchange = tokio::spawn(async move { forward_exchange(proxy, &request).await });
+        let flow = next_paused(&breakpoints).await;
+        assert_eq!(flow["request"]["url"], format!("http://{addr}/edit"));
+        let edit = serde_json::json!({ "body": "a=22" });
+        assert!(breakpoints.resume(flow["id"].as_u64().unwrap(), serde_json::from_value(edit).unwrap()).unwrap());
+        assert!(exchange.await.unwrap().unwrap().ends_with("\r\n\r\na=22"));
+
+        // 中止的请求不发往上游
+        let request = format!("GET http://{addr}/abort HTTP/1.1\r\nHost: {addr}\r\n\r\n");
+        let exchange = tokio::spawn(async move { forward_exchange(proxy, &request).await });
+        let flow = next_paused(&breakpoints).await;
+        assert!(breakpoints.abort(flow["id"].as_u64().unwrap()));
+        assert!(exchange.await.unwrap().unwrap().is_empty());
+        assert_eq!(backend.requests().len(), 1);
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("  Request Body (sent): a=22\n"));
+        assert!(log.contains("Request dropped by breakpoint"));
+    }
+
+    /// 测试用的拦截器：修改请求和响应，按路径直接应答或丢弃请求，记录上游错误
+    struct TestInterceptor {
+        errors: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Interceptor for TestInterceptor {
+        fn name(&self) -> &str {
+            "test-interceptor"
         }
-        if let Some(colon_pos) = line.find(':') {
-            let key = line[..colon_pos].trim().to_lowercase();
-            let value = line[colon_pos + 1..].trim().to_string();
-            log::info!("📋 Request Header: {key}: {value}");
-            headers_map.insert(key, value);
+
+        fn intercepts_host(&self, _host: &str) -> bool {
+            true
+        }
+
+        async fn on_connect(&self, host: &str, _port: u16) -> crate::interceptor::ConnectAction {
+            match host {
+                "blocked.invalid" => crate::interceptor::ConnectAction::Drop,
+                _ => crate::interceptor::ConnectAction::Continue,
+            }
+        }
+
+        async fn on_request(&self, flow: &mut Flow) -> crate::interceptor::Action {
+            match flow.request.path.as_str() {
+                "/local" => crate::interceptor::Action::Respond(FlowResponse {
+                    status: 200,
+                    headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+                    body: b"local".to_vec(),
+                }),
+                "/drop" => crate::interceptor::Action::Drop,
+                _ => {
+                    flow.request.headers.push(("X-Intercepted".to_string(), "1".to_string()));
+                    flow.request.body.extend_from_slice(b"!");
+                    crate::interceptor::Action::Continue
+                },
+            }
+        }
+
+        async fn on_response(&self, flow: &mut Flow) -> crate::interceptor::Action {
+            if let Some(response) = flow.response.as_mut() {
+                response.body.make_ascii_uppercase();
+            }
+            crate::interceptor::Action::Continue
+        }
+
+        async fn on_error(&self, flow: &Flow, error: &anyhow::Error) {
+            self.errors.lock().unwrap().push(format!("{} {error}", flow.request.path));
         }
     }
-    
-    // 设置Host头
-    new_request.push_str(&format!("Host: {host}:{port}\r\n"));
-    
-    // 添加或保留其他必要头部
-    if !headers_map.contains_key("user-agent") {
-        new_request.push_str("User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/125.0.0.0 Safari/537.36\r\n");
+
+    /// 返回收到的请求体的上游响应
+    fn echo_response(body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+        response.extend_from_slice(body);
+        response
     }
-    if !headers_map.contains_key("accept") {
-        new_request.push_str("Accept: text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8\r\n");
+
+    #[tokio::test]
+    async fn test_on_connect_drop_before_tunnel() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let interceptor = TestInterceptor { errors: Default::default() };
+        let interceptors = Arc::new(InterceptorChain::new(vec![Arc::new(interceptor)]));
+        let proxy = spawn_proxy(&config, Arc::clone(&interceptors), &logger).await;
+
+        // CONNECT被拒绝时返回403，不会先返回200建立隧道
+        for request in ["CONNECT blocked.invalid:443 HTTP/1.1\r\nHost: blocked.invalid:443\r\n\r\n", "GET http://blocked.invalid/ HTTP/1.1\r\nHost: blocked.invalid\r\n\r\n"] {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, FORBIDDEN_RESPONSE, "{}", String::from_utf8_lossy(&response));
+        }
+
+        // SOCKS5应答“规则不允许连接”后关闭连接
+        let proxy = spawn_socks5_proxy(&config, interceptors, &logger).await;
+        let (mut client, reply) = socks5_connect(proxy, "blocked.invalid", 443).await;
+        assert_eq!(reply, 0x02);
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
     }
-    if !headers_map.contains_key("accept-encoding") {
-        new_request.push_str("Accept-Encoding: gzip, deflate, br\r\n");
+
+    #[tokio::test]
+    async fn test_reverse_proxy_interceptors() {
+        // 上游返回收到的请求体，并记录收到的请求
+        let backend = spawn_backend(|_, body| echo_response(body)).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let interceptor = TestInterceptor { errors: Arc::clone(&errors) };
+        let interceptors = Arc::new(InterceptorChain::new(vec![Arc::new(interceptor)]));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{}", backend.addr), Arc::clone(&interceptors), &logger).await;
+
+        // 拦截器修改发往上游的请求和返回给客户端的响应
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"POST /echo HTTP/1.1\r\nHost: frontend.local\r\nContent-Length: 2\r\n\r\nhi").await.unwrap();
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nHI!";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+        let recorded = backend.requests()[0].clone();
+        assert!(recorded.ends_with("\r\nContent-Length: 3\r\nX-Intercepted: 1\r\n\r\nhi!"), "{recorded}");
+
+        // 拦截器直接应答的请求不发往上游
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /local HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        let expected = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nlocal";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+
+        // 丢弃的请求直接断开客户端连接
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /drop HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        assert_eq!(backend.requests().len(), 1);
+
+        // 上游不可用时通知拦截器
+        let closed_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{closed_addr}"), interceptors, &logger).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /down HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert!(errors.lock().unwrap()[0].starts_with("/down "));
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("Request dropped by test-interceptor"));
+        assert!(log.contains("  Response Body (sent): HI!\n"));
     }
-    if !headers_map.contains_key("accept-language") {
-        new_request.push_str("Accept-Language: zh-CN,zh;q=0.9,en;q=0.8\r\n");
+
+    #[tokio::test]
+    async fn test_forward_proxy_interceptors() {
+        let backend = spawn_backend(|_, body| echo_response(body)).await;
+        let addr = backend.addr;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let interceptor = TestInterceptor { errors: Arc::clone(&errors) };
+        let proxy = spawn_proxy(&config, Arc::new(InterceptorChain::new(vec![Arc::new(interceptor)])), &logger).await;
+
+        // 拦截器修改发往上游的请求和返回给客户端的响应
+        let request = format!("POST http://{addr}/echo HTTP/1.1\r\nHost: {addr}\r\nContent-Length: 2\r\n\r\nhi");
+        let response = forward_exchange(proxy, &request).await.unwrap();
+        assert!(response.ends_with("\r\n\r\nHI!"), "{response}");
+        assert!(backend.requests()[0].contains("X-Intercepted: 1\r\n"));
+
+        // 直接应答和丢弃的请求不发往上游
+        let response = forward_exchange(proxy, &format!("GET http://{addr}/local HTTP/1.1\r\nHost: {addr}\r\n\r\n")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\nlocal"), "{response}");
+        let response = forward_exchange(proxy, &format!("GET http://{addr}/drop HTTP/1.1\r\nHost: {addr}\r\n\r\n")).await.unwrap();
+        assert!(response.is_empty());
+        assert_eq!(backend.requests().len(), 1);
+
+        // 上游不可用时返回502并通知拦截器
+        let closed_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let request = format!("GET http://{closed_addr}/down HTTP/1.1\r\nHost: {closed_addr}\r\n\r\n");
+        let response = forward_exchange(proxy, &request).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(errors.lock().unwrap()[0].starts_with("/down "));
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("Request dropped by test-interceptor"));
     }
-    
-    // 保留原始头部
-    for line in &lines[1..] {
-        if !line.is_empty() && !line.to_lowercase().starts_with("host:") {
-            new_request.push_str(line);
-            new_request.push_str("\r\n");
-        }
+
+    #[tokio::test]
+    async fn test_forward_proxy_scripts() {
+        let backend = spawn_backend(|_, _| text_response("upstream")).await;
+        let addr = backend.addr;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let script = temp_dir.path().join("flow.rhai");
+        std::fs::write(&script, r#"
+            fn on_request(req) {
+                if req.path == "/mock" {
+                    req.respond(200, "mocked", #{ "Content-Type": "text/plain" });
+                } else {
+                    req.set_header("X-Script", "1");
+                }
+            }
+            fn on_response(req, resp) {
+                resp.body = "scripted";
+            }
+        "#).unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.scripts = serde_json::from_value(serde_json::json!({ "rules": [
+            { "file": script, "hosts": ["127.0.0.1"] }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let interceptors = Arc::new(InterceptorChain::new(load_scripts(&config).unwrap()));
+        let proxy = spawn_proxy(&config, interceptors, &logger).await;
+
+        let response = forward_exchange(proxy, &format!("GET http://{addr}/page HTTP/1.1\r\nHost: {addr}\r\n\r\n")).await.unwrap();
+        assert!(response.ends_with("Content-Length: 8\r\n\r\nscripted"), "{response}");
+        assert!(backend.requests()[0].contains("X-Script: 1\r\n"));
+
+        // 脚本直接应答的请求不发往上游
+        let response = forward_exchange(proxy, &format!("GET http://{addr}/mock HTTP/1.1\r\nHost: {addr}\r\n\r\n")).await.unwrap();
+        assert!(response.ends_with("\r\n\r\nmocked"), "{response}");
+        assert_eq!(backend.requests().len(), 1);
     }
-    new_request.push_str("\r\n");
 
-    // 连接到目标服务器
-    log::info!("Connecting to target server: {host}:{port}");
-    let mut server_stream = TcpStream::connect(format!("{host}:{port}")).await?;
-    
-    // 转发请求
-    log::info!("Forwarding request to server...");
-    server_stream.write_all(new_request.as_bytes()).await?;
+    #[tokio::test]
+    async fn test_connect_tls_intercept_rules() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (mut config, acceptor) = create_tls_test_config("app.test", temp_dir.path());
+        let backend = spawn_tls_backend(Some(acceptor), |_, _| text_response("see https://cdn.example.com/app.js")).await;
+        let site_dir = tempfile::tempdir().unwrap();
+        let site = site_dir.path();
+        std::fs::write(site.join("app.js"), "console.log(1)").unwrap();
+        config.map_local = serde_json::from_value(serde_json::json!({ "rules": [
+            { "paths": ["/static/*"], "local": site }
+        ] })).unwrap();
+        config.headers = serde_json::from_value(serde_json::json!({ "rules": [
+            { "action": "set", "name": "X-Env", "value": "staging" },
+            { "phase": "response", "action": "set", "name": "Cache-Control", "value": "no-store" }
+        ] })).unwrap();
+        config.body = serde_json::from_value(serde_json::json!({ "rules": [
+            { "phase": "response", "action": "replace", "pattern": "https://cdn\\.example\\.com", "replacement": "http://localhost:8080" }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let interceptor = TestInterceptor { errors: Default::default() };
+        let interceptors = Arc::new(InterceptorChain::new(vec![Arc::new(interceptor)]));
+        let proxy = spawn_proxy(&config, interceptors, &logger).await;
 
-    // 转发请求体（如果有）
-    if let Some(body_start) = request.find("\r\n\r\n") {
-        let body = &request[body_start + 4..];
-        if !body.is_empty() {
-            log::info!("Forwarding request body ({} bytes)", body.len());
-            server_stream.write_all(body.as_bytes()).await?;
-        }
+        let port = backend.addr.port();
+        let mut client = connect_tls_via_proxy(proxy, "app.test", port, temp_dir.path()).await;
+
+        // Map Local在拦截的TLS连接上由本地文件应答
+        client.write_all(format!("GET /static/app.js HTTP/1.1\r\nHost: app.test:{port}\r\n\r\n").as_bytes()).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\nconsole.log(1)"), "{response}");
+
+        // 头部和消息体改写之后由拦截器处理
+        client.write_all(format!("POST /page HTTP/1.1\r\nHost: app.test:{port}\r\nContent-Length: 4\r\n\r\nping").as_bytes()).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.contains("\r\nCache-Control: no-store\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nSEE HTTP://LOCALHOST:8080/APP.JS"), "{response}");
+
+        // 拦截器直接应答的请求不发往上游
+        client.write_all(format!("GET /local HTTP/1.1\r\nHost: app.test:{port}\r\nConnection: close\r\n\r\n").as_bytes()).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.ends_with("\r\n\r\nlocal"), "{response}");
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST /page HTTP/1.1\r\n"));
+        assert!(requests[0].contains("X-Env: staging\r\n"));
+        assert!(requests[0].contains("X-Intercepted: 1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\nping!"), "{}", requests[0]);
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("Mapped To: file://{}", site.join("app.js").display())));
+        assert!(log.contains("  Response Body (sent): SEE HTTP://LOCALHOST:8080/APP.JS\n"), "{log}");
     }
 
-    // 使用新的响应处理器
-    let mut response_processor = HttpResponseProcessor::new(config.logging.domain_logs.response_body_limit);
-    let mut buffer = [0; 4096];
-    let mut total_response_buffer = Vec::new();
-    
-    log::info!("Reading HTTP response...");
-    
-    while let Ok(bytes_read) = server_stream.read(&mut buffer).await {
-        if bytes_read == 0 {
-            break;
-        }
-        
-        let chunk_data = &buffer[..bytes_read];
-        total_response_buffer.extend_from_slice(chunk_data);
-        
-        // 使用响应处理器处理数据块
-        match response_processor.process_chunk_http(chunk_data, &mut client_stream).await? {
-            ProcessingResult::Continue => continue,
-            ProcessingResult::Complete => break,
-        }
+    #[tokio::test]
+    async fn test_reverse_proxy_body_rewrite() {
+        // 上游记录收到的请求并返回chunked编码的JSON响应
+        let backend = spawn_backend(|_, _| {
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n{\"flags\":\r\nf\r\n{\"beta\":false}}\r\n0\r\n\r\n"
+        }).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.body = serde_json::from_value(serde_json::json!({ "rules": [
+            { "methods": ["POST"], "action": "json_delete", "pointer": "/debug" },
+            { "paths": ["/flags"], "phase": "response", "action": "json_set", "pointer": "/flags/beta", "value": true }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{}", backend.addr), create_interceptors(&config), &logger).await;
+
+        // chunked请求体改写后按Content-Length发送，同一连接上的后续请求不受影响
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let body = r#"{"debug":true,"name":"a"}"#;
+        let request = format!("POST /flags HTTP/1.1\r\nHost: frontend.local\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{body}\r\n0\r\n\r\n", body.len());
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.write_all(b"GET /other HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: 23\r\n\r\n{\"flags\":{\"beta\":true}}";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+        // 不匹配的请求原样转发chunked响应
+        let mut buffer = Vec::new();
+        let header_end = read_http_head(&mut client, &mut buffer).await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..header_end]).contains("Transfer-Encoding: chunked"));
+        drop(client);
+
+        let requests = backend.requests();
+        assert_eq!(backend.connections(), 1);
+        assert!(requests[0].starts_with("POST /flags HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("Content-Length: 12\r\n\r\n{\"name\":\"a\"}"), "{}", requests[0]);
+        assert!(requests[1].starts_with("GET /other HTTP/1.1\r\n"));
+
+        // 日志同时记录收到的和改写后的消息体
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains(&format!("  Request Body: {body}\n")));
+        assert!(log.contains("  Request Body (sent): {\"name\":\"a\"}\n"));
+        assert!(log.contains("  Response Body: {\"flags\":{\"beta\":false}}\n"));
+        assert!(log.contains("  Response Body (sent): {\"flags\":{\"beta\":true}}\n"));
     }
-    
-    // 解析响应头和状态码用于日志记录
-    let response_str = String::from_utf8_lossy(&total_response_buffer);
-    let response_lines: Vec<&str> = response_str.lines().collect();
-    let mut response_headers_map = HashMap::new();
-    let mut response_status = 0;
-    
-    if let Some(status_line) = response_lines.first() {
-        let status_parts: Vec<&str> = status_line.split_whitespace().collect();
-        if status_parts.len() >= 2 {
-            response_status = status_parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
-        }
+
+    #[tokio::test]
+    async fn test_forward_proxy_body_rewrite() {
+        // 上游返回收到的请求体
+        let backend = spawn_backend(|_, body| echo_response(body)).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.body = serde_json::from_value(serde_json::json!({ "rules": [
+            { "methods": ["POST"], "action": "json_delete", "pointer": "/debug" },
+            { "phase": "response", "action": "replace", "pattern": "\"a\"", "replacement": "\"b\"" }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_proxy(&config, create_interceptors(&config), &logger).await;
+
+        let addr = backend.addr;
+        let body = r#"{"debug":true,"name":"a"}"#;
+        let request = format!("POST http://{addr}/echo HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+        let response = forward_exchange(proxy, &request).await.unwrap();
+        assert!(response.ends_with("Content-Length: 12\r\nConnection: close\r\n\r\n{\"name\":\"b\"}"), "{response}");
+        let recorded = backend.requests()[0].clone();
+        assert!(recorded.ends_with("Content-Length: 12\r\n\r\n{\"name\":\"a\"}"), "{recorded}");
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("  Request Body (sent): {\"name\":\"a\"}\n"));
+        assert!(log.contains("  Response Body (sent): {\"name\":\"b\"}\n"));
     }
-    
-    let mut header_end = 0;
-    if let Some(pos) = total_response_buffer.windows(4).position(|w| w == b"\r\n\r\n") {
-        header_end = pos + 4;
+
+    #[tokio::test]
+    async fn test_reverse_proxy_brotli_body_rewrite() {
+        // 浏览器声明支持br时上游返回br压缩的响应
+        let html = b"<script src=\"https://cdn.example.com/app.js\"></script>";
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(html).unwrap();
+        let compressed = encoder.into_inner();
+        let backend = spawn_backend(move |request, _| {
+            assert!(request.contains("Accept-Encoding: gzip, deflate, br\r\n"));
+            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Encoding: br\r\nContent-Length: {}\r\n\r\n", compressed.len()).into_bytes();
+            response.extend_from_slice(&compressed);
+            response
+        }).await;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.body = serde_json::from_value(serde_json::json!({ "rules": [
+            { "phase": "response", "action": "replace", "pattern": "https://cdn\\.example\\.com", "replacement": "http://localhost:8080" }
+        ] })).unwrap();
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let proxy = spawn_reverse_proxy(&config, &format!("http://{}", backend.addr), create_interceptors(&config), &logger).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: frontend.local\r\nAccept-Encoding: gzip, deflate, br\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+
+        // 改写后的响应仍然是br压缩，Content-Length按重新压缩后的长度设置
+        let header_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&response[..header_end]).to_string();
+        let body = &response[header_end..];
+        assert!(head.contains("Content-Encoding: br\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())), "{head}");
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut brotli::Decompressor::new(body, 4096), &mut decoded).unwrap();
+        assert_eq!(decoded, "<script src=\"http://localhost:8080/app.js\"></script>");
+
+        let log = read_logs(&logger, temp_dir.path()).await;
+        assert!(log.contains("  Response Body: <script src=\"https://cdn.example.com/app.js\"></script>\n"), "{log}");
+        assert!(log.contains("  Response Body (sent): <script src=\"http://localhost:8080/app.js\"></script>\n"), "{log}");
     }
-    
-    for line in response_lines.iter().skip(1) {
-        if line.is_empty() {
-            break;
-        }
-        if let Some(colon_pos) = line.find(':') {
-            let key = line[..colon_pos].trim().to_lowercase();
-            let value = line[colon_pos + 1..].trim().to_string();
-            response_headers_map.insert(key, value);
+
+    #[tokio::test]
+    async fn test_pooled_connection_reused_across_clients() {
+        // 上游服务只接受一个连接，第二个客户端必须复用池中的连接
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend_task = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = Vec::new();
+            for _ in 0..2 {
+                let header_end = read_http_head(&mut stream, &mut buffer).await.unwrap().unwrap();
+                buffer.drain(..header_end);
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            }
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let target = UpstreamTarget::from_base_url(&format!("http://{backend_addr}")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let context = create_context(&config, create_interceptors(&config), &logger);
+        tokio::spawn(run_reverse_proxy_listener(listener, target.clone(), None, Arc::clone(&context)));
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nHost: frontend.local\r\nConnection: close\r\n\r\n").await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.ends_with(b"\r\n\r\nok"));
         }
+        backend_task.await.unwrap();
+
+        // 上游连接仍然保持，处理完后重新放回连接池
+        let connection = context.pool.checkout(&target.origin()).unwrap();
+        assert!(connection.reused);
     }
-    
-    // 使用新的DomainLogger记录完整的HTTP请求响应日志
-    let response_body_str = if response_processor.needs_decompression() {
-        // 使用解压缩后的响应体
-        response_processor.get_decompressed_body()
-    } else if header_end > 0 && header_end < total_response_buffer.len() {
-        // 使用原始响应体
-        String::from_utf8_lossy(&total_response_buffer[header_end..]).to_string()
-    } else {
-        String::new()
-    };
-    let duration_ms = start_time.elapsed().as_millis();
-    let log_entry = DomainLogger::create_log_entry(
-        host.clone(),
-        method.to_string(),
-        format!("http://{host}:{port}{path}"),
-        request_headers,
-        response_headers_map,
-        response_status,
-        request_body,
-        response_body_str,
-        url_params,
-        duration_ms,
-        None,
-    );
-    logger.log_request(log_entry);
-    
-    log::info!("✅ HTTP REQUEST COMPLETE - {} bytes transferred - Duration: {}ms", total_response_buffer.len(), duration_ms);
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_dns_override_keeps_host_header() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        let backend_task = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = Vec::new();
+            let header_end = read_http_head(&mut stream, &mut buffer).await.unwrap().unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            String::from_utf8_lossy(&buffer[..header_end]).to_string()
+        });
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum ProcessingResult {
-    Continue,
-    Complete,
-}
+        // 不存在的域名通过静态映射连接到本地服务
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.dns.hosts.insert("*.staging.invalid".to_string(), "127.0.0.1".parse().unwrap());
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let target = UpstreamTarget::from_base_url(&format!("http://api.staging.invalid:{backend_port}")).unwrap();
 
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let context = create_context(&config, create_interceptors(&config), &logger);
+        tokio::spawn(run_reverse_proxy_listener(listener, target.clone(), None, Arc::clone(&context)));
 
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: frontend.local\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.ends_with(b"\r\n\r\nok"));
 
+        let request = backend_task.await.unwrap();
+        assert!(request.contains(&format!("Host: api.staging.invalid:{backend_port}\r\n")));
+        let connection = context.pool.checkout(&target.origin()).unwrap();
+        assert_eq!(connection.resolved_ip, Some("127.0.0.1".parse().unwrap()));
+    }
 
+    #[tokio::test]
+    async fn test_timeouts_return_error_responses() {
+        // 读取请求后不再响应的上游
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = Vec::new();
+            read_http_head(&mut stream, &mut buffer).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+        });
 
-fn parse_url_and_target(url: &str, lines: &[&str]) -> Result<(String, u16, String)> {
-    let url_info = UrlInfo::parse(url, lines)?;
-    Ok((url_info.host, url_info.port, url_info.path))
-}
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(r#""*""#, temp_dir.path());
+        config.timeouts.first_byte_secs = 1;
+        config.timeouts.client_header_secs = 1;
+        let config = Arc::new(config);
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let target = UpstreamTarget::from_base_url(&format!("http://127.0.0.1:{backend_port}")).unwrap();
 
-#[derive(Debug)]
-struct UrlInfo {
-    host: String,
-    port: u16,
-    path: String,
-}
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let context = create_context(&config, create_interceptors(&config), &logger);
+        tokio::spawn(run_reverse_proxy_listener(listener, target.clone(), None, Arc::clone(&context)));
 
-impl UrlInfo {
-    fn parse(url: &str, lines: &[&str]) -> Result<Self> {
-        let scheme = if url.starts_with("http://") {
-            "http"
-        } else if url.starts_with("https://") {
-            "https"
-        } else {
-            "relative"
-        };
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"), "{response}");
+        // 超时的上游连接不放回连接池
+        assert!(context.pool.checkout(&target.origin()).is_none());
 
-        match scheme {
-            "http" | "https" => Self::parse_absolute_url(url, scheme),
-            "relative" => Self::parse_relative_url(url, lines),
-            _ => unreachable!(),
-        }
+        // 没有发送完整请求头的客户端收到408
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: front").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, REQUEST_TIMEOUT_RESPONSE);
     }
 
-    fn parse_absolute_url(url: &str, scheme: &str) -> Result<Self> {
-        let url_parts: Vec<&str> = url.splitn(3, '/').collect();
-        let host_port = url_parts.get(2).unwrap_or(&"");
-        
-        let (host, port) = Self::parse_host_port(host_port, scheme)?;
-        let path = Self::build_path(&url_parts[2..]);
-        
-        Ok(UrlInfo { host, port, path })
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_requests() {
+        // 延迟响应的上游
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = Vec::new();
+            read_http_head(&mut stream, &mut buffer).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let target = UpstreamTarget::from_base_url(&format!("http://127.0.0.1:{backend_port}")).unwrap();
+        let context = create_context(&config, create_interceptors(&config), &logger);
+        let shutdown = context.shutdown.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let accept_loop = tokio::spawn(run_reverse_proxy_listener(listener, target, None, context));
+
+        // 空闲的keep-alive连接和正在等待响应的连接
+        let mut idle = TcpStream::connect(proxy).await.unwrap();
+        let mut busy = TcpStream::connect(proxy).await.unwrap();
+        busy.write_all(b"GET / HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(shutdown.active_connections(), 2);
+
+        shutdown.shutdown();
+        accept_loop.await.unwrap();
+
+        // 空闲连接立即关闭，正在处理的请求仍然收到完整响应
+        let mut response = Vec::new();
+        idle.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        let mut response = Vec::new();
+        busy.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).ends_with("\r\n\r\ndone"));
+        assert!(shutdown.drain(Duration::from_secs(1)).await);
     }
 
-    fn parse_relative_url(url: &str, lines: &[&str]) -> Result<Self> {
-        let host_line = lines
-            .iter()
-            .find(|line| line.to_lowercase().starts_with("host:"))
-            .ok_or_else(|| anyhow::anyhow!("Missing Host header"))?;
+    #[test]
+    fn test_upstream_error_response_status() {
+        let timeout = anyhow::Error::new(crate::timeout::TimeoutError {
+            phase: TimeoutPhase::Connect,
+            after: Duration::from_secs(10),
+        });
+        let (status, response) = upstream_error_response("::1", 443, &timeout);
+        assert_eq!(status, 504);
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(response.contains("<b>[::1]:443</b>"));
+        assert!(response.contains("Connecting to upstream timed out after 10s"));
 
-        let host_info = host_line[5..].trim();
-        let (host, port) = Self::parse_host_port(host_info, "http")?;
-        
-        Ok(UrlInfo {
-            host,
-            port,
-            path: url.to_string(),
-        })
+        let (status, _) = upstream_error_response("example.com", 443, &anyhow::anyhow!("connection refused"));
+        assert_eq!(status, 502);
     }
 
-    fn parse_host_port(host_port: &str, scheme: &str) -> Result<(String, u16)> {
-        let parts: Vec<&str> = host_port.splitn(2, ':').collect();
-        let host = parts[0].to_string();
-        let default_port = match scheme {
-            "http" => 80,
-            "https" => 443,
-            _ => 80,
-        };
-        
-        let port = parts
-            .get(1)
-            .and_then(|p| p.parse().ok())
-            .unwrap_or(default_port);
-            
-        Ok((host, port))
+    #[test]
+    fn test_parse_url_and_target() {
+        let (host, port, path) = parse_url_and_target("http://localhost:18080/api?id=1", &[]).unwrap();
+        assert_eq!((host.as_str(), port, path.as_str()), ("localhost", 18080, "/api?id=1"));
+
+        let (host, port, path) = parse_url_and_target("/index.html", &["GET /index.html HTTP/1.1", "Host: [::1]:8080"]).unwrap();
+        assert_eq!((host.as_str(), port, path.as_str()), ("::1", 8080, "/index.html"));
+
+        assert!(parse_url_and_target("http://user@example.com/", &[]).is_err());
+        assert!(parse_url_and_target("/", &["GET / HTTP/1.1", "Host: ::1:8080"]).is_err());
+        assert!(parse_url_and_target("/", &["GET / HTTP/1.1"]).is_err());
     }
 
-    fn build_path(url_parts: &[&str]) -> String {
-        if url_parts.is_empty() {
-            "/".to_string()
-        } else {
-            format!("/{}", url_parts.join("/"))
-        }
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(b"\x00\x01ABCDEFGHIJKLMNOPQ");
+        let lines: Vec<&str> = dump.lines().skip(1).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].trim(), "00000000  00 01 41 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e  |..ABCDEFGHIJKLMN|");
+        assert!(lines[1].trim().starts_with("00000010  4f 50 51"));
+        assert!(lines[1].ends_with("|OPQ|"));
     }
-}
 
-async fn tunnel_connection_with_logging(
-    client_stream: TcpStream,
-    server_stream: TcpStream,
-) -> Result<(u64, u64), std::io::Error> {
-    let (mut client_reader, mut client_writer) = tokio::io::split(client_stream);
-    let (mut server_reader, mut server_writer) = tokio::io::split(server_stream);
+    #[tokio::test]
+    async fn test_connect_raw_tcp_relay() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
 
-    let client_to_server = tokio::io::copy(&mut client_reader, &mut server_writer);
-    let server_to_client = tokio::io::copy(&mut server_reader, &mut client_writer);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""127.0.0.1""#, temp_dir.path()));
+        let logger = DomainLogger::new(Arc::clone(&config));
 
-    let (bytes_client_to_server, bytes_server_to_client) = tokio::try_join!(client_to_server, server_to_client)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, create_context(&config, create_interceptors(&config), &logger)).await.unwrap();
+        });
 
-    Ok((bytes_client_to_server, bytes_server_to_client))
-}
+        // 拦截目标上的非TLS、非HTTP流量原样转发
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(format!("CONNECT {target} HTTP/1.1\r\n\r\n").as_bytes()).await.unwrap();
+        let mut established = [0; 39];
+        client.read_exact(&mut established).await.unwrap();
+        assert!(established.starts_with(b"HTTP/1.1 200"));
 
+        client.write_all(b"\x00\x01binary").await.unwrap();
+        let mut echoed = [0; 8];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"\x00\x01binary");
 
+        // 客户端关闭写方向后，关闭传递到服务器，回显服务器随之关闭
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
 
-fn load_certificates(cert_pem: &[u8]) -> Vec<rustls::Certificate> {
-    let mut reader = BufReader::new(Cursor::new(cert_pem));
-    rustls_pemfile::certs(&mut reader)
-        .unwrap()
-        .into_iter()
-        .map(rustls::Certificate)
-        .collect()
-}
+    #[tokio::test]
+    async fn test_untrusted_upstream_returns_bad_gateway() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(create_test_config(r#""*""#, temp_dir.path()));
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
 
-fn load_private_key(key_pem: &[u8]) -> rustls::PrivateKey {
-    let mut reader = BufReader::new(Cursor::new(key_pem));
-    let key = rustls_pemfile::pkcs8_private_keys(&mut reader)
-        .unwrap()
-        .into_iter()
-        .next()
-        .unwrap();
-    rustls::PrivateKey(key)
-}
\ No newline at end of file
+        // 上游证书由没有被信任的CA签发
+        let acceptor = create_tls_acceptor(&cert_manager, "localhost").unwrap();
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = backend.accept().await {
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let logger = DomainLogger::new(Arc::clone(&config));
+        let target = UpstreamTarget::from_base_url(&format!("https://localhost:{backend_port}")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(run_reverse_proxy_listener(listener, target, None, create_context(&config, create_interceptors(&config), &logger)));
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: frontend.local\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{response}");
+        assert!(response.contains("Upstream certificate chain:"));
+        assert!(response.contains("subject=CN=localhost; issuer=CN=study-proxy"));
+    }
+
+    #[test]
+    fn test_bad_gateway_response_escapes_error() {
+        let response = bad_gateway_response("example.com", 443, &anyhow::anyhow!("<script>"));
+        let response = String::from_utf8(response).unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("&lt;script&gt;"));
+    }
+}
@@ -0,0 +1,551 @@
+use crate::config::{BodyAction, Config, HeaderAction, RewritePhase};
+use crate::proxy::BodyCapture;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// 改写消息体时最多暂存的原始消息体长度，超过时不改写
+pub const MAX_BODY_REWRITE_SIZE: usize = 16 * 1024 * 1024;
+
+/// 有序的头部列表，保留原始的大小写和重复的头部
+pub type HeaderList = Vec<(String, String)>;
+
+/// 一个请求或响应匹配的头部改写
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewrite {
+    /// 按顺序执行的改写动作
+    actions: Vec<HeaderAction>,
+}
+
+impl HeaderRewrite {
+    /// 查找匹配请求的头部改写规则
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    /// * `phase` - 改写请求头还是响应头
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    pub fn new(config: &Config, phase: RewritePhase, host: &str, method: &str, path: &str) -> Self {
+        Self {
+            actions: config.header_actions_for(phase, host, method, path),
+        }
+    }
+
+    /// 是否没有需要执行的改写
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// 按顺序对头部列表执行所有改写动作
+    ///
+    /// # 参数
+    /// * `headers` - 要改写的头部列表
+    pub fn apply(&self, headers: &mut HeaderList) {
+        for action in &self.actions {
+            match action {
+                HeaderAction::Add { name, value } => headers.push((name.clone(), value.clone())),
+                HeaderAction::Set { name, value } => {
+                    // 替换第一个同名头部以保持位置，删除其余的
+                    let position = headers.iter().position(|(key, _)| key.eq_ignore_ascii_case(name));
+                    headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+                    let index = position.unwrap_or(headers.len());
+                    headers.insert(index, (name.clone(), value.clone()));
+                },
+                HeaderAction::Default { name, value } => {
+                    if !headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name)) {
+                        headers.push((name.clone(), value.clone()));
+                    }
+                },
+                HeaderAction::Remove { name } => headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name)),
+                HeaderAction::Replace { name, pattern, replacement } => {
+                    for (key, value) in headers.iter_mut() {
+                        if key.eq_ignore_ascii_case(name) {
+                            *value = pattern.replace_all(value, replacement.as_str()).to_string();
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// 改写HTTP/2的头部，改写后不合法的头部被忽略
+    ///
+    /// # 参数
+    /// * `headers` - 要改写的头部
+    pub fn apply_to_header_map(&self, headers: &mut http::HeaderMap) {
+        if self.is_empty() {
+            return;
+        }
+        let mut list: HeaderList = headers.iter()
+            .map(|(key, value)| (key.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+            .collect();
+        self.apply(&mut list);
+
+        headers.clear();
+        for (key, value) in list {
+            let name = http::HeaderName::from_bytes(key.as_bytes());
+            match (name, http::HeaderValue::from_str(&value)) {
+                (Ok(name), Ok(value)) => {
+                    headers.append(name, value);
+                },
+                _ => log::warn!("Ignoring invalid rewritten header {key}: {value:?}"),
+            }
+        }
+    }
+}
+
+/// 一个请求或响应匹配的消息体改写
+#[derive(Debug, Clone, Default)]
+pub struct BodyRewrite {
+    /// 按顺序执行的改写动作
+    actions: Vec<BodyAction>,
+}
+
+/// 改写后的消息体
+#[derive(Debug)]
+pub struct RewrittenBody {
+    /// 按原Content-Encoding重新压缩后的消息体
+    pub encoded: Vec<u8>,
+    /// 改写后解压的消息体
+    pub decoded: Vec<u8>,
+}
+
+impl RewrittenBody {
+    /// 日志记录用的消息体
+    ///
+    /// # 参数
+    /// * `limit` - 消息体记录长度限制
+    pub fn body_for_log(&self, limit: i64) -> String {
+        let mut capture = BodyCapture::new(limit);
+        capture.feed(&self.decoded);
+        capture.as_string()
+    }
+}
+
+impl BodyRewrite {
+    /// 查找匹配请求的消息体改写规则
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    /// * `phase` - 改写请求体还是响应体
+    /// * `host` - 请求的主机
+    /// * `method` - 请求方法
+    /// * `path` - 请求路径
+    pub fn new(config: &Config, phase: RewritePhase, host: &str, method: &str, path: &str) -> Self {
+        Self {
+            actions: config.body_actions_for(phase, host, method, path),
+        }
+    }
+
+    /// 是否没有需要执行的改写
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// 解压消息体，执行所有改写动作后按原编码重新压缩
+    ///
+    /// # 参数
+    /// * `body` - 去除分帧后的原始消息体
+    /// * `content_encoding` - 消息体的Content-Encoding
+    ///
+    /// # 返回值
+    /// 消息体没有变化、编码不支持或解压失败时返回None，此时应原样转发
+    pub fn apply(&self, body: &[u8], content_encoding: Option<&str>) -> Option<RewrittenBody> {
+        let Some(encoding) = BodyEncoding::parse(content_encoding) else {
+            log::warn!("Skipping body rewrite of unsupported Content-Encoding {content_encoding:?}");
+            return None;
+        };
+        let decoded = match encoding.decode(body) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                log::warn!("Skipping body rewrite, failed to decode {encoding:?} body: {e}");
+                return None;
+            },
+        };
+
+        let rewritten = self.rewrite(&decoded);
+        if rewritten == decoded {
+            return None;
+        }
+        match encoding.encode(&rewritten) {
+            Ok(encoded) => Some(RewrittenBody { encoded, decoded: rewritten }),
+            Err(e) => {
+                log::warn!("Skipping body rewrite, failed to encode {encoding:?} body: {e}");
+                None
+            },
+        }
+    }
+
+    /// 按顺序对解压后的消息体执行所有改写动作
+    fn rewrite(&self, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        for action in &self.actions {
+            match action {
+                BodyAction::Replace { pattern, replacement } => match std::str::from_utf8(&body) {
+                    Ok(text) => body = pattern.replace_all(text, replacement.as_str()).into_owned().into_bytes(),
+                    Err(_) => log::warn!("Skipping regex rewrite of non UTF-8 body"),
+                },
+                BodyAction::JsonSet { pointer, value } => {
+                    patch_json(&mut body, pointer, |json| json_set(json, pointer, value.clone()));
+                },
+                BodyAction::JsonDelete { pointer } => {
+                    patch_json(&mut body, pointer, |json| json_delete(json, pointer));
+                },
+            }
+        }
+        body
+    }
+}
+
+/// 重新压缩br消息体的质量（0-11），兼顾压缩率和速度
+const BROTLI_QUALITY: u32 = 5;
+/// 重新压缩br消息体的窗口大小（log2）
+const BROTLI_WINDOW: u32 = 22;
+
+/// 支持改写的Content-Encoding
+#[derive(Debug, Clone, Copy)]
+enum BodyEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl BodyEncoding {
+    /// 解析Content-Encoding，不支持的编码返回None
+    fn parse(content_encoding: Option<&str>) -> Option<Self> {
+        match content_encoding.map(|e| e.trim().to_lowercase()).as_deref() {
+            None | Some("") | Some("identity") => Some(BodyEncoding::Identity),
+            Some("gzip") | Some("x-gzip") => Some(BodyEncoding::Gzip),
+            Some("deflate") => Some(BodyEncoding::Deflate),
+            Some("br") => Some(BodyEncoding::Brotli),
+            _ => None,
+        }
+    }
+
+    /// 解压消息体
+    fn decode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        match self {
+            BodyEncoding::Identity => decoded.extend_from_slice(body),
+            BodyEncoding::Gzip => {
+                flate2::read::GzDecoder::new(body).read_to_end(&mut decoded)?;
+            },
+            BodyEncoding::Deflate => {
+                flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded)?;
+            },
+            BodyEncoding::Brotli => {
+                brotli::Decompressor::new(body, 4096).read_to_end(&mut decoded)?;
+            },
+        }
+        Ok(decoded)
+    }
+
+    /// 压缩消息体
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            BodyEncoding::Identity => Ok(body.to_vec()),
+            BodyEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            BodyEncoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            BodyEncoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(body)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            },
+        }
+    }
+}
+
+/// 把消息体解析为JSON并执行修改，修改成功时重新序列化
+///
+/// # 参数
+/// * `body` - 消息体，不是JSON或修改没有生效时保持不变
+/// * `pointer` - 修改的JSON Pointer（用于日志）
+/// * `patch` - 修改函数，返回是否修改了JSON
+fn patch_json(body: &mut Vec<u8>, pointer: &str, patch: impl FnOnce(&mut Value) -> bool) {
+    let mut json: Value = match serde_json::from_slice(body) {
+        Ok(json) => json,
+        Err(e) => {
+            log::warn!("Skipping JSON rewrite of {pointer}, body is not JSON: {e}");
+            return;
+        },
+    };
+    if !patch(&mut json) {
+        log::debug!("JSON Pointer {pointer} not found in body");
+        return;
+    }
+    if let Ok(patched) = serde_json::to_vec(&json) {
+        *body = patched;
+    }
+}
+
+/// 拆分JSON Pointer为父节点的Pointer和最后一级的键（已按RFC 6901反转义）
+fn split_pointer(pointer: &str) -> Option<(&str, String)> {
+    let (parent, key) = pointer.rsplit_once('/')?;
+    Some((parent, key.replace("~1", "/").replace("~0", "~")))
+}
+
+/// 设置JSON Pointer指向的值，目标不存在时在父对象中新增字段或向父数组追加元素（键为 `-` 或数组长度）
+fn json_set(json: &mut Value, pointer: &str, value: Value) -> bool {
+    if let Some(target) = json.pointer_mut(pointer) {
+        *target = value;
+        return true;
+    }
+    let Some((parent, key)) = split_pointer(pointer) else {
+        return false;
+    };
+    match json.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(key, value);
+            true
+        },
+        Some(Value::Array(items)) if key == "-" || key == items.len().to_string() => {
+            items.push(value);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// 删除JSON Pointer指向的字段或数组元素
+fn json_delete(json: &mut Value, pointer: &str) -> bool {
+    let Some((parent, key)) = split_pointer(pointer) else {
+        return false;
+    };
+    match json.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(&key).is_some(),
+        Some(Value::Array(items)) => match key.parse::<usize>() {
+            Ok(index) if index < items.len() => {
+                items.remove(index);
+                true
+            },
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// 按Content-Encoding解压消息体
+///
+/// # 返回值
+/// 编码不支持或解压失败时返回None
+pub fn decode_body(body: &[u8], content_encoding: Option<&str>) -> Option<Vec<u8>> {
+    BodyEncoding::parse(content_encoding)?.decode(body).ok()
+}
+
+/// 按头部中的Content-Encoding压缩消息体，并按压缩后的长度设置Content-Length
+///
+/// 编码不支持或压缩失败时去掉Content-Encoding，发送未压缩的消息体。
+///
+/// # 参数
+/// * `body` - 解压后的消息体
+/// * `headers` - 要修改的头部列表
+pub fn encode_body(body: &[u8], headers: &mut HeaderList) -> Vec<u8> {
+    let encoded = BodyEncoding::parse(find_header(headers, "content-encoding"))
+        .and_then(|encoding| encoding.encode(body).ok());
+    let encoded = encoded.unwrap_or_else(|| {
+        headers.retain(|(key, _)| !key.eq_ignore_ascii_case("content-encoding"));
+        body.to_vec()
+    });
+    set_content_length(headers, encoded.len());
+    encoded
+}
+
+/// 查找头部的值（不区分大小写），有多个同名头部时返回第一个
+pub fn find_header<'a>(headers: &'a HeaderList, name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// 按改写后的消息体长度设置Content-Length，并去掉chunked分帧
+///
+/// # 参数
+/// * `headers` - 要修改的头部列表
+/// * `length` - 改写后的消息体长度
+pub fn set_content_length(headers: &mut HeaderList, length: usize) {
+    headers.retain(|(key, _)| !key.eq_ignore_ascii_case("transfer-encoding"));
+    match headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("content-length")) {
+        Some((_, value)) => *value = length.to_string(),
+        None => headers.push(("Content-Length".to_string(), length.to_string())),
+    }
+}
+
+/// 解析请求头或响应头的头部行
+///
+/// # 参数
+/// * `lines` - 请求行或状态行之后的行，遇到空行结束
+pub fn parse_header_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> HeaderList {
+    lines.into_iter()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// 生成头部行，每行以 `\r\n` 结尾
+pub fn format_header_lines(headers: &HeaderList) -> String {
+    headers.iter().map(|(key, value)| format!("{key}: {value}\r\n")).collect()
+}
+
+/// 转换为日志记录使用的格式
+pub fn headers_for_log(headers: &HeaderList) -> HashMap<String, String> {
+    headers.iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_config(section: &str, rules: &str) -> Config {
+        serde_json::from_str(&format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": ["*"], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" }},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }},
+            "{section}": {{ "rules": {rules} }}
+        }}"#)).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite_request_headers() {
+        let config = create_test_config("headers", r#"[
+            { "action": "default", "name": "User-Agent", "value": "study-proxy" },
+            { "action": "default", "name": "Accept", "value": "*/*" },
+            { "action": "set", "name": "x-env", "value": "staging" },
+            { "action": "add", "name": "X-Trace", "value": "2" },
+            { "hosts": ["api.example.com"], "action": "remove", "name": "cookie" },
+            { "action": "replace", "name": "Authorization", "pattern": "^Bearer (.*)$", "replacement": "Token $1" },
+            { "phase": "response", "action": "remove", "name": "Accept" }
+        ]"#);
+        let mut headers = parse_header_lines([
+            "Accept: text/html",
+            "X-Env: prod",
+            "Cookie: a=1",
+            "X-Trace: 1",
+            "X-Env: prod2",
+            "Authorization: Bearer abc",
+            "",
+            "Ignored: after empty line",
+        ]);
+
+        let rewrite = HeaderRewrite::new(&config, RewritePhase::Request, "api.example.com", "GET", "/");
+        rewrite.apply(&mut headers);
+        let expected = [
+            ("Accept", "text/html"),
+            ("x-env", "staging"),
+            ("X-Trace", "1"),
+            ("Authorization", "Token abc"),
+            ("User-Agent", "study-proxy"),
+            ("X-Trace", "2"),
+        ];
+        let expected: HeaderList = expected.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(headers, expected);
+        assert!(format_header_lines(&headers).starts_with("Accept: text/html\r\nx-env: staging\r\n"));
+
+        // 不匹配的主机不删除Cookie
+        let mut headers = parse_header_lines(["Cookie: a=1"]);
+        HeaderRewrite::new(&config, RewritePhase::Request, "example.com", "GET", "/").apply(&mut headers);
+        assert_eq!(headers[0], ("Cookie".to_string(), "a=1".to_string()));
+    }
+
+    #[test]
+    fn test_rewrite_header_map() {
+        let config = create_test_config("headers", r#"[
+            { "phase": "response", "action": "set", "name": "Cache-Control", "value": "no-store" },
+            { "phase": "response", "action": "add", "name": "X-Bad", "value": "line\nbreak" }
+        ]"#);
+        let mut headers = http::HeaderMap::new();
+        headers.insert("cache-control", "max-age=60".parse().unwrap());
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+
+        HeaderRewrite::new(&config, RewritePhase::Response, "example.com", "GET", "/").apply_to_header_map(&mut headers);
+        assert_eq!(headers["cache-control"], "no-store");
+        assert_eq!(headers.get_all("set-cookie").iter().count(), 2);
+        assert!(!headers.contains_key("x-bad"));
+    }
+    #[test]
+    fn test_rewrite_json_body() {
+        let config = create_test_config("body", r#"[
+            { "phase": "response", "action": "json_set", "pointer": "/flags/beta", "value": true },
+            { "phase": "response", "action": "json_set", "pointer": "/items/-", "value": { "id": 3 } },
+            { "phase": "response", "action": "json_delete", "pointer": "/items/0" },
+            { "phase": "response", "action": "json_delete", "pointer": "/a~1b" },
+            { "phase": "response", "action": "json_set", "pointer": "/missing/key", "value": 1 },
+            { "phase": "response", "action": "replace", "pattern": "\"id\":(\\d)", "replacement": "\"id\":\"$1\"" }
+        ]"#);
+        let rewrite = BodyRewrite::new(&config, RewritePhase::Response, "example.com", "GET", "/");
+        let body = br#"{"flags":{"beta":false},"items":[{"id":1},{"id":2}],"a/b":0}"#;
+
+        let rewritten = rewrite.apply(body, None).unwrap();
+        let json: Value = serde_json::from_slice(&rewritten.encoded).unwrap();
+        assert_eq!(json, serde_json::json!({ "flags": { "beta": true }, "items": [{ "id": "2" }, { "id": "3" }] }));
+        assert_eq!(rewritten.decoded, rewritten.encoded);
+        assert_eq!(rewritten.body_for_log(5), "{\"fla");
+
+        // 不是JSON的消息体只执行正则替换，没有变化时返回None
+        assert!(rewrite.apply(b"plain text", None).is_none());
+        assert!(BodyRewrite::new(&config, RewritePhase::Request, "example.com", "GET", "/").is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_encoded_body() {
+        let config = create_test_config("body", r#"[
+            { "action": "replace", "pattern": "https://cdn\\.example\\.com", "replacement": "http://localhost:8080" }
+        ]"#);
+        let rewrite = BodyRewrite::new(&config, RewritePhase::Request, "example.com", "POST", "/");
+        let html = b"<script src=\"https://cdn.example.com/app.js\"></script>";
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(html).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let rewritten = rewrite.apply(&gzipped, Some("gzip")).unwrap();
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&rewritten.encoded[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "<script src=\"http://localhost:8080/app.js\"></script>");
+
+        // br压缩的消息体同样解压改写后重新压缩
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(html).unwrap();
+        let compressed = encoder.into_inner();
+        let rewritten = rewrite.apply(&compressed, Some("br")).unwrap();
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&rewritten.encoded[..], 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "<script src=\"http://localhost:8080/app.js\"></script>");
+        assert_eq!(rewritten.body_for_log(100), decoded);
+
+        // 不支持的编码或无法解压的消息体原样转发
+        assert!(rewrite.apply(html, Some("zstd")).is_none());
+        assert!(rewrite.apply(html, Some("br")).is_none());
+        assert!(rewrite.apply(html, Some("gzip")).is_none());
+
+        let mut headers = parse_header_lines(["Transfer-Encoding: chunked", "content-length: 10", "Content-Encoding: gzip"]);
+        set_content_length(&mut headers, 42);
+        assert_eq!(format_header_lines(&headers), "content-length: 42\r\nContent-Encoding: gzip\r\n");
+
+        let encoded = encode_body(html, &mut headers);
+        assert_eq!(find_header(&headers, "Content-Length"), Some(encoded.len().to_string().as_str()));
+        assert_eq!(decode_body(&encoded, find_header(&headers, "content-encoding")).unwrap(), html);
+        // 不支持的编码去掉Content-Encoding后发送未压缩的消息体
+        let mut headers = parse_header_lines(["Content-Encoding: zstd"]);
+        assert_eq!(encode_body(html, &mut headers), html);
+        assert_eq!(format_header_lines(&headers), format!("Content-Length: {}\r\n", html.len()));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,746 @@
+use crate::config::{Config, RewritePhase, ScriptRule};
+use crate::interceptor::{async_trait, Action, Flow, FlowResponse, Interceptor};
+use crate::rewrite::HeaderList;
+use anyhow::Result;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// 脚本单次调用允许执行的最大操作数，防止死循环阻塞代理
+const MAX_SCRIPT_OPERATIONS: u64 = 10_000_000;
+
+/// 检查脚本文件是否变化的最小间隔
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 脚本函数的返回值
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// 存储的值
+type StorageValues = Arc<Mutex<serde_json::Map<String, serde_json::Value>>>;
+
+/// 脚本的本地存储，所有脚本共享，重新加载脚本后保留
+///
+/// 配置了持久化文件时启动时从文件加载，修改后由后台线程写回文件，连续的修改合并为一次写入。
+#[derive(Debug)]
+struct ScriptStorage {
+    /// 存储的值
+    values: StorageValues,
+    /// 通知后台线程写入文件，以及后台线程的句柄
+    writer: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl ScriptStorage {
+    /// 打开本地存储
+    ///
+    /// # 参数
+    /// * `file` - 持久化文件（可选），文件不存在时从空存储开始
+    fn open(file: Option<&str>) -> Result<Self> {
+        let file = file.map(PathBuf::from);
+        let values = match &file {
+            Some(path) if path.exists() => {
+                let content = std::fs::read_to_string(path)?;
+                serde_json::from_str(&content)
+                    .map_err(|e| anyhow::anyhow!("Invalid script storage file {}: {e}", path.display()))?
+            },
+            _ => serde_json::Map::new(),
+        };
+        let values = Arc::new(Mutex::new(values));
+        let writer = match file {
+            Some(path) => {
+                let (sender, receiver) = std::sync::mpsc::channel();
+                let values = Arc::clone(&values);
+                let handle = std::thread::Builder::new()
+                    .name("script-storage".to_string())
+                    .spawn(move || {
+                        while receiver.recv().is_ok() {
+                            // 合并等待中的修改，只写入最新的内容
+                            while receiver.try_recv().is_ok() {}
+                            persist(&path, &values);
+                        }
+                    })?;
+                Some((sender, handle))
+            },
+            None => None,
+        };
+        Ok(Self { values, writer })
+    }
+
+    /// 读取值，不存在时返回Null
+    fn get(&self, key: &str) -> serde_json::Value {
+        self.values.lock().unwrap().get(key).cloned().unwrap_or_default()
+    }
+
+    /// 设置值，值为Null时删除
+    fn set(&self, key: &str, value: serde_json::Value) {
+        let mut values = self.values.lock().unwrap();
+        match value {
+            serde_json::Value::Null => values.remove(key),
+            value => values.insert(key.to_string(), value),
+        };
+        if let Some((sender, _)) = &self.writer {
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Drop for ScriptStorage {
+    /// 等待后台线程写完最后的修改
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.writer.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+/// 把存储的值写入持久化文件
+///
+/// # 参数
+/// * `path` - 持久化文件
+/// * `values` - 存储的值
+fn persist(path: &std::path::Path, values: &StorageValues) {
+    let content = serde_json::to_string_pretty(&*values.lock().unwrap()).unwrap_or_default();
+    if let Err(e) = std::fs::write(path, content) {
+        log::warn!("Failed to write script storage {}: {e}", path.display());
+    }
+}
+
+/// 脚本处理中的流，`req` 和 `resp` 共享同一个流
+#[derive(Debug)]
+struct FlowState {
+    /// 脚本修改后的流
+    flow: Flow,
+    /// 脚本要求的处理方式（直接应答或丢弃）
+    action: Option<Action>,
+}
+
+/// 脚本中的请求（`req`）
+#[derive(Debug, Clone)]
+struct ScriptRequest(Arc<Mutex<FlowState>>);
+
+/// 脚本中的响应（`resp`）
+#[derive(Debug, Clone)]
+struct ScriptResponse(Arc<Mutex<FlowState>>);
+
+/// 请求和响应共用的头部和消息体访问
+trait ScriptMessage: Clone + Send + Sync + 'static {
+    /// 访问头部和消息体
+    fn with_message<R>(&mut self, f: impl FnOnce(&mut HeaderList, &mut Vec<u8>) -> R) -> R;
+}
+
+impl ScriptMessage for ScriptRequest {
+    fn with_message<R>(&mut self, f: impl FnOnce(&mut HeaderList, &mut Vec<u8>) -> R) -> R {
+        let mut state = self.0.lock().unwrap();
+        let request = &mut state.flow.request;
+        f(&mut request.headers, &mut request.body)
+    }
+}
+
+impl ScriptMessage for ScriptResponse {
+    fn with_message<R>(&mut self, f: impl FnOnce(&mut HeaderList, &mut Vec<u8>) -> R) -> R {
+        let mut state = self.0.lock().unwrap();
+        let response = state.flow.response.as_mut().expect("response handle without response");
+        f(&mut response.headers, &mut response.body)
+    }
+}
+
+/// 转换为脚本中的错误
+fn script_error(e: impl std::fmt::Display) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+/// 把JSON值转换为脚本中的值
+fn json_to_dynamic(value: &serde_json::Value) -> ScriptResult<Dynamic> {
+    rhai::serde::to_dynamic(value)
+}
+
+/// 把脚本中的值转换为JSON值
+fn dynamic_to_json(value: &Dynamic) -> ScriptResult<serde_json::Value> {
+    rhai::serde::from_dynamic(value)
+}
+
+/// 头部转换为脚本中的对象，同名头部的值用逗号连接
+fn headers_to_map(headers: &HeaderList) -> Map {
+    let mut map = Map::new();
+    for (key, value) in headers {
+        let existing = map.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case(key));
+        match existing {
+            Some((_, existing)) => *existing = format!("{existing}, {value}").into(),
+            None => {
+                map.insert(key.as_str().into(), value.clone().into());
+            },
+        }
+    }
+    map
+}
+
+/// 脚本中的对象转换为头部
+fn map_to_headers(map: Map) -> HeaderList {
+    map.into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+/// 注册请求和响应共用的头部、消息体和JSON访问
+fn register_message_api<T: ScriptMessage>(engine: &mut Engine) {
+    engine
+        .register_get_set(
+            "headers",
+            |message: &mut T| message.with_message(|headers, _| headers_to_map(headers)),
+            |message: &mut T, map: Map| message.with_message(|headers, _| *headers = map_to_headers(map)),
+        )
+        .register_fn("header", |message: &mut T, name: &str| {
+            message.with_message(|headers, _| {
+                headers.iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| Dynamic::from(value.clone()))
+                    .unwrap_or(Dynamic::UNIT)
+            })
+        })
+        .register_fn("set_header", |message: &mut T, name: &str, value: Dynamic| {
+            message.with_message(|headers, _| {
+                let value = value.to_string();
+                match headers.iter().position(|(key, _)| key.eq_ignore_ascii_case(name)) {
+                    Some(index) => {
+                        headers[index].1 = value;
+                        let mut position = 0;
+                        headers.retain(|(key, _)| {
+                            position += 1;
+                            position - 1 == index || !key.eq_ignore_ascii_case(name)
+                        });
+                    },
+                    None => headers.push((name.to_string(), value)),
+                }
+            })
+        })
+        .register_fn("remove_header", |message: &mut T, name: &str| {
+            message.with_message(|headers, _| headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name)))
+        })
+        .register_get_set(
+            "body",
+            |message: &mut T| message.with_message(|_, body| String::from_utf8_lossy(body).to_string()),
+            |message: &mut T, value: String| message.with_message(|_, body| *body = value.into_bytes()),
+        )
+        .register_get_set(
+            "json",
+            |message: &mut T| -> ScriptResult<Dynamic> {
+                let value: serde_json::Value = message.with_message(|_, body| serde_json::from_slice(body))
+                    .map_err(|e| script_error(format!("Body is not valid JSON: {e}")))?;
+                json_to_dynamic(&value)
+            },
+            |message: &mut T, value: Dynamic| -> ScriptResult<()> {
+                let value = serde_json::to_vec(&dynamic_to_json(&value)?).map_err(script_error)?;
+                message.with_message(|_, body| *body = value);
+                Ok(())
+            },
+        );
+}
+
+/// 创建脚本引擎，注册请求、响应、JSON、日志和本地存储的接口
+fn create_engine(storage: Arc<ScriptStorage>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+    engine.on_print(|text| log::info!("📜 {text}"));
+    engine.on_debug(|text, source, position| log::debug!("📜 {}{position:?}: {text}", source.unwrap_or_default()));
+
+    // 请求
+    engine
+        .register_type_with_name::<ScriptRequest>("Request")
+        .register_get("host", |req: &mut ScriptRequest| req.0.lock().unwrap().flow.host.clone())
+        .register_get_set(
+            "method",
+            |req: &mut ScriptRequest| req.0.lock().unwrap().flow.request.method.clone(),
+            |req: &mut ScriptRequest, method: String| -> ScriptResult<()> {
+                req.0.lock().unwrap().flow.request.set_method(&method).map_err(script_error)
+            },
+        )
+        .register_get_set(
+            "url",
+            |req: &mut ScriptRequest| req.0.lock().unwrap().flow.request.url(),
+            |req: &mut ScriptRequest, url: String| -> ScriptResult<()> {
+                req.0.lock().unwrap().flow.request.set_url(&url).map_err(script_error)
+            },
+        )
+        .register_get_set(
+            "path",
+            |req: &mut ScriptRequest| req.0.lock().unwrap().flow.request.path.clone(),
+            |req: &mut ScriptRequest, path: String| -> ScriptResult<()> {
+                if !path.starts_with('/') {
+                    return Err(script_error(format!("Path must start with '/': {path}")));
+                }
+                req.0.lock().unwrap().flow.request.path = path;
+                Ok(())
+            },
+        )
+        .register_fn("respond", |req: &mut ScriptRequest, status: i64, body: &str| -> ScriptResult<()> {
+            respond(req, status, body, Map::new())
+        })
+        .register_fn("respond", |req: &mut ScriptRequest, status: i64, body: &str, headers: Map| -> ScriptResult<()> {
+            respond(req, status, body, headers)
+        })
+        .register_fn("drop", |req: &mut ScriptRequest| req.0.lock().unwrap().action = Some(Action::Drop));
+    register_message_api::<ScriptRequest>(&mut engine);
+
+    // 响应
+    engine
+        .register_type_with_name::<ScriptResponse>("Response")
+        .register_get_set(
+            "status",
+            |resp: &mut ScriptResponse| resp.with_status(|status| *status as i64),
+            |resp: &mut ScriptResponse, status: i64| -> ScriptResult<()> {
+                let status = parse_status(status)?;
+                resp.with_status(|current| *current = status);
+                Ok(())
+            },
+        )
+        .register_fn("drop", |resp: &mut ScriptResponse| resp.0.lock().unwrap().action = Some(Action::Drop));
+    register_message_api::<ScriptResponse>(&mut engine);
+
+    // JSON
+    engine
+        .register_fn("parse_json", |text: &str| -> ScriptResult<Dynamic> {
+            let value: serde_json::Value = serde_json::from_str(text).map_err(script_error)?;
+            json_to_dynamic(&value)
+        })
+        .register_fn("to_json", |value: Dynamic| -> ScriptResult<String> {
+            serde_json::to_string(&dynamic_to_json(&value)?).map_err(script_error)
+        });
+
+    // 日志
+    engine
+        .register_fn("log_info", |message: &str| log::info!("📜 {message}"))
+        .register_fn("log_warn", |message: &str| log::warn!("📜 {message}"))
+        .register_fn("log_error", |message: &str| log::error!("📜 {message}"));
+
+    // 本地存储
+    let get_storage = Arc::clone(&storage);
+    let set_storage = Arc::clone(&storage);
+    engine
+        .register_fn("storage_get", move |key: &str| json_to_dynamic(&get_storage.get(key)))
+        .register_fn("storage_set", move |key: &str, value: Dynamic| -> ScriptResult<()> {
+            set_storage.set(key, dynamic_to_json(&value)?);
+            Ok(())
+        })
+        .register_fn("storage_remove", move |key: &str| storage.set(key, serde_json::Value::Null));
+
+    engine
+}
+
+impl ScriptResponse {
+    /// 访问响应状态码
+    fn with_status<R>(&mut self, f: impl FnOnce(&mut u16) -> R) -> R {
+        let mut state = self.0.lock().unwrap();
+        let response = state.flow.response.as_mut().expect("response handle without response");
+        f(&mut response.status)
+    }
+}
+
+/// 检查脚本设置的状态码
+fn parse_status(status: i64) -> ScriptResult<u16> {
+    match u16::try_from(status) {
+        Ok(status) if (100..1000).contains(&status) => Ok(status),
+        _ => Err(script_error(format!("Invalid status code: {status}"))),
+    }
+}
+
+/// 脚本直接应答请求
+fn respond(req: &mut ScriptRequest, status: i64, body: &str, headers: Map) -> ScriptResult<()> {
+    let response = FlowResponse {
+        status: parse_status(status)?,
+        headers: map_to_headers(headers),
+        body: body.as_bytes().to_vec(),
+    };
+    req.0.lock().unwrap().action = Some(Action::Respond(response));
+    Ok(())
+}
+
+/// 按配置加载的Rhai脚本，作为拦截器处理匹配的请求和响应
+///
+/// 脚本可以定义 `on_request(req)` 和 `on_response(req, resp)`，没有定义的回调不会被调用，
+/// 回调在阻塞线程池中执行，不占用处理连接的线程。
+/// 后台线程每秒检查一次脚本文件，修改后重新编译并替换当前的脚本，加载失败时继续使用之前的版本。
+pub struct ScriptInterceptor {
+    /// 脚本规则
+    rule: ScriptRule,
+    /// 所有脚本共享的脚本引擎
+    engine: Arc<Engine>,
+    /// 当前加载的脚本
+    script: RwLock<Arc<AST>>,
+    /// 上次加载时文件的修改时间和长度，变化时重新加载
+    version: Mutex<(Option<SystemTime>, u64)>,
+}
+
+impl ScriptInterceptor {
+    /// 加载脚本
+    ///
+    /// # 参数
+    /// * `rule` - 脚本规则
+    /// * `engine` - 脚本引擎
+    fn load(rule: ScriptRule, engine: Arc<Engine>) -> Result<Self> {
+        let (ast, version) = compile(&engine, &rule.file)
+            .map_err(|e| anyhow::anyhow!("Failed to load script {}: {e}", rule.file))?;
+        log::info!("📜 Loaded script {}", rule.file);
+        Ok(Self {
+            rule,
+            engine,
+            script: RwLock::new(Arc::new(ast)),
+            version: Mutex::new(version),
+        })
+    }
+
+    /// 启动后台线程，每隔 [`RELOAD_CHECK_INTERVAL`] 检查一次脚本文件，脚本被释放后线程退出
+    fn watch(script: &Arc<Self>) -> Result<()> {
+        let script = Arc::downgrade(script);
+        std::thread::Builder::new()
+            .name("script-watcher".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(RELOAD_CHECK_INTERVAL);
+                let Some(script) = script.upgrade() else {
+                    break;
+                };
+                script.reload_if_changed();
+            })?;
+        Ok(())
+    }
+
+    /// 脚本文件变化时重新加载，编译期间不影响使用当前脚本的请求
+    fn reload_if_changed(&self) {
+        let Ok(version) = file_version(&self.rule.file) else {
+            return;
+        };
+        let mut loaded = self.version.lock().unwrap();
+        if version == *loaded {
+            return;
+        }
+        // 加载失败时也记录文件版本，文件再次修改前不重复加载
+        *loaded = version;
+        match compile(&self.engine, &self.rule.file) {
+            Ok((ast, version)) => {
+                log::info!("🔄 Reloaded script {}", self.rule.file);
+                *self.script.write().unwrap() = Arc::new(ast);
+                *loaded = version;
+            },
+            Err(e) => log::error!("❌ Failed to reload script {}, keeping previous version: {e}", self.rule.file),
+        }
+    }
+
+    /// 当前的脚本
+    fn current(&self) -> Arc<AST> {
+        Arc::clone(&self.script.read().unwrap())
+    }
+
+    /// 调用脚本的回调，按脚本的修改更新流
+    ///
+    /// 脚本出错时流保持不变，错误记录在 `flow.error` 中。
+    ///
+    /// # 参数
+    /// * `flow` - 处理中的流
+    /// * `callback` - 回调函数名
+    async fn call(&self, flow: &mut Flow, callback: &'static str) -> Action {
+        let engine = Arc::clone(&self.engine);
+        let ast = self.current();
+        let input = flow.clone();
+        let result = tokio::task::spawn_blocking(move || run_callback(&engine, &ast, input, callback))
+            .await
+            .unwrap_or_else(|e| Err(script_error(e)));
+        match result {
+            Ok((modified, action)) => {
+                *flow = modified;
+                action.unwrap_or(Action::Continue)
+            },
+            Err(e) => {
+                log::error!("❌ Script {} failed in {callback}: {e}", self.rule.file);
+                let error = format!("Script {} failed in {callback}: {e}", self.rule.file);
+                flow.error = Some(match flow.error.take() {
+                    Some(existing) => format!("{existing}; {error}"),
+                    None => error,
+                });
+                Action::Continue
+            },
+        }
+    }
+}
+
+/// 执行脚本的回调
+///
+/// # 参数
+/// * `engine` - 脚本引擎
+/// * `ast` - 编译后的脚本
+/// * `flow` - 处理中的流
+/// * `callback` - 回调函数名
+///
+/// # 返回值
+/// 脚本修改后的流和要求的处理方式，脚本出错时返回错误
+fn run_callback(engine: &Engine, ast: &AST, flow: Flow, callback: &str) -> ScriptResult<(Flow, Option<Action>)> {
+    let has_response = flow.response.is_some();
+    let state = Arc::new(Mutex::new(FlowState { flow, action: None }));
+    let request = ScriptRequest(Arc::clone(&state));
+    let mut scope = Scope::new();
+    if has_response {
+        let _ = engine.call_fn::<Dynamic>(&mut scope, ast, callback, (request, ScriptResponse(Arc::clone(&state))))?;
+    } else {
+        let _ = engine.call_fn::<Dynamic>(&mut scope, ast, callback, (request,))?;
+    }
+    let mut state = state.lock().unwrap();
+    let action = state.action.take();
+    Ok((state.flow.clone(), action))
+}
+
+#[async_trait]
+impl Interceptor for ScriptInterceptor {
+    fn name(&self) -> &str {
+        &self.rule.file
+    }
+
+    fn intercepts_host(&self, host: &str) -> bool {
+        self.rule.flow.matches_host(host)
+    }
+
+    fn matches(&self, phase: RewritePhase, host: &str, method: &str, path: &str) -> bool {
+        let (callback, arity) = callback_for(phase);
+        self.rule.flow.matches(host, method, path)
+            && self.current().iter_functions().any(|f| f.name == callback && f.params.len() == arity)
+    }
+
+    async fn on_request(&self, flow: &mut Flow) -> Action {
+        self.call(flow, callback_for(RewritePhase::Request).0).await
+    }
+
+    async fn on_response(&self, flow: &mut Flow) -> Action {
+        self.call(flow, callback_for(RewritePhase::Response).0).await
+    }
+}
+
+/// 请求和响应对应的回调函数名和参数个数
+fn callback_for(phase: RewritePhase) -> (&'static str, usize) {
+    match phase {
+        RewritePhase::Request => ("on_request", 1),
+        RewritePhase::Response => ("on_response", 2),
+    }
+}
+
+/// 文件的修改时间和长度
+fn file_version(file: &str) -> std::io::Result<(Option<SystemTime>, u64)> {
+    let metadata = std::fs::metadata(file)?;
+    Ok((metadata.modified().ok(), metadata.len()))
+}
+
+/// 读取并编译脚本
+fn compile(engine: &Engine, file: &str) -> Result<(AST, (Option<SystemTime>, u64))> {
+    let version = file_version(file)?;
+    let source = std::fs::read_to_string(file)?;
+    let mut ast = engine.compile(source).map_err(|e| anyhow::anyhow!("{e}"))?;
+    ast.set_source(file);
+    Ok((ast, version))
+}
+
+/// 按配置加载所有脚本
+///
+/// # 参数
+/// * `config` - 配置信息
+///
+/// # 返回值
+/// 按配置顺序排列的脚本拦截器，脚本无法加载或编译时返回错误
+pub fn load_scripts(config: &Config) -> Result<Vec<Arc<dyn Interceptor>>> {
+    if config.scripts.rules.is_empty() {
+        return Ok(Vec::new());
+    }
+    let storage = Arc::new(ScriptStorage::open(config.scripts.storage_file.as_deref())?);
+    let engine = Arc::new(create_engine(storage));
+    config.scripts.rules.iter()
+        .map(|rule| {
+            let script = Arc::new(ScriptInterceptor::load(rule.clone(), Arc::clone(&engine))?);
+            ScriptInterceptor::watch(&script)?;
+            Ok(script as Arc<dyn Interceptor>)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interceptor::FlowRequest;
+    use tempfile::TempDir;
+
+    fn create_config(temp_dir: &TempDir, script: &str) -> Config {
+        let file = temp_dir.path().join("script.rhai");
+        std::fs::write(&file, script).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "proxy": { "host": "127.0.0.1", "port": 8888 },
+            "target": { "domains": ["*"], "ports": ["*"] },
+            "certificates": { "ca_cert": "certs/ca.crt", "ca_key": "certs/ca.key" },
+            "logging": {
+                "level": "debug",
+                "output": "file",
+                "log_dir": "logs",
+                "program_log": "proxy.log",
+                "domain_logs": { "enabled": true, "format": "{date}_{domain}.log" }
+            },
+            "scripts": {
+                "rules": [{ "file": file.to_str().unwrap(), "hosts": ["api.example.com"] }],
+                "storage_file": temp_dir.path().join("storage.json").to_str().unwrap()
+            }
+        })).unwrap()
+    }
+
+    fn load_script(temp_dir: &TempDir, script: &str) -> Arc<dyn Interceptor> {
+        load_scripts(&create_config(temp_dir, script)).unwrap().remove(0)
+    }
+
+    fn create_flow(response: Option<FlowResponse>) -> Flow {
+        Flow {
+            host: "api.example.com".to_string(),
+            request: FlowRequest {
+                method: "GET".to_string(),
+                origin: "https://api.example.com".to_string(),
+                path: "/v1/user".to_string(),
+                headers: vec![("Host".to_string(), "api.example.com".to_string())],
+                body: br#"{"name":"alice"}"#.to_vec(),
+            },
+            response,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_modify_request_and_response() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = load_script(&temp_dir, r#"
+            fn on_request(req) {
+                req.method = "POST";
+                req.url = req.url + "?debug=1";
+                req.set_header("X-Script", req.header("host"));
+                let body = req.json;
+                body.name = "bob";
+                req.json = body;
+            }
+            fn on_response(req, resp) {
+                resp.status = 201;
+                resp.remove_header("Server");
+                resp.body = to_json(#{ method: req.method, hits: 1 });
+            }
+        "#);
+        assert!(script.intercepts_host("api.example.com"));
+        assert!(!script.intercepts_host("www.example.com"));
+        assert!(script.matches(RewritePhase::Request, "api.example.com", "GET", "/"));
+        assert!(script.matches(RewritePhase::Response, "api.example.com", "GET", "/"));
+
+        let mut flow = create_flow(None);
+        assert_eq!(script.on_request(&mut flow).await, Action::Continue);
+        assert_eq!(flow.request.method, "POST");
+        assert_eq!(flow.request.url(), "https://api.example.com/v1/user?debug=1");
+        assert_eq!(flow.request.headers[1], ("X-Script".to_string(), "api.example.com".to_string()));
+        assert_eq!(flow.request.body, br#"{"name":"bob"}"#);
+
+        flow.response = Some(FlowResponse { status: 200, headers: vec![("Server".to_string(), "x".to_string())], body: Vec::new() });
+        assert_eq!(script.on_response(&mut flow).await, Action::Continue);
+        let response = flow.response.unwrap();
+        assert_eq!(response.status, 201);
+        assert!(response.headers.is_empty());
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&response.body).unwrap(), serde_json::json!({ "method": "POST", "hits": 1 }));
+        assert!(flow.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_respond_drop_and_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = load_script(&temp_dir, r#"
+            fn on_request(req) {
+                if req.path == "/mock" {
+                    req.respond(200, "mocked", #{ "Content-Type": "text/plain" });
+                } else if req.path == "/drop" {
+                    req.drop();
+                } else {
+                    req.set_header("X-Partial", "1");
+                    req.url = "https://evil.com/";
+                }
+            }
+        "#);
+        assert!(!script.matches(RewritePhase::Response, "api.example.com", "GET", "/"));
+
+        let mut flow = create_flow(None);
+        flow.request.path = "/mock".to_string();
+        let expected = FlowResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"mocked".to_vec(),
+        };
+        assert_eq!(script.on_request(&mut flow).await, Action::Respond(expected));
+
+        flow.request.path = "/drop".to_string();
+        assert_eq!(script.on_request(&mut flow).await, Action::Drop);
+
+        // 脚本出错时流保持不变，错误记录在流中
+        let mut flow = create_flow(None);
+        assert_eq!(script.on_request(&mut flow).await, Action::Continue);
+        assert_eq!(flow.request.headers.len(), 1);
+        assert!(flow.error.unwrap().contains("URL must stay on https://api.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_hot_reload() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = create_config(&temp_dir, r#"fn on_request(req) { req.path = "/v1"; }"#);
+        let engine = Arc::new(create_engine(Arc::new(ScriptStorage::open(None).unwrap())));
+        let script = ScriptInterceptor::load(config.scripts.rules[0].clone(), engine).unwrap();
+        let file = temp_dir.path().join("script.rhai");
+
+        // 编译失败时继续使用之前的版本
+        std::fs::write(&file, "fn on_request(req) {").unwrap();
+        script.reload_if_changed();
+        let mut flow = create_flow(None);
+        script.on_request(&mut flow).await;
+        assert_eq!(flow.request.path, "/v1");
+
+        // 文件没有变化时不重新加载
+        let previous = script.current();
+        script.reload_if_changed();
+        assert!(Arc::ptr_eq(&previous, &script.current()));
+
+        std::fs::write(&file, r#"fn on_response(req, resp) { resp.status = 204; }"#).unwrap();
+        script.reload_if_changed();
+        assert!(!script.matches(RewritePhase::Request, "api.example.com", "GET", "/"));
+        assert!(script.matches(RewritePhase::Response, "api.example.com", "GET", "/"));
+    }
+
+    #[tokio::test]
+    async fn test_storage_and_json_helpers() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = r#"
+            fn on_request(req) {
+                let count = storage_get("count");
+                if count == () { count = 0; }
+                storage_set("count", count + 1);
+                storage_set("last", parse_json(req.body));
+                storage_remove("missing");
+                log_info(`request ${count}`);
+                req.set_header("X-Count", count);
+            }
+        "#;
+        let script = load_script(&temp_dir, source);
+        for _ in 0..2 {
+            script.on_request(&mut create_flow(None)).await;
+        }
+
+        // 本地存储由后台线程写入文件，释放脚本时等待写入完成，重新启动后保留
+        drop(script);
+        let storage: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(temp_dir.path().join("storage.json")).unwrap()).unwrap();
+        assert_eq!(storage, serde_json::json!({ "count": 2, "last": { "name": "alice" } }));
+        let script = load_script(&temp_dir, source);
+        let mut flow = create_flow(None);
+        script.on_request(&mut flow).await;
+        assert_eq!(flow.request.headers[1].1, "2");
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = create_config(&temp_dir, "fn on_request(req) {");
+        assert!(load_scripts(&config).is_err());
+        config.scripts.rules[0].file = temp_dir.path().join("missing.rhai").to_str().unwrap().to_string();
+        assert!(load_scripts(&config).is_err());
+        config.scripts.rules.clear();
+        assert!(load_scripts(&config).unwrap().is_empty());
+    }
+}
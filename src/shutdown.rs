@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+/// 代理服务器的关闭句柄
+///
+/// 触发关闭后监听循环停止接受新连接，空闲的keep-alive连接在下一个请求之前关闭，
+/// 正在处理的连接通过 [`ShutdownHandle::drain`] 等待结束。句柄可以克隆后在其他任务中使用。
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug)]
+struct ShutdownState {
+    /// 是否已触发关闭
+    signal: watch::Sender<bool>,
+    /// 正在处理的连接数
+    active: AtomicUsize,
+    /// 连接数降为0时通知
+    drained: Notify,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownHandle {
+    /// 创建未触发的关闭句柄
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(ShutdownState {
+                signal: watch::Sender::new(false),
+                active: AtomicUsize::new(0),
+                drained: Notify::new(),
+            }),
+        }
+    }
+
+    /// 触发关闭，可以重复调用
+    pub fn shutdown(&self) {
+        self.inner.signal.send_replace(true);
+    }
+
+    /// 是否已触发关闭
+    pub fn is_shutdown(&self) -> bool {
+        *self.inner.signal.borrow()
+    }
+
+    /// 等待关闭被触发
+    pub async fn wait(&self) {
+        let mut receiver = self.inner.signal.subscribe();
+        // 发送端随句柄一起存在，不会返回错误
+        let _ = receiver.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// 登记一个正在处理的连接，返回的guard释放时结束登记
+    pub fn track(&self) -> ConnectionGuard {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// 正在处理的连接数
+    pub fn active_connections(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// 等待所有登记的连接结束
+    ///
+    /// # 参数
+    /// * `grace` - 最长等待时间
+    ///
+    /// # 返回值
+    /// 所有连接都已结束返回true，超时返回false
+    pub async fn drain(&self, grace: Duration) -> bool {
+        let wait_all = async {
+            loop {
+                let drained = self.inner.drained.notified();
+                if self.active_connections() == 0 {
+                    return;
+                }
+                drained.await;
+            }
+        };
+        tokio::time::timeout(grace, wait_all).await.is_ok()
+    }
+}
+
+/// 正在处理的连接的登记，释放时减少连接数
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    inner: Arc<ShutdownState>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.inner.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.drained.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_signal() {
+        let handle = ShutdownHandle::new();
+        assert!(!handle.is_shutdown());
+
+        let waiter = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.wait().await }
+        });
+        handle.shutdown();
+        waiter.await.unwrap();
+        assert!(handle.is_shutdown());
+
+        // 触发之后开始等待的任务立即返回
+        handle.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_connections() {
+        let handle = ShutdownHandle::new();
+        assert!(handle.drain(Duration::from_millis(10)).await);
+
+        let guard = handle.track();
+        let stuck = handle.track();
+        assert_eq!(handle.active_connections(), 2);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+        assert!(!handle.drain(Duration::from_millis(100)).await);
+        assert_eq!(handle.active_connections(), 1);
+
+        drop(stuck);
+        assert!(handle.drain(Duration::from_millis(100)).await);
+    }
+}
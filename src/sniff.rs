@@ -0,0 +1,284 @@
+use anyhow::Result;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// 嗅探时最多查看的字节数
+const MAX_SNIFF_SIZE: usize = 16384;
+
+/// 等待客户端首批数据的最长时间（服务器先发言的协议不会有数据）
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// 数据不完整时重新查看的间隔
+const SNIFF_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// TLS握手记录的内容类型
+pub const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// 客户端连接首批数据的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    /// TLS握手
+    Tls,
+    /// 明文HTTP请求
+    Http,
+    /// 其他协议或客户端没有发送数据
+    Unknown,
+}
+
+/// 查看客户端首批数据但不消费
+///
+/// TLS等待完整的ClientHello记录，HTTP等待完整的请求头，
+/// 超时或达到上限时返回已收到的数据。
+///
+/// # 参数
+/// * `stream` - 客户端连接
+///
+/// # 返回值
+/// 返回查看到的数据
+pub async fn peek_initial_data(stream: &TcpStream) -> Result<Vec<u8>> {
+    let deadline = tokio::time::Instant::now() + SNIFF_TIMEOUT;
+    let mut buffer = vec![0; MAX_SNIFF_SIZE];
+    let mut peeked = 0;
+
+    loop {
+        let n = match tokio::time::timeout_at(deadline, stream.peek(&mut buffer)).await {
+            Ok(result) => result?,
+            Err(_) => break,
+        };
+        if n == 0 || n == buffer.len() || is_complete(&buffer[..n]) {
+            peeked = n;
+            break;
+        }
+
+        // 数据不完整，等待更多数据到达后重新查看
+        if n == peeked {
+            if tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(SNIFF_RETRY_INTERVAL).await;
+        }
+        peeked = n;
+    }
+
+    buffer.truncate(peeked);
+    Ok(buffer)
+}
+
+/// 判断查看到的数据是否足以识别
+fn is_complete(data: &[u8]) -> bool {
+    match classify(data) {
+        StreamKind::Tls => tls_record_length(data).map(|len| data.len() >= len).unwrap_or(false),
+        StreamKind::Http => data.windows(4).any(|w| w == b"\r\n\r\n"),
+        StreamKind::Unknown => !data.is_empty(),
+    }
+}
+
+/// 根据首批数据判断协议类型
+pub fn classify(data: &[u8]) -> StreamKind {
+    if data.first() == Some(&TLS_HANDSHAKE_RECORD) {
+        return StreamKind::Tls;
+    }
+    if looks_like_http(data) {
+        return StreamKind::Http;
+    }
+    StreamKind::Unknown
+}
+
+/// 数据是否以HTTP请求行开头
+fn looks_like_http(data: &[u8]) -> bool {
+    const METHODS: [&[u8]; 9] = [
+        b"GET ", b"POST ", b"PUT ", b"DELETE ", b"HEAD ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE ",
+    ];
+    // 数据不足以包含完整方法名时，按前缀判断
+    METHODS.iter().any(|method| {
+        let len = method.len().min(data.len());
+        len > 0 && data[..len] == method[..len]
+    })
+}
+
+/// 读取TLS记录总长度（含5字节记录头）
+fn tls_record_length(data: &[u8]) -> Option<usize> {
+    if data.len() < 5 {
+        return None;
+    }
+    Some(5 + u16::from_be_bytes([data[3], data[4]]) as usize)
+}
+
+/// 从TLS ClientHello中解析SNI主机名
+///
+/// # 参数
+/// * `data` - 以TLS握手记录开头的数据
+///
+/// # 返回值
+/// 返回SNI主机名，数据不完整或没有SNI扩展时返回None
+pub fn parse_sni(data: &[u8]) -> Option<String> {
+    let record_end = tls_record_length(data)?.min(data.len());
+    let mut reader = Reader { data: &data[..record_end], pos: 5 };
+
+    // 握手类型必须是ClientHello
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    reader.skip(3)?; // 握手消息长度
+    reader.skip(2 + 32)?; // client_version和random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+
+    let extensions_len = reader.u16()? as usize;
+    let extensions_end = (reader.pos + extensions_len).min(reader.data.len());
+    while reader.pos + 4 <= extensions_end {
+        let extension_type = reader.u16()?;
+        let extension_len = reader.u16()? as usize;
+        if extension_type != 0x0000 {
+            reader.skip(extension_len)?;
+            continue;
+        }
+
+        // server_name扩展：列表长度、名称类型、名称长度、名称
+        reader.skip(2)?;
+        if reader.u8()? != 0x00 {
+            return None;
+        }
+        let name_len = reader.u16()? as usize;
+        let name = reader.bytes(name_len)?;
+        return String::from_utf8(name.to_vec()).ok();
+    }
+
+    None
+}
+
+/// 从明文HTTP请求头中读取Host（不含端口）
+pub fn parse_http_host(data: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(data);
+    let value = head.lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case("host").then(|| value.trim().to_string())
+        })?;
+
+    // 去掉端口，IPv6地址去掉方括号
+    crate::net::parse_authority(&value, 80).ok().map(|(host, _)| host)
+}
+
+/// 字节读取辅助结构
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造带SNI扩展的最小ClientHello
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut sni = Vec::new();
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+
+        let mut extensions = Vec::new();
+        // 放在SNI前面的其他扩展
+        extensions.extend_from_slice(&[0x00, 0x17, 0x00, 0x00]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![TLS_HANDSHAKE_RECORD, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let hello = client_hello("api.example.com");
+        assert_eq!(classify(&hello), StreamKind::Tls);
+        assert!(is_complete(&hello));
+        assert_eq!(parse_sni(&hello), Some("api.example.com".to_string()));
+
+        // 不完整的ClientHello
+        assert!(!is_complete(&hello[..20]));
+        assert_eq!(parse_sni(&hello[..20]), None);
+    }
+
+    #[test]
+    fn test_classify_http() {
+        let request = b"GET /index.html HTTP/1.1\r\nHost: example.com:8080\r\n\r\n";
+        assert_eq!(classify(request), StreamKind::Http);
+        assert!(is_complete(request));
+        assert!(!is_complete(&request[..30]));
+        assert_eq!(parse_http_host(request), Some("example.com".to_string()));
+        assert_eq!(parse_http_host(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"), Some("::1".to_string()));
+        assert_eq!(parse_http_host(b"GET / HTTP/1.1\r\n\r\n"), None);
+
+        assert_eq!(classify(b"PO"), StreamKind::Http);
+        assert_eq!(classify(b"SSH-2.0-OpenSSH"), StreamKind::Unknown);
+        assert_eq!(classify(b""), StreamKind::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_peek_does_not_consume() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        // 请求头分两次到达
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(b"Host: example.com\r\n\r\n").await.unwrap();
+            client
+        });
+
+        let peeked = peek_initial_data(&server).await.unwrap();
+        assert!(peeked.ends_with(b"\r\n\r\n"));
+
+        let _client = writer.await.unwrap();
+        let mut read = vec![0; peeked.len()];
+        server.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, peeked);
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::Result;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::Socks5Config;
+
+/// SOCKS协议版本
+const SOCKS_VERSION: u8 = 5;
+
+/// 认证方式：无认证
+const METHOD_NO_AUTH: u8 = 0x00;
+/// 认证方式：用户名/密码
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+/// 没有可接受的认证方式
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+/// CONNECT命令
+const COMMAND_CONNECT: u8 = 0x01;
+
+/// 应答：成功
+const REPLY_SUCCEEDED: u8 = 0x00;
+/// 应答：规则不允许连接
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+/// 应答：不支持的命令
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+/// 应答：不支持的地址类型
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// 完成SOCKS5握手并返回客户端请求的目标
+///
+/// 只支持CONNECT命令。读取请求后不应答，由调用方决定是否允许连接后调用`reply_socks5`。
+///
+/// # 参数
+/// * `stream` - 客户端连接
+/// * `config` - SOCKS5配置（配置了用户名时要求用户名/密码认证）
+///
+/// # 返回值
+/// 返回目标主机和端口
+pub async fn accept_socks5<S>(stream: &mut S, config: &Socks5Config) -> Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 协商认证方式
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        anyhow::bail!("Unsupported SOCKS version: {}", header[0]);
+    }
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let required = if config.username.is_some() { METHOD_USERNAME_PASSWORD } else { METHOD_NO_AUTH };
+    if !methods.contains(&required) {
+        stream.write_all(&[SOCKS_VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        anyhow::bail!("SOCKS5 client does not support the required authentication method");
+    }
+    stream.write_all(&[SOCKS_VERSION, required]).await?;
+
+    if required == METHOD_USERNAME_PASSWORD {
+        authenticate(stream, config).await?;
+    }
+
+    // 读取请求
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != SOCKS_VERSION {
+        anyhow::bail!("Unsupported SOCKS version: {}", request[0]);
+    }
+
+    let host = match request[3] {
+        0x01 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        },
+        0x03 => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length).await?;
+            let mut name = vec![0; length[0] as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| anyhow::anyhow!("Invalid SOCKS5 domain name"))?
+        },
+        0x04 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        },
+        atyp => {
+            send_reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            anyhow::bail!("Unsupported SOCKS5 address type: {atyp}");
+        },
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+
+    if request[1] != COMMAND_CONNECT {
+        send_reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        anyhow::bail!("Unsupported SOCKS5 command: {}", request[1]);
+    }
+
+    Ok((host, port))
+}
+
+/// 应答客户端的CONNECT请求
+///
+/// # 参数
+/// * `stream` - 客户端连接
+/// * `allowed` - 是否允许连接，不允许时应答“规则不允许连接”，客户端随后关闭连接
+pub async fn reply_socks5<S: AsyncWrite + Unpin>(stream: &mut S, allowed: bool) -> Result<()> {
+    send_reply(stream, if allowed { REPLY_SUCCEEDED } else { REPLY_NOT_ALLOWED }).await
+}
+
+/// 用户名/密码认证（RFC 1929）
+async fn authenticate<S>(stream: &mut S, config: &Socks5Config) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut version = [0; 2];
+    stream.read_exact(&mut version).await?;
+    let mut username = vec![0; version[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut length = [0; 1];
+    stream.read_exact(&mut length).await?;
+    let mut password = vec![0; length[0] as usize];
+    stream.read_exact(&mut password).await?;
+
+    let expected_username = config.username.as_deref().unwrap_or("");
+    let expected_password = config.password.as_deref().unwrap_or("");
+    if username != expected_username.as_bytes() || password != expected_password.as_bytes() {
+        stream.write_all(&[0x01, 0x01]).await?;
+        anyhow::bail!("SOCKS5 authentication failed for user {}", String::from_utf8_lossy(&username));
+    }
+    stream.write_all(&[0x01, 0x00]).await?;
+    Ok(())
+}
+
+/// 发送应答，绑定地址固定为0.0.0.0:0
+async fn send_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: u8) -> Result<()> {
+    stream.write_all(&[SOCKS_VERSION, reply, 0, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config(username: Option<&str>) -> Socks5Config {
+        Socks5Config {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port: 1080,
+            username: username.map(str::to_string),
+            password: username.map(|_| "secret".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_no_auth_domain() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let handshake = tokio::spawn(async move {
+            let target = accept_socks5(&mut server, &create_config(None)).await?;
+            reply_socks5(&mut server, true).await?;
+            anyhow::Ok(target)
+        });
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+
+        client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&443u16.to_be_bytes()).await.unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], REPLY_SUCCEEDED);
+
+        let (host, port) = handshake.await.unwrap().unwrap();
+        assert_eq!(host, "example.com");
+        assert_eq!(port, 443);
+    }
+
+    #[tokio::test]
+    async fn test_accept_password_auth() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let handshake = tokio::spawn(async move {
+            let target = accept_socks5(&mut server, &create_config(Some("user"))).await?;
+            reply_socks5(&mut server, false).await?;
+            anyhow::Ok(target)
+        });
+
+        client.write_all(&[5, 2, 0, 2]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 2]);
+
+        client.write_all(b"\x01\x04user\x06secret").await.unwrap();
+        let mut status = [0; 2];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [1, 0]);
+
+        client.write_all(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80]).await.unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], REPLY_NOT_ALLOWED);
+
+        let (host, port) = handshake.await.unwrap().unwrap();
+        assert_eq!(host, "10.0.0.1");
+        assert_eq!(port, 80);
+    }
+
+    #[tokio::test]
+    async fn test_reject_wrong_password_and_missing_method() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let handshake = tokio::spawn(async move { accept_socks5(&mut server, &create_config(Some("user"))).await });
+        client.write_all(&[5, 2, 0, 2]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(b"\x01\x04user\x05wrong").await.unwrap();
+        let mut status = [0; 2];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [1, 1]);
+        assert!(handshake.await.unwrap().is_err());
+
+        // 要求认证时不接受无认证方式
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let handshake = tokio::spawn(async move { accept_socks5(&mut server, &create_config(Some("user"))).await });
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, METHOD_NOT_ACCEPTABLE]);
+        assert!(handshake.await.unwrap().is_err());
+    }
+}
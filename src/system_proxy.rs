@@ -17,7 +17,7 @@ pub struct SystemProxyManager {
     platform: Platform,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[allow(dead_code)]
 struct OriginalProxySettings {
     http_proxy: Option<String>,
@@ -98,7 +98,7 @@ impl SystemProxyManager {
     // macOS 实现
     async fn set_macos_proxy(&self, _proxy_url: &str) -> Result<()> {
         let output = Command::new("networksetup")
-            .args(&["-setwebproxy", "Wi-Fi", "127.0.0.1", "8888"])
+            .args(["-setwebproxy", "Wi-Fi", "127.0.0.1", "8888"])
             .output()
             .context("Failed to set macOS HTTP proxy")?;
 
@@ -108,7 +108,7 @@ impl SystemProxyManager {
         }
 
         let output = Command::new("networksetup")
-            .args(&["-setsecurewebproxy", "Wi-Fi", "127.0.0.1", "8888"])
+            .args(["-setsecurewebproxy", "Wi-Fi", "127.0.0.1", "8888"])
             .output()
             .context("Failed to set macOS HTTPS proxy")?;
 
@@ -123,7 +123,7 @@ impl SystemProxyManager {
 
     async fn unset_macos_proxy(&self, _original: OriginalProxySettings) -> Result<()> {
         let output = Command::new("networksetup")
-            .args(&["-setwebproxystate", "Wi-Fi", "off"])
+            .args(["-setwebproxystate", "Wi-Fi", "off"])
             .output()
             .context("Failed to disable macOS HTTP proxy")?;
 
@@ -133,7 +133,7 @@ impl SystemProxyManager {
         }
 
         let output = Command::new("networksetup")
-            .args(&["-setsecurewebproxystate", "Wi-Fi", "off"])
+            .args(["-setsecurewebproxystate", "Wi-Fi", "off"])
             .output()
             .context("Failed to disable macOS HTTPS proxy")?;
 
@@ -148,11 +148,11 @@ impl SystemProxyManager {
 
     async fn get_macos_proxy_settings(&self) -> Result<OriginalProxySettings> {
         let http_output = Command::new("networksetup")
-            .args(&["-getwebproxy", "Wi-Fi"])
+            .args(["-getwebproxy", "Wi-Fi"])
             .output()?;
 
         let https_output = Command::new("networksetup")
-            .args(&["-getsecurewebproxy", "Wi-Fi"])
+            .args(["-getsecurewebproxy", "Wi-Fi"])
             .output()?;
 
         let http_proxy = parse_macos_proxy_output(&http_output.stdout)?;
@@ -171,7 +171,7 @@ impl SystemProxyManager {
     async fn set_windows_proxy(&self, proxy_url: &str) -> Result<()> {
         let proxy_server = proxy_url.trim_start_matches("http://");
         let output = Command::new("reg")
-            .args(&[
+            .args([
                 "add",
                 "HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings",
                 "/v",
@@ -190,7 +190,7 @@ impl SystemProxyManager {
         }
 
         let output = Command::new("reg")
-            .args(&[
+            .args([
                 "add",
                 "HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings",
                 "/v",
@@ -214,7 +214,7 @@ impl SystemProxyManager {
 
     async fn unset_windows_proxy(&self, _original: OriginalProxySettings) -> Result<()> {
         let output = Command::new("reg")
-            .args(&[
+            .args([
                 "add",
                 "HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings",
                 "/v",
@@ -251,7 +251,7 @@ impl SystemProxyManager {
 
         // 尝试设置GNOME代理
         let _ = Command::new("gsettings")
-            .args(&[
+            .args([
                 "set",
                 "org.gnome.system.proxy",
                 "mode",
@@ -260,7 +260,7 @@ impl SystemProxyManager {
             .output();
 
         let _ = Command::new("gsettings")
-            .args(&[
+            .args([
                 "set",
                 "org.gnome.system.proxy.http",
                 "host",
@@ -269,7 +269,7 @@ impl SystemProxyManager {
             .output();
 
         let _ = Command::new("gsettings")
-            .args(&[
+            .args([
                 "set",
                 "org.gnome.system.proxy.http",
                 "port",
@@ -288,7 +288,7 @@ impl SystemProxyManager {
         env::remove_var("HTTPS_PROXY");
 
         let _ = Command::new("gsettings")
-            .args(&[
+            .args([
                 "set",
                 "org.gnome.system.proxy",
                 "mode",
@@ -315,18 +315,6 @@ impl SystemProxyManager {
     }
 }
 
-impl Default for OriginalProxySettings {
-    fn default() -> Self {
-        Self {
-            http_proxy: None,
-            https_proxy: None,
-            ftp_proxy: None,
-            socks_proxy: None,
-            auto_proxy: None,
-        }
-    }
-}
-
 fn detect_platform() -> Platform {
     #[cfg(target_os = "windows")]
     return Platform::Windows;
@@ -352,10 +340,10 @@ fn parse_macos_proxy_output(output: &[u8]) -> Result<Option<String>> {
     for line in lines {
         if line.contains("Enabled: Yes") {
             enabled = true;
-        } else if line.starts_with("Server: ") {
-            server = line[8..].trim().to_string();
-        } else if line.starts_with("Port: ") {
-            port = line[6..].trim().to_string();
+        } else if let Some(value) = line.strip_prefix("Server: ") {
+            server = value.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("Port: ") {
+            port = value.trim().to_string();
         }
     }
     
@@ -0,0 +1,250 @@
+use anyhow::Result;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// 发生超时的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// 读取客户端请求头
+    ClientHeader,
+    /// 建立上游连接
+    Connect,
+    /// TLS握手
+    TlsHandshake,
+    /// 等待上游响应的第一个字节
+    FirstByte,
+    /// 隧道空闲
+    TunnelIdle,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TimeoutPhase::ClientHeader => "Reading client request headers",
+            TimeoutPhase::Connect => "Connecting to upstream",
+            TimeoutPhase::TlsHandshake => "TLS handshake",
+            TimeoutPhase::FirstByte => "Waiting for the first upstream response byte",
+            TimeoutPhase::TunnelIdle => "Idle tunnel",
+        };
+        f.write_str(name)
+    }
+}
+
+/// 超时错误，可以通过 [`timeout_phase`] 从anyhow错误链中识别
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// 超时的阶段
+    pub phase: TimeoutPhase,
+    /// 配置的超时时间
+    pub after: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.phase, self.after)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// 在限定时间内执行异步操作
+///
+/// # 参数
+/// * `phase` - 操作所属的阶段，用于错误信息
+/// * `limit` - 超时时间，None表示不限制
+/// * `future` - 要执行的操作
+///
+/// # 返回值
+/// 返回操作的结果，超时时返回 [`TimeoutError`]
+pub async fn with_timeout<F, T, E>(phase: TimeoutPhase, limit: Option<Duration>, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<anyhow::Error>,
+{
+    let result = match limit {
+        Some(after) => tokio::time::timeout(after, future).await
+            .map_err(|_| TimeoutError { phase, after })?,
+        None => future.await,
+    };
+    result.map_err(Into::into)
+}
+
+/// 查找错误链中的超时错误
+///
+/// # 返回值
+/// 错误由超时引起时返回超时的阶段
+pub fn timeout_phase(error: &anyhow::Error) -> Option<TimeoutPhase> {
+    error.chain()
+        .find_map(|e| e.downcast_ref::<TimeoutError>())
+        .map(|e| e.phase)
+}
+
+/// 记录连接最近一次读写的时间，多个流共享同一个记录
+#[derive(Debug, Clone)]
+pub struct IdleTracker {
+    /// 记录创建的时间
+    start: Instant,
+    /// 最近一次读写距创建时间的毫秒数
+    last_active_ms: Arc<AtomicU64>,
+}
+
+impl Default for IdleTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdleTracker {
+    /// 创建记录，创建时间视为最近一次活动
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_active_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// 包装流，读写数据时更新活动时间
+    pub fn wrap<S>(&self, stream: S) -> Tracked<S> {
+        Tracked {
+            inner: stream,
+            tracker: self.clone(),
+        }
+    }
+
+    /// 记录一次活动
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last_active_ms.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// 距最近一次活动的时间
+    fn idle_for(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last_active)
+    }
+
+    /// 等待连接空闲超过指定时间
+    ///
+    /// # 参数
+    /// * `limit` - 空闲超时时间，None表示永远不会返回
+    async fn expired(&self, limit: Option<Duration>) -> TimeoutError {
+        let Some(after) = limit else {
+            return std::future::pending().await;
+        };
+        loop {
+            let idle = self.idle_for();
+            if idle >= after {
+                return TimeoutError { phase: TimeoutPhase::TunnelIdle, after };
+            }
+            tokio::time::sleep(after - idle).await;
+        }
+    }
+
+    /// 执行双向转发，连接空闲超时后放弃
+    ///
+    /// 转发的流需要先通过 [`IdleTracker::wrap`] 包装，否则任何数据都不会重置空闲时间。
+    ///
+    /// # 参数
+    /// * `limit` - 空闲超时时间，None表示不限制
+    /// * `future` - 转发操作
+    ///
+    /// # 返回值
+    /// 返回转发的结果，空闲超时时返回 [`TimeoutError`]
+    pub async fn run<F: Future>(&self, limit: Option<Duration>, future: F) -> Result<F::Output, TimeoutError> {
+        tokio::select! {
+            output = future => Ok(output),
+            error = self.expired(limit) => Err(error),
+        }
+    }
+}
+
+/// 读写时更新 [`IdleTracker`] 的流
+pub struct Tracked<S> {
+    inner: S,
+    tracker: IdleTracker,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            this.tracker.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(n)) if n > 0) {
+            this.tracker.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let limit = Some(Duration::from_millis(50));
+        let value = with_timeout(TimeoutPhase::Connect, limit, async { Ok::<_, std::io::Error>(7) }).await.unwrap();
+        assert_eq!(value, 7);
+
+        let error = with_timeout(TimeoutPhase::Connect, limit, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<_, anyhow::Error>(())
+        }).await.unwrap_err();
+        assert_eq!(timeout_phase(&error), Some(TimeoutPhase::Connect));
+        assert_eq!(error.to_string(), "Connecting to upstream timed out after 50ms");
+
+        // 经过context包装后仍然可以识别
+        let error = error.context("Failed to connect to example.com:443");
+        assert_eq!(timeout_phase(&error), Some(TimeoutPhase::Connect));
+        assert_eq!(timeout_phase(&anyhow::anyhow!("connection refused")), None);
+    }
+
+    #[tokio::test]
+    async fn test_idle_tracker() {
+        let tracker = IdleTracker::new();
+        let (client, server) = tokio::io::duplex(64);
+        let (mut client, mut server) = (tracker.wrap(client), tracker.wrap(server));
+
+        // 持续有数据时不会超时
+        let limit = Some(Duration::from_millis(100));
+        let result = tracker.run(limit, async {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                client.write_all(b"x").await.unwrap();
+                server.read_exact(&mut [0; 1]).await.unwrap();
+            }
+        }).await;
+        assert!(result.is_ok());
+
+        let error = tracker.run(limit, server.read(&mut [0; 1])).await.unwrap_err();
+        assert_eq!(error.phase, TimeoutPhase::TunnelIdle);
+
+        assert!(tracker.run(None, async { 1 }).await.is_ok());
+    }
+}
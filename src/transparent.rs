@@ -0,0 +1,99 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// 获取被重定向连接的原始目标地址
+///
+/// 透明模式下连接由iptables/nftables重定向到代理端口，
+/// 需要通过该接口找回客户端原本要连接的地址。测试时可以注入固定地址。
+pub trait OriginalDestination: Send + Sync {
+    /// 查询连接的原始目标地址
+    ///
+    /// # 参数
+    /// * `stream` - 被重定向的客户端连接
+    fn lookup(&self, stream: &TcpStream) -> io::Result<SocketAddr>;
+}
+
+/// 通过SO_ORIGINAL_DST获取原始目标地址（iptables/nftables REDIRECT）
+#[cfg(target_os = "linux")]
+pub struct SoOriginalDst;
+
+#[cfg(target_os = "linux")]
+impl OriginalDestination for SoOriginalDst {
+    fn lookup(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+        use std::os::unix::io::AsRawFd;
+
+        let fd = stream.as_raw_fd();
+
+        if stream.local_addr()?.is_ipv4() {
+            // SAFETY: sockaddr_in是纯数据结构，内核按传入的长度写入
+            let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IP,
+                    libc::SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+        } else {
+            // SAFETY: 同上
+            let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    libc::IP6T_SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id).into())
+        }
+    }
+}
+
+/// 当前平台默认的原始目标查询方式
+///
+/// # 返回值
+/// 只有Linux支持透明模式，其他平台返回None
+pub fn default_lookup() -> Option<std::sync::Arc<dyn OriginalDestination>> {
+    #[cfg(target_os = "linux")]
+    {
+        Some(std::sync::Arc::new(SoOriginalDst))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_so_original_dst_without_redirect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        // 没有经过REDIRECT的连接没有原始目标记录（未加载conntrack时同样报错）
+        assert!(SoOriginalDst.lookup(&server).is_err());
+    }
+}
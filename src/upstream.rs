@@ -0,0 +1,357 @@
+use anyhow::Result;
+use base64::Engine;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::{Config, UpstreamProxyRule, UpstreamProxyType};
+use crate::net::format_authority;
+use crate::timeout::{with_timeout, TimeoutPhase};
+
+/// CONNECT响应头最大长度
+const MAX_CONNECT_RESPONSE_SIZE: usize = 8192;
+
+/// 明文HTTP请求经过HTTP上游代理转发时需要的信息
+#[derive(Debug, Clone)]
+pub struct HttpForwardProxy {
+    /// Proxy-Authorization头的值（如果配置了认证）
+    pub authorization: Option<String>,
+}
+
+/// 建立到目标的TCP连接，按上游代理规则直连或通过父代理建立隧道
+///
+/// 用于隧道模式和拦截模式，HTTP代理使用CONNECT建立隧道。
+/// 连接超时包含DNS解析和与上游代理的握手。
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+/// * `pinned_ip` - 直接连接时使用的IP，为None时解析主机名
+///
+/// # 返回值
+/// 返回到目标的TCP流
+pub async fn connect_tunnel(config: &Config, host: &str, port: u16, pinned_ip: Option<IpAddr>) -> Result<TcpStream> {
+    let connect = async {
+        match config.upstream_proxy_for(host) {
+            Some(rule) if rule.proxy_type != UpstreamProxyType::Direct => {
+                connect_via_proxy(rule, host, port).await
+            },
+            _ => {
+                let addrs = match pinned_ip {
+                    Some(ip) => vec![SocketAddr::new(ip, port)],
+                    None => crate::dns::resolve(config, host, port).await?,
+                };
+                Ok(TcpStream::connect(&addrs[..]).await?)
+            },
+        }
+    };
+    with_timeout(TimeoutPhase::Connect, config.timeouts.connect(), connect).await
+}
+
+/// 获取上游连接实际连接的IP
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 目标主机
+/// * `stream` - `connect_tunnel`或`connect_http`返回的连接
+///
+/// # 返回值
+/// 直接连接时返回解析出的目标IP，经过上游代理时由代理解析域名，返回None
+pub fn resolved_ip(config: &Config, host: &str, stream: &TcpStream) -> Option<IpAddr> {
+    match config.upstream_proxy_for(host) {
+        Some(rule) if rule.proxy_type != UpstreamProxyType::Direct => None,
+        _ => stream.peer_addr().ok().map(|addr| addr.ip()),
+    }
+}
+
+/// 建立明文HTTP请求使用的连接
+///
+/// HTTP代理不使用CONNECT，而是直接连接代理并以absolute-form发送请求。
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 目标主机
+/// * `port` - 目标端口
+///
+/// # 返回值
+/// 返回TCP流，以及经过HTTP代理时的转发信息
+pub async fn connect_http(
+    config: &Config,
+    host: &str,
+    port: u16,
+) -> Result<(TcpStream, Option<HttpForwardProxy>)> {
+    match config.upstream_proxy_for(host) {
+        Some(rule) if rule.proxy_type == UpstreamProxyType::Http => {
+            log::info!("Forwarding HTTP request for {host}:{port} via HTTP proxy {}:{}", rule.host, rule.port);
+            let stream = with_timeout(TimeoutPhase::Connect, config.timeouts.connect(), connect_proxy_server(rule)).await?;
+            let forward = HttpForwardProxy { authorization: basic_authorization(rule) };
+            Ok((stream, Some(forward)))
+        },
+        _ => Ok((connect_tunnel(config, host, port, None).await?, None)),
+    }
+}
+
+/// 查找明文HTTP请求需要经过的HTTP上游代理
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 目标主机
+///
+/// # 返回值
+/// 目标使用HTTP上游代理时返回转发信息，否则返回None
+pub fn http_forward_proxy(config: &Config, host: &str) -> Option<HttpForwardProxy> {
+    match config.upstream_proxy_for(host) {
+        Some(rule) if rule.proxy_type == UpstreamProxyType::Http => {
+            Some(HttpForwardProxy { authorization: basic_authorization(rule) })
+        },
+        _ => None,
+    }
+}
+
+/// 通过上游代理建立到目标的隧道
+async fn connect_via_proxy(rule: &UpstreamProxyRule, host: &str, port: u16) -> Result<TcpStream> {
+    let mut stream = connect_proxy_server(rule).await?;
+    match rule.proxy_type {
+        UpstreamProxyType::Http => {
+            log::info!("Connecting to {host}:{port} via HTTP proxy {}:{}", rule.host, rule.port);
+            http_connect(&mut stream, rule, host, port).await?;
+        },
+        UpstreamProxyType::Socks5 => {
+            log::info!("Connecting to {host}:{port} via SOCKS5 proxy {}:{}", rule.host, rule.port);
+            socks5_connect(&mut stream, rule, host, port).await?;
+        },
+        UpstreamProxyType::Direct => {},
+    }
+    Ok(stream)
+}
+
+/// 连接上游代理服务器
+async fn connect_proxy_server(rule: &UpstreamProxyRule) -> Result<TcpStream> {
+    if rule.host.is_empty() || rule.port == 0 {
+        anyhow::bail!("Upstream proxy rule for {:?} has no host or port", rule.domains);
+    }
+    TcpStream::connect((rule.host.as_str(), rule.port)).await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to upstream proxy {}:{}: {e}", rule.host, rule.port))
+}
+
+/// 生成Basic认证头的值
+fn basic_authorization(rule: &UpstreamProxyRule) -> Option<String> {
+    let username = rule.username.as_deref()?;
+    let password = rule.password.as_deref().unwrap_or("");
+    let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+    Some(format!("Basic {credentials}"))
+}
+
+/// 通过HTTP代理的CONNECT方法建立隧道
+async fn http_connect(stream: &mut TcpStream, rule: &UpstreamProxyRule, host: &str, port: u16) -> Result<()> {
+    let authority = format_authority(host, port);
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(authorization) = basic_authorization(rule) {
+        request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // 逐字节读取响应头，避免读到隧道中的数据
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 {
+            anyhow::bail!("Upstream proxy closed connection during CONNECT");
+        }
+        response.push(byte[0]);
+        if response.len() > MAX_CONNECT_RESPONSE_SIZE {
+            anyhow::bail!("Upstream proxy CONNECT response too large");
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    let status_code: u16 = status_line.split_whitespace().nth(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+    if !(200..300).contains(&status_code) {
+        anyhow::bail!("Upstream proxy refused CONNECT {authority}: {status_line}");
+    }
+    Ok(())
+}
+
+/// 通过SOCKS5代理建立隧道
+async fn socks5_connect(stream: &mut TcpStream, rule: &UpstreamProxyRule, host: &str, port: u16) -> Result<()> {
+    // 协商认证方式：无认证，配置了用户名时同时提供用户名/密码认证
+    let greeting: &[u8] = if rule.username.is_some() { &[5, 2, 0, 2] } else { &[5, 1, 0] };
+    stream.write_all(greeting).await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        anyhow::bail!("Invalid SOCKS5 server version: {}", reply[0]);
+    }
+    match reply[1] {
+        0 => {},
+        2 => {
+            let username = rule.username.as_deref().unwrap_or("").as_bytes();
+            let password = rule.password.as_deref().unwrap_or("").as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                anyhow::bail!("SOCKS5 username or password too long");
+            }
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(username);
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password);
+            stream.write_all(&auth).await?;
+
+            let mut auth_reply = [0; 2];
+            stream.read_exact(&mut auth_reply).await?;
+            if auth_reply[1] != 0 {
+                anyhow::bail!("SOCKS5 authentication failed");
+            }
+        },
+        method => anyhow::bail!("SOCKS5 server rejected authentication methods ({method:#x})"),
+    }
+
+    // CONNECT请求，域名交给代理解析
+    let mut request = vec![5, 1, 0];
+    match host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.len() > 255 {
+                anyhow::bail!("Host name too long for SOCKS5: {host}");
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!("SOCKS5 CONNECT to {host}:{port} failed with reply code {}", reply[1]);
+    }
+
+    // 跳过绑定地址和端口
+    let address_length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length).await?;
+            length[0] as usize
+        },
+        atyp => anyhow::bail!("Invalid SOCKS5 address type: {atyp}"),
+    };
+    let mut bound = vec![0; address_length + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn create_rule(proxy_type: UpstreamProxyType, port: u16, username: Option<&str>) -> UpstreamProxyRule {
+        UpstreamProxyRule {
+            domains: vec!["*".to_string()],
+            proxy_type,
+            host: "127.0.0.1".to_string(),
+            port,
+            username: username.map(str::to_string),
+            password: username.map(|_| "secret".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_with_basic_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\ntunnel").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let rule = create_rule(UpstreamProxyType::Http, port, Some("user"));
+        let mut stream = connect_via_proxy(&rule, "example.com", 443).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+
+        // 隧道中的数据不能被CONNECT响应的读取吞掉
+        let mut data = [0; 6];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"tunnel");
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).await.unwrap();
+            stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+        });
+
+        let rule = create_rule(UpstreamProxyType::Http, port, None);
+        let error = connect_via_proxy(&rule, "example.com", 443).await.unwrap_err();
+        assert!(error.to_string().contains("407"));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_with_password() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0; 13];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x06secret");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 18];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1F, 0x90]).await.unwrap();
+            request
+        });
+
+        let rule = create_rule(UpstreamProxyType::Socks5, port, Some("user"));
+        connect_via_proxy(&rule, "example.com", 443).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert_eq!(&request[..5], &[5, 1, 0, 3, 11]);
+        assert_eq!(&request[5..16], b"example.com");
+        assert_eq!(&request[16..], &443u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_format_authority() {
+        assert_eq!(format_authority("example.com", 443), "example.com:443");
+        assert_eq!(format_authority("::1", 8080), "[::1]:8080");
+    }
+}
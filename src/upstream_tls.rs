@@ -0,0 +1,512 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls;
+use tokio_rustls::rustls;
+
+use crate::config::{ClientCertificateRule, Config, TlsVersion};
+use crate::timeout::{timeout_phase, with_timeout, TimeoutPhase};
+use crate::upstream::connect_tunnel;
+
+/// 与上游的TLS握手失败
+///
+/// 除失败原因外还带有上游出示的证书链，方便排查证书问题。
+#[derive(Debug)]
+pub struct UpstreamTlsError {
+    /// 上游主机
+    pub host: String,
+    /// 上游端口
+    pub port: u16,
+    /// 失败原因（来自TLS库）
+    pub reason: String,
+    /// 上游证书链摘要，第一个是站点证书（获取失败时为空）
+    pub chain: Vec<String>,
+}
+
+impl fmt::Display for UpstreamTlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream TLS handshake with {}:{} failed: {}", self.host, self.port, self.reason)?;
+        if self.chain.is_empty() {
+            return write!(f, "\nUpstream certificate chain: unavailable");
+        }
+        write!(f, "\nUpstream certificate chain:")?;
+        for (index, certificate) in self.chain.iter().enumerate() {
+            write!(f, "\n  [{index}] {certificate}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UpstreamTlsError {}
+
+/// 向上游提供HTTP/2和HTTP/1.1
+pub const OFFER_H2: &[&str] = &["h2", "http/1.1"];
+/// 只向上游提供HTTP/1.1
+pub const OFFER_HTTP1: &[&str] = &["http/1.1"];
+
+/// 选择上游TLS连接器的条件：是否校验证书、客户端证书规则的序号、ALPN协议
+type ConnectorKey = (bool, Option<usize>, &'static [&'static str]);
+
+/// 按配置预先创建的上游TLS连接器
+///
+/// 证书文件只在启动时读取一次，每次握手按主机匹配的规则选择连接器，
+/// 不在异步任务中读取文件或重新创建连接器。
+pub struct TlsConnectors {
+    connectors: HashMap<ConnectorKey, tokio_native_tls::TlsConnector>,
+}
+
+impl TlsConnectors {
+    /// 读取CA证书和客户端证书，为每种规则组合创建连接器
+    ///
+    /// # 参数
+    /// * `config` - 配置信息
+    ///
+    /// # 返回值
+    /// 证书文件无法读取或解析时返回错误
+    pub fn new(config: &Config) -> Result<Self> {
+        let ca_certificates = config.upstream_tls.ca_bundles.iter()
+            .map(|path| load_ca_bundle(path))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        let identities = config.upstream_tls.client_certificates.iter()
+            .map(load_identity)
+            .collect::<Result<Vec<_>>>()?;
+
+        // 只为规则中出现过的校验设置创建连接器
+        let mut verify_settings = vec![config.upstream_tls.verify];
+        for rule in &config.upstream_tls.rules {
+            if !verify_settings.contains(&rule.verify) {
+                verify_settings.push(rule.verify);
+            }
+        }
+
+        let mut connectors = HashMap::new();
+        for verify in verify_settings {
+            for client_certificate in std::iter::once(None).chain((0..identities.len()).map(Some)) {
+                for alpn in [OFFER_H2, OFFER_HTTP1] {
+                    let mut builder = native_tls::TlsConnector::builder();
+                    builder
+                        .danger_accept_invalid_certs(!verify)
+                        .min_protocol_version(config.upstream_tls.min_version.map(native_protocol))
+                        .request_alpns(alpn);
+                    if let Some(index) = client_certificate {
+                        builder.identity(identities[index].clone());
+                    }
+                    if verify {
+                        for certificate in &ca_certificates {
+                            builder.add_root_certificate(certificate.clone());
+                        }
+                    }
+                    let connector = tokio_native_tls::TlsConnector::from(builder.build()?);
+                    connectors.insert((verify, client_certificate, alpn), connector);
+                }
+            }
+        }
+        Ok(Self { connectors })
+    }
+
+    /// 选择连接指定主机使用的连接器
+    fn select(&self, config: &Config, host: &str, alpn: &'static [&'static str]) -> Result<&tokio_native_tls::TlsConnector> {
+        let verify = config.upstream_tls_verify(host);
+        if !verify {
+            log::debug!("Upstream certificate verification disabled for {host}");
+        }
+        if let Some(label) = config.client_certificate_for(host).and_then(identity_label) {
+            log::info!("🔑 Presenting client certificate {label} to {host}");
+        }
+        self.connectors.get(&(verify, config.client_certificate_index(host), alpn))
+            .ok_or_else(|| anyhow::anyhow!("No upstream TLS connector for ALPN {alpn:?}"))
+    }
+}
+
+/// 在已建立的连接上与上游完成TLS握手
+///
+/// 按配置决定是否校验证书、额外信任的CA和最低TLS版本。
+/// 握手失败时重新连接同一个IP获取证书链，返回`UpstreamTlsError`。
+///
+/// # 参数
+/// * `connectors` - 预先创建的连接器
+/// * `config` - 配置信息
+/// * `host` - 目标主机（同时用于SNI和证书校验）
+/// * `port` - 目标端口
+/// * `stream` - 到目标的TCP连接
+/// * `alpn` - 向上游提供的ALPN协议（`OFFER_H2`或`OFFER_HTTP1`）
+///
+/// # 返回值
+/// 返回与上游的TLS连接
+pub async fn handshake(
+    connectors: &TlsConnectors,
+    config: &Config,
+    host: &str,
+    port: u16,
+    stream: TcpStream,
+    alpn: &'static [&'static str],
+) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
+    let connector = connectors.select(config, host, alpn)?;
+    let dialed_ip = crate::upstream::resolved_ip(config, host, &stream);
+    let limit = config.timeouts.tls_handshake();
+    let error = match with_timeout(TimeoutPhase::TlsHandshake, limit, connector.connect(host, stream)).await {
+        Ok(tls_stream) => return Ok(tls_stream),
+        // 超时不是证书问题，不再获取证书链
+        Err(e) if timeout_phase(&e).is_some() => {
+            log::warn!("❌ TLS handshake with upstream {host}:{port} failed: {e}");
+            return Err(e);
+        },
+        Err(e) => e,
+    };
+
+    log::warn!("❌ TLS handshake with upstream {host}:{port} failed: {error}");
+    let chain = match fetch_certificate_chain(config, host, port, dialed_ip).await {
+        Ok(chain) => chain,
+        Err(e) => {
+            log::debug!("Failed to fetch certificate chain from {host}:{port}: {e}");
+            Vec::new()
+        }
+    };
+    Err(UpstreamTlsError {
+        host: host.to_string(),
+        port,
+        reason: error.to_string(),
+        chain,
+    }.into())
+}
+
+/// 向指定主机出示的客户端证书名称，用于日志记录
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 上游主机
+///
+/// # 返回值
+/// 返回证书文件路径，没有配置客户端证书时返回None
+pub fn client_identity(config: &Config, host: &str) -> Option<String> {
+    config.client_certificate_for(host).and_then(identity_label)
+}
+
+/// 上游TLS连接实际连接的IP，用于日志记录
+///
+/// # 参数
+/// * `config` - 配置信息
+/// * `host` - 上游主机
+/// * `stream` - `handshake`返回的TLS连接
+pub fn resolved_ip(config: &Config, host: &str, stream: &tokio_native_tls::TlsStream<TcpStream>) -> Option<IpAddr> {
+    crate::upstream::resolved_ip(config, host, stream.get_ref().get_ref().get_ref())
+}
+
+/// 客户端证书规则对应的证书文件
+fn identity_label(rule: &ClientCertificateRule) -> Option<String> {
+    rule.pkcs12.clone().or_else(|| rule.cert.clone())
+}
+
+/// 加载客户端证书和私钥
+///
+/// # 参数
+/// * `rule` - 客户端证书规则，优先使用PKCS#12文件
+fn load_identity(rule: &ClientCertificateRule) -> Result<native_tls::Identity> {
+    match (&rule.pkcs12, &rule.cert, &rule.key) {
+        (Some(path), _, _) => {
+            let password = rule.password.as_deref().unwrap_or("");
+            native_tls::Identity::from_pkcs12(&read_file(path)?, password)
+                .map_err(|e| anyhow::anyhow!("Failed to load PKCS#12 client certificate {path}: {e}"))
+        },
+        (None, Some(cert), Some(key)) => {
+            native_tls::Identity::from_pkcs8(&read_file(cert)?, &read_file(key)?)
+                .map_err(|e| anyhow::anyhow!("Failed to load client certificate {cert}: {e}"))
+        },
+        _ => anyhow::bail!("Client certificate for {:?} needs either pkcs12 or both cert and key", rule.domains),
+    }
+}
+
+/// 读取证书相关文件，错误信息中带上路径
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {path}: {e}"))
+}
+
+/// 配置的TLS版本对应的native-tls协议
+fn native_protocol(version: TlsVersion) -> native_tls::Protocol {
+    match version {
+        TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+        TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+        TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+    }
+}
+
+/// 读取PEM格式的CA证书文件
+///
+/// # 参数
+/// * `path` - 证书文件路径，文件中可以包含多个证书
+fn load_ca_bundle(path: &str) -> Result<Vec<native_tls::Certificate>> {
+    let certificates = pem::parse_many(read_file(path)?)?
+        .iter()
+        .filter(|block| block.tag() == "CERTIFICATE")
+        .map(|block| native_tls::Certificate::from_der(block.contents()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        anyhow::bail!("No certificates found in CA bundle {path}");
+    }
+    Ok(certificates)
+}
+
+/// 重新连接上游并记录其出示的证书链
+///
+/// 系统TLS库在握手失败后拿不到证书链，所以单独用rustls握手一次，
+/// 收到证书后立即中止握手。
+async fn fetch_certificate_chain(config: &Config, host: &str, port: u16, ip: Option<IpAddr>) -> Result<Vec<String>> {
+    let recorder = Arc::new(ChainRecorder::default());
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(recorder.clone())
+        .with_no_client_auth();
+    let server_name = rustls::ServerName::try_from(host)?;
+
+    let stream = connect_tunnel(config, host, port, ip).await?;
+    // 握手必然失败，只关心记录下来的证书
+    let handshake = tokio_rustls::TlsConnector::from(Arc::new(client_config)).connect(server_name, stream);
+    let _ = with_timeout(TimeoutPhase::TlsHandshake, config.timeouts.tls_handshake(), handshake).await;
+
+    let chain = recorder.chain.lock().unwrap();
+    if chain.is_empty() {
+        anyhow::bail!("Upstream did not present a certificate");
+    }
+    Ok(chain.iter().map(|certificate| describe_certificate(&certificate.0)).collect())
+}
+
+/// 只记录证书链、不接受任何证书的校验器
+#[derive(Default)]
+struct ChainRecorder {
+    chain: Mutex<Vec<rustls::Certificate>>,
+}
+
+impl rustls::client::ServerCertVerifier for ChainRecorder {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let mut chain = self.chain.lock().unwrap();
+        chain.push(end_entity.clone());
+        chain.extend_from_slice(intermediates);
+        Err(rustls::Error::General("certificate chain recorded".to_string()))
+    }
+}
+
+/// 生成证书摘要：主题、签发者、有效期和SAN
+fn describe_certificate(der: &[u8]) -> String {
+    use x509_parser::extensions::GeneralName;
+
+    let certificate = match x509_parser::parse_x509_certificate(der) {
+        Ok((_, certificate)) => certificate,
+        Err(e) => return format!("unparsable certificate ({} bytes): {e}", der.len()),
+    };
+
+    let validity = certificate.validity();
+    let mut description = format!(
+        "subject={}; issuer={}; valid {} to {}",
+        certificate.subject(),
+        certificate.issuer(),
+        validity.not_before,
+        validity.not_after,
+    );
+    if let Ok(Some(san)) = certificate.subject_alternative_name() {
+        let names: Vec<String> = san.value.general_names.iter()
+            .map(|name| match name {
+                GeneralName::DNSName(dns) => dns.to_string(),
+                GeneralName::IPAddress(ip) => format_ip(ip),
+                other => other.to_string(),
+            })
+            .collect();
+        description.push_str(&format!("; san={}", names.join(", ")));
+    }
+    description
+}
+
+/// 将SAN中的IP地址字节格式化为文本
+fn format_ip(bytes: &[u8]) -> String {
+    match bytes.len() {
+        4 => std::net::Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap()).to_string(),
+        16 => std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()).to_string(),
+        _ => format!("{bytes:02x?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::CertManager;
+    use crate::proxy::create_tls_acceptor;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// 创建测试配置，证书放在临时目录中
+    fn create_test_config(temp_dir: &std::path::Path, upstream_tls: &str) -> Config {
+        let dir = temp_dir.to_str().unwrap();
+        serde_json::from_str(&format!(r#"{{
+            "proxy": {{ "host": "127.0.0.1", "port": 8888 }},
+            "target": {{ "domains": ["*"], "ports": ["*"] }},
+            "certificates": {{ "ca_cert": "{dir}/ca.crt", "ca_key": "{dir}/ca.key" }},
+            "upstream_tls": {upstream_tls},
+            "logging": {{
+                "level": "debug",
+                "output": "file",
+                "log_dir": "{dir}",
+                "program_log": "proxy.log",
+                "domain_logs": {{ "enabled": true, "format": "{{date}}_{{domain}}.log" }}
+            }}
+        }}"#)).unwrap()
+    }
+
+    /// 启动使用CA签发的localhost证书的TLS服务器，可以接受多个连接
+    async fn start_tls_server(config: &Config) -> u16 {
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
+        let acceptor = create_tls_acceptor(&cert_manager, "localhost").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut tls_stream) = acceptor.accept(stream).await {
+                        let _ = tls_stream.write_all(b"hello").await;
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    async fn connect(config: &Config, port: u16) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
+        let connectors = TlsConnectors::new(config)?;
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        handshake(&connectors, config, "localhost", port, stream, OFFER_HTTP1).await
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_upstream_reports_chain() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = create_test_config(temp_dir.path(), "{}");
+        let port = start_tls_server(&config).await;
+
+        let error = connect(&config, port).await.unwrap_err();
+        let tls_error = error.downcast_ref::<UpstreamTlsError>().expect("expected UpstreamTlsError");
+        assert_eq!(tls_error.port, port);
+        assert_eq!(tls_error.chain.len(), 1);
+        assert!(tls_error.chain[0].contains("subject=CN=localhost"), "{}", tls_error.chain[0]);
+        assert!(tls_error.chain[0].contains("san=localhost"), "{}", tls_error.chain[0]);
+        assert!(error.to_string().contains("[0] subject=CN=localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_trusted_ca_bundle_and_verify_rule() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let ca_path = temp_dir.path().join("ca.crt");
+        let upstream_tls = format!(r#"{{ "ca_bundles": ["{}"], "min_version": "1.2" }}"#, ca_path.display());
+        let config = create_test_config(temp_dir.path(), &upstream_tls);
+        let port = start_tls_server(&config).await;
+        assert!(connect(&config, port).await.is_ok());
+
+        // 按域名关闭校验后不需要信任CA
+        let config = create_test_config(
+            temp_dir.path(),
+            r#"{ "rules": [{ "domains": ["localhost"], "verify": false }] }"#,
+        );
+        assert!(connect(&config, port).await.is_ok());
+
+        // 文件中没有证书时报错
+        std::fs::write(temp_dir.path().join("empty.pem"), "").unwrap();
+        assert!(load_ca_bundle(temp_dir.path().join("empty.pem").to_str().unwrap()).is_err());
+
+        // 证书文件在创建连接器时读取，无法读取时启动失败
+        let missing = create_test_config(temp_dir.path(), r#"{ "ca_bundles": ["/nonexistent/ca.pem"] }"#);
+        assert!(TlsConnectors::new(&missing).is_err());
+    }
+
+    /// 启动要求客户端证书的TLS服务器，只接受一个连接，返回端口和握手结果
+    async fn start_mtls_server(cert_manager: &CertManager, ca_pem: &[u8]) -> (u16, tokio::task::JoinHandle<bool>) {
+        let (cert_pem, key_pem) = cert_manager.generate_site_cert("localhost").unwrap();
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..]).unwrap().into_iter().map(rustls::Certificate).collect();
+        let key = rustls::PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut &key_pem[..]).unwrap().remove(0));
+
+        let mut roots = rustls::RootCertStore::empty();
+        for ca in rustls_pemfile::certs(&mut &ca_pem[..]).unwrap() {
+            roots.add(&rustls::Certificate(ca)).unwrap();
+        }
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed())
+            .with_single_cert(certs, key)
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            match acceptor.accept(stream).await {
+                Ok(mut tls_stream) => {
+                    let _ = tls_stream.write_all(b"hello").await;
+                    tls_stream.get_ref().1.peer_certificates().is_some_and(|certs| !certs.is_empty())
+                },
+                Err(_) => false,
+            }
+        });
+        (port, server)
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_presented() {
+        use tokio::io::AsyncReadExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let ca_path = dir.join("ca.crt");
+        let client_cert = dir.join("client.crt");
+        let client_key = dir.join("client.key");
+        let upstream_tls = format!(
+            r#"{{ "ca_bundles": ["{}"], "client_certificates": [{{ "domains": ["localhost"], "cert": "{}", "key": "{}" }}] }}"#,
+            ca_path.display(),
+            client_cert.display(),
+            client_key.display(),
+        );
+        let config = create_test_config(dir, &upstream_tls);
+        let cert_manager = CertManager::new(
+            &config.certificates.ca_cert,
+            &config.certificates.ca_key,
+            &config.certificates.name,
+        ).unwrap();
+        let (cert_pem, key_pem) = cert_manager.generate_site_cert("client.local").unwrap();
+        std::fs::write(&client_cert, cert_pem).unwrap();
+        std::fs::write(&client_key, key_pem).unwrap();
+        let ca_pem = std::fs::read(&ca_path).unwrap();
+
+        assert_eq!(client_identity(&config, "localhost").as_deref(), client_cert.to_str());
+        assert!(client_identity(&config, "example.com").is_none());
+
+        let (port, server) = start_mtls_server(&cert_manager, &ca_pem).await;
+        let mut tls_stream = connect(&config, port).await.unwrap();
+        let mut greeting = [0; 5];
+        tls_stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+        assert!(server.await.unwrap());
+
+        // 不出示客户端证书时服务器拒绝连接
+        let config = create_test_config(dir, &format!(r#"{{ "ca_bundles": ["{}"] }}"#, ca_path.display()));
+        let (port, server) = start_mtls_server(&cert_manager, &ca_pem).await;
+        if let Ok(mut tls_stream) = connect(&config, port).await {
+            assert!(tls_stream.read_exact(&mut greeting).await.is_err());
+        }
+        assert!(!server.await.unwrap());
+    }
+}
@@ -0,0 +1,494 @@
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::domain_logger::{DomainLogger, WebSocketDirection, WebSocketMessage};
+use crate::proxy::BodyCapture;
+
+/// WebSocket帧头最大长度（2字节基本头 + 8字节扩展长度 + 4字节掩码）
+const MAX_FRAME_HEADER_SIZE: usize = 14;
+
+/// WebSocket操作码
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+/// 解析出的WebSocket事件
+#[derive(Debug)]
+pub enum WebSocketEvent {
+    /// 完整的文本或二进制消息（已合并分片）
+    Message(WebSocketMessage, Vec<u8>),
+    /// 控制帧（close、ping、pong）
+    Control(WebSocketMessage, Vec<u8>),
+}
+
+/// 当前帧的解析状态
+#[derive(Debug, Clone, Copy)]
+enum FrameState {
+    /// 等待帧头
+    Header,
+    /// 等待负载数据
+    Payload {
+        /// 剩余负载长度
+        remaining: u64,
+        /// 已读取的负载长度，用于计算掩码位置
+        offset: u64,
+    },
+}
+
+/// WebSocket帧解析器
+///
+/// 数据本身按原样转发，解析器只用于日志记录：
+/// 识别帧边界、去除掩码、合并分片消息，并按limit记录负载内容。
+#[derive(Debug)]
+pub struct FrameParser {
+    /// 消息方向
+    direction: WebSocketDirection,
+    /// 解析状态
+    state: FrameState,
+    /// 尚未读完的帧头
+    header: Vec<u8>,
+    /// 当前帧是否为最后一个分片
+    fin: bool,
+    /// 当前帧是否设置了RSV1（permessage-deflate压缩）
+    compressed: bool,
+    /// 当前帧操作码
+    opcode: u8,
+    /// 当前帧掩码
+    mask: Option<[u8; 4]>,
+    /// 当前帧负载长度
+    payload_length: u64,
+    /// 正在合并的消息信息
+    message: Option<WebSocketMessage>,
+    /// 正在合并的消息负载
+    message_payload: BodyCapture,
+    /// 控制帧负载（最长125字节）
+    control_payload: Vec<u8>,
+    /// 负载记录长度限制
+    payload_limit: i64,
+}
+
+impl FrameParser {
+    /// 创建帧解析器
+    ///
+    /// # 参数
+    /// * `direction` - 消息方向
+    /// * `payload_limit` - 负载记录长度限制（0不记录，负数不限制）
+    pub fn new(direction: WebSocketDirection, payload_limit: i64) -> Self {
+        Self {
+            direction,
+            state: FrameState::Header,
+            header: Vec::with_capacity(MAX_FRAME_HEADER_SIZE),
+            fin: false,
+            compressed: false,
+            opcode: 0,
+            mask: None,
+            payload_length: 0,
+            message: None,
+            message_payload: BodyCapture::new(payload_limit),
+            control_payload: Vec::new(),
+            payload_limit,
+        }
+    }
+
+    /// 处理一段数据，返回其中完整的消息和控制帧
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<WebSocketEvent>> {
+        let mut events = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            match self.state {
+                FrameState::Header => {
+                    let needed = self.header_size_needed();
+                    let to_copy = (needed - self.header.len()).min(data.len() - pos);
+                    self.header.extend_from_slice(&data[pos..pos + to_copy]);
+                    pos += to_copy;
+
+                    // 扩展长度和掩码决定帧头总长度，可能需要继续读取
+                    if self.header.len() == self.header_size_needed() {
+                        self.parse_header()?;
+                        if self.payload_length == 0 {
+                            self.finish_frame(&mut events);
+                        }
+                    }
+                },
+
+                FrameState::Payload { remaining, offset } => {
+                    let to_read = remaining.min((data.len() - pos) as u64) as usize;
+                    let mut payload = data[pos..pos + to_read].to_vec();
+                    if let Some(mask) = self.mask {
+                        for (i, byte) in payload.iter_mut().enumerate() {
+                            *byte ^= mask[((offset + i as u64) % 4) as usize];
+                        }
+                    }
+                    pos += to_read;
+
+                    if self.opcode >= OPCODE_CLOSE {
+                        self.control_payload.extend_from_slice(&payload);
+                    } else {
+                        self.message_payload.feed(&payload);
+                    }
+
+                    let remaining = remaining - to_read as u64;
+                    self.state = FrameState::Payload { remaining, offset: offset + to_read as u64 };
+                    if remaining == 0 {
+                        self.finish_frame(&mut events);
+                    }
+                },
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// 根据已读取的帧头计算帧头总长度
+    fn header_size_needed(&self) -> usize {
+        if self.header.len() < 2 {
+            return 2;
+        }
+        let extended = match self.header[1] & 0x7F {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        2 + extended + mask
+    }
+
+    /// 解析完整的帧头
+    fn parse_header(&mut self) -> Result<()> {
+        let header = std::mem::take(&mut self.header);
+        self.fin = header[0] & 0x80 != 0;
+        self.compressed = header[0] & 0x40 != 0;
+        self.opcode = header[0] & 0x0F;
+
+        let (payload_length, mask_start) = match header[1] & 0x7F {
+            126 => (u16::from_be_bytes([header[2], header[3]]) as u64, 4),
+            127 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&header[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            },
+            length => (length as u64, 2),
+        };
+        self.payload_length = payload_length;
+        self.mask = (header[1] & 0x80 != 0).then(|| {
+            [header[mask_start], header[mask_start + 1], header[mask_start + 2], header[mask_start + 3]]
+        });
+
+        match self.opcode {
+            OPCODE_CONTINUATION if self.message.is_none() => {
+                anyhow::bail!("WebSocket continuation frame without a message");
+            },
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {},
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if payload_length > 125 || !self.fin {
+                    anyhow::bail!("Invalid WebSocket control frame");
+                }
+            },
+            opcode => anyhow::bail!("Unknown WebSocket opcode: {opcode:#x}"),
+        }
+
+        self.state = FrameState::Payload { remaining: payload_length, offset: 0 };
+        Ok(())
+    }
+
+    /// 当前帧读取完毕
+    fn finish_frame(&mut self, events: &mut Vec<WebSocketEvent>) {
+        self.state = FrameState::Header;
+
+        if self.opcode >= OPCODE_CLOSE {
+            let payload = std::mem::take(&mut self.control_payload);
+            let mut info = self.frame_info(self.opcode);
+            info.fragments = 1;
+            info.payload_length = self.payload_length;
+            if self.opcode == OPCODE_CLOSE && payload.len() >= 2 {
+                info.close_code = Some(u16::from_be_bytes([payload[0], payload[1]]));
+                events.push(WebSocketEvent::Control(info, payload[2..].to_vec()));
+            } else {
+                events.push(WebSocketEvent::Control(info, payload));
+            }
+            return;
+        }
+
+        // 数据帧：第一个分片决定消息类型
+        let info = self.frame_info(self.opcode);
+        let message = self.message.get_or_insert(info);
+        message.fragments += 1;
+        message.payload_length += self.payload_length;
+
+        if self.fin {
+            let message = self.message.take().unwrap_or_else(|| self.frame_info(self.opcode));
+            let mut payload = std::mem::replace(&mut self.message_payload, BodyCapture::new(self.payload_limit));
+            payload.finish();
+            events.push(WebSocketEvent::Message(message, payload.into_bytes()));
+        }
+    }
+
+    /// 当前帧的基本信息
+    fn frame_info(&self, opcode: u8) -> WebSocketMessage {
+        WebSocketMessage {
+            direction: self.direction,
+            opcode,
+            fragments: 0,
+            masked: self.mask.is_some(),
+            compressed: self.compressed,
+            payload_length: 0,
+            close_code: None,
+        }
+    }
+}
+
+/// WebSocket连接信息，用于日志记录
+#[derive(Clone)]
+pub struct WebSocketContext {
+    /// 目标主机
+    pub host: String,
+    /// 升级请求的完整URL
+    pub url: String,
+    /// 客户端到服务器方向的负载记录限制
+    pub request_payload_limit: i64,
+    /// 服务器到客户端方向的负载记录限制
+    pub response_payload_limit: i64,
+    /// 日志记录器
+    pub logger: Arc<DomainLogger>,
+}
+
+/// 在客户端和服务器之间转发WebSocket帧，并记录每条消息
+///
+/// # 参数
+/// * `client_stream` - 客户端流
+/// * `server_stream` - 服务器流
+/// * `client_initial` - 升级前已从客户端读取的数据
+/// * `server_initial` - 升级响应之后已从服务器读取的数据
+/// * `context` - 连接信息
+///
+/// # 返回值
+/// 返回(客户端发送字节数, 服务器发送字节数)
+pub async fn relay_websocket<C, S>(
+    client_stream: C,
+    server_stream: S,
+    client_initial: Vec<u8>,
+    server_initial: Vec<u8>,
+    context: WebSocketContext,
+) -> Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    log::info!("🔌 WEBSOCKET UPGRADE ======================================");
+    log::info!("🔗 URL: {}", context.url);
+
+    let (mut client_reader, mut client_writer) = tokio::io::split(client_stream);
+    let (mut server_reader, mut server_writer) = tokio::io::split(server_stream);
+
+    let client_to_server = relay_direction(
+        &mut client_reader,
+        &mut server_writer,
+        client_initial,
+        FrameParser::new(WebSocketDirection::ClientToServer, context.request_payload_limit),
+        &context,
+    );
+    let server_to_client = relay_direction(
+        &mut server_reader,
+        &mut client_writer,
+        server_initial,
+        FrameParser::new(WebSocketDirection::ServerToClient, context.response_payload_limit),
+        &context,
+    );
+
+    let (client_bytes, server_bytes) = tokio::try_join!(client_to_server, server_to_client)?;
+    log::info!("WebSocket to {} closed: client={client_bytes}, server={server_bytes}", context.url);
+    Ok((client_bytes, server_bytes))
+}
+
+/// 单方向转发帧数据
+async fn relay_direction<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    initial: Vec<u8>,
+    mut parser: FrameParser,
+    context: &WebSocketContext,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total_bytes = 0;
+    let mut parse_failed = false;
+    let mut data = initial;
+    let mut buffer = [0; 8192];
+
+    loop {
+        if !data.is_empty() {
+            // 解析失败后不再记录，但继续原样转发
+            if !parse_failed {
+                match parser.feed(&data) {
+                    Ok(events) => events.into_iter().for_each(|event| log_event(event, context)),
+                    Err(e) => {
+                        log::warn!("Failed to parse WebSocket frames for {}: {e}", context.url);
+                        parse_failed = true;
+                    }
+                }
+            }
+            writer.write_all(&data).await?;
+            total_bytes += data.len() as u64;
+        }
+
+        let bytes_read = reader.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            let _ = writer.shutdown().await;
+            return Ok(total_bytes);
+        }
+        data = buffer[..bytes_read].to_vec();
+    }
+}
+
+/// 记录WebSocket事件
+fn log_event(event: WebSocketEvent, context: &WebSocketContext) {
+    let (message, payload) = match event {
+        WebSocketEvent::Message(message, payload) => (message, payload),
+        WebSocketEvent::Control(message, payload) if message.opcode == OPCODE_CLOSE => (message, payload),
+        WebSocketEvent::Control(message, _) => {
+            log::debug!("WebSocket {} {} on {}", message.direction, opcode_name(message.opcode), context.url);
+            return;
+        },
+    };
+
+    log::info!(
+        "WebSocket {} {} ({} bytes) on {}",
+        message.direction,
+        opcode_name(message.opcode),
+        message.payload_length,
+        context.url
+    );
+
+    // 文本和close原因按UTF-8记录，二进制按十六进制记录
+    let payload = if message.opcode == OPCODE_BINARY {
+        payload.iter().map(|b| format!("{b:02x}")).collect()
+    } else {
+        String::from_utf8_lossy(&payload).to_string()
+    };
+
+    let log_entry = DomainLogger::create_websocket_log_entry(
+        context.host.clone(),
+        context.url.clone(),
+        message,
+        payload,
+    );
+    context.logger.log_request(log_entry);
+}
+
+/// 操作码名称
+pub fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        OPCODE_CONTINUATION => "continuation",
+        OPCODE_TEXT => "text",
+        OPCODE_BINARY => "binary",
+        OPCODE_CLOSE => "close",
+        OPCODE_PING => "ping",
+        OPCODE_PONG => "pong",
+        _ => "unknown",
+    }
+}
+
+/// 判断请求是否为WebSocket升级请求
+///
+/// # 参数
+/// * `headers` - 请求头（键为小写）
+pub fn is_websocket_upgrade(headers: &std::collections::HashMap<String, String>) -> bool {
+    headers.get("upgrade")
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造一个带掩码的客户端帧
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_parse_fragmented_masked_message() {
+        let mut data = masked_frame(false, OPCODE_TEXT, b"Hel");
+        // 分片之间插入ping控制帧
+        data.extend(masked_frame(true, OPCODE_PING, b""));
+        data.extend(masked_frame(true, OPCODE_CONTINUATION, b"lo"));
+
+        let mut parser = FrameParser::new(WebSocketDirection::ClientToServer, -1);
+        let mut events = Vec::new();
+        // 逐字节输入，覆盖帧头跨读取的情况
+        for byte in &data {
+            events.extend(parser.feed(std::slice::from_ref(byte)).unwrap());
+        }
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], WebSocketEvent::Control(info, _) if info.opcode == OPCODE_PING));
+        match &events[1] {
+            WebSocketEvent::Message(info, payload) => {
+                assert_eq!(info.opcode, OPCODE_TEXT);
+                assert_eq!(info.fragments, 2);
+                assert_eq!(info.payload_length, 5);
+                assert!(info.masked);
+                assert_eq!(payload, b"Hello");
+            },
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_unmasked_extended_length_and_close() {
+        let payload = vec![0xAB; 300];
+        let mut data = vec![0x80 | OPCODE_BINARY, 126];
+        data.extend_from_slice(&300u16.to_be_bytes());
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&[0x80 | OPCODE_CLOSE, 6, 0x03, 0xE8, b'b', b'y', b'e', b'!']);
+
+        let mut parser = FrameParser::new(WebSocketDirection::ServerToClient, 10);
+        let events = parser.feed(&data).unwrap();
+
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            WebSocketEvent::Message(info, captured) => {
+                assert_eq!(info.payload_length, 300);
+                assert!(!info.masked);
+                // 按limit截断记录
+                assert_eq!(captured.len(), 10);
+            },
+            event => panic!("unexpected event: {event:?}"),
+        }
+        match &events[1] {
+            WebSocketEvent::Control(info, reason) => {
+                assert_eq!(info.close_code, Some(1000));
+                assert_eq!(info.payload_length, 6);
+                assert_eq!(reason, b"bye!");
+            },
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let mut parser = FrameParser::new(WebSocketDirection::ClientToServer, -1);
+        assert!(parser.feed(&masked_frame(true, OPCODE_CONTINUATION, b"x")).is_err());
+
+        let mut parser = FrameParser::new(WebSocketDirection::ClientToServer, -1);
+        assert!(parser.feed(&masked_frame(true, 0x3, b"")).is_err());
+    }
+}